@@ -0,0 +1,22 @@
+//! Any text: the parser must not panic, and a matrix it accepts must print
+//! and parse back to the same bits. NaN is printed without its sign or
+//! payload, so it only has to stay NaN.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use matrix_mul::Matrix;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(text) = std::str::from_utf8(data) else {
+        return;
+    };
+    let Ok(matrix) = Matrix::try_from_str(text) else {
+        return;
+    };
+    let again = Matrix::try_from_str(&matrix.to_string()).expect("printed matrix parses");
+    assert_eq!(again.shape(), matrix.shape());
+    for (x, y) in matrix.data().iter().zip(again.data()) {
+        assert!(x.to_bits() == y.to_bits() || (x.is_nan() && y.is_nan()), "{} printed as {}", x, y);
+    }
+});
@@ -0,0 +1,49 @@
+//! Example matrix-mul plugin: the (max, min) semiring used to compose fuzzy
+//! relations. Build with `cargo build -p fuzzy-max-min` and run
+//! `cargo run --features plugins -- --plugin target/debug/libfuzzy_max_min.so ...`.
+
+use std::panic::{self, UnwindSafe};
+
+/// Must match `PluginV1` in matrix-mul's src/plugin.rs.
+#[repr(C)]
+pub struct PluginV1 {
+    pub abi_version: u32,
+    pub identity: f64,
+    pub combine: extern "C" fn(f64, f64, *mut f64) -> i32,
+    pub reduce: extern "C" fn(f64, f64, *mut f64) -> i32,
+}
+
+// A panic must not unwind into the host, so every entry point catches it
+// and returns a nonzero status instead.
+fn guarded(out: *mut f64, f: impl FnOnce() -> f64 + UnwindSafe) -> i32 {
+    match panic::catch_unwind(f) {
+        Ok(value) => {
+            // The host always passes a valid pointer.
+            unsafe { *out = value };
+            0
+        }
+        Err(_) => 1,
+    }
+}
+
+// Memberships are in [0, 1]; a NaN means the input is broken.
+extern "C" fn combine(a: f64, b: f64, out: *mut f64) -> i32 {
+    guarded(out, || {
+        assert!(!a.is_nan() && !b.is_nan(), "membership is NaN");
+        a.min(b)
+    })
+}
+
+extern "C" fn reduce(acc: f64, x: f64, out: *mut f64) -> i32 {
+    guarded(out, || acc.max(x))
+}
+
+#[no_mangle]
+pub extern "C" fn matrix_mul_plugin_v1() -> PluginV1 {
+    PluginV1 {
+        abi_version: 1,
+        identity: 0.0,
+        combine,
+        reduce,
+    }
+}
@@ -0,0 +1,317 @@
+//! `--op accuracy-report`: how far each multiply kernel lands from an
+//! accurate reference.
+//!
+//! The reference sums every dot product with compensated summation and
+//! exact products (Ogita, Rump and Oishi's Dot2), which is as accurate as a
+//! dot product computed in twice the working precision. Each kernel in
+//! `ALGORITHMS` is compared with it element by element.
+
+use std::fmt;
+
+use clap::clap_derive::ArgEnum;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    blocked::DEFAULT_BLOCK_SIZE, semiring::Arithmetic, strassen, CancelToken, Context, Matrix, MultiplyOptions, Orientation,
+};
+
+/// A kernel the report covers.
+pub(crate) struct Registered {
+    pub(crate) name: &'static str,
+    pub(crate) multiply: fn(&Matrix, &Matrix) -> Matrix,
+    /// Results of an experimental kernel are spot-checked after every CLI
+    /// run, even without --spot-check.
+    pub(crate) experimental: bool,
+}
+
+/// Whether the kernel the CLI reports as `name` is experimental.
+pub fn is_experimental(name: &str) -> bool {
+    ALGORITHMS.iter().any(|algorithm| algorithm.name == name && algorithm.experimental)
+}
+
+/// Every kernel computing the ordinary product of two general matrices.
+pub(crate) const ALGORITHMS: &[Registered] = &[
+    Registered {
+        name: "seq",
+        multiply: |a, b| a.multiply_plain(b),
+        experimental: false,
+    },
+    Registered {
+        name: "par",
+        multiply: |a, b| a.multiply_par_plain(b),
+        experimental: false,
+    },
+    Registered {
+        name: "rows",
+        multiply: |a, b| a.multiply_fused(b, &CancelToken::new(), &[], &Context::new()).unwrap(),
+        experimental: false,
+    },
+    Registered {
+        name: "packed",
+        multiply: |a, b| {
+            let options = MultiplyOptions::new().orientation(Orientation::Packed).shortcuts(false);
+            a.multiply_with(b, &options).unwrap()
+        },
+        experimental: false,
+    },
+    Registered {
+        name: "transposed-view",
+        multiply: |a, b| a.multiply_by_transpose(&b.transpose(), &MultiplyOptions::new()).unwrap().0,
+        experimental: false,
+    },
+    Registered {
+        name: "semiring",
+        multiply: |a, b| a.multiply_semiring(b, &Arithmetic).unwrap(),
+        experimental: false,
+    },
+    Registered {
+        name: "blocked",
+        multiply: |a, b| a.multiply_blocked_par(b, DEFAULT_BLOCK_SIZE).unwrap(),
+        experimental: false,
+    },
+    Registered {
+        name: "strassen",
+        multiply: |a, b| a.multiply_strassen_par(b, strassen::DEFAULT_CUTOFF).unwrap(),
+        experimental: true,
+    },
+];
+
+/// Inputs generated to stress the kernels in different ways.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ArgEnum)]
+pub enum Regime {
+    /// Uniform in [0, 1): no cancellation, every kernel should be close.
+    WellConditioned,
+    /// Hilbert entries 1/(i+j+1) on the left, the same with random signs on
+    /// the right, so dot products cancel.
+    Hilbert,
+    /// Random signs and magnitudes spread over 20 orders of magnitude.
+    DynamicRange,
+}
+
+impl Regime {
+    /// An `rows x inner` and an `inner x cols` operand.
+    pub fn generate(self, rows: usize, inner: usize, cols: usize, seed: u64) -> (Matrix, Matrix) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut fill = |rows: usize, cols: usize, f: &mut dyn FnMut(&mut StdRng, usize, usize) -> f64| {
+            let mut data = Vec::with_capacity(rows * cols);
+            for i in 0..rows {
+                for j in 0..cols {
+                    data.push(f(&mut rng, i, j));
+                }
+            }
+            Matrix::new_unchecked(rows, cols, data)
+        };
+        let sign = |rng: &mut StdRng| if rng.gen::<bool>() { 1.0 } else { -1.0 };
+        match self {
+            Regime::WellConditioned => (
+                fill(rows, inner, &mut |rng, _, _| rng.gen()),
+                fill(inner, cols, &mut |rng, _, _| rng.gen()),
+            ),
+            Regime::Hilbert => (
+                fill(rows, inner, &mut |_, i, j| 1.0 / (i + j + 1) as f64),
+                fill(inner, cols, &mut |rng, i, j| sign(rng) / (i + j + 1) as f64),
+            ),
+            Regime::DynamicRange => {
+                let mut value = |rng: &mut StdRng, _, _| sign(rng) * rng.gen::<f64>() * 10f64.powf(rng.gen_range(-10.0..10.0));
+                (fill(rows, inner, &mut value), fill(inner, cols, &mut value))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Regime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Regime::WellConditioned => "well-conditioned",
+            Regime::Hilbert => "hilbert",
+            Regime::DynamicRange => "dynamic-range",
+        })
+    }
+}
+
+// a + b as a rounded sum and its exact rounding error.
+fn two_sum(a: f64, b: f64) -> (f64, f64) {
+    let s = a + b;
+    let z = s - a;
+    (s, (a - (s - z)) + (b - z))
+}
+
+/// The dot product of the pairs, summed by Dot2.
+pub(crate) fn compensated_dot(pairs: impl Iterator<Item = (f64, f64)>) -> f64 {
+    let (mut sum, mut error) = (0.0, 0.0);
+    for (a, b) in pairs {
+        let product = a * b;
+        let (s, e) = two_sum(sum, product);
+        sum = s;
+        error += e + a.mul_add(b, -product);
+    }
+    sum + error
+}
+
+impl Matrix {
+    /// The product with every element summed by Dot2.
+    pub(crate) fn multiply_compensated(&self, other: &Matrix) -> Matrix {
+        assert_eq!(self.cols, other.rows);
+
+        let mut result = Matrix::new_unchecked(self.rows, other.cols, vec![0.0; self.rows * other.cols]);
+        for i in 0..self.rows {
+            for j in 0..other.cols {
+                result.set(i, j, compensated_dot((0..self.cols).map(|k| (self.get(i, k), other.get(k, j)))));
+            }
+        }
+        result
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct AccuracyRow {
+    pub(crate) algorithm: &'static str,
+    pub(crate) max_abs: f64,
+    /// Relative to the reference element; infinite where the reference is
+    /// zero and the result is not.
+    pub(crate) max_rel: f64,
+    /// Element with the largest relative error, if any differs.
+    pub(crate) worst: Option<(usize, usize)>,
+}
+
+impl AccuracyRow {
+    fn compare(algorithm: &'static str, result: &Matrix, reference: &Matrix) -> AccuracyRow {
+        let mut row = AccuracyRow {
+            algorithm,
+            max_abs: 0.0,
+            max_rel: 0.0,
+            worst: None,
+        };
+        for (index, (&x, &r)) in result.data.iter().zip(&reference.data).enumerate() {
+            let abs = (x - r).abs();
+            let rel = if abs == 0.0 { 0.0 } else { abs / r.abs() };
+            row.max_abs = row.max_abs.max(abs);
+            if rel > row.max_rel || (rel.is_nan() && row.worst.is_none()) {
+                row.max_rel = rel;
+                row.worst = Some((index / reference.cols, index % reference.cols));
+            }
+        }
+        row
+    }
+}
+
+/// One table of the report, for one pair of inputs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccuracyReport {
+    pub(crate) title: String,
+    pub(crate) rows: Vec<AccuracyRow>,
+}
+
+impl AccuracyReport {
+    /// Runs every kernel in `ALGORITHMS` on `a` and `b`. Returns the report
+    /// and the reference product.
+    pub fn run(title: String, a: &Matrix, b: &Matrix) -> (AccuracyReport, Matrix) {
+        let reference = a.multiply_compensated(b);
+        let rows = ALGORITHMS
+            .iter()
+            .map(|algorithm| AccuracyRow::compare(algorithm.name, &(algorithm.multiply)(a, b), &reference))
+            .collect();
+        (AccuracyReport { title, rows }, reference)
+    }
+}
+
+impl fmt::Display for AccuracyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.title)?;
+        writeln!(f, "{:<16} {:>14} {:>14}  worst element", "algorithm", "max abs error", "max rel error")?;
+        for row in &self.rows {
+            let worst = match row.worst {
+                Some((i, j)) => format!("({}, {})", i, j),
+                None => "-".to_owned(),
+            };
+            writeln!(f, "{:<16} {:>14.3e} {:>14.3e}  {}", row.algorithm, row.max_abs, row.max_rel, worst)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The ill-conditioned inputs and their product, from testdata.
+    fn fixture() -> (Matrix, Matrix, Matrix) {
+        let load = |name: &str| {
+            let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join(name);
+            let (rows, cols, data) = crate::npy::read(&std::fs::read(path).unwrap()).unwrap();
+            Matrix::new_unchecked(rows, cols, data)
+        };
+        (load("ill_conditioned_a.npy"), load("ill_conditioned_b.npy"), load("ill_conditioned_c.npy"))
+    }
+
+    #[test]
+    fn compensated_reference_is_exact_on_cancellation() {
+        let a = Matrix::new_unchecked(1, 3, vec![1e16, 1.0, -1e16]);
+        let b = Matrix::new_unchecked(3, 1, vec![1.0, 1.0, 1.0]);
+        assert_eq!(a.multiply(&b).unwrap().data, vec![0.0]);
+        assert_eq!(a.multiply_compensated(&b).data, vec![1.0]);
+    }
+
+    #[test]
+    fn report_covers_every_algorithm() {
+        for regime in [Regime::WellConditioned, Regime::Hilbert, Regime::DynamicRange] {
+            let (a, b) = regime.generate(5, 7, 3, 1);
+            assert_eq!((a.shape(), b.shape()), ((5, 7), (7, 3)));
+            assert_eq!(regime.generate(5, 7, 3, 1), (a.clone(), b.clone()));
+
+            let (report, reference) = AccuracyReport::run(regime.to_string(), &a, &b);
+            assert_eq!(reference, a.multiply_compensated(&b));
+            let names: Vec<&str> = report.rows.iter().map(|row| row.algorithm).collect();
+            assert_eq!(names, ALGORITHMS.iter().map(|algorithm| algorithm.name).collect::<Vec<_>>());
+            for name in ["seq", "par", "blocked", "strassen"] {
+                assert!(names.contains(&name), "{:?}", names);
+            }
+
+            let table = report.to_string();
+            for name in names {
+                assert!(table.lines().any(|line| line.starts_with(name)), "{}", table);
+            }
+        }
+    }
+
+    #[test]
+    fn reference_beats_naive_on_ill_conditioned_fixture() {
+        let (a, b, exact) = fixture();
+
+        let naive = AccuracyRow::compare("seq", &a.multiply(&b).unwrap(), &exact);
+        let reference = AccuracyRow::compare("reference", &a.multiply_compensated(&b), &exact);
+        assert!(reference.max_abs <= naive.max_abs, "{:?} {:?}", reference, naive);
+    }
+
+    #[test]
+    fn naive_is_no_worse_than_strassen_on_ill_conditioned_fixture() {
+        let (a, b, exact) = fixture();
+        let naive = AccuracyRow::compare("seq", &a.multiply(&b).unwrap(), &exact);
+        // 20x20 times 20x13 is below the default cutoff, so only smaller
+        // cutoffs recurse; each is strictly worse than the naive sum here.
+        for cutoff in [1, 4, 8] {
+            let product = a.multiply_strassen_par(&b, cutoff).unwrap();
+            let strassen = AccuracyRow::compare("strassen", &product, &exact);
+            assert!(naive.max_abs <= strassen.max_abs, "cutoff {}: {:?} {:?}", cutoff, naive, strassen);
+        }
+
+        // The report's strassen row is the kernel at its default cutoff.
+        let (report, _) = AccuracyReport::run("ill-conditioned".to_owned(), &a, &b);
+        let row = report.rows.iter().find(|row| row.algorithm == "strassen").unwrap();
+        let product = a.multiply_strassen_par(&b, strassen::DEFAULT_CUTOFF).unwrap();
+        let reference = a.multiply_compensated(&b);
+        assert_eq!(*row, AccuracyRow::compare("strassen", &product, &reference));
+        assert!(is_experimental("strassen") && !is_experimental("seq"));
+    }
+
+    #[test]
+    fn worst_element() {
+        let reference = Matrix::new_unchecked(1, 3, vec![1.0, 0.0, 4.0]);
+        let row = AccuracyRow::compare("x", &Matrix::new_unchecked(1, 3, vec![1.5, 0.0, 5.0]), &reference);
+        assert_eq!((row.max_abs, row.max_rel, row.worst), (1.0, 0.5, Some((0, 0))));
+        let row = AccuracyRow::compare("x", &reference, &reference);
+        assert_eq!((row.max_abs, row.max_rel, row.worst), (0.0, 0.0, None));
+        let row = AccuracyRow::compare("x", &Matrix::new_unchecked(1, 3, vec![1.0, 1e-300, 4.0]), &reference);
+        assert_eq!((row.max_rel, row.worst), (f64::INFINITY, Some((0, 1))));
+    }
+}
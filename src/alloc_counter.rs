@@ -0,0 +1,82 @@
+//! Test-only global allocator that counts allocations of a given size made
+//! by the current thread, and the most memory it held at once.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+
+struct CountingAllocator;
+
+thread_local! {
+    static WATCHED_SIZE: Cell<usize> = const { Cell::new(usize::MAX) };
+    static COUNT: Cell<usize> = const { Cell::new(0) };
+    // Bytes allocated minus bytes freed by this thread, and the highest
+    // that has been since `peak_bytes` started watching.
+    static LIVE: Cell<isize> = const { Cell::new(0) };
+    static PEAK: Cell<isize> = const { Cell::new(0) };
+}
+
+fn record(layout: Layout) {
+    let _ = WATCHED_SIZE.try_with(|watched| {
+        if watched.get() == layout.size() {
+            let _ = COUNT.try_with(|count| count.set(count.get() + 1));
+        }
+    });
+}
+
+fn track(delta: isize) {
+    let _ = LIVE.try_with(|live| {
+        live.set(live.get() + delta);
+        let _ = PEAK.try_with(|peak| peak.set(peak.get().max(live.get())));
+    });
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        record(layout);
+        track(layout.size() as isize);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        record(layout);
+        track(layout.size() as isize);
+        unsafe { System.alloc_zeroed(layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        record(Layout::from_size_align(new_size, layout.align()).unwrap());
+        track(new_size as isize - layout.size() as isize);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        track(-(layout.size() as isize));
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Runs `f` and returns how many allocations of exactly `size` bytes it
+/// made on the calling thread.
+pub fn count_allocations<R>(size: usize, f: impl FnOnce() -> R) -> (usize, R) {
+    let previous = WATCHED_SIZE.with(|watched| watched.replace(size));
+    let before = COUNT.with(|count| count.get());
+    let result = f();
+    let after = COUNT.with(|count| count.get());
+    WATCHED_SIZE.with(|watched| watched.set(previous));
+    (after - before, result)
+}
+
+/// Runs `f` and returns the most memory the calling thread held at once
+/// while it ran, beyond what it held before, in bytes.
+pub fn peak_bytes<R>(f: impl FnOnce() -> R) -> (usize, R) {
+    let before = LIVE.with(|live| live.get());
+    let previous = PEAK.with(|peak| peak.replace(before));
+    let result = f();
+    let peak = PEAK.with(|peak| peak.replace(previous.max(peak.get())));
+    ((peak - before).max(0) as usize, result)
+}
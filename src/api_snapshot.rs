@@ -0,0 +1,224 @@
+//! The public API, as read off the source, against the copy committed in
+//! testdata/public-api.txt, so that changing it is never an accident.
+//!
+//! What counts is what a downstream crate can name: the root items that are
+//! not `#[doc(hidden)]`, and for each of those types its public methods,
+//! fields, variants and trait impls, wherever in the crate they are. The
+//! modules the CLI uses are hidden and left out. This reads the text rather
+//! than the compiler's view, so it expects the layout the crate already
+//! uses: items at column 0, members indented by four spaces.
+//!
+//! After an intended change, `UPDATE_PUBLIC_API=1 cargo test api_snapshot`
+//! rewrites the snapshot; its diff is then the API change to review.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::Path,
+};
+
+type Sections = BTreeMap<String, BTreeSet<String>>;
+
+// The CLI's own files; everything else in src/ is the library.
+const BINARY_FILES: [&str; 2] = ["main.rs", "wizard.rs"];
+
+fn normalize(text: &str) -> String {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    let text = text.replace("( ", "(").replace(", )", ")").replace(",)", ")");
+    text.trim_end_matches('{').trim_end_matches(';').trim().to_owned()
+}
+
+// `Foo` in `pub struct Foo<T> {`, `pub fn foo(`, `pub use a::{b, c as Foo};`...
+fn declared_names(decl: &str) -> Vec<String> {
+    let ident = |s: &str| s.chars().take_while(|c| c.is_alphanumeric() || *c == '_').collect::<String>();
+    if let Some(path) = decl.strip_prefix("pub use ") {
+        let list = match path.split_once('{') {
+            Some((_, list)) => list.trim_end_matches('}'),
+            None => path.rsplit("::").next().unwrap(),
+        };
+        return list
+            .split(',')
+            .map(|item| ident(item.rsplit(" as ").next().unwrap().trim()))
+            .filter(|name| !name.is_empty())
+            .collect();
+    }
+    let kind_then_name = decl.split_whitespace().nth(2).unwrap_or("");
+    vec![ident(kind_then_name)]
+}
+
+// The type an `impl` line is for: `Matrix` in `impl<'a> Mul<&'a Matrix> for &'a Matrix {`.
+fn impl_target(header: &str) -> String {
+    let mut target = match header.rsplit_once(" for ") {
+        Some((_, target)) => target,
+        None => {
+            let rest = header["impl".len()..].trim_start();
+            if rest.starts_with('<') {
+                let mut depth = 0;
+                let end = rest
+                    .char_indices()
+                    .find(|&(_, c)| {
+                        depth += match c {
+                            '<' => 1,
+                            '>' => -1,
+                            _ => 0,
+                        };
+                        depth == 0
+                    })
+                    .map_or(rest.len(), |(i, _)| i + 1);
+                &rest[end..]
+            } else {
+                rest
+            }
+        }
+    }
+    .trim()
+    .trim_start_matches('&');
+    if target.starts_with('\'') {
+        target = target.split_once(' ').map_or("", |(_, rest)| rest);
+    }
+    let path: String = target.chars().take_while(|c| c.is_alphanumeric() || *c == '_' || *c == ':').collect();
+    path.rsplit("::").next().unwrap().to_owned()
+}
+
+// The root items of lib.rs that are not hidden, and the names they declare.
+fn root_items(lib: &str, sections: &mut Sections) -> BTreeSet<String> {
+    let mut names = BTreeSet::new();
+    let (mut hidden, mut macro_export) = (false, false);
+    for line in lib.lines() {
+        if line.starts_with("#[") {
+            hidden |= line == "#[doc(hidden)]";
+            macro_export |= line == "#[macro_export]";
+            continue;
+        }
+        if line.starts_with("pub ") && !hidden {
+            let decl = normalize(line);
+            names.extend(declared_names(&decl));
+            sections.entry("crate".to_owned()).or_default().insert(decl);
+        } else if let Some(name) = line.strip_prefix("macro_rules! ").filter(|_| macro_export) {
+            sections.entry("crate".to_owned()).or_default().insert(format!("macro {}!", normalize(name)));
+        }
+        if !line.starts_with("//") {
+            hidden = false;
+            macro_export = false;
+        }
+    }
+    names
+}
+
+// Members of the public types, from one file.
+fn members(source: &str, public: &BTreeSet<String>, sections: &mut Sections) {
+    let mut section: Option<(String, bool)> = None;
+    let mut lines = source.lines();
+    while let Some(line) = lines.next() {
+        if line == "}" {
+            section = None;
+            continue;
+        }
+        if !line.starts_with(' ') {
+            let decl = normalize(line);
+            let (name, methods) = if decl.starts_with("impl") {
+                // A trait impl's methods are the trait's.
+                (impl_target(&decl), !decl.contains(" for "))
+            } else if ["pub struct ", "pub enum ", "pub trait "].iter().any(|kind| decl.starts_with(kind)) {
+                (declared_names(&decl).remove(0), true)
+            } else {
+                continue;
+            };
+            if public.contains(&name) && line.ends_with('{') {
+                sections.entry(decl.clone()).or_default();
+                section = Some((decl, methods));
+            }
+            continue;
+        }
+        let Some((header, methods)) = &section else {
+            continue;
+        };
+        let Some(member) = line.strip_prefix("    ").filter(|m| !m.starts_with(' ')) else {
+            continue;
+        };
+        let is_trait = header.starts_with("pub trait ");
+        let is_enum = header.starts_with("pub enum ");
+        let wanted = member.starts_with("pub fn ")
+            || member.starts_with("pub const ")
+            || (header.starts_with("pub struct ") && member.starts_with("pub "))
+            || (is_trait && member.starts_with("fn "))
+            || (is_enum && member.starts_with(|c: char| c.is_ascii_uppercase()));
+        if !*methods || !wanted {
+            continue;
+        }
+        // Signatures that go over several lines end at their body or `;`.
+        let mut signature = member.to_owned();
+        if member.contains(" fn ") || member.starts_with("fn ") {
+            while !signature.contains('{') && !signature.ends_with(';') {
+                match lines.next() {
+                    Some(next) => signature = format!("{} {}", signature, next.trim()),
+                    None => break,
+                }
+            }
+            signature = signature.split(" {").next().unwrap().to_owned();
+        } else if is_enum {
+            signature = normalize(member.split([' ', '(', ',']).next().unwrap());
+        }
+        sections.get_mut(header).unwrap().insert(normalize(signature.trim_end_matches(',')));
+    }
+}
+
+fn snapshot(src: &Path) -> String {
+    let mut sections = Sections::new();
+    let public = root_items(&fs::read_to_string(src.join("lib.rs")).unwrap(), &mut sections);
+    let mut files: Vec<_> = fs::read_dir(src)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "rs"))
+        .filter(|path| !BINARY_FILES.iter().any(|name| path.ends_with(name)))
+        .collect();
+    files.sort();
+    for path in files {
+        members(&fs::read_to_string(path).unwrap(), &public, &mut sections);
+    }
+
+    let mut text = String::new();
+    for (header, members) in sections {
+        text.push_str(&header);
+        text.push('\n');
+        for member in members {
+            text.push_str("    ");
+            text.push_str(&member);
+            text.push('\n');
+        }
+    }
+    text
+}
+
+#[test]
+fn public_api_is_unchanged() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let path = root.join("testdata").join("public-api.txt");
+    let current = snapshot(&root.join("src"));
+    if std::env::var_os("UPDATE_PUBLIC_API").is_some() {
+        fs::write(&path, &current).unwrap();
+        return;
+    }
+    let committed = fs::read_to_string(&path).unwrap_or_default();
+    if committed != current {
+        let removed: Vec<&str> = committed.lines().filter(|line| !current.lines().any(|l| l == *line)).collect();
+        let added: Vec<&str> = current.lines().filter(|line| !committed.lines().any(|l| l == *line)).collect();
+        panic!(
+            "the public API changed; if that is intended, rerun with UPDATE_PUBLIC_API=1 and commit {}\nremoved:\n{}\nadded:\n{}",
+            path.display(),
+            removed.join("\n"),
+            added.join("\n")
+        );
+    }
+}
+
+#[test]
+fn reads_declarations() {
+    assert_eq!(declared_names("pub use context::{Context, LogEvent}"), ["Context", "LogEvent"]);
+    assert_eq!(declared_names("pub use MatrixError as Error"), ["Error"]);
+    assert_eq!(declared_names("pub struct Number(pub f64, pub Precision)"), ["Number"]);
+    assert_eq!(impl_target("impl Matrix"), "Matrix");
+    assert_eq!(impl_target("impl<'a> std::ops::Mul<&'a Matrix> for &'a Matrix"), "Matrix");
+    assert_eq!(impl_target("impl<T: Into<f64>> From<Vec<T>> for Matrix"), "Matrix");
+    assert_eq!(impl_target("impl<'a> MatrixView<'a>"), "MatrixView");
+}
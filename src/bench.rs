@@ -0,0 +1,771 @@
+//! `--op bench`: repeated timings of each algorithm, saved as named
+//! baselines and compared against them later.
+//!
+//! Each algorithm runs `--warmup` times untimed, which pays for page faults
+//! and the allocator's first requests, and then `--runs` times timed.
+//!
+//! A baseline is a JSON file under `.matrix-mul/baselines/` in the working
+//! directory:
+//!
+//! `{"version":1,"cpu":"...","threads":8,"cases":[{"shape":"64x64x64",
+//! "algo":"par","threads":4,"runs":10,"median_s":0.0012,"mad_s":0.00003}]}`
+//!
+//! A case regressed when its new median exceeds the baseline median by more
+//! than `Threshold::percent` plus `Threshold::mads` times the baseline's
+//! median absolute deviation.
+//!
+//! `--phases parse,format` also times reading and writing each file format
+//! on an in-memory buffer, made once per run, as cases named after the
+//! phase and format (`parse-csv`) on the shape of that matrix. They go into
+//! baselines and comparisons like the multiply cases.
+
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    events::quote,
+    numfmt::{format_f64_roundtrip, parse_f64},
+    Matrix, MatrixError, Precision,
+};
+
+pub const BASELINE_VERSION: u32 = 1;
+
+/// Times one run of a closure. Tests use a fake one.
+pub trait Clock {
+    fn time(&mut self, f: &mut dyn FnMut()) -> Duration;
+}
+
+pub struct WallClock;
+
+impl Clock for WallClock {
+    fn time(&mut self, f: &mut dyn FnMut()) -> Duration {
+        let start = Instant::now();
+        f();
+        start.elapsed()
+    }
+}
+
+/// Timing distribution of one algorithm on one shape.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BenchCase {
+    /// "NxMxK" for an n x m by m x k multiply.
+    pub shape: String,
+    pub algo: String,
+    pub threads: usize,
+    pub runs: usize,
+    /// In seconds.
+    pub median: f64,
+    pub mad: f64,
+}
+
+impl BenchCase {
+    pub fn new(shape: String, algo: &str, threads: usize, stats: &BenchStats) -> BenchCase {
+        BenchCase {
+            shape,
+            algo: algo.to_owned(),
+            threads,
+            runs: stats.runs,
+            median: stats.median,
+            mad: stats.mad,
+        }
+    }
+
+    fn key(&self) -> (&str, &str, usize) {
+        (&self.shape, &self.algo, self.threads)
+    }
+}
+
+/// The timed runs of one case, in seconds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BenchStats {
+    pub runs: usize,
+    pub min: f64,
+    pub mean: f64,
+    /// Sample standard deviation; 0 for a single run.
+    pub stddev: f64,
+    pub median: f64,
+    pub mad: f64,
+}
+
+impl BenchStats {
+    pub fn new(times: Vec<f64>) -> BenchStats {
+        let runs = times.len();
+        let min = times.iter().copied().fold(f64::INFINITY, f64::min);
+        let mean = times.iter().sum::<f64>() / runs as f64;
+        let stddev = if runs > 1 {
+            (times.iter().map(|t| (t - mean).powi(2)).sum::<f64>() / (runs - 1) as f64).sqrt()
+        } else {
+            0.0
+        };
+        let (median, mad) = median_and_mad(times);
+        BenchStats { runs, min, mean, stddev, median, mad }
+    }
+}
+
+/// Calls `f` `warmup` times, then `runs` (at least one) times under `clock`,
+/// and returns the timings of the latter with what the last call returned.
+pub fn bench<T>(clock: &mut impl Clock, runs: usize, warmup: usize, mut f: impl FnMut() -> T) -> (BenchStats, T) {
+    for _ in 0..warmup {
+        f();
+    }
+    let mut last = None;
+    let times = (0..runs.max(1)).map(|_| clock.time(&mut || last = Some(f())).as_secs_f64()).collect();
+    (BenchStats::new(times), last.unwrap())
+}
+
+/// One line per case with its statistics, and the rate that the mean is
+/// for a multiply of `flops` floating-point operations.
+pub fn table(cases: &[(&str, BenchStats)], flops: f64) -> String {
+    let ms = |s: f64| format!("{:.3} ms", s * 1000.0);
+    let mut text = format!(
+        "{:<20} {:>5} {:>12} {:>12} {:>12} {:>12} {:>9}\n",
+        "algo", "runs", "min", "mean", "stddev", "median", "GFLOP/s"
+    );
+    for (algo, stats) in cases {
+        text.push_str(&format!(
+            "{:<20} {:>5} {:>12} {:>12} {:>12} {:>12} {:>9.3}\n",
+            algo,
+            stats.runs,
+            ms(stats.min),
+            ms(stats.mean),
+            ms(stats.stddev),
+            ms(stats.median),
+            flops / stats.mean / 1e9
+        ));
+    }
+    text
+}
+
+/// The formats `--phases parse,format` times, as `--input-format` names them.
+pub const IO_FORMATS: [&str; 3] = ["text", "csv", "bin"];
+
+/// The timings of parsing or formatting one buffer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IoCase {
+    /// `parse-text`, `format-bin`...
+    pub name: String,
+    pub stats: BenchStats,
+    /// Of the formatted matrix.
+    pub bytes: usize,
+    pub elements: usize,
+}
+
+impl IoCase {
+    /// At the mean time, as `table` rates multiplies.
+    pub fn mb_per_s(&self) -> f64 {
+        self.bytes as f64 / self.stats.mean / 1e6
+    }
+
+    pub fn elements_per_s(&self) -> f64 {
+        self.elements as f64 / self.stats.mean
+    }
+}
+
+fn format_into(format: &str, m: &Matrix, out: &mut Vec<u8>) -> io::Result<()> {
+    match format {
+        "csv" => m.write_csv(out, Precision::Full),
+        "bin" => m.write_binary(out),
+        _ => m.write_text(out, Precision::Full),
+    }
+}
+
+fn parse_from(format: &str, bytes: &[u8]) -> Result<Matrix, MatrixError> {
+    let text = || std::str::from_utf8(bytes).map_err(|err| MatrixError::Message(err.to_string()));
+    match format {
+        "csv" => Matrix::from_csv(text()?),
+        "bin" => Matrix::read_binary(bytes),
+        _ => Matrix::try_from_str(text()?),
+    }
+}
+
+/// Times writing `m` in each of `formats` to memory when `format` is set,
+/// and reading it back when `parse` is, in that order per format. Every
+/// parse must give back `m`, which full precision guarantees.
+pub fn bench_io(
+    clock: &mut impl Clock,
+    runs: usize,
+    warmup: usize,
+    m: &Matrix,
+    formats: &[&str],
+    parse: bool,
+    format: bool,
+) -> Result<Vec<IoCase>, MatrixError> {
+    let mut cases = Vec::new();
+    for &name in formats {
+        let mut buffer = Vec::new();
+        format_into(name, m, &mut buffer)?;
+        let case = |phase: &str, stats| IoCase {
+            name: format!("{}-{}", phase, name),
+            stats,
+            bytes: buffer.len(),
+            elements: m.data.len(),
+        };
+        if format {
+            let mut out = Vec::with_capacity(buffer.len());
+            let (stats, written) = bench(clock, runs, warmup, || {
+                out.clear();
+                format_into(name, m, &mut out)
+            });
+            written?;
+            cases.push(case("format", stats));
+        }
+        if parse {
+            let (stats, parsed) = bench(clock, runs, warmup, || parse_from(name, &buffer));
+            if parsed? != *m {
+                return Err(MatrixError::Message(format!("{} did not read back the matrix it wrote", name)));
+            }
+            cases.push(case("parse", stats));
+        }
+    }
+    Ok(cases)
+}
+
+/// `table` for parse and format cases, with their rates in bytes and
+/// elements.
+pub fn io_table(cases: &[IoCase]) -> String {
+    let ms = |s: f64| format!("{:.3} ms", s * 1000.0);
+    let mut text = format!(
+        "{:<20} {:>5} {:>12} {:>12} {:>12} {:>9} {:>12}\n",
+        "phase", "runs", "min", "mean", "median", "MB/s", "elements/s"
+    );
+    for case in cases {
+        text.push_str(&format!(
+            "{:<20} {:>5} {:>12} {:>12} {:>12} {:>9.1} {:>12.3e}\n",
+            case.name,
+            case.stats.runs,
+            ms(case.stats.min),
+            ms(case.stats.mean),
+            ms(case.stats.median),
+            case.mb_per_s(),
+            case.elements_per_s()
+        ));
+    }
+    text
+}
+
+fn median(sorted: &[f64]) -> f64 {
+    let mid = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        (sorted[mid - 1] + sorted[mid]) / 2.0
+    } else {
+        sorted[mid]
+    }
+}
+
+pub fn median_and_mad(mut times: Vec<f64>) -> (f64, f64) {
+    times.sort_by(f64::total_cmp);
+    let m = median(&times);
+    let mut deviations: Vec<f64> = times.iter().map(|t| (t - m).abs()).collect();
+    deviations.sort_by(f64::total_cmp);
+    (m, median(&deviations))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Baseline {
+    pub cpu: String,
+    /// Cores available to the run that recorded it.
+    pub threads: usize,
+    pub cases: Vec<BenchCase>,
+}
+
+impl Baseline {
+    pub fn new(cases: Vec<BenchCase>) -> Baseline {
+        Baseline {
+            cpu: cpu_model(),
+            threads: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            cases,
+        }
+    }
+
+    pub fn path(name: &str) -> PathBuf {
+        Path::new(".matrix-mul").join("baselines").join(format!("{}.json", name))
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, self.to_json())
+    }
+
+    pub fn load(path: &Path) -> io::Result<Baseline> {
+        let text = fs::read_to_string(path)?;
+        Baseline::from_json(&text).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    pub fn to_json(&self) -> String {
+        let cases: Vec<String> = self
+            .cases
+            .iter()
+            .map(|case| {
+                format!(
+                    "{{\"shape\":{},\"algo\":{},\"threads\":{},\"runs\":{},\"median_s\":{},\"mad_s\":{}}}",
+                    quote(&case.shape),
+                    quote(&case.algo),
+                    case.threads,
+                    case.runs,
+                    format_f64_roundtrip(case.median),
+                    format_f64_roundtrip(case.mad)
+                )
+            })
+            .collect();
+        format!(
+            "{{\"version\":{},\"cpu\":{},\"threads\":{},\"cases\":[{}]}}\n",
+            BASELINE_VERSION,
+            quote(&self.cpu),
+            self.threads,
+            cases.join(",")
+        )
+    }
+
+    pub fn from_json(text: &str) -> Result<Baseline, String> {
+        let value = Json::parse(text)?;
+        let version = value.get("version").and_then(Json::as_f64);
+        if version != Some(BASELINE_VERSION as f64) {
+            return Err(format!("unsupported baseline version {:?}", version));
+        }
+
+        let mut cases = Vec::new();
+        for case in field(&value, "cases")?.as_array().ok_or("\"cases\" is not an array")? {
+            cases.push(BenchCase {
+                shape: string(case, "shape")?,
+                algo: string(case, "algo")?,
+                threads: number(case, "threads")? as usize,
+                runs: number(case, "runs")? as usize,
+                median: number(case, "median_s")?,
+                mad: number(case, "mad_s")?,
+            });
+        }
+        Ok(Baseline {
+            cpu: string(&value, "cpu")?,
+            threads: number(&value, "threads")? as usize,
+            cases,
+        })
+    }
+}
+
+fn field<'a>(value: &'a Json, name: &str) -> Result<&'a Json, String> {
+    value.get(name).ok_or_else(|| format!("baseline is missing \"{}\"", name))
+}
+
+fn string(value: &Json, name: &str) -> Result<String, String> {
+    let text = field(value, name)?.as_str();
+    text.map(str::to_owned).ok_or_else(|| format!("\"{}\" is not a string", name))
+}
+
+fn number(value: &Json, name: &str) -> Result<f64, String> {
+    field(value, name)?.as_f64().ok_or_else(|| format!("\"{}\" is not a number", name))
+}
+
+/// The "model name" line of /proc/cpuinfo, where there is one.
+fn cpu_model() -> String {
+    fs::read_to_string("/proc/cpuinfo")
+        .ok()
+        .and_then(|info| {
+            info.lines()
+                .find(|line| line.starts_with("model name"))
+                .and_then(|line| line.split(':').nth(1))
+                .map(|model| model.trim().to_owned())
+        })
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+/// How much slower a case may get before it counts as a regression.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Threshold {
+    pub percent: f64,
+    pub mads: f64,
+}
+
+impl Default for Threshold {
+    fn default() -> Threshold {
+        Threshold { percent: 5.0, mads: 2.0 }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Delta {
+    pub case: BenchCase,
+    /// The matching baseline median, if the baseline has this case.
+    pub baseline_median: Option<f64>,
+    pub percent: Option<f64>,
+    pub regressed: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Comparison {
+    pub deltas: Vec<Delta>,
+    /// Ways the baseline's machine differs from this one.
+    pub mismatches: Vec<String>,
+}
+
+impl Comparison {
+    pub fn new(baseline: &Baseline, current: &Baseline, threshold: Threshold) -> Comparison {
+        let mut mismatches = Vec::new();
+        if baseline.cpu != current.cpu {
+            mismatches.push(format!("CPU {} in the baseline, {} now", baseline.cpu, current.cpu));
+        }
+        if baseline.threads != current.threads {
+            mismatches.push(format!("{} cores in the baseline, {} now", baseline.threads, current.threads));
+        }
+
+        let deltas = current
+            .cases
+            .iter()
+            .map(|case| {
+                let base = baseline.cases.iter().find(|base| base.key() == case.key());
+                let limit = base.map(|base| base.median * (1.0 + threshold.percent / 100.0) + threshold.mads * base.mad);
+                Delta {
+                    case: case.clone(),
+                    baseline_median: base.map(|base| base.median),
+                    percent: base.map(|base| (case.median / base.median - 1.0) * 100.0),
+                    regressed: limit.is_some_and(|limit| case.median > limit),
+                }
+            })
+            .collect();
+        Comparison { deltas, mismatches }
+    }
+
+    pub fn regressions(&self) -> usize {
+        self.deltas.iter().filter(|delta| delta.regressed).count()
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ms = |s: f64| format!("{:.3} ms", s * 1000.0);
+        writeln!(f, "{:<16} {:<18} {:>7} {:>14} {:>14} {:>9}", "shape", "algo", "threads", "baseline", "now", "change")?;
+        for delta in &self.deltas {
+            let case = &delta.case;
+            let (baseline, change) = match (delta.baseline_median, delta.percent) {
+                (Some(median), Some(percent)) => (ms(median), format!("{:+.1}%", percent)),
+                _ => ("-".to_owned(), "new".to_owned()),
+            };
+            write!(
+                f,
+                "{:<16} {:<18} {:>7} {:>14} {:>14} {:>9}",
+                case.shape,
+                case.algo,
+                case.threads,
+                baseline,
+                ms(case.median),
+                change
+            )?;
+            writeln!(f, "{}", if delta.regressed { "  REGRESSION" } else { "" })?;
+        }
+        Ok(())
+    }
+}
+
+/// Just enough JSON to read baselines back.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub(crate) fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = JsonParser {
+            bytes: text.as_bytes(),
+            pos: 0,
+        };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos != parser.bytes.len() {
+            return Err(format!("trailing data at byte {}", parser.pos));
+        }
+        Ok(value)
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_f64(&self) -> Option<f64> {
+        match self {
+            Json::Number(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+struct JsonParser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl JsonParser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.bytes.get(self.pos).is_some_and(u8::is_ascii_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.bytes.get(self.pos) == Some(&byte) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("expected '{}' at byte {}", byte as char, self.pos))
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        let rest = &self.bytes[self.pos..];
+        for (word, value) in [("null", Json::Null), ("true", Json::Bool(true)), ("false", Json::Bool(false))] {
+            if rest.starts_with(word.as_bytes()) {
+                self.pos += word.len();
+                return Ok(value);
+            }
+        }
+        match rest.first() {
+            Some(b'"') => self.string().map(Json::String),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                self.skip_whitespace();
+                if self.bytes.get(self.pos) == Some(&b']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.skip_whitespace();
+                    match self.bytes.get(self.pos) {
+                        Some(b',') => self.pos += 1,
+                        _ => break,
+                    }
+                }
+                self.expect(b']')?;
+                Ok(Json::Array(items))
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut fields = Vec::new();
+                self.skip_whitespace();
+                if self.bytes.get(self.pos) == Some(&b'}') {
+                    self.pos += 1;
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.string()?;
+                    self.expect(b':')?;
+                    fields.push((key, self.value()?));
+                    self.skip_whitespace();
+                    match self.bytes.get(self.pos) {
+                        Some(b',') => self.pos += 1,
+                        _ => break,
+                    }
+                }
+                self.expect(b'}')?;
+                Ok(Json::Object(fields))
+            }
+            _ => {
+                let end = rest
+                    .iter()
+                    .position(|b| !matches!(b, b'0'..=b'9' | b'-' | b'+' | b'.' | b'e' | b'E'))
+                    .unwrap_or(rest.len());
+                let number = std::str::from_utf8(&rest[..end]).ok().and_then(parse_f64);
+                self.pos += end;
+                number.map(Json::Number).ok_or_else(|| format!("expected a value at byte {}", self.pos - end))
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect(b'"')?;
+        let mut out = String::new();
+        loop {
+            let c = match std::str::from_utf8(&self.bytes[self.pos..]).ok().and_then(|s| s.chars().next()) {
+                Some(c) => c,
+                None => return Err("unterminated string".to_owned()),
+            };
+            self.pos += c.len_utf8();
+            match c {
+                '"' => return Ok(out),
+                '\\' => {
+                    let escape = self.bytes.get(self.pos).copied().ok_or("unterminated string")?;
+                    self.pos += 1;
+                    match escape {
+                        b'n' => out.push('\n'),
+                        b'r' => out.push('\r'),
+                        b't' => out.push('\t'),
+                        b'u' => {
+                            let hex = self.bytes.get(self.pos..self.pos + 4).ok_or("bad \\u escape")?;
+                            let code = u32::from_str_radix(std::str::from_utf8(hex).map_err(|e| e.to_string())?, 16)
+                                .map_err(|e| e.to_string())?;
+                            out.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+                            self.pos += 4;
+                        }
+                        other => out.push(other as char),
+                    }
+                }
+                c => out.push(c),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Hands out preset durations in order.
+    struct FakeClock(Vec<u64>);
+
+    impl Clock for FakeClock {
+        fn time(&mut self, f: &mut dyn FnMut()) -> Duration {
+            f();
+            Duration::from_millis(self.0.remove(0))
+        }
+    }
+
+    fn case(algo: &str, median: f64, mad: f64) -> BenchCase {
+        BenchCase {
+            shape: "64x64x64".to_owned(),
+            algo: algo.to_owned(),
+            threads: 4,
+            runs: 5,
+            median,
+            mad,
+        }
+    }
+
+    fn baseline(cases: Vec<BenchCase>) -> Baseline {
+        Baseline {
+            cpu: "Test CPU \"X\"".to_owned(),
+            threads: 8,
+            cases,
+        }
+    }
+
+    #[test]
+    fn median_and_mad_of_runs() {
+        let mut calls = 0;
+        let mut clock = FakeClock(vec![12, 10, 11, 30, 10]);
+        let (stats, _) = bench(&mut clock, 5, 0, || calls += 1);
+        let case = BenchCase::new("2x2x2".to_owned(), "seq", 1, &stats);
+        assert_eq!(calls, 5);
+        assert_eq!(case.runs, 5);
+        assert!((case.median - 0.011).abs() < 1e-12 && (case.mad - 0.001).abs() < 1e-12, "{:?}", case);
+        assert_eq!(median_and_mad(vec![1.0, 4.0, 2.0, 3.0]), (2.5, 1.0));
+    }
+
+    #[test]
+    fn warmup_runs_are_not_timed() {
+        // The clock has only the timed runs' durations, so timing a warmup
+        // would run out of them.
+        let mut calls = 0;
+        let mut clock = FakeClock(vec![4, 2, 6, 4]);
+        let (stats, last) = bench(&mut clock, 4, 3, || {
+            calls += 1;
+            calls
+        });
+        assert_eq!((calls, last, stats.runs), (7, 7, 4));
+        assert!((stats.min - 0.002).abs() < 1e-12 && (stats.mean - 0.004).abs() < 1e-12, "{:?}", stats);
+        // Sample deviation: sqrt((0 + 4 + 4 + 0) / 3) ms.
+        assert!((stats.stddev - (8.0f64 / 3.0).sqrt() / 1000.0).abs() < 1e-12, "{:?}", stats);
+
+        let (single, _) = bench(&mut FakeClock(vec![5]), 0, 0, || ());
+        assert_eq!((single.runs, single.stddev), (1, 0.0));
+
+        // 2e9 operations in a mean of 4 ms.
+        let table = table(&[("seq", stats), ("par", single)], 2e9);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().all(|line| line.len() == lines[0].len()), "{}", table);
+        assert!(lines[1].starts_with("seq ") && lines[1].ends_with(" 1.633 ms     4.000 ms   500.000"), "{}", table);
+    }
+
+    #[test]
+    fn times_parse_and_format() {
+        let m = Matrix::random_seeded(7, 5, 3);
+        let mut clock = FakeClock(vec![2; 12]);
+        let cases = bench_io(&mut clock, 2, 1, &m, &IO_FORMATS, true, true).unwrap();
+        let names: Vec<&str> = cases.iter().map(|case| case.name.as_str()).collect();
+        assert_eq!(names, ["format-text", "parse-text", "format-csv", "parse-csv", "format-bin", "parse-bin"]);
+        assert!(clock.0.is_empty());
+        for case in &cases {
+            assert_eq!((case.elements, case.stats.runs), (35, 2));
+            assert!(case.mb_per_s() > 0.0 && case.elements_per_s() == 17_500.0, "{:?}", case);
+        }
+        // 32 bytes of header, then 8 per element.
+        assert_eq!(cases[4].bytes, 32 + 8 * 35);
+        assert_eq!(cases[0].bytes, m.to_string().len());
+
+        let parse_only = bench_io(&mut WallClock, 1, 0, &m, &["csv"], true, false).unwrap();
+        assert_eq!(parse_only.len(), 1);
+        assert!(parse_only[0].name == "parse-csv" && parse_only[0].mb_per_s() > 0.0);
+        let table = io_table(&cases);
+        assert_eq!(table.lines().count(), 7);
+        assert!(table.lines().nth(1).unwrap().contains(" 1.750e4"), "{}", table);
+    }
+
+    #[test]
+    fn baseline_round_trips() {
+        let saved = baseline(vec![case("seq", 0.0123, 1e-4), case("par", 3.5e-3, 0.0)]);
+        assert_eq!(Baseline::from_json(&saved.to_json()).unwrap(), saved);
+        assert!(Baseline::from_json("{\"version\":2}").is_err());
+        assert!(Baseline::from_json("{\"version\":1,\"cpu\":\"x\"").is_err());
+
+        let dir = std::env::temp_dir().join(format!("matrix-mul-bench-{}", std::process::id()));
+        let path = dir.join("baselines").join("main.json");
+        saved.save(&path).unwrap();
+        assert_eq!(Baseline::load(&path).unwrap(), saved);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn flags_regressions_beyond_noise() {
+        let old = baseline(vec![case("seq", 1.0, 0.01), case("par", 1.0, 0.0)]);
+        let threshold = Threshold::default();
+
+        // 5% + 2 * MAD of 1% allows up to 1.07.
+        let comparison = Comparison::new(&old, &baseline(vec![case("seq", 1.069, 0.0), case("par", 1.06, 0.0)]), threshold);
+        assert_eq!(
+            comparison.deltas.iter().map(|delta| delta.regressed).collect::<Vec<_>>(),
+            vec![false, true]
+        );
+        assert_eq!(comparison.regressions(), 1);
+        assert!((comparison.deltas[1].percent.unwrap() - 6.0).abs() < 1e-9);
+        assert!(comparison.mismatches.is_empty());
+        assert!(comparison.to_string().contains("REGRESSION"), "{}", comparison);
+
+        // Faster is never a regression, and new cases have nothing to compare.
+        let mut now = baseline(vec![case("seq", 0.5, 0.0), case("strassen", 9.0, 0.0)]);
+        now.cpu = "Other CPU".to_owned();
+        now.threads = 2;
+        let comparison = Comparison::new(&old, &now, Threshold { percent: 0.0, mads: 0.0 });
+        assert_eq!(comparison.regressions(), 0);
+        assert_eq!(comparison.deltas[1].baseline_median, None);
+        assert_eq!(comparison.mismatches.len(), 2);
+    }
+}
@@ -0,0 +1,92 @@
+//! Writes the seeded input pairs for the NumPy oracle fixtures.
+//!
+//! `cargo run --bin testdata-gen -- --out testdata` writes NAME_a.npy and
+//! NAME_b.npy for every fixture set, plus oracle.py; running
+//! `python3 testdata/oracle.py testdata` then writes the NAME_c.npy products
+//! that the tests compare against, and testdata/oracle.txt with the NumPy
+//! version that computed them. The outputs are committed, so neither step is
+//! needed to run `cargo test`.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use clap::Parser;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+#[allow(dead_code)]
+#[path = "../../npy.rs"]
+mod npy;
+
+const ORACLE: &str = include_str!("oracle.py");
+
+#[derive(Parser, Debug)]
+#[clap(about = "Generates input fixtures for the NumPy oracle tests")]
+struct Args {
+    /// Directory to write the fixtures to
+    #[clap(long, value_parser, default_value = "testdata")]
+    out: PathBuf,
+
+    /// Seed for the random inputs
+    #[clap(long, value_parser, default_value_t = 2022)]
+    seed: u64,
+}
+
+struct Input {
+    rows: usize,
+    cols: usize,
+    data: Vec<f64>,
+}
+
+impl Input {
+    fn from_fn(rows: usize, cols: usize, mut f: impl FnMut(usize, usize) -> f64) -> Input {
+        let data = (0..rows * cols).map(|i| f(i / cols, i % cols)).collect();
+        Input { rows, cols, data }
+    }
+
+    fn uniform(rows: usize, cols: usize, rng: &mut StdRng) -> Input {
+        Input::from_fn(rows, cols, |_, _| rng.gen_range(-1.0..1.0))
+    }
+
+    fn save(&self, path: &Path) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        npy::write(&mut out, self.rows, self.cols, &self.data)?;
+        out.flush()
+    }
+}
+
+fn main() -> io::Result<()> {
+    let args = Args::parse();
+    let mut rng = StdRng::seed_from_u64(args.seed);
+
+    // A Hilbert matrix times values spanning sixteen orders of magnitude with
+    // mixed signs, so dot products cancel heavily.
+    let hilbert = Input::from_fn(20, 20, |i, j| 1.0 / (i + j + 1) as f64);
+    let wide = Input::from_fn(20, 13, |_, _| {
+        let sign = if rng.gen() { 1.0 } else { -1.0 };
+        sign * 10f64.powf(rng.gen_range(-8.0..8.0))
+    });
+
+    let sets = [
+        ("square", Input::uniform(64, 64, &mut rng), Input::uniform(64, 64, &mut rng)),
+        ("rectangular", Input::uniform(37, 53, &mut rng), Input::uniform(53, 29, &mut rng)),
+        ("ill_conditioned", hilbert, wide),
+    ];
+
+    fs::create_dir_all(&args.out)?;
+    for (name, a, b) in &sets {
+        a.save(&args.out.join(format!("{}_a.npy", name)))?;
+        b.save(&args.out.join(format!("{}_b.npy", name)))?;
+        println!("{}: {}x{} times {}x{}", name, a.rows, a.cols, b.rows, b.cols);
+    }
+    fs::write(args.out.join("oracle.py"), ORACLE)?;
+
+    println!(
+        "Now run `python3 {} {}` to compute the reference products",
+        args.out.join("oracle.py").display(),
+        args.out.display()
+    );
+    Ok(())
+}
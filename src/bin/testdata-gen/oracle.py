@@ -0,0 +1,86 @@
+#!/usr/bin/env python3
+"""Computes reference products for the fixtures written by testdata-gen.
+
+Usage: python3 oracle.py [--fsum] DIR
+
+For every NAME_a.npy / NAME_b.npy pair in DIR, writes NAME_c.npy holding
+NAME_a @ NAME_b, computed by NumPy, and records the NumPy version in
+DIR/oracle.txt. Without NumPy the script stops, unless --fsum is given: then
+each element is the math.fsum of the rounded products, an independent
+reference but not NumPy's, and oracle.txt says so.
+"""
+
+import ast
+import math
+import pathlib
+import struct
+import sys
+
+try:
+    import numpy
+except ImportError:
+    numpy = None
+
+
+def load(path):
+    raw = path.read_bytes()
+    assert raw[:6] == b"\x93NUMPY", f"{path} is not an .npy file"
+    if raw[6] == 1:
+        (header_len,) = struct.unpack("<H", raw[8:10])
+        start = 10
+    else:
+        (header_len,) = struct.unpack("<I", raw[8:12])
+        start = 12
+    header = ast.literal_eval(raw[start:start + header_len].decode("latin1"))
+    assert header["descr"] == "<f8", f"{path}: expected float64"
+    rows, cols = header["shape"]
+    values = struct.unpack(f"<{rows * cols}d", raw[start + header_len:])
+    if header["fortran_order"]:
+        return [[values[c * rows + r] for c in range(cols)] for r in range(rows)]
+    return [list(values[r * cols:(r + 1) * cols]) for r in range(rows)]
+
+
+def save(path, matrix):
+    rows, cols = len(matrix), len(matrix[0])
+    header = "{'descr': '<f8', 'fortran_order': False, 'shape': (%d, %d), }" % (rows, cols)
+    header += " " * ((64 - (10 + len(header) + 1) % 64) % 64) + "\n"
+    with open(path, "wb") as out:
+        out.write(b"\x93NUMPY\x01\x00" + struct.pack("<H", len(header)) + header.encode("latin1"))
+        for row in matrix:
+            out.write(struct.pack(f"<{cols}d", *row))
+
+
+def product(a, b):
+    columns = list(zip(*b))
+    return [[math.fsum(x * y for x, y in zip(row, col)) for col in columns] for row in a]
+
+
+def main(directory, fsum):
+    directory = pathlib.Path(directory)
+    pairs = sorted(directory.glob("*_a.npy"))
+    if not pairs:
+        sys.exit(f"no *_a.npy files in {directory}")
+    if numpy is None and not fsum:
+        sys.exit("NumPy is not installed; install it, or pass --fsum for the math.fsum reference")
+
+    for a_path in pairs:
+        name = a_path.name[: -len("_a.npy")]
+        b_path = directory / f"{name}_b.npy"
+        c_path = directory / f"{name}_c.npy"
+        if numpy is not None:
+            numpy.save(c_path, numpy.load(a_path) @ numpy.load(b_path))
+            oracle = f"numpy {numpy.__version__}"
+        else:
+            save(c_path, product(load(a_path), load(b_path)))
+            oracle = "math.fsum, without NumPy"
+        print(f"{c_path}: {oracle}")
+    (directory / "oracle.txt").write_text(f"{oracle}\n")
+
+
+if __name__ == "__main__":
+    args = sys.argv[1:]
+    fsum = "--fsum" in args
+    args = [arg for arg in args if arg != "--fsum"]
+    if len(args) != 1:
+        sys.exit(__doc__)
+    main(args[0], fsum)
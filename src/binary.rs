@@ -0,0 +1,689 @@
+//! Binary matrix files.
+//!
+//! The native format is a 32-byte header followed by the elements in row
+//! order, and is laid out so that the same matrix always gives the same
+//! bytes:
+//!
+//! | offset | size | field                                         |
+//! |--------|------|-----------------------------------------------|
+//! | 0      | 8    | magic, `MMULBIN\0`                            |
+//! | 8      | 1    | format version, 1                             |
+//! | 9      | 1    | byte order: 0 little-endian, 1 big-endian     |
+//! | 10     | 6    | reserved, zero                                |
+//! | 16     | 8    | rows, u64 in the file's byte order            |
+//! | 24     | 8    | cols, u64 in the file's byte order            |
+//! | 32     | 8·n  | elements, f64 in the file's byte order        |
+//!
+//! Nothing else is stored; in particular no timestamps, which belong in a
+//! sidecar file. `Matrix::write_binary` writes little-endian files, and
+//! `Matrix::read_binary` reads either order.
+//!
+//! The elements may also be laid out with a leading dimension, as by
+//! programs that hand row-major buffers to BLAS: row `i` starts at element
+//! `i * ld` of the data, and elements `cols..ld` of every row, including the
+//! last, are padding. `ld == cols` is the dense layout. The header does not
+//! record `ld`, so the reader has to be told it, and the data is then
+//! `rows * ld` elements.
+
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    path::Path,
+};
+
+use crate::{invariants, Matrix, MatrixError};
+
+const F64_BYTES: usize = std::mem::size_of::<f64>();
+
+pub(crate) const MAGIC: [u8; 8] = *b"MMULBIN\0";
+pub(crate) const VERSION: u8 = 1;
+pub(crate) const HEADER_BYTES: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub(crate) enum ByteOrder {
+    #[default]
+    Little,
+    Big,
+}
+
+impl ByteOrder {
+    fn u64_bytes(self, x: u64) -> [u8; 8] {
+        match self {
+            ByteOrder::Little => x.to_le_bytes(),
+            ByteOrder::Big => x.to_be_bytes(),
+        }
+    }
+
+    fn f64_bytes(self, x: f64) -> [u8; 8] {
+        self.u64_bytes(x.to_bits())
+    }
+
+    fn u64_from(self, bytes: [u8; 8]) -> u64 {
+        match self {
+            ByteOrder::Little => u64::from_le_bytes(bytes),
+            ByteOrder::Big => u64::from_be_bytes(bytes),
+        }
+    }
+
+    fn f64_from(self, bytes: [u8; 8]) -> f64 {
+        f64::from_bits(self.u64_from(bytes))
+    }
+}
+
+pub(crate) fn header(rows: usize, cols: usize, order: ByteOrder) -> [u8; HEADER_BYTES] {
+    let mut header = [0; HEADER_BYTES];
+    header[..8].copy_from_slice(&MAGIC);
+    header[8] = VERSION;
+    header[9] = order as u8;
+    header[16..24].copy_from_slice(&order.u64_bytes(rows as u64));
+    header[24..].copy_from_slice(&order.u64_bytes(cols as u64));
+    header
+}
+
+/// Writes a binary file a band of rows at a time, for results produced in
+/// pieces. Each row is padded with zeros to `ld` elements, so with
+/// `ld == cols` the bytes are the same as from Matrix::write_binary.
+pub(crate) struct BinaryWriter<W: Write> {
+    writer: W,
+    order: ByteOrder,
+    cols: usize,
+    ld: usize,
+    rows_left: usize,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> BinaryWriter<W> {
+    /// Writes the header. Fails if `ld` is less than `cols`.
+    pub(crate) fn new(
+        mut writer: W,
+        rows: usize,
+        cols: usize,
+        ld: usize,
+        order: ByteOrder,
+    ) -> io::Result<BinaryWriter<W>> {
+        check_layout(rows, cols, ld).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+        writer.write_all(&header(rows, cols, order))?;
+        let mut binary = BinaryWriter {
+            writer,
+            order,
+            cols,
+            ld,
+            rows_left: rows,
+            buf: Vec::new(),
+        };
+        // A band of no columns is empty however many rows it holds, so the
+        // rows, which are nothing but padding, are all written here.
+        if cols == 0 {
+            if ld > 0 {
+                for _ in 0..rows {
+                    binary.write_row(&[])?;
+                }
+            }
+            binary.rows_left = 0;
+        }
+        Ok(binary)
+    }
+
+    /// Appends whole rows; for a matrix of no columns, which `new` has
+    /// written already, only empty bands. Panics if `band` is not a whole
+    /// number of rows or runs past the row count given to `new`.
+    pub(crate) fn write_rows(&mut self, band: &[f64]) -> io::Result<()> {
+        if self.cols == 0 {
+            assert!(band.is_empty(), "band must hold whole rows");
+            return Ok(());
+        }
+        let rows = band.len() / self.cols;
+        assert_eq!(rows * self.cols, band.len(), "band must hold whole rows");
+        assert!(rows <= self.rows_left, "more rows than the header declares");
+        self.rows_left -= rows;
+        band.chunks_exact(self.cols).try_for_each(|row| self.write_row(row))
+    }
+
+    fn write_row(&mut self, row: &[f64]) -> io::Result<()> {
+        self.buf.clear();
+        for &x in row {
+            self.buf.extend_from_slice(&self.order.f64_bytes(x));
+        }
+        invariants::strided_rows(1, row.len(), self.ld, self.ld);
+        self.buf.resize(self.ld * F64_BYTES, 0);
+        self.writer.write_all(&self.buf)
+    }
+
+    /// Fails if fewer rows were written than the header declares.
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        if self.rows_left > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} rows were never written", self.rows_left),
+            ));
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Reads a binary file a band of rows at a time, dropping the padding; the
+/// counterpart of BinaryWriter.
+pub(crate) struct BinaryReader<R: Read> {
+    reader: R,
+    order: ByteOrder,
+    rows: usize,
+    cols: usize,
+    ld: usize,
+    rows_left: usize,
+    // Bytes of data read so far, and in the whole file.
+    read: u64,
+    expected: u64,
+    buf: Vec<u8>,
+}
+
+impl<R: Read> BinaryReader<R> {
+    /// Reads the header of a file whose rows are `ld` elements apart, or
+    /// `cols` elements without `ld`.
+    pub(crate) fn new(mut reader: R, ld: Option<usize>) -> Result<BinaryReader<R>, MatrixError> {
+        let (order, rows, cols) = read_header(&mut reader)?;
+        let ld = ld.unwrap_or(cols);
+        let expected = check_layout(rows, cols, ld)?;
+        Ok(BinaryReader {
+            reader,
+            order,
+            rows,
+            cols,
+            ld,
+            rows_left: rows,
+            read: 0,
+            expected,
+            buf: Vec::new(),
+        })
+    }
+
+    pub(crate) fn shape(&self) -> (usize, usize) {
+        (self.rows, self.cols)
+    }
+
+    /// Appends up to `max_rows` more rows to `data` and returns how many;
+    /// none once every row has been read. A file that ends first is a
+    /// `SizeMismatch`.
+    pub(crate) fn read_rows(&mut self, max_rows: usize, data: &mut Vec<f64>) -> Result<usize, MatrixError> {
+        // Rows of no elements take no bytes, so they all come at once.
+        let rows = if self.ld == 0 { self.rows_left } else { self.rows_left.min(max_rows.max(1)) };
+        let n = (rows as u64).saturating_mul(self.ld as u64).saturating_mul(F64_BYTES as u64);
+        self.buf.clear();
+        (&mut self.reader).take(n).read_to_end(&mut self.buf).map_err(io_error)?;
+        self.read += self.buf.len() as u64;
+        if (self.buf.len() as u64) < n {
+            return Err(MatrixError::SizeMismatch {
+                expected: self.expected,
+                found: self.read,
+            });
+        }
+
+        let order = self.order;
+        let value = |b: &[u8]| order.f64_from(b.try_into().unwrap());
+        if self.ld == self.cols {
+            data.extend(self.buf.chunks_exact(F64_BYTES).map(value));
+        } else {
+            invariants::strided_rows(rows, self.cols, self.ld, self.buf.len() / F64_BYTES);
+            for row in self.buf.chunks_exact(self.ld * F64_BYTES) {
+                data.extend(row[..self.cols * F64_BYTES].chunks_exact(F64_BYTES).map(value));
+            }
+        }
+        self.rows_left -= rows;
+        Ok(rows)
+    }
+
+    /// Fails if the file goes on after the last row.
+    pub(crate) fn finish(mut self) -> Result<(), MatrixError> {
+        debug_assert_eq!(self.rows_left, 0, "finished before the last row");
+        let trailing = io::copy(&mut self.reader, &mut io::sink()).map_err(io_error)?;
+        if trailing > 0 {
+            return Err(MatrixError::SizeMismatch {
+                expected: self.expected,
+                found: self.read + trailing,
+            });
+        }
+        Ok(())
+    }
+}
+
+// The bytes of data in a file of `rows` rows `ld` elements apart, after
+// checking that a row, and the whole file, fit in memory; the readers and
+// the writer allocate them.
+fn check_layout(rows: usize, cols: usize, ld: usize) -> Result<u64, MatrixError> {
+    if ld < cols {
+        return Err(MatrixError::LeadingDimension { ld, cols });
+    }
+    let row_bytes = ld.checked_mul(F64_BYTES).ok_or(MatrixError::LayoutTooLarge { rows, ld })?;
+    let bytes = rows.checked_mul(row_bytes).ok_or(MatrixError::LayoutTooLarge { rows, ld })?;
+    Ok(bytes as u64)
+}
+
+fn io_error(err: io::Error) -> MatrixError {
+    MatrixError::from(err)
+}
+
+// Elements read at a time, so that a corrupt header claiming a huge
+// matrix fails at the end of the file instead of allocating for it.
+const READ_ELEMENTS: usize = 64 * 1024;
+
+fn read_header(reader: &mut impl Read) -> Result<(ByteOrder, usize, usize), MatrixError> {
+    let mut header = [0; HEADER_BYTES];
+    if let Err(err) = reader.read_exact(&mut header) {
+        return Err(match err.kind() {
+            io::ErrorKind::UnexpectedEof => MatrixError::InvalidHeader("file is shorter than the header".to_owned()),
+            _ => io_error(err),
+        });
+    }
+    if header[..8] != MAGIC {
+        return Err(MatrixError::InvalidHeader("no MMULBIN magic".to_owned()));
+    }
+    if header[8] != VERSION {
+        return Err(MatrixError::InvalidHeader(format!("unsupported version {}", header[8])));
+    }
+    let order = match header[9] {
+        0 => ByteOrder::Little,
+        1 => ByteOrder::Big,
+        other => return Err(MatrixError::InvalidHeader(format!("unknown byte order {}", other))),
+    };
+    let dim = |bytes: &[u8]| {
+        let x = order.u64_from(bytes.try_into().unwrap());
+        usize::try_from(x).map_err(|_| MatrixError::InvalidHeader(format!("dimension {} is too large", x)))
+    };
+    Ok((order, dim(&header[16..24])?, dim(&header[24..32])?))
+}
+
+// A read-only mapping of a whole file.
+#[cfg(unix)]
+struct Mapping {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+#[cfg(unix)]
+impl Mapping {
+    fn new(file: &File) -> io::Result<Mapping> {
+        use std::os::unix::io::AsRawFd;
+
+        let len = usize::try_from(file.metadata()?.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "file is too large to map"))?;
+        if len == 0 {
+            // mmap refuses empty mappings.
+            return Ok(Mapping {
+                ptr: std::ptr::null_mut(),
+                len,
+            });
+        }
+        // SAFETY: a private, read-only mapping of an open file, unmapped in
+        // drop. As with any mapped file, another process truncating it
+        // while it is mapped makes the reads past the new end fault.
+        let ptr = unsafe { libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_PRIVATE, file.as_raw_fd(), 0) };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping { ptr, len })
+    }
+
+    fn bytes(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        // SAFETY: `ptr` is a mapping of `len` readable bytes that lasts as
+        // long as `self`.
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            // SAFETY: the mapping made in `new`, which nothing uses after this.
+            unsafe { libc::munmap(self.ptr, self.len) };
+        }
+    }
+}
+
+impl Matrix {
+    /// Reads a native binary file, in either byte order. The data must be
+    /// exactly `rows * cols` elements; a truncated file or trailing bytes
+    /// are a `SizeMismatch`.
+    pub fn read_binary(reader: impl Read) -> Result<Matrix, MatrixError> {
+        Matrix::read_binary_ld(reader, None)
+    }
+
+    /// Like read_binary, for a file whose rows are `ld` elements apart, of
+    /// which all but the first `cols` are padding. `None` is `cols`.
+    pub fn read_binary_ld(reader: impl Read, ld: Option<usize>) -> Result<Matrix, MatrixError> {
+        let mut reader = BinaryReader::new(io::BufReader::new(reader), ld)?;
+        let band = READ_ELEMENTS / reader.ld.max(1);
+        let mut data = Vec::new();
+        while reader.read_rows(band, &mut data)? > 0 {}
+        let (rows, cols) = reader.shape();
+        reader.finish()?;
+        Ok(Matrix::try_new(rows, cols, data)?)
+    }
+
+    /// Like read_binary_ld, for the file at `path`, which is mapped into
+    /// memory instead of read, so that its size is checked against the
+    /// header before anything is copied.
+    #[cfg(unix)]
+    pub fn map_binary(path: &Path, ld: Option<usize>) -> Result<Matrix, MatrixError> {
+        let file = File::open(path).map_err(io_error)?;
+        let mapping = Mapping::new(&file).map_err(io_error)?;
+        let mut bytes = mapping.bytes();
+        let (order, rows, cols) = read_header(&mut bytes)?;
+        Matrix::from_strided_bytes(bytes, rows, cols, ld.unwrap_or(cols), order)
+    }
+
+    #[cfg(not(unix))]
+    pub fn map_binary(path: &Path, ld: Option<usize>) -> Result<Matrix, MatrixError> {
+        Matrix::read_binary_ld(File::open(path).map_err(io_error)?, ld)
+    }
+
+    /// The shape in a native binary file's header. Reads the 32 bytes of
+    /// the header and nothing else, so `reader` may be a whole file.
+    pub fn read_binary_shape(mut reader: impl Read) -> Result<(usize, usize), MatrixError> {
+        read_header(&mut reader).map(|(_, rows, cols)| (rows, cols))
+    }
+
+    /// Writes a native binary file, little-endian.
+    pub fn write_binary(&self, writer: impl Write) -> io::Result<()> {
+        self.write_binary_as(writer, self.cols, ByteOrder::Little)
+    }
+
+    /// Writes a binary file, little-endian, with rows `ld` elements apart
+    /// and the padding zero. Fails if `ld` is less than `cols`.
+    pub fn write_binary_ld(&self, writer: impl Write, ld: usize) -> io::Result<()> {
+        self.write_binary_as(writer, ld, ByteOrder::Little)
+    }
+
+    /// Writes a binary file with rows `ld` elements apart, in `order`.
+    pub(crate) fn write_binary_as(&self, writer: impl Write, ld: usize, order: ByteOrder) -> io::Result<()> {
+        let mut binary = BinaryWriter::new(io::BufWriter::new(writer), self.rows, self.cols, ld, order)?;
+        binary.write_rows(&self.data)?;
+        binary.finish().map(drop)
+    }
+
+    // The data of a file after its header: `rows * ld` elements in
+    // `order`, with the padding dropped.
+    fn from_strided_bytes(
+        bytes: &[u8],
+        rows: usize,
+        cols: usize,
+        ld: usize,
+        order: ByteOrder,
+    ) -> Result<Matrix, MatrixError> {
+        let expected = check_layout(rows, cols, ld)?;
+        if bytes.len() as u64 != expected {
+            return Err(MatrixError::SizeMismatch {
+                expected,
+                found: bytes.len() as u64,
+            });
+        }
+
+        invariants::strided_rows(rows, cols, ld, bytes.len() / F64_BYTES);
+        let mut data = Vec::with_capacity(rows * cols);
+        for row in bytes.chunks_exact((ld * F64_BYTES).max(1)).take(rows) {
+            data.extend(
+                row[..cols * F64_BYTES]
+                    .chunks_exact(F64_BYTES)
+                    .map(|b| order.f64_from(b.try_into().unwrap())),
+            );
+        }
+        Ok(Matrix::try_new(rows, cols, data)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SENTINEL: f64 = -12345.678;
+
+    // A binary file of `m` with leading dimension `ld`, the padding filled
+    // with SENTINEL.
+    fn padded(m: &Matrix, ld: usize) -> Vec<u8> {
+        let mut bytes = header(m.rows, m.cols, ByteOrder::Little).to_vec();
+        for i in 0..m.rows {
+            for j in 0..ld {
+                let x = if j < m.cols { m.get(i, j) } else { SENTINEL };
+                bytes.extend_from_slice(&x.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    fn seeded(rows: usize, cols: usize) -> Matrix {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng = StdRng::seed_from_u64(229);
+        Matrix::new_unchecked(rows, cols, (0..rows * cols).map(|_| rng.gen_range(-1.0..1.0)).collect())
+    }
+
+    #[test]
+    fn binary_header_layout() {
+        let m = Matrix::new_unchecked(2, 3, vec![1.0, -0.0, 2.5, f64::NAN, 1e300, -1.0]);
+        for order in [ByteOrder::Little, ByteOrder::Big] {
+            let mut bytes = Vec::new();
+            m.write_binary_as(&mut bytes, m.cols, order).unwrap();
+            assert_eq!(bytes.len(), HEADER_BYTES + 6 * 8);
+            assert_eq!(&bytes[..8], b"MMULBIN\0");
+            assert_eq!(bytes[8], 1);
+            assert_eq!(bytes[9], order as u8);
+            assert_eq!(&bytes[10..16], &[0; 6]);
+            assert_eq!(&bytes[16..24], &order.u64_bytes(2));
+            assert_eq!(&bytes[24..32], &order.u64_bytes(3));
+            assert_eq!(&bytes[32 + 8..32 + 16], &order.f64_bytes(-0.0));
+        }
+        let mut little = Vec::new();
+        m.write_binary(&mut little).unwrap();
+        assert_eq!(&little[32..40], &1.0f64.to_le_bytes());
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let mut m = seeded(100, 73);
+        m.set(3, 4, -0.0);
+        m.set(5, 6, f64::NAN);
+        for order in [ByteOrder::Little, ByteOrder::Big] {
+            let mut bytes = Vec::new();
+            m.write_binary_as(&mut bytes, m.cols, order).unwrap();
+            let read = Matrix::read_binary(&bytes[..]).unwrap();
+            assert_eq!((read.shape(), read.bits()), (m.shape(), m.bits()));
+        }
+        let mut empty = Vec::new();
+        Matrix::new_unchecked(0, 5, vec![]).write_binary(&mut empty).unwrap();
+        assert_eq!(Matrix::read_binary(&empty[..]).unwrap().shape(), (0, 5));
+    }
+
+    #[test]
+    fn malformed_binary_is_rejected() {
+        let mut bytes = Vec::new();
+        seeded(100, 73).write_binary(&mut bytes).unwrap();
+        let data_bytes = 100 * 73 * 8;
+        let read = |bytes: &[u8]| Matrix::read_binary(bytes);
+        assert_eq!(
+            read(&bytes[..bytes.len() - 3]),
+            Err(MatrixError::SizeMismatch { expected: data_bytes, found: data_bytes - 3 })
+        );
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert_eq!(read(&longer), Err(MatrixError::SizeMismatch { expected: data_bytes, found: data_bytes + 1 }));
+
+        // A header claiming more than there is fails without allocating it.
+        let mut huge = bytes.clone();
+        huge[16..24].copy_from_slice(&(1u64 << 40).to_le_bytes());
+        assert!(matches!(read(&huge), Err(MatrixError::SizeMismatch { expected, .. }) if expected == 73 << 43));
+
+        assert!(matches!(read(&bytes[..20]), Err(MatrixError::InvalidHeader(_))));
+        for (offset, value) in [(0, b'm'), (8, 2), (9, 7)] {
+            let mut bad = bytes.clone();
+            bad[offset] = value;
+            assert!(matches!(read(&bad), Err(MatrixError::InvalidHeader(_))), "byte {}", offset);
+        }
+        let text = b"1 2\n3 4\n".repeat(10);
+        assert!(read(&text).unwrap_err().to_string().contains("no MMULBIN magic"));
+    }
+
+    #[test]
+    fn shape_reads_only_the_header() {
+        let mut bytes = Vec::new();
+        seeded(7, 3).write_binary(&mut bytes).unwrap();
+        let mut rest = &bytes[..];
+        assert_eq!(Matrix::read_binary_shape(&mut rest), Ok((7, 3)));
+        assert_eq!(rest.len(), 7 * 3 * 8);
+
+        // The data is never looked at, so a truncated file still has a shape.
+        assert_eq!(Matrix::read_binary_shape(&bytes[..HEADER_BYTES]), Ok((7, 3)));
+        assert!(matches!(Matrix::read_binary_shape(&bytes[..20]), Err(MatrixError::InvalidHeader(_))));
+    }
+
+    #[test]
+    fn binary_output_is_deterministic() {
+        let dir = std::env::temp_dir().join(format!("matrix-mul-binary-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str| {
+            let path = dir.join(name);
+            let product = seeded(37, 20).multiply(&seeded(20, 41)).unwrap();
+            product
+                .write_binary(std::fs::File::create(&path).unwrap())
+                .unwrap();
+            std::fs::read(&path).unwrap()
+        };
+        let first = write("first.bin");
+        assert_eq!(first, write("second.bin"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn band_writer_matches_single_pass() {
+        let m = seeded(23, 9);
+        for order in [ByteOrder::Little, ByteOrder::Big] {
+            // write_binary_as is BinaryWriter too, so the bytes are built
+            // here independently.
+            let mut single = header(23, 9, order).to_vec();
+            for &x in &m.data {
+                single.extend_from_slice(&order.f64_bytes(x));
+            }
+            let mut whole = Vec::new();
+            m.write_binary_as(&mut whole, 9, order).unwrap();
+            assert_eq!(whole, single);
+
+            let mut writer = BinaryWriter::new(Vec::new(), 23, 9, 9, order).unwrap();
+            for band in m.data.chunks(5 * 9) {
+                writer.write_rows(band).unwrap();
+            }
+            assert_eq!(writer.finish().unwrap(), single);
+        }
+
+        let mut short = BinaryWriter::new(Vec::new(), 23, 9, 9, ByteOrder::Little).unwrap();
+        short.write_rows(&m.data[..9]).unwrap();
+        assert!(short.finish().is_err());
+    }
+
+    #[test]
+    fn band_writer_pads_and_handles_no_columns() {
+        let m = seeded(6, 4);
+        let mut padded = Vec::new();
+        m.write_binary_as(&mut padded, 7, ByteOrder::Big).unwrap();
+        let mut writer = BinaryWriter::new(Vec::new(), 6, 4, 7, ByteOrder::Big).unwrap();
+        writer.write_rows(&m.data[..8]).unwrap();
+        writer.write_rows(&m.data[8..]).unwrap();
+        assert_eq!(writer.finish().unwrap(), padded);
+        assert_eq!(padded.len(), HEADER_BYTES + 6 * 7 * 8);
+
+        // Rows of no columns are all written by `new`, padding and all.
+        for ld in [0, 3] {
+            let mut writer = BinaryWriter::new(Vec::new(), 5, 0, ld, ByteOrder::Little).unwrap();
+            writer.write_rows(&[]).unwrap();
+            let bytes = writer.finish().unwrap();
+            assert_eq!(bytes.len(), HEADER_BYTES + 5 * ld * 8);
+            let read = Matrix::read_binary_ld(&bytes[..], Some(ld)).unwrap();
+            assert_eq!(read.shape(), (5, 0));
+        }
+        assert!(BinaryWriter::new(Vec::new(), 6, 4, 3, ByteOrder::Little).is_err());
+    }
+
+    #[test]
+    fn padding_is_skipped() {
+        let a = Matrix::random(7, 5);
+        let b = Matrix::random(5, 3);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.bin");
+        std::fs::write(&path, padded(&a, 8)).unwrap();
+
+        let read_a = Matrix::map_binary(&path, Some(8)).unwrap();
+        let read_b = Matrix::read_binary_ld(&padded(&b, 4)[..], Some(4)).unwrap();
+        assert_eq!(read_a, a);
+        assert_eq!(read_b, b);
+        assert!(!read_a.data.contains(&SENTINEL));
+
+        let product = read_a.multiply(&read_b).unwrap();
+        assert_eq!(product, a.multiply(&b).unwrap());
+        assert!(!product.data.contains(&SENTINEL));
+
+        // Without the leading dimension the file is the wrong size.
+        assert!(matches!(Matrix::map_binary(&path, None), Err(MatrixError::SizeMismatch { .. })));
+        let mut dense = Vec::new();
+        a.write_binary(&mut dense).unwrap();
+        std::fs::write(&path, dense).unwrap();
+        assert_eq!(Matrix::map_binary(&path, None).unwrap(), a);
+    }
+
+    #[test]
+    fn layout_errors() {
+        let a = Matrix::random(3, 4);
+        let bytes = padded(&a, 6);
+        let dir = tempfile::tempdir().unwrap();
+        let map = |bytes: &[u8], ld| {
+            let path = dir.path().join("m.bin");
+            std::fs::write(&path, bytes).unwrap();
+            Matrix::map_binary(&path, Some(ld))
+        };
+        let read = |bytes: &[u8], ld| Matrix::read_binary_ld(bytes, Some(ld));
+
+        for reader in [&map as &dyn Fn(&[u8], usize) -> _, &read] {
+            assert_eq!(reader(&bytes, 3), Err(MatrixError::LeadingDimension { ld: 3, cols: 4 }));
+            assert_eq!(
+                reader(&bytes[..bytes.len() - 8], 6),
+                Err(MatrixError::SizeMismatch { expected: 144, found: 136 })
+            );
+            let mut longer = bytes.clone();
+            longer.extend_from_slice(&[0; 8]);
+            assert_eq!(reader(&longer, 6), Err(MatrixError::SizeMismatch { expected: 144, found: 152 }));
+            assert!(matches!(reader(&bytes[..20], 6), Err(MatrixError::InvalidHeader(_))));
+            let huge = usize::MAX / 4;
+            assert_eq!(reader(&bytes, huge), Err(MatrixError::LayoutTooLarge { rows: 3, ld: huge }));
+        }
+
+        // Nothing is written for a layout that does not fit.
+        let mut out = Vec::new();
+        let err = Matrix::random(2, 3).write_binary_ld(&mut out, usize::MAX).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), format!("2 rows {} elements apart do not fit in memory", usize::MAX));
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn write_with_leading_dimension() {
+        let a = Matrix::random(4, 3);
+        let mut bytes = Vec::new();
+        a.write_binary_as(&mut bytes, 5, ByteOrder::Little).unwrap();
+
+        assert_eq!(bytes.len(), HEADER_BYTES + 4 * 5 * 8);
+        assert_eq!(Matrix::read_binary_shape(&bytes[..]), Ok((4, 3)));
+        assert_eq!(Matrix::read_binary_ld(&bytes[..], Some(5)).unwrap(), a);
+        for row in bytes[HEADER_BYTES..].chunks(5 * 8) {
+            assert!(row[3 * 8..].iter().all(|&b| b == 0));
+        }
+        assert!(a.write_binary_as(&mut Vec::new(), 2, ByteOrder::Little).is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "invariant: 2 rows of 4 elements 3 apart outside a buffer of 6")]
+    fn invariants_catch_corrupted_leading_dimension() {
+        let bytes = padded(&Matrix::random(2, 3), 3);
+        let mut reader = BinaryReader::new(&bytes[..], Some(3)).unwrap();
+        reader.cols = 4;
+        reader.read_rows(2, &mut Vec::new()).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+//! `--mode blocked`: the product a tile at a time, for matrices too large
+//! for the plain kernels to keep their rows of `other` in cache.
+//!
+//! The output is cut into blocks of `block_size` rows and, within those,
+//! `block_size` columns, and the inner dimension likewise. Inside a tile
+//! the loops run i-k-j, so the innermost one walks a row of `other` and a
+//! row of the output, both contiguous. Every element still gets its
+//! products added in increasing k, one at a time onto 0.0, which is what
+//! the plain kernels do, so the result is the same to the bit. The parallel
+//! variant hands each block of rows to its own task.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rayon::prelude::*;
+
+use crate::{invariants, Algorithm, CancelToken, Matrix, MatrixError};
+
+/// The default `--block-size`: three 64x64 tiles of f64 are 96KB.
+pub const DEFAULT_BLOCK_SIZE: usize = 64;
+
+impl Matrix {
+    /// `self * other`, tiled. A `block_size` of 0 is taken as 1, and one
+    /// above every dimension as the largest dimension.
+    pub fn multiply_blocked(&self, other: &Matrix, block_size: usize) -> Result<Matrix, MatrixError> {
+        self.multiply_blocked_with(other, block_size, Algorithm::Seq, &CancelToken::new())
+    }
+
+    /// `multiply_blocked` with one rayon task per block of rows.
+    pub fn multiply_blocked_par(&self, other: &Matrix, block_size: usize) -> Result<Matrix, MatrixError> {
+        self.multiply_blocked_with(other, block_size, Algorithm::Par, &CancelToken::new())
+    }
+
+    /// Either of the above, giving up between blocks of rows once `token` is
+    /// cancelled.
+    pub fn multiply_blocked_with(
+        &self,
+        other: &Matrix,
+        block_size: usize,
+        algorithm: Algorithm,
+        token: &CancelToken,
+    ) -> Result<Matrix, MatrixError> {
+        self.check_inner(other)?;
+        let (rows, cols) = (self.rows, other.cols);
+        // A block larger than every dimension tiles the same as one that
+        // just covers them, and keeps the products below from overflowing.
+        let block_size = block_size.clamp(1, rows.max(self.cols).max(cols).max(1));
+        let band_rows = block_size.min(rows).max(1);
+        let mut result = Matrix::new_unchecked(rows, cols, vec![0.0; rows * cols]);
+        let rows_completed = AtomicUsize::new(0);
+        let band = |(block, out): (usize, &mut [f64])| {
+            if !token.is_cancelled() {
+                let first = block * band_rows;
+                self.row_block(other, first, block_size, out);
+                rows_completed.fetch_add(out.len() / cols, Ordering::Relaxed);
+            }
+        };
+        // Zero columns leave nothing to compute, and nothing to split.
+        if cols > 0 {
+            let band_len = band_rows * cols;
+            match algorithm {
+                Algorithm::Seq => result.data.chunks_mut(band_len).enumerate().for_each(band),
+                Algorithm::Par => result.data.par_chunks_mut(band_len).enumerate().for_each(band),
+            }
+        }
+
+        let rows_completed = rows_completed.into_inner();
+        if rows_completed < rows && cols > 0 {
+            return Err(MatrixError::Cancelled { rows_completed });
+        }
+        Ok(result)
+    }
+
+    // Rows `first..` of the product, as many as `out` holds, tile by tile.
+    fn row_block(&self, other: &Matrix, first: usize, block_size: usize, out: &mut [f64]) {
+        let (inner, cols) = (self.cols, other.cols);
+        invariants::row_band(self.rows, cols, first, out.len());
+        let rows = out.len() / cols;
+        for k0 in (0..inner).step_by(block_size) {
+            let k1 = (k0 + block_size).min(inner);
+            for j0 in (0..cols).step_by(block_size) {
+                let j1 = (j0 + block_size).min(cols);
+                invariants::tile(self.shape(), first, k0, rows, k1 - k0);
+                invariants::tile(other.shape(), k0, j0, k1 - k0, j1 - j0);
+                for i in 0..rows {
+                    let a = &self.row(first + i)[k0..k1];
+                    let out = &mut out[i * cols + j0..i * cols + j1];
+                    for (k, &x) in (k0..k1).zip(a) {
+                        for (cell, y) in out.iter_mut().zip(&other.row(k)[j0..j1]) {
+                            *cell += x * y;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_plain_multiply() {
+        let shapes = [(65, 130, 67), (64, 64, 64), (1, 1, 1), (3, 200, 2), (130, 1, 65), (0, 5, 4), (4, 0, 3), (4, 3, 0)];
+        for (n, m, k) in shapes {
+            let a = Matrix::random(n, m);
+            let b = Matrix::random(m, k);
+            let expected = a.multiply(&b).unwrap();
+            for block_size in [0, 1, 7, 64, 1000, 1 << 62, usize::MAX] {
+                let seq = a.multiply_blocked(&b, block_size).unwrap();
+                let par = a.multiply_blocked_par(&b, block_size).unwrap();
+                assert_eq!(seq.shape(), (n, k));
+                assert_eq!(seq.bits(), expected.bits(), "{}x{}x{} block {}", n, m, k, block_size);
+                assert_eq!(par.bits(), expected.bits(), "{}x{}x{} block {}", n, m, k, block_size);
+            }
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let a = Matrix::random(65, 130);
+        assert!(matches!(a.multiply_blocked(&a, 64), Err(MatrixError::DimensionMismatch { .. })));
+
+        let b = Matrix::random(130, 67);
+        let token = CancelToken::new();
+        token.cancel();
+        for algorithm in [Algorithm::Seq, Algorithm::Par] {
+            let result = a.multiply_blocked_with(&b, 64, algorithm, &token);
+            assert!(matches!(result, Err(MatrixError::Cancelled { rows_completed: 0 })), "{:?}", result);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "invariant: band of 8 elements from row 2 of a 4x2 result")]
+    fn invariants_catch_band_past_the_end() {
+        let (a, b) = (Matrix::random(4, 3), Matrix::random(3, 2));
+        a.row_block(&b, 2, 2, &mut [0.0; 8]);
+    }
+}
@@ -0,0 +1,64 @@
+//! Cooperative cancellation for long multiplications.
+//!
+//! Kernels poll the token once per output row, so a cancelled run stops
+//! within roughly one row's worth of work.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// A token that also counts as cancelled once `timeout` has passed. A
+    /// timeout too long for an `Instant` never passes.
+    pub fn with_timeout(timeout: Duration) -> CancelToken {
+        CancelToken {
+            cancelled: Arc::default(),
+            deadline: Instant::now().checked_add(timeout),
+        }
+    }
+
+    /// Cancels this token and every clone of it.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+            || self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clones_share_state() {
+        let token = CancelToken::new();
+        let clone = token.clone();
+        assert!(!clone.is_cancelled());
+        token.cancel();
+        assert!(clone.is_cancelled());
+    }
+
+    #[test]
+    fn deadline() {
+        assert!(CancelToken::with_timeout(Duration::ZERO).is_cancelled());
+        assert!(!CancelToken::with_timeout(Duration::from_secs(3600)).is_cancelled());
+        assert!(!CancelToken::with_timeout(Duration::MAX).is_cancelled());
+    }
+}
@@ -0,0 +1,121 @@
+//! Dropping rows or columns before a multiply, and putting them back after.
+//!
+//! Row i of a product only depends on row i of the left operand, so rows
+//! that are all zero there can be left out of the multiply and restored as
+//! zero rows of the product with `expand_rows`.
+
+use crate::{Matrix, MatrixError};
+
+impl Matrix {
+    /// The rows where `keep` is true, and their indices in `self`.
+    pub(crate) fn compress_rows(&self, keep: &[bool]) -> (Matrix, Vec<usize>) {
+        assert_eq!(keep.len(), self.rows, "one flag per row");
+        let indices: Vec<usize> = (0..self.rows).filter(|&i| keep[i]).collect();
+        let mut data = Vec::with_capacity(indices.len() * self.cols);
+        for &i in &indices {
+            data.extend_from_slice(self.row(i));
+        }
+        (Matrix::new_unchecked(indices.len(), self.cols, data), indices)
+    }
+
+    /// The columns where `keep` is true, and their indices in `self`.
+    /// `keep` must have one flag per column.
+    pub fn compress_cols(&self, keep: &[bool]) -> Result<(Matrix, Vec<usize>), MatrixError> {
+        if keep.len() != self.cols {
+            return Err(MatrixError::WrongLength { what: "column flags", expected: self.cols, found: keep.len() });
+        }
+        let indices: Vec<usize> = (0..self.cols).filter(|&j| keep[j]).collect();
+        let mut data = Vec::with_capacity(self.rows * indices.len());
+        for i in 0..self.rows {
+            data.extend(indices.iter().map(|&j| self.get(i, j)));
+        }
+        Ok((Matrix::new_unchecked(self.rows, indices.len(), data), indices))
+    }
+
+    /// The inverse of `compress_rows`: row r of `self` becomes row
+    /// `indices[r]` of an `original_rows`-row matrix, and the other rows
+    /// are zero. `indices` must have one index per row, each below
+    /// `original_rows`.
+    pub fn expand_rows(&self, original_rows: usize, indices: &[usize]) -> Result<Matrix, MatrixError> {
+        if indices.len() != self.rows {
+            return Err(MatrixError::WrongLength { what: "row indices", expected: self.rows, found: indices.len() });
+        }
+        if let Some(&index) = indices.iter().find(|&&i| i >= original_rows) {
+            return Err(MatrixError::RowOutOfRange { index, rows: original_rows });
+        }
+        let mut result = Matrix::new_unchecked(original_rows, self.cols, vec![0.0; original_rows * self.cols]);
+        for (r, &i) in indices.iter().enumerate() {
+            result.data[i * self.cols..(i + 1) * self.cols].copy_from_slice(self.row(r));
+        }
+        Ok(result)
+    }
+
+    /// True for each row with an element of magnitude above `tolerance`.
+    pub(crate) fn nonzero_rows(&self, tolerance: f64) -> Vec<bool> {
+        (0..self.rows).map(|i| self.row(i).iter().any(|x| x.abs() > tolerance)).collect()
+    }
+
+    /// `compress_rows` keeping the rows `nonzero_rows` finds.
+    pub fn drop_zero_rows(&self, tolerance: f64) -> (Matrix, Vec<usize>) {
+        self.compress_rows(&self.nonzero_rows(tolerance))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_zero_rows() -> Matrix {
+        Matrix::new_unchecked(5, 3, vec![
+            0.0, 0.0, 0.0,
+            1.0, -2.0, 0.5,
+            0.0, 0.0, 0.0,
+            0.0, 1e-12, 0.0,
+            3.0, 0.0, 4.0,
+        ])
+    }
+
+    #[test]
+    fn compress_multiply_expand() {
+        let a = with_zero_rows();
+        let b = Matrix::random(3, 4);
+        let (compressed, indices) = a.drop_zero_rows(0.0);
+        assert_eq!(indices, vec![1, 3, 4]);
+        let product = compressed.multiply(&b).unwrap().expand_rows(a.rows, &indices).unwrap();
+        assert_eq!(product, a.multiply(&b).unwrap());
+
+        let (_, indices) = a.drop_zero_rows(1e-9);
+        assert_eq!(indices, vec![1, 4]);
+    }
+
+    #[test]
+    fn mapping_round_trips() {
+        let a = with_zero_rows();
+        let keep = [true, false, true, false, true];
+        let (rows, indices) = a.compress_rows(&keep);
+        assert_eq!((rows.shape(), indices.clone()), ((3, 3), vec![0, 2, 4]));
+        let expanded = rows.expand_rows(5, &indices).unwrap();
+        for (i, &kept) in keep.iter().enumerate() {
+            let expected = if kept { a.row(i).to_vec() } else { vec![0.0; 3] };
+            assert_eq!(expanded.row(i), &expected[..]);
+        }
+
+        let (cols, indices) = a.compress_cols(&[true, false, true]).unwrap();
+        assert_eq!(indices, vec![0, 2]);
+        assert_eq!(cols.transpose().row(1), a.transpose().row(2));
+        assert_eq!(a.compress_cols(&[false; 3]).unwrap().0.shape(), (5, 0));
+    }
+
+    #[test]
+    fn wrong_lengths() {
+        let a = with_zero_rows();
+        assert_eq!(
+            a.compress_cols(&[true; 2]).unwrap_err(),
+            MatrixError::WrongLength { what: "column flags", expected: 3, found: 2 }
+        );
+        let (rows, _) = a.compress_rows(&[true, false, true, false, true]);
+        let err = rows.expand_rows(5, &[0, 2]).unwrap_err();
+        assert_eq!(err.to_string(), "expected 3 row indices, found 2");
+        assert_eq!(rows.expand_rows(4, &[0, 2, 4]).unwrap_err(), MatrixError::RowOutOfRange { index: 4, rows: 4 });
+    }
+}
@@ -0,0 +1,307 @@
+//! Hooks for code that embeds the multiply instead of running the CLI.
+//!
+//! The multiply never prints. By default it runs on rayon's global pool and
+//! allocates whatever it needs; a `Context` set with
+//! `MultiplyOptions::context` replaces each of those defaults. The CLI
+//! builds its own from its flags.
+
+use std::{fmt, sync::Arc, time::Instant};
+
+use rayon::ThreadPool;
+
+use crate::{
+    trace::{Band, Trace},
+    Algorithm, MatrixError, Orientation,
+};
+
+/// A decision the multiply dispatcher made.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[non_exhaustive]
+pub enum LogEvent {
+    /// One operand was a zero matrix or a multiple of the identity.
+    Shortcut,
+    /// Par was requested but the problem ran on the calling thread.
+    RanInline { flops: u64 },
+    /// The general kernel ran.
+    Kernel {
+        algorithm: Algorithm,
+        orientation: Orientation,
+    },
+    /// The row kernel ran on f32 copies of the operands.
+    Narrowed { algorithm: Algorithm },
+    /// A view was copied out because the kernel needs contiguous rows.
+    Materialized { rows: usize, cols: usize },
+}
+
+impl fmt::Display for LogEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogEvent::Shortcut => write!(f, "skipped the multiply, zero or identity operand"),
+            LogEvent::RanInline { flops } => write!(f, "ran on the calling thread ({} flops)", flops),
+            LogEvent::Kernel { algorithm, orientation } => {
+                write!(f, "{:?} row kernel, {}", algorithm, orientation.describe())
+            }
+            LogEvent::Narrowed { algorithm } => write!(f, "{:?} row kernel, operands narrowed to f32", algorithm),
+            LogEvent::Materialized { rows, cols } => write!(f, "copied out a {}x{} view to multiply it", rows, cols),
+        }
+    }
+}
+
+type ProgressFn = dyn Fn(usize, usize) + Send + Sync;
+type LogFn = dyn Fn(LogEvent) + Send + Sync;
+type RowFn = dyn Fn(usize, &[f64]) + Send + Sync;
+
+#[derive(Clone, Default)]
+pub struct Context {
+    progress: Option<Arc<ProgressFn>>,
+    rows: Option<Arc<RowFn>>,
+    log: Option<Arc<LogFn>>,
+    pool: Option<Arc<ThreadPool>>,
+    tasks: Option<usize>,
+    allocation_budget: Option<u64>,
+    trace: Option<Arc<Trace>>,
+}
+
+impl fmt::Debug for Context {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Context")
+            .field("progress", &self.progress.is_some())
+            .field("rows", &self.rows.is_some())
+            .field("log", &self.log.is_some())
+            .field("pool", &self.pool.as_ref().map(|pool| pool.current_num_threads()))
+            .field("tasks", &self.tasks)
+            .field("allocation_budget", &self.allocation_budget)
+            .field("trace", &self.trace.is_some())
+            .finish()
+    }
+}
+
+impl Context {
+    pub fn new() -> Context {
+        Context::default()
+    }
+
+    /// Called with (rows done, total rows) as output rows are finished, from
+    /// whichever thread finished them. Kernels that do not go row by row
+    /// report every row at once when they finish.
+    pub fn progress(mut self, progress: impl Fn(usize, usize) + Send + Sync + 'static) -> Context {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    /// Called with (row index, row) as each output row is finished, after
+    /// its epilogues, from whichever thread finished it.
+    pub fn rows(mut self, rows: impl Fn(usize, &[f64]) + Send + Sync + 'static) -> Context {
+        self.rows = Some(Arc::new(rows));
+        self
+    }
+
+    pub fn log(mut self, log: impl Fn(LogEvent) + Send + Sync + 'static) -> Context {
+        self.log = Some(Arc::new(log));
+        self
+    }
+
+    /// Run parallel kernels on `pool` instead of the global one.
+    pub fn pool(mut self, pool: Arc<ThreadPool>) -> Context {
+        self.pool = Some(pool);
+        self
+    }
+
+    /// Split a parallel multiply into at most `tasks` tasks, so that it
+    /// leaves the rest of the pool to other multiplies.
+    pub(crate) fn tasks(mut self, tasks: usize) -> Context {
+        self.tasks = Some(tasks.max(1));
+        self
+    }
+
+    /// Rows each task of a parallel multiply of `rows` rows takes at least.
+    pub(crate) fn min_rows_per_task(&self, rows: usize) -> usize {
+        self.tasks.map_or(1, |tasks| rows.div_ceil(tasks).max(1))
+    }
+
+    /// Fail with `MatrixError::AllocationBudget` instead of allocating more
+    /// than `bytes` for one multiply.
+    pub fn allocation_budget(mut self, bytes: u64) -> Context {
+        self.allocation_budget = Some(bytes);
+        self
+    }
+
+    /// Record the row kernels' bands, and separate epilogue passes, in
+    /// `trace`.
+    pub fn trace(mut self, trace: Arc<Trace>) -> Context {
+        self.trace = Some(trace);
+        self
+    }
+
+    /// Whether the plain kernels, which neither report rows nor limit
+    /// their tasks, would ignore part of this context.
+    pub(crate) fn needs_row_loop(&self) -> bool {
+        self.progress.is_some() || self.rows.is_some() || self.tasks.is_some() || self.trace.is_some()
+    }
+
+    /// A band of rows for the trace, if there is one.
+    pub(crate) fn band(&self) -> Option<Band<'_>> {
+        self.trace.as_ref().map(|trace| trace.band())
+    }
+
+    /// Records a span from `start` until now in the trace, if there is one.
+    pub(crate) fn span(&self, name: &str, start: Instant) {
+        if let Some(trace) = &self.trace {
+            trace.span(name, "compute", start, Instant::now(), Vec::new());
+        }
+    }
+
+    pub(crate) fn report_row(&self, i: usize, row: &[f64]) {
+        if let Some(rows) = &self.rows {
+            rows(i, row);
+        }
+    }
+
+    pub(crate) fn report_progress(&self, done: usize, total: usize) {
+        if let Some(progress) = &self.progress {
+            progress(done, total);
+        }
+    }
+
+    pub(crate) fn emit(&self, event: LogEvent) {
+        if let Some(log) = &self.log {
+            log(event);
+        }
+    }
+
+    pub(crate) fn install<R: Send>(&self, f: impl FnOnce() -> R + Send) -> R {
+        match &self.pool {
+            Some(pool) => pool.install(f),
+            None => f(),
+        }
+    }
+
+    /// Checks that allocating `bytes` in total stays within the budget.
+    pub(crate) fn check_allocation(&self, bytes: u64) -> Result<(), MatrixError> {
+        match self.allocation_budget {
+            Some(budget) if bytes > budget => Err(MatrixError::AllocationBudget { requested: bytes, budget }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        process::Command,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+    };
+
+    use super::*;
+    use crate::{Matrix, MultiplyOptions};
+
+    #[test]
+    fn log_sink_sees_decisions() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&events);
+        let context = Context::new().log(move |event| sink.lock().unwrap().push(event));
+        let options = MultiplyOptions::new().algorithm(Algorithm::Par).context(context);
+
+        let a = Matrix::random(4, 4);
+        a.multiply_with(&Matrix::identity(4), &options).unwrap();
+        a.multiply_with(&Matrix::random(4, 3), &options).unwrap();
+        a.multiply_with(&Matrix::random(4, 3), &options.clone().inline_below(0)).unwrap();
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                LogEvent::Shortcut,
+                LogEvent::RanInline { flops: 96 },
+                LogEvent::Kernel {
+                    algorithm: Algorithm::Seq,
+                    orientation: Orientation::Strided,
+                },
+                LogEvent::Kernel {
+                    algorithm: Algorithm::Par,
+                    orientation: Orientation::Strided,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn progress_and_pool() {
+        let pool = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(2).build().unwrap());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let seen = Arc::clone(&calls);
+        let context = Context::new().pool(pool).progress(move |done, total| {
+            assert!(done <= total && total == 30);
+            assert_eq!(rayon::current_num_threads(), 2);
+            seen.fetch_add(1, Ordering::Relaxed);
+        });
+        let options = MultiplyOptions::new().algorithm(Algorithm::Par).inline_below(0).context(context);
+
+        let a = Matrix::random(30, 20);
+        let b = Matrix::random(20, 10);
+        assert_eq!(a.multiply_with(&b, &options).unwrap(), a.multiply(&b).unwrap());
+        assert_eq!(calls.load(Ordering::Relaxed), 30);
+        // Paths without a row loop report once, when they finish.
+        Matrix::random(30, 1).multiply_with(&Matrix::random(1, 10), &options).unwrap();
+        assert_eq!(calls.load(Ordering::Relaxed), 31);
+    }
+
+    #[test]
+    fn allocation_budget() {
+        let a = Matrix::random(100, 100);
+        let b = Matrix::random(100, 100);
+        let options = MultiplyOptions::new().context(Context::new().allocation_budget(1024));
+        assert!(matches!(
+            a.multiply_with(&b, &options),
+            Err(MatrixError::AllocationBudget {
+                requested: 80_000,
+                budget: 1024
+            })
+        ));
+
+        // A packed copy counts against the budget as well.
+        let options = MultiplyOptions::new().context(Context::new().allocation_budget(100_000));
+        assert!(matches!(
+            a.multiply_with(&b, &options.clone().orientation(Orientation::Packed)),
+            Err(MatrixError::AllocationBudget {
+                requested: 160_000,
+                ..
+            })
+        ));
+        assert!(a.multiply_with(&b, &options.orientation(Orientation::Strided)).is_ok());
+    }
+
+    // Run by `headless_is_silent` in a child process, whose stdout is not
+    // captured by the test harness.
+    #[test]
+    #[ignore]
+    fn headless_child() {
+        let a = Matrix::random(40, 30);
+        let b = Matrix::random(30, 20);
+        for options in [
+            MultiplyOptions::new(),
+            MultiplyOptions::new().algorithm(Algorithm::Par),
+            MultiplyOptions::new().algorithm(Algorithm::Par).orientation(Orientation::Packed),
+        ] {
+            a.multiply_with(&b, &options).unwrap();
+            a.multiply_with(&Matrix::identity(30), &options).unwrap();
+            a.multiply_by_transpose(&b.transpose(), &options).unwrap();
+        }
+    }
+
+    #[test]
+    fn headless_is_silent() {
+        let output = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "context::tests::headless_child", "--ignored", "--nocapture", "--test-threads=1"])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        for line in stdout.lines() {
+            let harness = line.is_empty() || line.starts_with("running ") || line.starts_with("test ");
+            assert!(harness, "unexpected output: {}", line);
+        }
+        assert!(stdout.contains("test context::tests::headless_child ... ok"), "{}", stdout);
+    }
+}
@@ -0,0 +1,604 @@
+//! `--distribute FILE`: one multiply split across several `--serve`
+//! workers.
+//!
+//! The product is cut into bands of rows. The right matrix goes to each
+//! worker once, by `PUT /operands` before its first band, and each band is
+//! one `POST /multiply` of its rows of the left matrix and the handle the
+//! worker gave back, all in the text format, which is exact. A worker whose
+//! operand cache will not hold the right matrix is sent it with every band
+//! instead. A worker has one band out at a time and takes the next as soon
+//! as it answers, so faster workers take more.
+//!
+//! A band whose worker fails, by refusing the connection, dropping it or
+//! not answering in time, or by answering 408 or 5xx, goes back in the
+//! queue for the next free worker; a worker that fails `MAX_FAILURES` times
+//! in a row is dropped. Any other error status is about the problem itself
+//! and ends the multiply, since no other worker would do better. Once the
+//! queue is empty, an idle worker also takes a band that has been out longer
+//! than `straggler_after`, and whichever answer comes first is kept.
+//!
+//! Cancelling `DistributeOptions::cancel`, or its deadline passing, drops
+//! the requests in flight and ends the multiply with
+//! `MatrixError::Cancelled`.
+
+use std::{
+    collections::VecDeque,
+    ops::Range,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::Notify,
+};
+
+use crate::{CancelToken, Matrix, MatrixError};
+
+/// Failures in a row after which a worker gets no more bands.
+const MAX_FAILURES: usize = 3;
+
+/// Bands per worker when `DistributeOptions::band_rows` is 0: enough for
+/// faster workers to take more.
+const BANDS_PER_WORKER: usize = 4;
+
+/// How often a request in flight looks at `DistributeOptions::cancel`.
+const CANCEL_POLL: Duration = Duration::from_millis(20);
+
+#[derive(Clone, Debug)]
+pub struct DistributeOptions {
+    /// Rows per band, or 0 for about four bands per worker.
+    pub band_rows: usize,
+    /// For one band, from connecting to the end of the answer.
+    pub request_timeout: Duration,
+    /// A band out this long may be given to a second worker.
+    pub straggler_after: Duration,
+    pub cancel: CancelToken,
+}
+
+impl Default for DistributeOptions {
+    fn default() -> DistributeOptions {
+        DistributeOptions {
+            band_rows: 0,
+            request_timeout: Duration::from_secs(300),
+            straggler_after: Duration::from_secs(30),
+            cancel: CancelToken::new(),
+        }
+    }
+}
+
+/// What happened on the way to the product.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DistributeReport {
+    pub bands: usize,
+    /// Bands sent again after their worker failed.
+    pub retries: usize,
+    /// Bands also sent to a second worker for being slow.
+    pub redispatched: usize,
+    /// Bands each worker answered, in the order of the list.
+    pub answered: Vec<usize>,
+}
+
+/// The workers in `text`: one `HOST:PORT` per line. Blank lines and lines
+/// starting with `#` are skipped.
+pub fn parse_workers(text: &str) -> Result<Vec<String>, String> {
+    let workers: Vec<String> = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect();
+    let has_port = |worker: &&String| worker.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+    if let Some(bad) = workers.iter().find(|worker| !has_port(worker)) {
+        return Err(format!("expected HOST:PORT, found '{}'", bad));
+    }
+    if workers.is_empty() {
+        return Err("no workers listed".to_owned());
+    }
+    Ok(workers)
+}
+
+#[derive(Debug)]
+struct Band {
+    rows: Range<usize>,
+    result: Option<Matrix>,
+    /// When it was first sent, and to how many workers it is out now.
+    sent: Option<Instant>,
+    out: usize,
+}
+
+#[derive(Debug)]
+struct State {
+    bands: Vec<Band>,
+    queue: VecDeque<usize>,
+    done: usize,
+    live_workers: usize,
+    error: Option<MatrixError>,
+    report: DistributeReport,
+}
+
+impl State {
+    fn finished(&self) -> bool {
+        self.done == self.bands.len() || self.error.is_some()
+    }
+
+    // The next band for an idle worker: a queued one, or else the one out
+    // the longest, if that is long enough and nobody else is on it yet.
+    fn next_band(&mut self, straggler_after: Duration) -> Option<usize> {
+        if let Some(i) = self.queue.pop_front() {
+            return Some(i);
+        }
+        let (i, band) = self
+            .bands
+            .iter()
+            .enumerate()
+            .filter(|(_, band)| band.result.is_none() && band.out == 1)
+            .min_by_key(|(_, band)| band.sent)?;
+        if band.sent?.elapsed() < straggler_after {
+            return None;
+        }
+        self.report.redispatched += 1;
+        Some(i)
+    }
+}
+
+enum Failure {
+    /// Worth another worker's try.
+    Retry(String),
+    Fatal(MatrixError),
+}
+
+/// `a * b`, computed by `workers`.
+pub async fn distribute(
+    a: &Matrix,
+    b: &Matrix,
+    workers: &[String],
+    options: &DistributeOptions,
+) -> Result<(Matrix, DistributeReport), MatrixError> {
+    a.check_inner(b)?;
+    if a.rows == 0 || b.cols == 0 {
+        let empty = Matrix::new_unchecked(a.rows, b.cols, vec![0.0; a.rows * b.cols]);
+        return Ok((empty, DistributeReport::default()));
+    }
+    if workers.is_empty() {
+        return Err(MatrixError::NoWorkers);
+    }
+
+    let band_rows = match options.band_rows {
+        0 => a.rows.div_ceil(workers.len() * BANDS_PER_WORKER),
+        n => n,
+    };
+    let bands: Vec<Band> = (0..a.rows)
+        .step_by(band_rows)
+        .map(|start| Band {
+            rows: start..(start + band_rows).min(a.rows),
+            result: None,
+            sent: None,
+            out: 0,
+        })
+        .collect();
+    let state = Arc::new(Mutex::new(State {
+        queue: (0..bands.len()).collect(),
+        report: DistributeReport {
+            bands: bands.len(),
+            answered: vec![0; workers.len()],
+            ..DistributeReport::default()
+        },
+        bands,
+        done: 0,
+        live_workers: workers.len(),
+        error: None,
+    }));
+    let changed = Arc::new(Notify::new());
+    let (a, right) = (Arc::new(a.clone()), Arc::new(b.to_string()));
+
+    let tasks: Vec<_> = workers
+        .iter()
+        .enumerate()
+        .map(|(w, addr)| {
+            let worker = Worker {
+                index: w,
+                addr: addr.clone(),
+                a: Arc::clone(&a),
+                right: Arc::clone(&right),
+                uploaded: Mutex::new(None),
+                cols: b.cols,
+                options: options.clone(),
+                state: Arc::clone(&state),
+                changed: Arc::clone(&changed),
+            };
+            tokio::spawn(worker.run())
+        })
+        .collect();
+
+    // Workers still waiting on a straggler's duplicate are not waited for.
+    while !state.lock().unwrap().finished() && !options.cancel.is_cancelled() {
+        let _ = tokio::time::timeout(Duration::from_millis(50), changed.notified()).await;
+    }
+    for task in tasks {
+        task.abort();
+    }
+
+    let mut state = state.lock().unwrap();
+    if !state.finished() {
+        let rows_completed = state.bands.iter().filter(|band| band.result.is_some()).map(|band| band.rows.len()).sum();
+        return Err(MatrixError::Cancelled { rows_completed });
+    }
+    if let Some(err) = state.error.take() {
+        return Err(err);
+    }
+    let mut data = Vec::with_capacity(a.rows * b.cols);
+    for band in &mut state.bands {
+        data.append(&mut band.result.take().unwrap().data);
+    }
+    Ok((Matrix::new_unchecked(a.rows, b.cols, data), std::mem::take(&mut state.report)))
+}
+
+#[derive(Clone, Debug)]
+enum Right {
+    /// The handle `PUT /operands` answered.
+    Handle(String),
+    /// The text itself, for a worker whose operand cache refused it.
+    Inline,
+}
+
+struct Worker {
+    index: usize,
+    addr: String,
+    a: Arc<Matrix>,
+    /// The right operand, formatted once for every worker.
+    right: Arc<String>,
+    /// How this worker's bands name it, once it has been uploaded.
+    uploaded: Mutex<Option<Right>>,
+    cols: usize,
+    options: DistributeOptions,
+    state: Arc<Mutex<State>>,
+    changed: Arc<Notify>,
+}
+
+impl Worker {
+    async fn run(self) {
+        let mut failures = 0;
+        loop {
+            let next = {
+                let mut state = self.state.lock().unwrap();
+                if state.finished() {
+                    return;
+                }
+                let next = state.next_band(self.options.straggler_after);
+                if let Some(i) = next {
+                    let band = &mut state.bands[i];
+                    band.out += 1;
+                    band.sent.get_or_insert_with(Instant::now);
+                }
+                next.map(|i| (i, state.bands[i].rows.clone()))
+            };
+            let Some((i, rows)) = next else {
+                // Nothing to do until a band is answered, fails or straggles.
+                let wait = (self.options.straggler_after / 4).min(Duration::from_secs(1));
+                let _ = tokio::time::timeout(wait, self.changed.notified()).await;
+                continue;
+            };
+
+            let request = tokio::time::timeout(self.options.request_timeout, self.multiply(rows.clone()));
+            let answer = tokio::select! {
+                answer = request => answer.unwrap_or_else(|_| Err(Failure::Retry("timed out".to_owned()))),
+                _ = cancelled(&self.options.cancel) => {
+                    self.changed.notify_waiters();
+                    return;
+                }
+            };
+            let stop = {
+                let mut state = self.state.lock().unwrap();
+                self.record(&mut state, i, answer, &mut failures);
+                failures == MAX_FAILURES
+            };
+            self.changed.notify_waiters();
+            if stop {
+                return;
+            }
+        }
+    }
+
+    fn record(&self, state: &mut State, i: usize, answer: Result<Matrix, Failure>, failures: &mut usize) {
+        state.bands[i].out -= 1;
+        match answer {
+            Ok(product) => {
+                *failures = 0;
+                state.report.answered[self.index] += 1;
+                if state.bands[i].result.is_none() {
+                    state.bands[i].result = Some(product);
+                    state.done += 1;
+                }
+            }
+            Err(Failure::Fatal(err)) => {
+                state.error.get_or_insert(err);
+            }
+            Err(Failure::Retry(reason)) => {
+                *failures += 1;
+                let band = &state.bands[i];
+                if band.result.is_none() && band.out == 0 {
+                    state.queue.push_front(i);
+                    state.report.retries += 1;
+                }
+                if *failures == MAX_FAILURES {
+                    state.live_workers -= 1;
+                    eprintln!("Warning: dropping worker {} after {} failures: {}", self.addr, failures, reason);
+                    if state.live_workers == 0 {
+                        state.error.get_or_insert(MatrixError::WorkersFailed {
+                            last: self.addr.clone(),
+                            reason,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    // Rows `rows` of the product, from one POST /multiply.
+    async fn multiply(&self, rows: Range<usize>) -> Result<Matrix, Failure> {
+        let cols = self.a.cols;
+        let left = Matrix::new_unchecked(rows.len(), cols, self.a.data[rows.start * cols..rows.end * cols].to_vec());
+        let right = self.right_operand().await?;
+        let body = match &right {
+            Right::Handle(handle) => format!("{}X\n@{}", left, handle),
+            Right::Inline => format!("{}X\n{}", left, self.right),
+        };
+        let (status, body) = self.request("POST /multiply", &body).await?;
+        match status {
+            200 => {}
+            // Evicted from the worker's cache since: the next band uploads it again.
+            404 if matches!(right, Right::Handle(_)) => {
+                *self.uploaded.lock().unwrap() = None;
+                return Err(Failure::Retry("lost the right operand from its cache".to_owned()));
+            }
+            status => return Err(self.rejected(status, body)),
+        }
+        // A truncated answer parses as the wrong shape, not an error.
+        match Matrix::try_from_str(body.trim_end()) {
+            Ok(product) if product.shape() == (rows.len(), self.cols) => Ok(product),
+            Ok(product) => Err(Failure::Retry(format!("answered a {}x{} band", product.rows, product.cols))),
+            Err(err) => Err(Failure::Retry(err.to_string())),
+        }
+    }
+
+    // How this worker's bands name the right operand, uploading it the
+    // first time.
+    async fn right_operand(&self) -> Result<Right, Failure> {
+        if let Some(right) = self.uploaded.lock().unwrap().clone() {
+            return Ok(right);
+        }
+        let (status, body) = self.request("PUT /operands", &self.right).await?;
+        let right = match status {
+            201 => {
+                let handle = body.split("\"handle\":\"").nth(1).and_then(|rest| rest.split('"').next());
+                let handle = handle.ok_or_else(|| Failure::Retry(format!("answered no handle: {}", body)))?;
+                Right::Handle(handle.to_owned())
+            }
+            // Too large for its cache, or another matrix holds the handle.
+            409 | 413 => Right::Inline,
+            status => return Err(self.rejected(status, body)),
+        };
+        *self.uploaded.lock().unwrap() = Some(right.clone());
+        Ok(right)
+    }
+
+    // The status and body of one request, `target` being its method and
+    // path. A 408 or 5xx is the worker's failure and so a `Failure::Retry`.
+    async fn request(&self, target: &str, body: &str) -> Result<(u16, String), Failure> {
+        let retry = |err: std::io::Error| Failure::Retry(err.to_string());
+        let mut stream = TcpStream::connect(self.addr.as_str()).await.map_err(retry)?;
+        let head = format!("{} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n\r\n", target, self.addr, body.len());
+        stream.write_all(head.as_bytes()).await.map_err(retry)?;
+        stream.write_all(body.as_bytes()).await.map_err(retry)?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.map_err(retry)?;
+
+        let response = String::from_utf8_lossy(&response);
+        let (head, body) = response
+            .split_once("\r\n\r\n")
+            .ok_or_else(|| Failure::Retry("connection closed before the answer ended".to_owned()))?;
+        match head.split(' ').nth(1).and_then(|s| s.parse::<u16>().ok()) {
+            Some(408) | Some(500..=599) | None => {
+                Err(Failure::Retry(format!("answered {}", head.lines().next().unwrap_or(""))))
+            }
+            Some(status) => Ok((status, body.to_owned())),
+        }
+    }
+
+    // Any other error status is about the problem, not the worker.
+    fn rejected(&self, status: u16, message: String) -> Failure {
+        Failure::Fatal(MatrixError::WorkerRejected {
+            worker: self.addr.clone(),
+            status,
+            message,
+        })
+    }
+}
+
+// Resolves once `token` is cancelled, which it has no way to announce.
+async fn cancelled(token: &CancelToken) {
+    while !token.is_cancelled() {
+        tokio::time::sleep(CANCEL_POLL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{
+        server::{Limits, Server},
+        Algorithm,
+    };
+
+    async fn worker() -> String {
+        serve(Arc::new(Server::new(Limits::default(), Algorithm::Par))).await
+    }
+
+    async fn serve(server: Arc<Server>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(server.serve_on(listener));
+        addr
+    }
+
+    // Accepts connections and drops each one once the request has started
+    // arriving, as a worker that dies mid-band would.
+    async fn crashing_worker() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let _ = stream.read(&mut [0; 64]).await;
+            }
+        });
+        addr
+    }
+
+    // Accepts connections and never answers.
+    async fn hung_worker() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                held.push(stream);
+            }
+        });
+        addr
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn two_workers_over_loopback() {
+        let workers = vec![worker().await, worker().await];
+        let a = Matrix::random(300, 300);
+        let b = Matrix::random(300, 300);
+        let (product, report) = distribute(&a, &b, &workers, &DistributeOptions::default()).await.unwrap();
+        assert_eq!(product, a.multiply(&b).unwrap());
+        assert_eq!(report.bands, 8);
+        assert_eq!(report.answered.iter().sum::<usize>(), 8);
+        assert_eq!((report.retries, report.redispatched), (0, 0));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn failed_bands_go_to_other_workers() {
+        // Nothing listens on a port just released.
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().to_string();
+        let workers = vec![crashing_worker().await, closed, worker().await];
+        let a = Matrix::random(300, 40);
+        let b = Matrix::random(40, 30);
+        let options = DistributeOptions {
+            band_rows: 16,
+            ..DistributeOptions::default()
+        };
+        let (product, report) = distribute(&a, &b, &workers, &options).await.unwrap();
+        assert_eq!(product, a.multiply(&b).unwrap());
+        assert_eq!(report.answered[..2], [0, 0]);
+        assert_eq!(report.answered[2], 19);
+        // Each failing worker took a band at the start, and may have failed
+        // its others before the last band was done.
+        assert!((2..=2 * MAX_FAILURES).contains(&report.retries), "{:?}", report);
+
+        match distribute(&a, &b, &workers[..2], &options).await {
+            Err(MatrixError::WorkersFailed { last, .. }) => assert!(workers[..2].contains(&last)),
+            other => panic!("{:?}", other.map(|(_, report)| report)),
+        }
+        assert_eq!(distribute(&a, &b, &[], &options).await.unwrap_err(), MatrixError::NoWorkers);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn the_right_operand_is_uploaded_once() {
+        let a = Matrix::random(60, 20);
+        let b = Matrix::random(20, 10);
+        let options = DistributeOptions {
+            band_rows: 10,
+            ..DistributeOptions::default()
+        };
+        // One parse per band of A, and B once, or for a worker whose cache
+        // cannot keep it, once refused and again with every band.
+        for (operand_cache_bytes, parses) in [(Limits::default().operand_cache_bytes, 7), (0, 13)] {
+            let limits = Limits {
+                operand_cache_bytes,
+                ..Limits::default()
+            };
+            let server = Arc::new(Server::new(limits, Algorithm::Par));
+            let workers = vec![serve(Arc::clone(&server)).await];
+            let (product, _) = distribute(&a, &b, &workers, &options).await.unwrap();
+            assert_eq!(product, a.multiply(&b).unwrap());
+            assert_eq!(server.parses.load(std::sync::atomic::Ordering::Relaxed), parses);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn rejected_bands_end_the_multiply() {
+        let limits = Limits {
+            max_elements: 100,
+            ..Limits::default()
+        };
+        let workers = vec![serve(Arc::new(Server::new(limits, Algorithm::Par))).await];
+        let a = Matrix::random(60, 20);
+        let b = Matrix::random(20, 10);
+        match distribute(&a, &b, &workers, &DistributeOptions::default()).await {
+            Err(MatrixError::WorkerRejected { worker, status: 413, .. }) => assert_eq!(worker, workers[0]),
+            other => panic!("{:?}", other.map(|(_, report)| report)),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn stragglers_are_sent_again() {
+        let workers = vec![hung_worker().await, worker().await];
+        let a = Matrix::random(60, 20);
+        let b = Matrix::random(20, 10);
+        let options = DistributeOptions {
+            band_rows: 10,
+            straggler_after: Duration::from_millis(200),
+            ..DistributeOptions::default()
+        };
+        let start = Instant::now();
+        let (product, report) = distribute(&a, &b, &workers, &options).await.unwrap();
+        assert_eq!(product, a.multiply(&b).unwrap());
+        assert_eq!(report.answered, [0, 6]);
+        assert_eq!(report.redispatched, 1);
+        // Long before the hung request would time out.
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn cancelling_drops_the_requests() {
+        let workers = vec![hung_worker().await, worker().await];
+        let a = Matrix::random(60, 20);
+        let b = Matrix::random(20, 10);
+        let cancelled = DistributeOptions {
+            cancel: CancelToken::new(),
+            ..DistributeOptions::default()
+        };
+        cancelled.cancel.cancel();
+        let err = distribute(&a, &b, &workers, &cancelled).await.unwrap_err();
+        assert_eq!(err, MatrixError::Cancelled { rows_completed: 0 });
+
+        // A deadline stops the band the hung worker holds; the other
+        // worker's bands may be done by then.
+        let options = DistributeOptions {
+            band_rows: 10,
+            cancel: CancelToken::with_timeout(Duration::from_millis(300)),
+            ..DistributeOptions::default()
+        };
+        let start = Instant::now();
+        match distribute(&a, &b, &workers, &options).await {
+            Err(MatrixError::Cancelled { rows_completed }) => assert!(rows_completed < 60),
+            other => panic!("{:?}", other.map(|(_, report)| report)),
+        }
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn workers_file() {
+        let text = "# the cluster\n10.0.0.1:7000\n\n  worker-b:7001  \n";
+        assert_eq!(parse_workers(text).unwrap(), ["10.0.0.1:7000", "worker-b:7001"]);
+        assert!(parse_workers("10.0.0.1\n").unwrap_err().contains("'10.0.0.1'"));
+        assert!(parse_workers("# nobody\n").is_err());
+    }
+}
@@ -0,0 +1,273 @@
+//! Matrices of other elements than f64, for `--dtype`.
+//!
+//! `Matrix<T>` with an `Element` has a plain row-times-column product,
+//! `Matrix::<T>::multiply`, and with a `Parse` element it reads the same
+//! text as the f64 parser, minus the options. Everything else the crate
+//! does is for `Matrix<f64>` alone: the blocked, fused and shortcut
+//! kernels are written for f64, and f64 is not an `Element`.
+
+use std::{
+    fmt,
+    io::{self, Write},
+};
+
+use rayon::prelude::*;
+
+use crate::{numfmt, Algorithm, Matrix, MatrixError};
+
+/// What a matrix can hold to be multiplied.
+pub trait Element: Copy + Send + Sync {
+    /// What products are summed in, which may be wider than `Self`.
+    type Sum: Copy + Send + Sync;
+
+    fn zero() -> Self::Sum;
+
+    /// `sum + a * b`, or None if that does not fit in `Sum`.
+    fn mul_add(sum: Self::Sum, a: Self, b: Self) -> Option<Self::Sum>;
+}
+
+/// Why a token is not an element.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TokenError {
+    Invalid,
+    OutOfRange,
+}
+
+/// Elements read from text.
+pub trait Parse: Element {
+    /// The `--dtype` name, for errors.
+    const NAME: &'static str;
+
+    fn parse_token(token: &str) -> Result<Self, TokenError>;
+}
+
+/// Summed in f32; nothing overflows into an error, since f32 has infinities.
+impl Element for f32 {
+    type Sum = f32;
+
+    fn zero() -> f32 {
+        0.0
+    }
+
+    fn mul_add(sum: f32, a: f32, b: f32) -> Option<f32> {
+        Some(sum + a * b)
+    }
+}
+
+/// Rounded once, to the nearest f32. A finite value too large for f32 is
+/// out of range rather than an infinity.
+impl Parse for f32 {
+    const NAME: &'static str = "f32";
+
+    fn parse_token(token: &str) -> Result<f32, TokenError> {
+        let x = numfmt::parse_f32(token).ok_or(TokenError::Invalid)?;
+        let unsigned = token.strip_prefix(['+', '-']).unwrap_or(token);
+        let infinity = unsigned.eq_ignore_ascii_case("inf") || unsigned.eq_ignore_ascii_case("infinity");
+        if x.is_infinite() && !infinity {
+            return Err(TokenError::OutOfRange);
+        }
+        Ok(x)
+    }
+}
+
+/// Summed in i128, where every single i64 * i64 product fits.
+impl Element for i64 {
+    type Sum = i128;
+
+    fn zero() -> i128 {
+        0
+    }
+
+    fn mul_add(sum: i128, a: i64, b: i64) -> Option<i128> {
+        sum.checked_add(a as i128 * b as i128)
+    }
+}
+
+impl Parse for i64 {
+    const NAME: &'static str = "i64";
+
+    fn parse_token(token: &str) -> Result<i64, TokenError> {
+        use std::num::IntErrorKind;
+
+        token.parse::<i64>().map_err(|err| match err.kind() {
+            IntErrorKind::PosOverflow | IntErrorKind::NegOverflow => TokenError::OutOfRange,
+            _ => TokenError::Invalid,
+        })
+    }
+}
+
+/// The element of i64 products, so that they can be written out; as an
+/// operand, every product and sum is checked.
+impl Element for i128 {
+    type Sum = i128;
+
+    fn zero() -> i128 {
+        0
+    }
+
+    fn mul_add(sum: i128, a: i128, b: i128) -> Option<i128> {
+        sum.checked_add(a.checked_mul(b)?)
+    }
+}
+
+/// Values in a CSV line, or a whitespace-separated one if it has no commas.
+pub fn fields(line: &str) -> Vec<&str> {
+    if line.contains(',') {
+        line.split(',').map(str::trim).collect()
+    } else {
+        line.split_whitespace().collect()
+    }
+}
+
+impl<T: Element + PartialEq> PartialEq for Matrix<T> {
+    fn eq(&self, other: &Matrix<T>) -> bool {
+        self.shape() == other.shape() && self.data == other.data
+    }
+}
+
+impl<T: Element + Eq> Eq for Matrix<T> {}
+
+impl<T: Element> Matrix<T> {
+    pub(crate) fn get(&self, row: usize, col: usize) -> T {
+        self.data[row * self.cols + col]
+    }
+
+    /// The product, each element summed by `T::mul_add` from `k = 0` up,
+    /// in the same order as `Matrix::multiply`. A sum that does not fit is
+    /// an `Overflow` error rather than a wrapped result.
+    pub fn multiply(&self, other: &Matrix<T>, algorithm: Algorithm) -> Result<Matrix<T::Sum>, MatrixError> {
+        if self.cols != other.rows {
+            return Err(MatrixError::DimensionMismatch {
+                left: self.shape(),
+                right: other.shape(),
+            });
+        }
+
+        // Transposed, so that the columns the kernel reads are contiguous.
+        let inner = self.cols;
+        let mut bt = Vec::with_capacity(other.data.len());
+        for j in 0..other.cols {
+            bt.extend((0..inner).map(|k| other.get(k, j)));
+        }
+        let row = |(i, out): (usize, &mut [T::Sum])| -> Result<(), MatrixError> {
+            let a = &self.data[i * inner..(i + 1) * inner];
+            for (j, cell) in out.iter_mut().enumerate() {
+                let mut sum = T::zero();
+                for (&x, &y) in a.iter().zip(&bt[j * inner..(j + 1) * inner]) {
+                    sum = T::mul_add(sum, x, y).ok_or(MatrixError::Overflow { row: i, col: j })?;
+                }
+                *cell = sum;
+            }
+            Ok(())
+        };
+        let mut data = vec![T::zero(); self.rows * other.cols];
+        let chunks = other.cols.max(1);
+        match algorithm {
+            Algorithm::Seq => data.chunks_mut(chunks).enumerate().try_for_each(row)?,
+            Algorithm::Par => data.par_chunks_mut(chunks).enumerate().try_for_each(row)?,
+        }
+        Ok(Matrix {
+            rows: self.rows,
+            cols: other.cols,
+            data,
+        })
+    }
+}
+
+impl<T: Parse> Matrix<T> {
+    /// One row per non-empty line, of values separated by commas or
+    /// whitespace. Every row must have as many values as the first, and
+    /// every value must be a `T`: a number too large for it is an
+    /// `OutOfRange` error.
+    pub fn parse(s: &str) -> Result<Matrix<T>, MatrixError> {
+        let mut data = Vec::new();
+        let mut rows = 0;
+        let mut cols = 0;
+        for (index, line) in s.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let values = fields(line);
+            if rows == 0 {
+                cols = values.len();
+            } else if values.len() != cols {
+                return Err(MatrixError::RaggedRow {
+                    line: index + 1,
+                    expected: cols,
+                    found: values.len(),
+                });
+            }
+            for token in values {
+                let column = token.as_ptr() as usize - line.as_ptr() as usize + 1;
+                let value = T::parse_token(token).map_err(|err| match err {
+                    TokenError::Invalid => MatrixError::InvalidNumber {
+                        line: index + 1,
+                        column,
+                        token: token.to_owned(),
+                    },
+                    TokenError::OutOfRange => MatrixError::OutOfRange {
+                        line: index + 1,
+                        column,
+                        token: token.to_owned(),
+                        dtype: T::NAME,
+                    },
+                })?;
+                data.push(value);
+            }
+            rows += 1;
+        }
+        Ok(Matrix { rows, cols, data })
+    }
+}
+
+impl<T: Element + fmt::Display> Matrix<T> {
+    /// Space-separated rows, each value as `T` displays it; for floats the
+    /// shortest text that parses back to the same value.
+    pub fn write(&self, mut writer: impl Write) -> io::Result<()> {
+        for row in self.data.chunks(self.cols.max(1)).take(self.rows) {
+            let line: Vec<String> = row.iter().map(|x| x.to_string()).collect();
+            writeln!(writer, "{}", line.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn out_of_range_is_not_invalid() {
+        assert_eq!(f32::parse_token("1e40"), Err(TokenError::OutOfRange));
+        assert_eq!(f32::parse_token("-3.5e38"), Err(TokenError::OutOfRange));
+        assert_eq!(f32::parse_token("3.4e38"), Ok(3.4e38));
+        assert_eq!(f32::parse_token("-inf"), Ok(f32::NEG_INFINITY));
+        assert_eq!(f32::parse_token("Infinity"), Ok(f32::INFINITY));
+        assert_eq!(f32::parse_token("1e"), Err(TokenError::Invalid));
+
+        assert_eq!(i64::parse_token("9223372036854775807"), Ok(i64::MAX));
+        assert_eq!(i64::parse_token("9223372036854775808"), Err(TokenError::OutOfRange));
+        assert_eq!(i64::parse_token("-9223372036854775809"), Err(TokenError::OutOfRange));
+        assert_eq!(i64::parse_token("1.0"), Err(TokenError::Invalid));
+
+        let err = Matrix::<f32>::parse("1 2\n3 1e40\n").unwrap_err();
+        assert_eq!(
+            err,
+            MatrixError::OutOfRange { line: 2, column: 3, token: "1e40".to_owned(), dtype: "f32" }
+        );
+        assert_eq!(err.to_string(), "line 2, column 3: 1e40 is out of the range of f32");
+    }
+
+    #[test]
+    fn products_of_products() {
+        // i64 products are i128, which multiply again with every step
+        // checked.
+        let a = Matrix::<i64>::parse("3037000500 0\n0 1").unwrap();
+        let square = a.multiply(&a, Algorithm::Seq).unwrap();
+        assert_eq!(square.data, [3037000500i128 * 3037000500, 0, 0, 1]);
+        let fourth = square.multiply(&square, Algorithm::Par).unwrap();
+        assert_eq!(fourth.get(0, 0), (3037000500i128 * 3037000500).pow(2));
+        let eighth = fourth.multiply(&fourth, Algorithm::Seq);
+        assert_eq!(eighth, Err(MatrixError::Overflow { row: 0, col: 0 }));
+    }
+}
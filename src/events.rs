@@ -0,0 +1,195 @@
+//! Machine-readable run events for frontends.
+//!
+//! Every event is one JSON object per line and carries the schema version in
+//! `"v"`. Events currently emitted:
+//!
+//! - `{"v":1,"event":"phase_started","phase":"load"}`
+//! - `{"v":1,"event":"phase_finished","phase":"load","elapsed_ms":1.25}`
+//! - `{"v":1,"event":"progress","done_rows":10,"total_rows":10}`
+//! - `{"v":1,"event":"warning","code":"ragged_rows","message":"...","count":3}`,
+//!   where `count` is only present for warnings about a number of rows or
+//!   elements
+//! - `{"v":1,"event":"result","path":"output.txt","checksum":"0123456789abcdef"}`
+//! - `{"v":1,"event":"soak","iterations":40,"total_ms":60012.5,"fastest_ms":1402.1,
+//!   "slowest_ms":1655.0,"first_quartile_ms":1410.3,"last_quartile_ms":1590.8,
+//!   "sustained_gflops":11.2,"peak_gflops":12.4}`
+//!
+//! Consumers should ignore fields and event types they do not know about.
+
+use std::{
+    io::{self, Write},
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{numfmt::format_f64_roundtrip, soak::SoakReport, trace::Trace, warnings::Warning};
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+pub struct EventSink {
+    out: Option<Box<dyn Write + Send>>,
+    warnings: Vec<Warning>,
+    trace: Option<Arc<Trace>>,
+}
+
+impl EventSink {
+    pub fn disabled() -> EventSink {
+        EventSink {
+            out: None,
+            warnings: Vec::new(),
+            trace: None,
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn connect(path: &Path) -> io::Result<EventSink> {
+        let stream = std::os::unix::net::UnixStream::connect(path)?;
+        Ok(EventSink::from_writer(stream))
+    }
+
+    #[cfg(windows)]
+    pub fn connect(path: &Path) -> io::Result<EventSink> {
+        let pipe = std::fs::OpenOptions::new().write(true).open(path)?;
+        Ok(EventSink::from_writer(pipe))
+    }
+
+    pub fn from_writer(writer: impl Write + Send + 'static) -> EventSink {
+        EventSink {
+            out: Some(Box::new(writer)),
+            warnings: Vec::new(),
+            trace: None,
+        }
+    }
+
+    /// Also records every finished phase as a span in `trace`.
+    pub fn set_trace(&mut self, trace: Arc<Trace>) {
+        self.trace = Some(trace);
+    }
+
+    pub fn trace(&self) -> Option<Arc<Trace>> {
+        self.trace.clone()
+    }
+
+    pub fn phase_started(&mut self, phase: &str) {
+        self.emit("phase_started", &[("phase", quote(phase))]);
+    }
+
+    pub fn phase_finished(&mut self, phase: &str, elapsed: Duration) {
+        if let Some(trace) = &self.trace {
+            let end = Instant::now();
+            trace.span(phase, "phase", end.checked_sub(elapsed).unwrap_or(end), end, Vec::new());
+        }
+        self.emit(
+            "phase_finished",
+            &[
+                ("phase", quote(phase)),
+                ("elapsed_ms", format_f64_roundtrip(elapsed.as_secs_f64() * 1000.0)),
+            ],
+        );
+    }
+
+    pub fn progress(&mut self, done_rows: usize, total_rows: usize) {
+        self.emit(
+            "progress",
+            &[
+                ("done_rows", done_rows.to_string()),
+                ("total_rows", total_rows.to_string()),
+            ],
+        );
+    }
+
+    /// Prints `warning` to stderr, sends it and keeps it for `warnings`.
+    pub fn warn(&mut self, warning: Warning) {
+        eprintln!("Warning: {}", warning);
+        let mut fields = vec![
+            ("code", quote(warning.code())),
+            ("message", quote(&warning.to_string())),
+        ];
+        if let Some(count) = warning.count() {
+            fields.push(("count", count.to_string()));
+        }
+        self.emit("warning", &fields);
+        self.warnings.push(warning);
+    }
+
+    /// Every warning given to `warn` so far.
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    pub fn result(&mut self, path: &str, checksum: u64) {
+        self.emit(
+            "result",
+            &[
+                ("path", quote(path)),
+                ("checksum", quote(&format!("{:016x}", checksum))),
+            ],
+        );
+    }
+
+    pub fn soak(&mut self, report: &SoakReport) {
+        let ms = |d: Duration| format_f64_roundtrip(d.as_secs_f64() * 1000.0);
+        self.emit(
+            "soak",
+            &[
+                ("iterations", report.iterations.to_string()),
+                ("total_ms", ms(report.total)),
+                ("fastest_ms", ms(report.fastest)),
+                ("slowest_ms", ms(report.slowest)),
+                ("first_quartile_ms", ms(report.first_quartile)),
+                ("last_quartile_ms", ms(report.last_quartile)),
+                ("sustained_gflops", format!("{}", report.sustained_gflops)),
+                ("peak_gflops", format!("{}", report.peak_gflops)),
+            ],
+        );
+    }
+
+    fn emit(&mut self, event: &str, fields: &[(&str, String)]) {
+        let out = match &mut self.out {
+            Some(out) => out,
+            None => return,
+        };
+
+        let mut line = format!("{{\"v\":{},\"event\":{}", SCHEMA_VERSION, quote(event));
+        for (key, value) in fields {
+            line.push_str(&format!(",{}:{}", quote(key), value));
+        }
+        line.push_str("}\n");
+
+        // A frontend going away must not abort the computation.
+        if let Err(err) = out.write_all(line.as_bytes()).and_then(|_| out.flush()) {
+            eprintln!("Control socket closed, no more events will be sent: {}", err);
+            self.out = None;
+        }
+    }
+}
+
+pub fn quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quote_escapes() {
+        assert_eq!(quote("a\"b\\c\n"), "\"a\\\"b\\\\c\\n\"");
+        assert_eq!(quote("\u{1}"), "\"\\u0001\"");
+    }
+}
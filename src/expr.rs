@@ -0,0 +1,241 @@
+//! Lazily evaluated elementwise expressions.
+//!
+//! `a.lazy().scale(2.0).add(&b)?.hadamard(&c)?` builds a small expression
+//! tree instead of three intermediate matrices; `eval` then computes every
+//! element of the result in a single parallel pass. Shapes are checked while
+//! the tree is built, so evaluation itself cannot fail. Matrix products are
+//! not expressions: multiply the materialized result instead.
+
+use rayon::prelude::*;
+
+use crate::{Matrix, MatrixError};
+
+enum Node<'a> {
+    Leaf(&'a Matrix),
+    Scale(Box<Node<'a>>, f64),
+    Add(Box<Node<'a>>, Box<Node<'a>>),
+    Sub(Box<Node<'a>>, Box<Node<'a>>),
+    Hadamard(Box<Node<'a>>, Box<Node<'a>>),
+}
+
+impl<'a> Node<'a> {
+    // Value of the element at `index` in row-major order.
+    fn at(&self, index: usize) -> f64 {
+        match self {
+            Node::Leaf(m) => m.data[index],
+            Node::Scale(a, factor) => a.at(index) * factor,
+            Node::Add(a, b) => a.at(index) + b.at(index),
+            Node::Sub(a, b) => a.at(index) - b.at(index),
+            Node::Hadamard(a, b) => a.at(index) * b.at(index),
+        }
+    }
+}
+
+/// An elementwise expression over borrowed matrices, computed by `eval`.
+///
+/// ```
+/// use matrix_mul::{matrix, Expr};
+///
+/// let (a, b) = (matrix![1.0, 2.0], matrix![3.0, 4.0]);
+/// let expr: Expr = a.lazy().scale(2.0).add(&b)?.hadamard(&a)?;
+/// assert_eq!(expr.eval(), matrix![5.0, 16.0]);
+/// # Ok::<(), matrix_mul::Error>(())
+/// ```
+pub struct Expr<'a> {
+    rows: usize,
+    cols: usize,
+    node: Node<'a>,
+}
+
+impl<'a> From<&'a Matrix> for Expr<'a> {
+    fn from(m: &'a Matrix) -> Expr<'a> {
+        Expr {
+            rows: m.rows,
+            cols: m.cols,
+            node: Node::Leaf(m),
+        }
+    }
+}
+
+impl<'a> Expr<'a> {
+    /// The shape of the result.
+    pub fn shape(&self) -> (usize, usize) {
+        (self.rows, self.cols)
+    }
+
+    fn combine(
+        self,
+        other: impl Into<Expr<'a>>,
+        op: fn(Box<Node<'a>>, Box<Node<'a>>) -> Node<'a>,
+    ) -> Result<Expr<'a>, MatrixError> {
+        let other = other.into();
+        if self.shape() != other.shape() {
+            return Err(MatrixError::DimensionMismatch {
+                left: self.shape(),
+                right: other.shape(),
+            });
+        }
+        Ok(Expr {
+            rows: self.rows,
+            cols: self.cols,
+            node: op(Box::new(self.node), Box::new(other.node)),
+        })
+    }
+
+    /// Every element times `factor`.
+    pub fn scale(self, factor: f64) -> Expr<'a> {
+        Expr {
+            rows: self.rows,
+            cols: self.cols,
+            node: Node::Scale(Box::new(self.node), factor),
+        }
+    }
+
+    /// `self + other`; the shapes must match. The `+` operator panics
+    /// where this returns the error.
+    #[allow(clippy::should_implement_trait)]
+    pub fn add(self, other: impl Into<Expr<'a>>) -> Result<Expr<'a>, MatrixError> {
+        self.combine(other, Node::Add)
+    }
+
+    /// `self - other`; the shapes must match.
+    #[allow(clippy::should_implement_trait)]
+    pub fn sub(self, other: impl Into<Expr<'a>>) -> Result<Expr<'a>, MatrixError> {
+        self.combine(other, Node::Sub)
+    }
+
+    /// The elementwise product; the shapes must match.
+    pub fn hadamard(self, other: impl Into<Expr<'a>>) -> Result<Expr<'a>, MatrixError> {
+        self.combine(other, Node::Hadamard)
+    }
+
+    /// The result, in a new matrix filled in one parallel pass.
+    pub fn eval(&self) -> Matrix {
+        let mut out = Matrix::new_unchecked(self.rows, self.cols, vec![0.0; self.rows * self.cols]);
+        self.fill(&mut out.data);
+        out
+    }
+
+    /// The result, written over `out`, which must have its shape.
+    pub fn eval_into(&self, out: &mut Matrix) -> Result<(), MatrixError> {
+        if out.shape() != self.shape() {
+            return Err(MatrixError::DimensionMismatch {
+                left: self.shape(),
+                right: out.shape(),
+            });
+        }
+        self.fill(&mut out.data);
+        Ok(())
+    }
+
+    fn fill(&self, data: &mut [f64]) {
+        data.par_iter_mut()
+            .enumerate()
+            .for_each(|(i, x)| *x = self.node.at(i));
+    }
+}
+
+impl Matrix {
+    /// This matrix as the leaf of an elementwise expression.
+    pub fn lazy(&self) -> Expr<'_> {
+        Expr::from(self)
+    }
+}
+
+// Operators panic on shape mismatch, like the indexing operators would;
+// use the methods to get a `Result` instead.
+mod ops {
+    use super::Expr;
+    use crate::Matrix;
+
+    macro_rules! lazy_op {
+        ($trait:ident, $method:ident) => {
+            impl<'a> std::ops::$trait<&'a Matrix> for &'a Matrix {
+                type Output = Expr<'a>;
+
+                fn $method(self, other: &'a Matrix) -> Expr<'a> {
+                    Expr::from(self).$method(other).unwrap_or_else(|err| panic!("{}", err))
+                }
+            }
+
+            impl<'a, T: Into<Expr<'a>>> std::ops::$trait<T> for Expr<'a> {
+                type Output = Expr<'a>;
+
+                fn $method(self, other: T) -> Expr<'a> {
+                    Expr::$method(self, other).unwrap_or_else(|err| panic!("{}", err))
+                }
+            }
+        };
+    }
+
+    lazy_op!(Add, add);
+    lazy_op!(Sub, sub);
+
+    impl<'a> std::ops::Mul<f64> for Expr<'a> {
+        type Output = Expr<'a>;
+
+        fn mul(self, factor: f64) -> Expr<'a> {
+            self.scale(factor)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc_counter;
+
+    #[test]
+    fn fused_equals_eager() {
+        let a = Matrix::random(31, 17);
+        let b = Matrix::random(31, 17);
+        let c = Matrix::random(31, 17);
+
+        let eager = a.scale(2.0).add(&b).unwrap().hadamard(&c).unwrap();
+        let lazy = a.lazy().scale(2.0).add(&b).unwrap().hadamard(&c).unwrap().eval();
+        assert_eq!(lazy, eager);
+
+        let mut out = Matrix::new_unchecked(31, 17, vec![0.0; 31 * 17]);
+        a.lazy().sub(&b).unwrap().eval_into(&mut out).unwrap();
+        assert_eq!(out, a.sub(&b).unwrap());
+    }
+
+    #[test]
+    fn single_allocation() {
+        let a = Matrix::random(64, 64);
+        let b = Matrix::random(64, 64);
+        let c = Matrix::random(64, 64);
+        let bytes = 64 * 64 * std::mem::size_of::<f64>();
+
+        let expr = a.lazy().scale(2.0).add(&b).unwrap().hadamard(&c).unwrap();
+        let (count, _) = alloc_counter::count_allocations(bytes, || expr.eval());
+        assert_eq!(count, 1);
+
+        let (count, _) = alloc_counter::count_allocations(bytes, || {
+            a.scale(2.0).add(&b).unwrap().hadamard(&c).unwrap()
+        });
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn shape_errors_at_construction() {
+        let a = Matrix::random(2, 3);
+        let b = Matrix::random(3, 2);
+
+        assert!(matches!(
+            a.lazy().scale(3.0).add(&b),
+            Err(MatrixError::DimensionMismatch { left: (2, 3), right: (3, 2) })
+        ));
+        let mut out = Matrix::new_unchecked(1, 1, vec![0.0]);
+        assert!(a.lazy().eval_into(&mut out).is_err());
+    }
+
+    #[test]
+    fn operators() {
+        let a = Matrix::random(5, 4);
+        let b = Matrix::random(5, 4);
+
+        let eager = a.add(&b).unwrap().scale(0.5).sub(&b).unwrap();
+        assert_eq!(((&a + &b) * 0.5 - &b).eval(), eager);
+    }
+}
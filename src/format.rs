@@ -0,0 +1,100 @@
+//! Number formatting shared by everything that prints matrix elements.
+//!
+//! `Precision::Full` is `numfmt::format_f64_roundtrip`, the shortest text
+//! that parses back to the same bits, and is what output files use unless
+//! asked otherwise.
+//! `Precision::Significant(n)` is for people reading the numbers: it rounds
+//! to n significant digits, drops trailing zeros, prints -0 as 0, and
+//! switches to scientific notation outside [1e-4, 10^n).
+
+use std::fmt;
+
+use crate::numfmt::format_f64_roundtrip;
+
+/// Significant digits for values printed to the terminal.
+pub const PREVIEW_DIGITS: usize = 6;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Precision {
+    #[default]
+    Full,
+    Significant(usize),
+}
+
+/// Displays an element with the given precision.
+#[derive(Clone, Copy, Debug)]
+pub struct Number(pub f64, pub Precision);
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Number(x, precision) = *self;
+        let digits = match precision {
+            Precision::Full => return f.pad(&format_f64_roundtrip(x)),
+            Precision::Significant(digits) => digits.max(1),
+        };
+        if x == 0.0 {
+            return write!(f, "0");
+        }
+        if !x.is_finite() {
+            return write!(f, "{}", x);
+        }
+
+        // The exponent after rounding, so 9.9999996 at 6 digits counts as 10.
+        let scientific = format!("{:.*e}", digits - 1, x);
+        let (mantissa, exponent) = scientific.split_once('e').unwrap();
+        let exponent: i32 = exponent.parse().unwrap();
+
+        if exponent < -4 || exponent >= digits as i32 {
+            write!(f, "{}e{}", trim_zeros(mantissa), exponent)
+        } else {
+            let decimals = (digits as i32 - 1 - exponent).max(0) as usize;
+            write!(f, "{}", trim_zeros(&format!("{:.*}", decimals, x)))
+        }
+    }
+}
+
+fn trim_zeros(s: &str) -> &str {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn six(x: f64) -> String {
+        Number(x, Precision::Significant(6)).to_string()
+    }
+
+    #[test]
+    fn awkward_values() {
+        assert_eq!(six(-0.0), "0");
+        assert_eq!(six(0.1 + 0.2), "0.3");
+        assert_eq!(six(1e-17), "1e-17");
+        assert_eq!(six(-1e-17), "-1e-17");
+        assert_eq!(six(12345678.9), "1.23457e7");
+        assert_eq!(six(123456.7), "123457");
+        assert_eq!(six(9.9999996), "10");
+        assert_eq!(six(999999.7), "1e6");
+        assert_eq!(six(0.0001), "0.0001");
+        assert_eq!(six(0.00001234), "1.234e-5");
+        assert_eq!(six(-2.5), "-2.5");
+        assert_eq!(six(1.0 / 3.0), "0.333333");
+        assert_eq!(six(f64::NAN), "NaN");
+        assert_eq!(six(f64::NEG_INFINITY), "-inf");
+        assert_eq!(Number(2.6, Precision::Significant(0)).to_string(), "3");
+    }
+
+    #[test]
+    fn full_precision_round_trips() {
+        for x in [-0.0, 0.1 + 0.2, 1e-17, 12345678.9, f64::MAX, f64::MIN_POSITIVE] {
+            let s = Number(x, Precision::Full).to_string();
+            assert_eq!(crate::numfmt::parse_f64(&s).unwrap().to_bits(), x.to_bits(), "{}", s);
+        }
+        assert_eq!(Number(-0.0, Precision::Full).to_string(), "-0");
+        assert_eq!(Number(1e-21, Precision::Full).to_string(), "1e-21");
+    }
+}
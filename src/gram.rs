@@ -0,0 +1,138 @@
+//! `--op aat` and `--op ata`: a matrix times its own transpose.
+//!
+//! The product is symmetric, so only the lower triangle is computed, each
+//! element as the dot product of two rows, and then copied across the
+//! diagonal; the result is symmetric to the bit. Row i of the triangle has
+//! i + 1 elements, so the parallel path splits the rows into ranges of
+//! equal work rather than equal length.
+
+use std::ops::Range;
+
+use rayon::prelude::*;
+
+use crate::{Algorithm, Matrix};
+
+impl Matrix {
+    /// `self * selfᵀ`.
+    pub fn multiply_by_own_transpose(&self, algorithm: Algorithm) -> Matrix {
+        let n = self.rows;
+        let mut result = Matrix::new_unchecked(n, n, vec![0.0; n * n]);
+        match algorithm {
+            Algorithm::Seq => self.lower_triangle(0..n, &mut result.data),
+            Algorithm::Par => {
+                let ranges = balanced_ranges(n, rayon::current_num_threads() * 4);
+                let mut bands = Vec::with_capacity(ranges.len());
+                let mut rest = &mut result.data[..];
+                for range in ranges {
+                    let (band, tail) = rest.split_at_mut(range.len() * n);
+                    bands.push((range, band));
+                    rest = tail;
+                }
+                bands.into_par_iter().for_each(|(range, band)| self.lower_triangle(range, band));
+            }
+        }
+        for i in 0..n {
+            for j in i + 1..n {
+                result.data[i * n + j] = result.data[j * n + i];
+            }
+        }
+        result
+    }
+
+    /// `selfᵀ * self`.
+    pub fn multiply_transpose_by_self(&self, algorithm: Algorithm) -> Matrix {
+        self.transpose().multiply_by_own_transpose(algorithm)
+    }
+
+    // Elements (i, j) with j <= i of `self * selfᵀ` for the rows in `rows`,
+    // into `out`, which holds those rows. Each is summed in the same order
+    // as the general kernel.
+    fn lower_triangle(&self, rows: Range<usize>, out: &mut [f64]) {
+        let n = self.rows;
+        for (i, out) in rows.zip(out.chunks_mut(n.max(1))) {
+            let a = self.row(i);
+            for (j, cell) in out.iter_mut().enumerate().take(i + 1) {
+                let mut sum = 0.0;
+                for (x, y) in a.iter().zip(self.row(j)) {
+                    sum += x * y;
+                }
+                *cell = sum;
+            }
+        }
+    }
+}
+
+/// Splits rows 0..n of a lower triangle into `parts` contiguous, non-empty
+/// ranges, or n when there are fewer rows, with about the same number of
+/// elements each.
+pub(crate) fn balanced_ranges(n: usize, parts: usize) -> Vec<Range<usize>> {
+    let parts = parts.clamp(1, n.max(1)) as u64;
+    let total = (n as u64) * (n as u64 + 1) / 2;
+    let mut ranges = Vec::with_capacity(parts as usize);
+    let (mut start, mut done) = (0, 0u64);
+    for i in 0..n {
+        let part = ranges.len() as u64 + 1;
+        if part == parts {
+            break;
+        }
+        done += i as u64 + 1;
+        // Close the range once it reaches its share of the work so far, or
+        // when only one row is left for each range still to come.
+        let rows_left = (n - i - 1) as u64;
+        if done * parts >= part * total || rows_left == parts - part {
+            ranges.push(start..i + 1);
+            start = i + 1;
+        }
+    }
+    if start < n {
+        ranges.push(start..n);
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_general_multiply() {
+        for (rows, cols) in [(1, 1), (7, 3), (31, 17), (20, 45), (0, 4), (4, 0)] {
+            let a = Matrix::random(rows, cols);
+            for algorithm in [Algorithm::Seq, Algorithm::Par] {
+                let aat = a.multiply_by_own_transpose(algorithm);
+                let ata = a.multiply_transpose_by_self(algorithm);
+                for (product, expected) in [(&aat, a.multiply(&a.transpose()).unwrap()), (&ata, a.transpose().multiply(&a).unwrap())] {
+                    assert_eq!(product.shape(), expected.shape());
+                    assert!(product.max_abs_diff(&expected) <= 1e-9, "{}x{}", rows, cols);
+                    assert_eq!(product.bits(), product.transpose().bits(), "{}x{} not symmetric", rows, cols);
+                }
+            }
+            assert_eq!(a.multiply_by_own_transpose(Algorithm::Par).bits(), a.multiply_by_own_transpose(Algorithm::Seq).bits());
+        }
+    }
+
+    #[test]
+    fn balanced_partition() {
+        for n in [1, 2, 3, 7, 15, 99, 101] {
+            for parts in [1, 2, 3, 4, 5, 16, 200] {
+                let ranges = balanced_ranges(n, parts);
+                assert_eq!(ranges.len(), parts.min(n), "n {} parts {}", n, parts);
+                assert_eq!(ranges[0].start, 0);
+                assert_eq!(ranges.last().unwrap().end, n);
+                for pair in ranges.windows(2) {
+                    assert_eq!(pair[0].end, pair[1].start);
+                }
+                assert!(ranges.iter().all(|r| !r.is_empty()));
+
+                // No range is more than a row longer than its share.
+                let work = |r: &Range<usize>| r.clone().map(|i| i + 1).sum::<usize>();
+                let share = n * (n + 1) / 2 / ranges.len();
+                assert!(ranges.iter().all(|r| work(r) <= share + n), "n {} parts {}: {:?}", n, parts, ranges);
+            }
+        }
+        // Later rows are longer, so their ranges are shorter.
+        let ranges = balanced_ranges(101, 4);
+        assert!(ranges[0].len() > 2 * ranges[3].len(), "{:?}", ranges);
+        assert!(balanced_ranges(0, 4).is_empty());
+    }
+}
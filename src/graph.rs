@@ -0,0 +1,117 @@
+//! Helpers for matrices used as graph adjacency matrices, where entry (i, j)
+//! is the weight (or count) of edges from vertex i to vertex j.
+
+use crate::{Matrix, MatrixError};
+
+impl Matrix {
+    /// Out-degree of every vertex: the row sums.
+    pub fn degrees(&self) -> Vec<f64> {
+        (0..self.rows).map(|i| self.row(i).iter().sum()).collect()
+    }
+
+    /// Entry (i, j) is 1 if j can be reached from i by a walk of one or more
+    /// edges, and 0 otherwise. Any non-zero entry counts as an edge.
+    pub fn transitive_closure(&self) -> Result<Matrix, MatrixError> {
+        self.check_square()?;
+
+        let mut reach = self.to_boolean();
+        // Each squaring doubles the walk length covered, so this converges
+        // after about log2(n) rounds.
+        loop {
+            let next = reach
+                .zip_map(&reach.multiply_plain(&reach).to_boolean(), |a, b| a.max(b))
+                .expect("same shape");
+            if next == reach {
+                return Ok(reach);
+            }
+            reach = next;
+        }
+    }
+
+    /// Entry (i, j) is the number of walks of exactly `length` edges from i
+    /// to j.
+    pub fn count_paths(&self, length: u32) -> Result<Matrix, MatrixError> {
+        self.pow(length)
+    }
+
+    fn to_boolean(&self) -> Matrix {
+        let data = self.data.iter().map(|&x| if x != 0.0 { 1.0 } else { 0.0 }).collect();
+        Matrix::new_unchecked(self.rows, self.cols, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matrix;
+
+    // 0 -> 1, 0 -> 2, 1 -> 2, 2 -> 3, 3 -> 1, and 4 is isolated.
+    fn graph() -> Matrix {
+        matrix![0.0, 1.0, 1.0, 0.0, 0.0;
+                0.0, 0.0, 1.0, 0.0, 0.0;
+                0.0, 0.0, 0.0, 1.0, 0.0;
+                0.0, 1.0, 0.0, 0.0, 0.0;
+                0.0, 0.0, 0.0, 0.0, 0.0]
+    }
+
+    fn bfs_reachability(m: &Matrix) -> Matrix {
+        let n = m.rows;
+        let mut reach = Matrix::new_unchecked(n, n, vec![0.0; n * n]);
+        for source in 0..n {
+            let mut queue: Vec<usize> = (0..n).filter(|&j| m.get(source, j) != 0.0).collect();
+            while let Some(v) = queue.pop() {
+                if reach.get(source, v) == 0.0 {
+                    reach.set(source, v, 1.0);
+                    queue.extend((0..n).filter(|&j| m.get(v, j) != 0.0));
+                }
+            }
+        }
+        reach
+    }
+
+    #[test]
+    fn degrees() {
+        assert_eq!(graph().degrees(), [2.0, 1.0, 1.0, 1.0, 0.0]);
+        assert_eq!(Matrix::new_unchecked(2, 0, vec![]).degrees(), [0.0, 0.0]);
+    }
+
+    #[test]
+    fn path_counts() {
+        let g = graph();
+        let two = g.count_paths(2).unwrap();
+        let three = g.count_paths(3).unwrap();
+
+        // 0 -> 1 -> 2 and 0 -> 2 -> 3.
+        assert_eq!((two.get(0, 2), two.get(0, 3)), (1.0, 1.0));
+        assert_eq!(two.get(1, 3), 1.0);
+        assert_eq!(two.get(0, 1), 0.0);
+        // 0 -> 1 -> 2 -> 3 and 0 -> 2 -> 3 -> 1.
+        assert_eq!((three.get(0, 3), three.get(0, 1)), (1.0, 1.0));
+        // The cycle 1 -> 2 -> 3 -> 1.
+        assert_eq!((three.get(1, 1), three.get(2, 2), three.get(3, 3)), (1.0, 1.0, 1.0));
+        assert_eq!(g.count_paths(0).unwrap(), Matrix::identity(5));
+    }
+
+    #[test]
+    fn closure_matches_bfs() {
+        let g = graph();
+        assert_eq!(g.transitive_closure().unwrap(), bfs_reachability(&g));
+
+        let random = Matrix::random(30, 30).to_boolean().zip_map(&Matrix::random(30, 30), |a, r| {
+            if r < 0.05 { a } else { 0.0 }
+        });
+        let random = random.unwrap();
+        assert_eq!(random.transitive_closure().unwrap(), bfs_reachability(&random));
+    }
+
+    #[test]
+    fn symmetric_and_square() {
+        assert!(!graph().is_symmetric());
+        let undirected = graph().add(&graph().transpose()).unwrap();
+        assert!(undirected.is_symmetric());
+        assert_eq!(
+            Matrix::random(2, 3).transitive_closure(),
+            Err(MatrixError::NotSquare { rows: 2, cols: 3 })
+        );
+    }
+}
@@ -0,0 +1,146 @@
+//! `--dtype i64`: integer matrices multiplied exactly.
+//!
+//! Values are parsed straight to i64, never through f64, which only holds
+//! integers exactly up to 2^53. Products are accumulated in i128, where
+//! every single i64 * i64 product fits, and a sum that overflows i128 is an
+//! error rather than a wrapped result.
+
+use crate::Matrix;
+
+pub type IntMatrix = Matrix<i64>;
+
+/// The exact product of two IntMatrix.
+pub type IntProduct = Matrix<i128>;
+
+/// A 192-bit two's complement accumulator, wide enough that summing i128
+/// values can only overflow after 2^63 of them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Wide {
+    high: i64,
+    low: u128,
+}
+
+impl Wide {
+    fn add(self, x: i128) -> Wide {
+        let (low, carry) = self.low.overflowing_add(x as u128);
+        let sign = if x < 0 { -1 } else { 0 };
+        Wide {
+            high: self.high + sign + carry as i64,
+            low,
+        }
+    }
+
+    fn from_i128(x: i128) -> Wide {
+        Wide::default().add(x)
+    }
+}
+
+/// Recomputes `cells` of `product` with a wider accumulator than the
+/// multiply used and returns the first one that differs.
+pub fn verify_exact(
+    a: &IntMatrix,
+    b: &IntMatrix,
+    product: &IntProduct,
+    cells: &[(usize, usize)],
+) -> Result<(), (usize, usize)> {
+    for &(i, j) in cells {
+        let exact = (0..a.cols).fold(Wide::default(), |sum, k| sum.add(a.get(i, k) as i128 * b.get(k, j) as i128));
+        if exact != Wide::from_i128(product.get(i, j)) {
+            return Err((i, j));
+        }
+    }
+    Ok(())
+}
+
+/// Integer values in `s` that an f64 cannot hold exactly.
+// The text parser counts these as it reads; kept to check it against.
+#[cfg(test)]
+pub(crate) fn inexact_in_f64(s: &str) -> usize {
+    s.lines()
+        .flat_map(crate::element::fields)
+        .filter(|token| crate::numfmt::parse_f64(token).is_some_and(|x| crate::numfmt::is_inexact_integer(token, x)))
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Algorithm, MatrixError};
+
+    const BIG: i64 = (1 << 53) + 1;
+
+    #[test]
+    fn parses_csv_and_whitespace() {
+        let m = IntMatrix::parse("1, 2,3\n\n4 5 6\n").unwrap();
+        assert_eq!((m.shape(), m.data.clone()), ((2, 3), vec![1, 2, 3, 4, 5, 6]));
+        assert!(matches!(
+            IntMatrix::parse("1,2\n3"),
+            Err(MatrixError::RaggedRow { line: 2, expected: 2, found: 1 })
+        ));
+        assert!(matches!(IntMatrix::parse("1,2.5"), Err(MatrixError::InvalidNumber { line: 1, column: 3, .. })));
+        assert!(matches!(IntMatrix::parse("1,,2"), Err(MatrixError::InvalidNumber { .. })));
+        assert!(matches!(
+            IntMatrix::parse("1,9223372036854775808"),
+            Err(MatrixError::OutOfRange { line: 1, column: 3, dtype: "i64", .. })
+        ));
+    }
+
+    #[test]
+    fn large_values_stay_exact() {
+        let text = format!("{},1\n2,{}", BIG, -BIG);
+        let a = IntMatrix::parse(&text).unwrap();
+        let b = IntMatrix::parse("1,0\n0,1").unwrap();
+        for algorithm in [Algorithm::Seq, Algorithm::Par] {
+            let c = a.multiply(&b, algorithm).unwrap();
+            assert_eq!(c.data, vec![BIG as i128, 1, 2, -BIG as i128]);
+            let mut out = Vec::new();
+            c.write(&mut out).unwrap();
+            assert_eq!(String::from_utf8(out).unwrap(), format!("{} 1\n2 {}\n", BIG, -BIG));
+        }
+
+        // Through f64 the same value is off by one, which is what the warning
+        // is about.
+        assert_eq!(inexact_in_f64(&text), 2);
+        assert_eq!(inexact_in_f64("9007199254740992 1"), 0);
+        let parsed = Matrix::from_string_with(&text.replace(',', " "), &crate::ParseOptions::default());
+        assert_eq!(parsed.unwrap().1.inexact_integers, 2);
+        // Past i64 too.
+        let past = "18446744073709551617 18446744073709551616 -99999999999999999999";
+        assert_eq!(inexact_in_f64(past), 2);
+        let parsed = Matrix::from_string_with(past, &crate::ParseOptions::default());
+        assert_eq!(parsed.unwrap().1.inexact_integers, 2);
+        let f = Matrix::try_from_str(&format!("{} 1", BIG)).unwrap();
+        assert_ne!(f.get(0, 0) as i64, BIG);
+
+        // Sums past i64 are still exact.
+        let a = IntMatrix::parse(&format!("{},{}", i64::MAX, i64::MAX)).unwrap();
+        let b = IntMatrix::parse("2\n2").unwrap();
+        assert_eq!(a.multiply(&b, Algorithm::Seq).unwrap().data, vec![4 * i64::MAX as i128]);
+    }
+
+    #[test]
+    fn overflow_is_an_error() {
+        let a = IntMatrix::parse(&format!("1,1\n{},{}", i64::MIN, i64::MIN)).unwrap();
+        let b = IntMatrix::parse(&format!("{}\n{}", i64::MIN, i64::MIN)).unwrap();
+        for algorithm in [Algorithm::Seq, Algorithm::Par] {
+            assert!(matches!(
+                a.multiply(&b, algorithm),
+                Err(MatrixError::Overflow { row: 1, col: 0 })
+            ));
+        }
+    }
+
+    #[test]
+    fn verifies_against_wide_sum() {
+        let a = IntMatrix::parse(&format!("{},{},-3\n4,5,6", i64::MAX, i64::MIN)).unwrap();
+        let b = IntMatrix::parse(&format!("{},1\n{},2\n7,3", i64::MAX, i64::MIN)).unwrap();
+        let mut c = a.multiply(&b, Algorithm::Seq).unwrap();
+        let cells = [(0, 0), (0, 1), (1, 0), (1, 1)];
+        assert_eq!(verify_exact(&a, &b, &c, &cells), Ok(()));
+        c.data[2] += 1;
+        assert_eq!(verify_exact(&a, &b, &c, &cells), Err((1, 0)));
+
+        assert_eq!(Wide::from_i128(-1).add(1), Wide::default());
+        assert_eq!(Wide::from_i128(i128::MAX).add(1), Wide { high: 0, low: 1 << 127 });
+    }
+}
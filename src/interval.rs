@@ -0,0 +1,229 @@
+//! `--dtype interval`: products as intervals guaranteed to contain the exact
+//! real result.
+//!
+//! Each element is a pair of bounds, and every operation on them rounds
+//! outwards: the rounded-to-nearest result of one f64 operation is within
+//! half an ulp of the exact one, so moving the lower bound one ulp down and
+//! the upper bound one ulp up encloses it, including on underflow and
+//! overflow. That costs a `next_down` and a `next_up` per operation rather
+//! than switching the FPU's rounding mode, which Rust has no way to do
+//! safely. Widths therefore grow by a few ulps of the running sum per term,
+//! linearly in the inner dimension.
+//!
+//! The guarantee is for finite inputs; a NaN or infinite bound gives
+//! intervals that mean nothing.
+
+use std::io::{self, Write};
+
+use crate::{element::Element, Matrix, Precision};
+
+/// The reals from `lo` to `hi`, both included.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Interval {
+    pub lo: f64,
+    pub hi: f64,
+}
+
+impl Interval {
+    /// The degenerate interval holding just `x`.
+    pub fn point(x: f64) -> Interval {
+        Interval { lo: x, hi: x }
+    }
+
+    pub fn width(self) -> f64 {
+        self.hi - self.lo
+    }
+
+    pub fn contains(self, x: f64) -> bool {
+        self.lo <= x && x <= self.hi
+    }
+}
+
+impl std::ops::Add for Interval {
+    type Output = Interval;
+
+    fn add(self, other: Interval) -> Interval {
+        Interval {
+            lo: (self.lo + other.lo).next_down(),
+            hi: (self.hi + other.hi).next_up(),
+        }
+    }
+}
+
+impl std::ops::Mul for Interval {
+    type Output = Interval;
+
+    fn mul(self, other: Interval) -> Interval {
+        let products = [
+            product(self.lo, other.lo),
+            product(self.lo, other.hi),
+            product(self.hi, other.lo),
+            product(self.hi, other.hi),
+        ];
+        Interval {
+            lo: products.iter().map(|p| p.lo).fold(f64::INFINITY, f64::min),
+            hi: products.iter().map(|p| p.hi).fold(f64::NEG_INFINITY, f64::max),
+        }
+    }
+}
+
+// x * y rounded outwards; a zero factor makes it exact.
+fn product(x: f64, y: f64) -> Interval {
+    let p = x * y;
+    if x == 0.0 || y == 0.0 {
+        Interval::point(p)
+    } else {
+        Interval { lo: p.next_down(), hi: p.next_up() }
+    }
+}
+
+/// Each element of a product encloses every `sum(a[i][k] * b[k][j])` with
+/// `a` and `b` ranging over the operands' intervals.
+impl Element for Interval {
+    type Sum = Interval;
+
+    fn zero() -> Interval {
+        Interval::point(0.0)
+    }
+
+    fn mul_add(sum: Interval, a: Interval, b: Interval) -> Option<Interval> {
+        Some(sum + a * b)
+    }
+}
+
+pub type IntervalMatrix = Matrix<Interval>;
+
+impl IntervalMatrix {
+    /// `m` with every element a degenerate interval.
+    pub fn from_points(m: &Matrix) -> IntervalMatrix {
+        IntervalMatrix {
+            rows: m.rows,
+            cols: m.cols,
+            data: m.data.iter().map(|&x| Interval::point(x)).collect(),
+        }
+    }
+
+    /// The lower bounds and the upper bounds, as two matrices.
+    pub fn bounds(&self) -> (Matrix, Matrix) {
+        let bound = |f: fn(&Interval) -> f64| Matrix::new_unchecked(self.rows, self.cols, self.data.iter().map(f).collect());
+        (bound(|x| x.lo), bound(|x| x.hi))
+    }
+
+    /// The widest element's width, 0 for an empty matrix.
+    pub fn max_width(&self) -> f64 {
+        self.data.iter().map(|x| x.width()).fold(0.0, f64::max)
+    }
+
+    /// The lower bounds, a line with `X`, then the upper bounds: two
+    /// operands of an input file. Always at full precision, since rounding
+    /// a bound to fewer digits could move it inwards.
+    pub fn write(&self, mut writer: impl Write) -> io::Result<()> {
+        let (lo, hi) = self.bounds();
+        lo.write_text(&mut writer, Precision::Full)?;
+        writeln!(writer, "X")?;
+        hi.write_text(&mut writer, Precision::Full)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::{Algorithm, MatrixError};
+
+    // Exact dot product of f64 values, as a sum of non-overlapping parts
+    // (Shewchuk's algorithm, as in Python's math.fsum), compared to `x`.
+    fn exact_cmp(terms: impl Iterator<Item = (f64, f64)>, x: f64) -> std::cmp::Ordering {
+        let mut partials: Vec<f64> = Vec::new();
+        let mut push = |mut v: f64| {
+            let mut kept = 0;
+            for i in 0..partials.len() {
+                let mut p = partials[i];
+                if v.abs() < p.abs() {
+                    std::mem::swap(&mut v, &mut p);
+                }
+                let hi = v + p;
+                let lo = p - (hi - v);
+                if lo != 0.0 {
+                    partials[kept] = lo;
+                    kept += 1;
+                }
+                v = hi;
+            }
+            partials.truncate(kept);
+            partials.push(v);
+        };
+        for (a, b) in terms {
+            // a * b is exactly p + e.
+            let p = a * b;
+            push(p);
+            push(a.mul_add(b, -p));
+        }
+        push(-x);
+        // Non-overlapping parts: the largest decides the sign.
+        let total = partials.iter().copied().fold(0.0, |m: f64, p| if p.abs() > m.abs() { p } else { m });
+        total.partial_cmp(&0.0).unwrap()
+    }
+
+    #[test]
+    fn encloses_point_products() {
+        use std::cmp::Ordering;
+
+        let mut rng = StdRng::seed_from_u64(7);
+        for case in 0..200 {
+            let (n, m, k) = (rng.gen_range(1..6), rng.gen_range(1..40), rng.gen_range(1..6));
+            // Mixed signs and magnitudes, so sums cancel and round.
+            let mut value = || rng.gen_range(-1.0..1.0) * 10f64.powi(rng.gen_range(-8..8));
+            let a = Matrix::new_unchecked(n, m, (0..n * m).map(|_| value()).collect());
+            let b = Matrix::new_unchecked(m, k, (0..m * k).map(|_| value()).collect());
+            let point = a.multiply(&b).unwrap();
+            for algorithm in [Algorithm::Seq, Algorithm::Par] {
+                let product = IntervalMatrix::from_points(&a).multiply(&IntervalMatrix::from_points(&b), algorithm).unwrap();
+                for i in 0..n {
+                    for j in 0..k {
+                        let cell = product.get(i, j);
+                        assert!(cell.contains(point.get(i, j)), "case {}: {:?} {}", case, cell, point.get(i, j));
+                        let terms = || (0..m).map(|t| (a.get(i, t), b.get(t, j)));
+                        assert_ne!(exact_cmp(terms(), cell.lo), Ordering::Less, "case {}", case);
+                        assert_ne!(exact_cmp(terms(), cell.hi), Ordering::Greater, "case {}", case);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn widths_grow_with_k() {
+        let width = |k: usize| {
+            let a = Matrix::new_unchecked(1, k, vec![0.1; k]);
+            let b = Matrix::new_unchecked(k, 1, vec![0.3; k]);
+            let product = IntervalMatrix::from_points(&a).multiply(&IntervalMatrix::from_points(&b), Algorithm::Seq).unwrap();
+            product.max_width() / product.data[0].lo
+        };
+        let widths: Vec<f64> = [1, 10, 100, 1000].into_iter().map(width).collect();
+        assert!(widths.windows(2).all(|w| w[0] < w[1]), "{:?}", widths);
+        // A few ulps per term, so roughly linear in k and nowhere near 1.
+        assert!(widths[0] < 1e-15 && widths[3] < 1e-11, "{:?}", widths);
+        assert!(widths[3] / widths[2] < 20.0, "{:?}", widths);
+
+        // Exact inputs stay exact through multiplication by zero.
+        let zero = IntervalMatrix::from_points(&Matrix::new_unchecked(2, 2, vec![0.0; 4]));
+        let ones = IntervalMatrix::from_points(&Matrix::new_unchecked(2, 1, vec![1.0; 2]));
+        let product = zero.multiply(&ones, Algorithm::Par).unwrap();
+        assert!(product.data.iter().all(|x| x.lo <= 0.0 && x.hi >= 0.0 && x.width() < 1e-300));
+        assert!(matches!(ones.multiply(&ones, Algorithm::Seq), Err(MatrixError::DimensionMismatch { .. })));
+    }
+
+    #[test]
+    fn writes_both_bounds() {
+        let m = IntervalMatrix {
+            rows: 1,
+            cols: 2,
+            data: vec![Interval { lo: -0.5, hi: 0.25 }, Interval::point(3.0)],
+        };
+        let mut out = Vec::new();
+        m.write(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "-0.5 3\nX\n0.25 3\n");
+    }
+}
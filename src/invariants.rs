@@ -0,0 +1,126 @@
+//! Internal consistency checks for index and stride arithmetic.
+//!
+//! These run in debug builds, in the crate's own unit tests, and in release
+//! builds when the `paranoid` feature is enabled; the `paranoid` test target
+//! runs the kernels with them on (`cargo test --release --features paranoid`).
+//! Otherwise they compile to nothing, so they may be called from inner loops.
+
+pub const ENABLED: bool = cfg!(any(debug_assertions, test, feature = "paranoid"));
+
+/// The backing buffer holds exactly `rows * cols` elements.
+#[inline(always)]
+pub fn buffer_len(rows: usize, cols: usize, len: usize) {
+    if ENABLED {
+        assert_eq!(
+            rows.checked_mul(cols),
+            Some(len),
+            "invariant: {}x{} matrix backed by {} elements",
+            rows,
+            cols,
+            len
+        );
+    }
+}
+
+/// `(row, col)` addresses an element of a `rows x cols` matrix.
+#[inline(always)]
+pub fn in_bounds(rows: usize, cols: usize, row: usize, col: usize) {
+    if ENABLED {
+        assert!(
+            row < rows && col < cols,
+            "invariant: index ({}, {}) outside {}x{} matrix",
+            row,
+            col,
+            rows,
+            cols
+        );
+    }
+}
+
+/// A chunk handed to a parallel worker covers exactly the row it claims to,
+/// so no two workers can write to the same element.
+#[inline(always)]
+pub fn row_chunk(rows: usize, cols: usize, row: usize, chunk_len: usize) {
+    if ENABLED {
+        assert!(
+            row < rows && chunk_len == cols,
+            "invariant: chunk of {} elements for row {} of a {}x{} result",
+            chunk_len,
+            row,
+            rows,
+            cols
+        );
+    }
+}
+
+/// A view with an offset and signed strides into a buffer of `len`
+/// elements reaches only elements of it. Indices are linear in the row and
+/// column, so it is enough that the four corners do.
+#[inline(always)]
+pub fn strided_view(rows: usize, cols: usize, offset: usize, row_stride: isize, col_stride: isize, len: usize) {
+    if ENABLED && rows > 0 && cols > 0 {
+        for (row, col) in [(0, 0), (0, cols - 1), (rows - 1, 0), (rows - 1, cols - 1)] {
+            let index = offset as isize + row as isize * row_stride + col as isize * col_stride;
+            assert!(
+                (0..len as isize).contains(&index),
+                "invariant: element ({}, {}) of a {}x{} view at {} outside a buffer of {}",
+                row,
+                col,
+                rows,
+                cols,
+                index,
+                len
+            );
+        }
+    }
+}
+
+/// A band of `len` elements for rows `first..` of a `rows x cols` result
+/// holds whole rows, all of them inside it. `cols` is not 0.
+#[inline(always)]
+pub fn row_band(rows: usize, cols: usize, first: usize, len: usize) {
+    if ENABLED {
+        assert!(
+            len.is_multiple_of(cols) && first + len / cols <= rows,
+            "invariant: band of {} elements from row {} of a {}x{} result",
+            len,
+            first,
+            rows,
+            cols
+        );
+    }
+}
+
+/// The `rows x cols` tile at (`top`, `left`) lies inside a matrix of
+/// `shape`.
+#[inline(always)]
+pub fn tile(shape: (usize, usize), top: usize, left: usize, rows: usize, cols: usize) {
+    if ENABLED {
+        assert!(
+            top + rows <= shape.0 && left + cols <= shape.1,
+            "invariant: {}x{} tile at ({}, {}) outside a {}x{} matrix",
+            rows,
+            cols,
+            top,
+            left,
+            shape.0,
+            shape.1
+        );
+    }
+}
+
+/// `rows` rows of `cols` elements, each `ld` elements after the last, do
+/// not overlap and end inside a buffer of `len` elements.
+#[inline(always)]
+pub fn strided_rows(rows: usize, cols: usize, ld: usize, len: usize) {
+    if ENABLED {
+        assert!(
+            cols <= ld && (rows == 0 || (rows - 1) * ld + cols <= len),
+            "invariant: {} rows of {} elements {} apart outside a buffer of {}",
+            rows,
+            cols,
+            ld,
+            len
+        );
+    }
+}
@@ -0,0 +1,2982 @@
+//! Dense matrices of f64 and their products.
+//!
+//! `Matrix::multiply` and `Matrix::multiply_par` are the plain products;
+//! `Matrix::multiply_with` takes `MultiplyOptions` for everything else the
+//! CLI offers. Nothing here panics on bad input: malformed text, shapes that
+//! do not fit and cancelled runs are all a `MatrixError`.
+//!
+//! The API is what the crate root exports. The public modules are there for
+//! the CLI and are hidden; `MultiplyOptions` is built up by methods, and the
+//! enums are `#[non_exhaustive]`, so options, errors and warnings can be
+//! added without a breaking release.
+//!
+//! ```
+//! use matrix_mul::{matrix, Algorithm, Error, Matrix, MatrixView, MultiplyOptions};
+//!
+//! let a = matrix![1.0, 2.0; 3.0, 4.0];
+//! let b = Matrix::try_new(2, 1, vec![1.0, 1.0]).unwrap();
+//! let product = a.multiply_with(&b, &MultiplyOptions::new().algorithm(Algorithm::Par))?;
+//! assert_eq!(product.data(), [3.0, 7.0]);
+//! assert_eq!(MatrixView::get(&product, 1, 0), 7.0);
+//!
+//! match product.multiply(&a) {
+//!     Err(Error::DimensionMismatch { left, right }) => assert_eq!((left, right), ((2, 1), (2, 2))),
+//!     Err(err) => panic!("{}", err),
+//!     Ok(_) => unreachable!(),
+//! }
+//! # Ok::<(), Error>(())
+//! ```
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Instant, Duration}, path::Path, io::{self, Write}, fs::File, ops::Range,
+};
+use rayon::prelude::*;
+use clap::clap_derive::ArgEnum;
+
+#[cfg(test)]
+mod alloc_counter;
+#[cfg(test)]
+mod api_snapshot;
+mod binary;
+mod cancel;
+mod chain;
+mod compress;
+mod context;
+mod events;
+#[cfg(feature = "lazy")]
+mod expr;
+mod graph;
+mod gram;
+mod invariants;
+mod markov;
+mod narrow;
+mod numfmt;
+mod operand_cache;
+mod prepared;
+// Only --plugin multiplies over other semirings so far.
+#[allow(dead_code)]
+mod semiring;
+mod soak;
+mod solve;
+mod transform;
+mod view;
+mod warnings;
+
+// What the CLI needs beyond the API. The binary is another crate, so these
+// have to be public, but they are not covered by semver and may change in
+// any release.
+#[doc(hidden)]
+pub mod accuracy;
+#[doc(hidden)]
+pub mod bench;
+#[doc(hidden)]
+pub mod blocked;
+#[doc(hidden)]
+pub mod cancellation;
+#[doc(hidden)]
+pub mod distribute;
+#[doc(hidden)]
+pub mod element;
+#[doc(hidden)]
+pub mod format;
+#[doc(hidden)]
+pub mod int;
+#[doc(hidden)]
+pub mod interval;
+#[doc(hidden)]
+pub mod npy;
+#[cfg(feature = "plugins")]
+#[doc(hidden)]
+pub mod plugin;
+#[doc(hidden)]
+pub mod preview;
+#[doc(hidden)]
+pub mod scheduler;
+#[doc(hidden)]
+pub mod server;
+#[doc(hidden)]
+pub mod single;
+#[doc(hidden)]
+pub mod sparse;
+#[doc(hidden)]
+pub mod spot_check;
+#[doc(hidden)]
+pub mod strassen;
+#[doc(hidden)]
+pub mod text_reader;
+#[doc(hidden)]
+pub mod tiles;
+#[doc(hidden)]
+pub mod trace;
+#[doc(hidden)]
+pub mod units;
+#[doc(hidden)]
+pub mod watch;
+
+pub use cancel::CancelToken;
+pub use chain::ChainPlan;
+pub use context::{Context, LogEvent};
+#[doc(hidden)]
+pub use events::{quote as quote_json, EventSink};
+#[cfg(feature = "lazy")]
+pub use expr::Expr;
+pub use format::{Number, Precision};
+pub use prepared::PreparedMatrix;
+use prepared::{Panels, Structure};
+pub use sparse::CooTarget;
+pub use transform::Transform;
+pub use view::{Flip, FlippedView, MatrixView, Orientation};
+pub use warnings::Warning;
+/// The error of every fallible operation but `Matrix::try_new`.
+pub use MatrixError as Error;
+
+/// Every method that reads a matrix takes `&self` and only reads `data`, so
+/// one matrix can be shared between threads and multiplied from all of them
+/// at once. There is no interior mutability; a cached derived value added
+/// later should live in a `OnceLock` to keep that true.
+///
+/// The elements are f64 unless `T` says otherwise. Other element types get
+/// only the shape accessors here, and a plain product from
+/// `element::Element`.
+#[derive(Clone, Debug)]
+pub struct Matrix<T = f64> {
+    pub(crate) rows: usize,
+    pub(crate) cols: usize,
+    pub(crate) data: Vec<T>,
+}
+
+/// `Matrix`, spelt out.
+pub type Matrixf64 = Matrix<f64>;
+
+const fn assert_send_sync<T: Send + Sync + ?Sized>() {}
+const _: () = assert_send_sync::<Matrix>();
+const _: () = assert_send_sync::<dyn MatrixView>();
+
+impl fmt::Display for Matrix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.display(Precision::Full))
+    }
+}
+
+/// Displays a matrix in the output file format with the given precision.
+struct MatrixDisplay<'a> {
+    matrix: &'a Matrix,
+    precision: Precision,
+}
+
+impl fmt::Display for MatrixDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let m = self.matrix;
+        for i in 0..m.rows {
+            for j in 0..m.cols {
+                if j > 0 {
+                    write!(f, " ")?;
+                }
+                write!(f, "{}", Number(m.data[i * m.cols + j], self.precision))?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T> Matrix<T> {
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    pub fn shape(&self) -> (usize, usize) {
+        (self.rows, self.cols)
+    }
+
+    /// The elements, row by row.
+    pub fn data(&self) -> &[T] {
+        &self.data
+    }
+}
+
+impl Matrix {
+    /// Fails unless `data` holds exactly `rows * cols` elements.
+    pub fn try_new(rows: usize, cols: usize, data: Vec<f64>) -> Result<Matrix, ShapeError> {
+        match rows.checked_mul(cols) {
+            None => Err(ShapeError::Overflow { rows, cols }),
+            Some(len) if len != data.len() => Err(ShapeError::LengthMismatch {
+                rows,
+                cols,
+                len: data.len(),
+            }),
+            Some(_) => Ok(Matrix { rows, cols, data }),
+        }
+    }
+
+    /// For callers that computed `data` for this shape themselves. The
+    /// length is only checked in debug and paranoid builds.
+    pub fn new_unchecked(rows: usize, cols: usize, data: Vec<f64>) -> Matrix {
+        invariants::buffer_len(rows, cols, data.len());
+        Matrix { rows, cols, data }
+    }
+
+    fn display(&self, precision: Precision) -> MatrixDisplay<'_> {
+        MatrixDisplay { matrix: self, precision }
+    }
+
+    /// Parses rows of space-separated numbers, one row per line.
+    pub fn try_from_str(s: &str) -> Result<Matrix, MatrixError> {
+        Matrix::from_string_with(s, &ParseOptions::default()).map(|(matrix, _)| matrix)
+    }
+
+    pub fn from_string_with(s: &str, options: &ParseOptions) -> Result<(Matrix, ParseReport), MatrixError> {
+        Matrix::from_string_map(s, options, |x| x)
+    }
+
+    /// Like from_string_with, but every parsed element is passed through
+    /// `transform` before it is stored.
+    pub fn from_string_map(
+        s: &str,
+        options: &ParseOptions,
+        transform: impl FnMut(f64) -> f64,
+    ) -> Result<(Matrix, ParseReport), MatrixError> {
+        text_reader::read_matrix(s.as_bytes(), options, transform)
+    }
+
+    /// Parses rows of comma-separated numbers, as pandas and spreadsheets
+    /// write them. Numbers may be quoted and have whitespace around them,
+    /// and blank lines at either end are ignored. There is no header row.
+    pub fn from_csv(s: &str) -> Result<Matrix, MatrixError> {
+        text_reader::read_csv(s.as_bytes(), &ParseOptions::default(), |x| x).map(|(matrix, _)| matrix)
+    }
+
+    pub fn random(rows: usize, cols: usize) -> Matrix {
+        let mut m = Matrix::new_unchecked(rows, cols, vec![0.0; rows * cols]);
+        for i in 0..m.data.len() {
+            m.data[i] = rand::random::<f64>();
+        }
+        m
+    }
+
+    /// `random`, but the same for the same seed on every run and platform.
+    pub fn random_seeded(rows: usize, cols: usize, seed: u64) -> Matrix {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        Matrix::random_with(rows, cols, 0.0..1.0, Dist::Uniform, &mut StdRng::seed_from_u64(seed))
+            .expect("0..1 is a valid range")
+    }
+
+    /// Elements drawn from `range` as `dist` says, row by row from `rng`.
+    /// An empty range, one wider than an f64 can hold, or one without an
+    /// integer for `Dist::Integer`, is a `MatrixError::InvalidRange`.
+    /// Without the width check, -1e308..1e308 would draw infinities and NaN.
+    pub fn random_with(
+        rows: usize,
+        cols: usize,
+        range: Range<f64>,
+        dist: Dist,
+        rng: &mut impl rand::Rng,
+    ) -> Result<Matrix, MatrixError> {
+        let invalid = MatrixError::InvalidRange { start: range.start, end: range.end, dist };
+        if range.start.partial_cmp(&range.end) != Some(std::cmp::Ordering::Less) {
+            return Err(invalid);
+        }
+        let width = range.end - range.start;
+        if !width.is_finite() {
+            return Err(invalid);
+        }
+        let (low, high) = (range.start.ceil() as i64, range.end.floor() as i64);
+        if dist == Dist::Integer && low > high {
+            return Err(invalid);
+        }
+        let mut value = || match dist {
+            Dist::Uniform => range.start + width * rng.gen::<f64>(),
+            // Box-Muller; 1 - u is never 0, so its log is finite.
+            Dist::Normal => {
+                let (u, v) = (1.0 - rng.gen::<f64>(), rng.gen::<f64>());
+                let z = (-2.0 * u.ln()).sqrt() * (std::f64::consts::TAU * v).cos();
+                range.start + width / 2.0 + z * width / 6.0
+            }
+            Dist::Integer => rng.gen_range(low..=high) as f64,
+        };
+        Ok(Matrix::new_unchecked(rows, cols, (0..rows * cols).map(|_| value()).collect()))
+    }
+
+    fn get(&self, row: usize, col: usize) -> f64 {
+        invariants::in_bounds(self.rows, self.cols, row, col);
+        self.data[row * self.cols + col]
+    }
+
+    fn row(&self, row: usize) -> &[f64] {
+        &self.data[row * self.cols..(row + 1) * self.cols]
+    }
+
+    fn set(&mut self, row: usize, col: usize, value: f64) {
+        invariants::in_bounds(self.rows, self.cols, row, col);
+        self.data[row * self.cols + col] = value;
+    }
+
+    /// The product, computed on the calling thread.
+    pub fn multiply(&self, other: &Matrix) -> Result<Matrix, MatrixError> {
+        self.check_inner(other)?;
+        Ok(self.multiply_plain(other))
+    }
+
+    /// The product, one rayon task per output row. Once `other` is large
+    /// enough to be worth the copy, its transpose is formed first and both
+    /// operands are read a row at a time; the result is the same.
+    pub fn multiply_par(&self, other: &Matrix) -> Result<Matrix, MatrixError> {
+        self.check_inner(other)?;
+        Ok(match view::choose(self.rows, other.shape()) {
+            Orientation::Packed => self.multiply_transposed_par(&other.transpose()),
+            _ => self.multiply_par_plain(other),
+        })
+    }
+
+    /// `self * other^T`, without forming the transpose: both operands are
+    /// read a row at a time, so `a.multiply_transposed(&b.transpose())` is
+    /// `a.multiply(&b)`, bit for bit, and faster once `b` is large.
+    pub fn multiply_transposed(&self, other: &Matrix) -> Result<Matrix, MatrixError> {
+        if self.cols != other.cols {
+            return Err(MatrixError::DimensionMismatch {
+                left: self.shape(),
+                right: (other.cols, other.rows),
+            });
+        }
+        let mut result = Matrix::zeros(self.rows, other.rows);
+        for (i, row) in result.data.chunks_mut(other.rows.max(1)).enumerate() {
+            self.multiply_row(&other.t(), i, row);
+        }
+        Ok(result)
+    }
+
+    fn check_inner(&self, other: &Matrix) -> Result<(), MatrixError> {
+        if self.cols == other.rows {
+            Ok(())
+        } else {
+            Err(MatrixError::DimensionMismatch {
+                left: self.shape(),
+                right: other.shape(),
+            })
+        }
+    }
+
+    // The kernels behind multiply and multiply_par, for callers that have
+    // checked the shapes.
+    pub(crate) fn multiply_plain(&self, other: &Matrix) -> Matrix {
+        assert_eq!(self.cols, other.rows);
+
+        let mut result = Matrix::new_unchecked(self.rows, other.cols, vec![0.0; self.rows * other.cols]);
+
+        for i in 0..self.rows {
+            for j in 0..other.cols {
+                let mut sum = 0.0;
+
+                for k in 0..self.cols {
+                    sum += self.get(i, k) * other.get(k, j);
+                }
+
+                result.set(i, j, sum);
+            }
+        }
+
+        result
+    }
+
+    // One task per output row, each owning its row of the result.
+    fn multiply_par_plain(&self, other: &Matrix) -> Matrix {
+        assert_eq!(self.cols, other.rows);
+
+        let mut result = Matrix::new_unchecked(self.rows, other.cols, vec![0.0; self.rows * other.cols]);
+        result
+            .data
+            .par_chunks_mut(other.cols.max(1))
+            .enumerate()
+            .for_each(|(i, row)| self.multiply_row(other, i, row));
+        result
+    }
+
+    // multiply_par_plain against the transpose of the right operand.
+    fn multiply_transposed_par(&self, other: &Matrix) -> Matrix {
+        assert_eq!(self.cols, other.cols);
+
+        let mut result = Matrix::zeros(self.rows, other.rows);
+        result
+            .data
+            .par_chunks_mut(other.rows.max(1))
+            .enumerate()
+            .for_each(|(i, row)| self.multiply_row(&other.t(), i, row));
+        result
+    }
+
+    // Computes output row `i` into `row`, in the same summation order as
+    // multiply.
+    fn multiply_row(&self, other: &impl MatrixView, i: usize, row: &mut [f64]) {
+        invariants::row_chunk(self.rows, other.shape().1, i, row.len());
+        for (j, cell) in row.iter_mut().enumerate() {
+            let mut sum = 0.0;
+
+            match other.column(j) {
+                Some(column) => {
+                    for (a, b) in self.row(i).iter().zip(column) {
+                        sum += a * b;
+                    }
+                }
+                None => {
+                    for k in 0..self.cols {
+                        sum += self.get(i, k) * other.get(k, j);
+                    }
+                }
+            }
+
+            *cell = sum;
+        }
+    }
+
+    #[cfg(test)]
+    fn multiply_cancellable(&self, other: &Matrix, token: &CancelToken) -> Result<Matrix, MatrixError> {
+        self.multiply_fused(other, token, &[], &Context::new())
+    }
+
+    // multiply_cancellable, running `epilogues` on each output row as soon as
+    // it is computed and then reporting the row to `context`.
+    fn multiply_fused(
+        &self,
+        other: &impl MatrixView,
+        token: &CancelToken,
+        epilogues: &[Epilogue],
+        context: &Context,
+    ) -> Result<Matrix, MatrixError> {
+        let (inner, cols) = other.shape();
+        assert_eq!(self.cols, inner);
+        fused_rows(self.rows, cols, token, epilogues, context, |i, row| self.multiply_row(other, i, row))
+    }
+
+    #[cfg(test)]
+    fn multiply_par_cancellable(&self, other: &Matrix, token: &CancelToken) -> Result<Matrix, MatrixError> {
+        self.multiply_par_fused(other, token, &[], &Context::new())
+    }
+
+    fn multiply_par_fused(
+        &self,
+        other: &impl MatrixView,
+        token: &CancelToken,
+        epilogues: &[Epilogue],
+        context: &Context,
+    ) -> Result<Matrix, MatrixError> {
+        let (inner, cols) = other.shape();
+        assert_eq!(self.cols, inner);
+        par_fused_rows(self.rows, cols, token, epilogues, context, |i, row| self.multiply_row(other, i, row))
+    }
+
+    /// Same result as `multiply_par`, but one task per output row so that
+    /// each task can be timed without touching shared state in the inner
+    /// loop.
+    pub fn multiply_par_profiled(&self, other: &Matrix) -> Result<(Matrix, ThreadProfile), MatrixError> {
+        self.check_inner(other)?;
+
+        let mut result = Matrix::new_unchecked(self.rows, other.cols, vec![0.0; self.rows * other.cols]);
+        let threads = rayon::current_num_threads();
+
+        let tasks: Vec<(usize, Duration)> = result
+            .data
+            .par_chunks_mut(other.cols.max(1))
+            .enumerate()
+            .map(|(i, row)| {
+                let start = Instant::now();
+                self.multiply_row(other, i, row);
+                (rayon::current_thread_index().unwrap_or(0), start.elapsed())
+            })
+            .collect();
+
+        Ok((result, ThreadProfile::from_tasks(threads, &tasks)))
+    }
+
+    // FNV-1a over the bit patterns of the elements, so two results only
+    // share a checksum if they are bitwise identical.
+    pub fn checksum(&self) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for value in &self.data {
+            for byte in value.to_bits().to_le_bytes() {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+        hash
+    }
+
+    /// Writes the matrix to `path`, replacing any file there, in the text
+    /// format `try_from_str` reads.
+    pub fn write_to(&self, path: &Path, precision: Precision) -> io::Result<()> {
+        self.write_text(File::create(path)?, precision)
+    }
+
+    /// `write_to` for any writer. Rows are formatted a band at a time, so
+    /// only a few bands of text are in memory at once, never all of it.
+    pub fn write_text(&self, out: impl Write, precision: Precision) -> io::Result<()> {
+        let band_rows = (WRITE_BAND_ELEMENTS / self.cols.max(1)).max(1);
+        let in_flight = 2 * rayon::current_num_threads();
+        self.write_text_par(&mut io::BufWriter::new(out), band_rows, in_flight, precision)
+    }
+
+    /// Rows of comma-separated values, each written in full so that
+    /// `from_csv` reads back the same matrix.
+    pub fn to_csv(&self) -> String {
+        let mut buf = Vec::new();
+        self.write_csv(&mut buf, Precision::Full).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    /// `write_text` with commas between the values.
+    pub fn write_csv(&self, out: impl Write, precision: Precision) -> io::Result<()> {
+        let band_rows = (WRITE_BAND_ELEMENTS / self.cols.max(1)).max(1);
+        let in_flight = 2 * rayon::current_num_threads();
+        self.write_bands(&mut io::BufWriter::new(out), band_rows, in_flight, precision, b',')
+    }
+
+    // Rows `rows`, the same bytes as `display(precision)` when `separator`
+    // is a space.
+    fn format_rows(&self, rows: std::ops::Range<usize>, precision: Precision, separator: u8) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for i in rows {
+            for j in 0..self.cols {
+                if j > 0 {
+                    buf.push(separator);
+                }
+                write!(buf, "{}", Number(self.data[i * self.cols + j], precision)).unwrap();
+            }
+            buf.push(b'\n');
+        }
+        buf
+    }
+
+    // Formats bands of `band_rows` rows in parallel and writes them in order.
+    // At most `max_in_flight` formatted bands are held in memory at once.
+    fn write_text_par(
+        &self,
+        out: &mut impl Write,
+        band_rows: usize,
+        max_in_flight: usize,
+        precision: Precision,
+    ) -> io::Result<()> {
+        self.write_bands(out, band_rows, max_in_flight, precision, b' ')
+    }
+
+    fn write_bands(
+        &self,
+        out: &mut impl Write,
+        band_rows: usize,
+        max_in_flight: usize,
+        precision: Precision,
+        separator: u8,
+    ) -> io::Result<()> {
+        self.write_bands_counted(out, band_rows, max_in_flight, precision, separator, &BandCount::default())
+    }
+
+    // write_bands, counting the formatted bands not yet written in `held`.
+    fn write_bands_counted(
+        &self,
+        out: &mut impl Write,
+        band_rows: usize,
+        max_in_flight: usize,
+        precision: Precision,
+        separator: u8,
+        held: &BandCount,
+    ) -> io::Result<()> {
+        let bands: Vec<usize> = (0..self.rows).step_by(band_rows.max(1)).collect();
+
+        for group in bands.chunks(max_in_flight.max(1)) {
+            let formatted: Vec<Vec<u8>> = group
+                .par_iter()
+                .map(|&start| {
+                    let band = self.format_rows(start..(start + band_rows).min(self.rows), precision, separator);
+                    held.formatted();
+                    band
+                })
+                .collect();
+            for band in formatted {
+                out.write_all(&band)?;
+                held.written();
+            }
+        }
+        out.flush()
+    }
+}
+
+// Formatted bands held by write_bands, and the most at any one time.
+#[derive(Default)]
+struct BandCount {
+    held: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl BandCount {
+    fn formatted(&self) {
+        let held = self.held.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak.fetch_max(held, Ordering::Relaxed);
+    }
+
+    fn written(&self) {
+        self.held.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Roughly how many elements go into one formatted band when writing text.
+const WRITE_BAND_ELEMENTS: usize = 1 << 18;
+
+// The row loop of the fused kernels: `kernel` computes row `i` of a `rows`
+// x `cols` product into its slice, and then the row's epilogues run and it
+// is reported to `context`.
+fn fused_rows(
+    rows: usize,
+    cols: usize,
+    token: &CancelToken,
+    epilogues: &[Epilogue],
+    context: &Context,
+    kernel: impl Fn(usize, &mut [f64]),
+) -> Result<Matrix, MatrixError> {
+    let mut result = Matrix::new_unchecked(rows, cols, vec![0.0; rows * cols]);
+    let mut band = context.band();
+    for (i, row) in result.data.chunks_mut(cols.max(1)).enumerate() {
+        if token.is_cancelled() {
+            return Err(MatrixError::Cancelled { rows_completed: i });
+        }
+        kernel(i, row);
+        let mut apply = || epilogues.iter().for_each(|epilogue| epilogue.apply_row(i, row));
+        match &mut band {
+            Some(band) => band.row(i, apply),
+            None => apply(),
+        }
+        context.report_row(i, row);
+        context.report_progress(i + 1, rows);
+    }
+
+    Ok(result)
+}
+
+fn par_fused_rows(
+    rows: usize,
+    cols: usize,
+    token: &CancelToken,
+    epilogues: &[Epilogue],
+    context: &Context,
+    kernel: impl Fn(usize, &mut [f64]) + Sync,
+) -> Result<Matrix, MatrixError> {
+    let mut result = Matrix::new_unchecked(rows, cols, vec![0.0; rows * cols]);
+    let rows_completed = AtomicUsize::new(0);
+    result
+        .data
+        .par_chunks_mut(cols.max(1))
+        .enumerate()
+        .with_min_len(context.min_rows_per_task(rows))
+        // One band per task, recorded as the task finishes.
+        .for_each_init(|| context.band(), |band, (i, row)| {
+            if !token.is_cancelled() {
+                kernel(i, row);
+                let mut apply = || epilogues.iter().for_each(|epilogue| epilogue.apply_row(i, row));
+                match band {
+                    Some(band) => band.row(i, apply),
+                    None => apply(),
+                }
+                context.report_row(i, row);
+                let done = rows_completed.fetch_add(1, Ordering::Relaxed) + 1;
+                context.report_progress(done, rows);
+            }
+        });
+
+    let rows_completed = rows_completed.into_inner();
+    if rows_completed < rows && cols > 0 {
+        return Err(MatrixError::Cancelled { rows_completed });
+    }
+    Ok(result)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[non_exhaustive]
+pub enum Algorithm {
+    #[default]
+    Seq,
+    /// Runs on the current rayon pool.
+    Par,
+}
+
+/// Below this many floating-point operations, dispatching to the thread
+/// pool costs more than the multiply itself. See the small_crossover test.
+const INLINE_BELOW_FLOPS: u64 = 1 << 17;
+
+/// Built with `new` and the methods that take and return it, so that new
+/// options are not breaking changes.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct MultiplyOptions {
+    algorithm: Algorithm,
+    auto_narrow: bool,
+    broadcast_scalars: bool,
+    cancel: Option<CancelToken>,
+    inline_below: u64,
+    shortcuts: bool,
+    epilogues: Vec<Epilogue>,
+    orientation: Option<Orientation>,
+    context: Context,
+}
+
+/// Work done on each row of the product right after it is computed, while
+/// it is still in cache, instead of in another pass over the result.
+#[derive(Clone, Debug)]
+pub enum Epilogue {
+    /// Adds the matching element of a matrix shaped like the product.
+    AddMatrix(Arc<Matrix>),
+    /// Multiplies by the matching element, usually of a 0/1 mask.
+    Mask(Arc<Matrix>),
+    // No CLI flag maps to this one.
+    #[allow(dead_code)]
+    Apply(fn(f64) -> f64),
+}
+
+impl Epilogue {
+    fn check(&self, shape: (usize, usize)) -> Result<(), MatrixError> {
+        match self {
+            Epilogue::AddMatrix(m) | Epilogue::Mask(m) if m.shape() != shape => {
+                Err(MatrixError::DimensionMismatch {
+                    left: shape,
+                    right: m.shape(),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn apply_row(&self, i: usize, row: &mut [f64]) {
+        match self {
+            Epilogue::AddMatrix(m) => row.iter_mut().zip(m.row(i)).for_each(|(x, y)| *x += y),
+            Epilogue::Mask(m) => row.iter_mut().zip(m.row(i)).for_each(|(x, y)| *x *= y),
+            Epilogue::Apply(f) => row.iter_mut().for_each(|x| *x = f(*x)),
+        }
+    }
+
+    /// What `apply_row` does to element (i, j) when it is `x`.
+    fn apply_at(&self, i: usize, j: usize, x: f64) -> f64 {
+        match self {
+            Epilogue::AddMatrix(m) => x + m.get(i, j),
+            Epilogue::Mask(m) => x * m.get(i, j),
+            Epilogue::Apply(f) => f(x),
+        }
+    }
+}
+
+impl Default for MultiplyOptions {
+    fn default() -> MultiplyOptions {
+        MultiplyOptions {
+            algorithm: Algorithm::default(),
+            auto_narrow: false,
+            broadcast_scalars: false,
+            cancel: None,
+            inline_below: INLINE_BELOW_FLOPS,
+            shortcuts: true,
+            epilogues: Vec::new(),
+            orientation: None,
+            context: Context::new(),
+        }
+    }
+}
+
+/// What multiply_with_report actually did.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct MultiplyReport {
+    /// `Algorithm::Par` was requested, but the problem was small enough to
+    /// run on the calling thread.
+    pub inlined: bool,
+    /// One operand was a zero matrix or a multiple of the identity, so the
+    /// product was formed without multiplying.
+    pub shortcut: bool,
+    /// How the general kernel read the right operand, if it ran.
+    pub orientation: Option<Orientation>,
+    /// Progress was reported row by row rather than once at the end.
+    pub row_progress: bool,
+    /// Both operands fit in f32 and were multiplied from f32 copies, see
+    /// `MultiplyOptions::auto_narrow`.
+    pub narrowed: bool,
+}
+
+impl MultiplyOptions {
+    pub fn new() -> MultiplyOptions {
+        MultiplyOptions::default()
+    }
+
+    pub fn algorithm(mut self, algorithm: Algorithm) -> MultiplyOptions {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// When every element of both operands is exactly an f32, multiply
+    /// copies of them stored as f32, which halves what the kernel reads.
+    /// Each element is widened back to the f64 it was before it is
+    /// multiplied, so the products and sums are those of the f64 kernel and
+    /// the result is the same to the bit. Off by default: checking costs a
+    /// pass over both operands, and the copies take a further half of their
+    /// size. Operands that do not fit, or copies that would go over the
+    /// allocation budget, are multiplied in f64 as usual.
+    pub fn auto_narrow(mut self, narrow: bool) -> MultiplyOptions {
+        self.auto_narrow = narrow;
+        self
+    }
+
+    /// When the shapes do not conform and one operand is 1x1, multiply the
+    /// other operand by its value instead of failing.
+    pub fn broadcast_scalars(mut self, broadcast: bool) -> MultiplyOptions {
+        self.broadcast_scalars = broadcast;
+        self
+    }
+
+    /// Stop early with `MatrixError::Cancelled` once `token` is cancelled.
+    pub fn cancel_token(mut self, token: CancelToken) -> MultiplyOptions {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Run problems needing fewer than `flops` floating-point operations
+    /// (2 * m * k * n) on the calling thread even when `Algorithm::Par` is
+    /// requested. 0 always uses the requested algorithm.
+    pub fn inline_below(mut self, flops: u64) -> MultiplyOptions {
+        self.inline_below = flops;
+        self
+    }
+
+    /// Form products by a zero matrix or a multiple of the identity without
+    /// multiplying. On by default; the result is the same either way.
+    pub fn shortcuts(mut self, shortcuts: bool) -> MultiplyOptions {
+        self.shortcuts = shortcuts;
+        self
+    }
+
+    /// Run `epilogue` on the product as part of the multiply. Epilogues run
+    /// in the order they are added.
+    pub fn epilogue(mut self, epilogue: Epilogue) -> MultiplyOptions {
+        self.epilogues.push(epilogue);
+        self
+    }
+
+    /// Read the right operand as `orientation` says instead of deciding by
+    /// size. The result is the same either way.
+    pub fn orientation(mut self, orientation: Orientation) -> MultiplyOptions {
+        self.orientation = Some(orientation);
+        self
+    }
+
+    /// Report progress and decisions, pick the thread pool and cap
+    /// allocations through `context`.
+    pub fn context(mut self, context: Context) -> MultiplyOptions {
+        self.context = context;
+        self
+    }
+
+    /// The epilogues added so far, in the order they run.
+    pub fn epilogues(&self) -> &[Epilogue] {
+        &self.epilogues
+    }
+
+    /// The context given to `context`, or an empty one.
+    pub fn get_context(&self) -> &Context {
+        &self.context
+    }
+
+    pub fn check_epilogues(&self, shape: (usize, usize)) -> Result<(), MatrixError> {
+        self.epilogues.iter().try_for_each(|epilogue| epilogue.check(shape))
+    }
+
+    // For results that did not come from a fused kernel.
+    pub fn apply_epilogues(&self, result: &mut Matrix) {
+        if self.epilogues.is_empty() {
+            return;
+        }
+        let start = Instant::now();
+        for (i, row) in result.data.chunks_mut(result.cols.max(1)).enumerate() {
+            for epilogue in &self.epilogues {
+                epilogue.apply_row(i, row);
+            }
+        }
+        self.context.span("epilogue", start);
+    }
+}
+
+impl Matrix {
+    /// The `rows x cols` matrix of zeros.
+    pub fn zeros(rows: usize, cols: usize) -> Matrix {
+        Matrix::new_unchecked(rows, cols, vec![0.0; rows * cols])
+    }
+
+    /// The `n x n` identity.
+    pub fn identity(n: usize) -> Matrix {
+        let mut m = Matrix::zeros(n, n);
+        for i in 0..n {
+            m.set(i, i, 1.0);
+        }
+        m
+    }
+
+    fn check_square(&self) -> Result<(), MatrixError> {
+        if self.rows != self.cols {
+            return Err(MatrixError::NotSquare {
+                rows: self.rows,
+                cols: self.cols,
+            });
+        }
+        Ok(())
+    }
+
+    /// `self` multiplied by itself `exp` times, by repeated squaring.
+    fn pow(&self, exp: u32) -> Result<Matrix, MatrixError> {
+        self.check_square()?;
+
+        let mut result = Matrix::identity(self.rows);
+        let mut base = self.clone();
+        let mut exp = exp;
+        while exp > 0 {
+            if exp & 1 == 1 {
+                result = result.multiply_plain(&base);
+            }
+            exp >>= 1;
+            if exp > 0 {
+                base = base.multiply_plain(&base);
+            }
+        }
+        Ok(result)
+    }
+
+    fn mul_vec(&self, v: &[f64]) -> Result<Vec<f64>, MatrixError> {
+        if v.len() != self.cols {
+            return Err(MatrixError::DimensionMismatch {
+                left: self.shape(),
+                right: (v.len(), 1),
+            });
+        }
+        Ok(self
+            .data
+            .chunks(self.cols.max(1))
+            .take(self.rows)
+            .map(|row| row.iter().zip(v).fold(0.0, |sum, (a, b)| sum + a * b))
+            .collect())
+    }
+
+    fn is_scalar(&self) -> bool {
+        self.rows == 1 && self.cols == 1
+    }
+
+    fn to_scalar(&self) -> Option<f64> {
+        if self.is_scalar() {
+            Some(self.data[0])
+        } else {
+            None
+        }
+    }
+
+    /// `Some(c)` when the matrix is c times the identity, including the
+    /// square zero matrix. Stops at the first element that does not fit.
+    fn as_scaled_identity(&self) -> Option<f64> {
+        if self.rows != self.cols || self.data.is_empty() {
+            return None;
+        }
+        let c = self.data[0];
+        let diagonal = self.cols + 1;
+        self.data
+            .iter()
+            .enumerate()
+            .all(|(index, &x)| if index % diagonal == 0 { x == c } else { x == 0.0 })
+            .then_some(c)
+    }
+
+    fn is_zero(&self) -> bool {
+        self.data.iter().all(|&x| x == 0.0)
+    }
+
+    // The product when one side is a zero matrix or c times the identity, in
+    // which case every element of the full multiply is `0.0 + x * c` for the
+    // matching element x of the other side. That only holds when the other
+    // side is finite: the full multiply turns inf * 0 into NaN.
+    // `structure` is that of `self`.
+    fn multiply_shortcut(&self, other: &Matrix, structure: &Structure) -> Option<Matrix> {
+        let finite = |m: &Matrix| m.data.iter().all(|x| x.is_finite());
+        let scaled = |m: &Matrix, c: f64| Matrix::new_unchecked(m.rows, m.cols, m.data.iter().map(|x| 0.0 + x * c).collect());
+
+        if let Some(c) = other.as_scaled_identity() {
+            return structure.is_finite(self).then(|| scaled(self, c));
+        }
+        if let Some(c) = structure.scaled_identity(self) {
+            return finite(other).then(|| scaled(other, c));
+        }
+        if (other.is_zero() && structure.is_finite(self)) || (structure.is_zero(self) && finite(other)) {
+            return Some(Matrix::new_unchecked(self.rows, other.cols, vec![0.0; self.rows * other.cols]));
+        }
+        None
+    }
+
+    pub fn multiply_with(&self, other: &Matrix, options: &MultiplyOptions) -> Result<Matrix, MatrixError> {
+        self.multiply_with_report(other, options).map(|(m, _)| m)
+    }
+
+    pub fn multiply_with_report(
+        &self,
+        other: &Matrix,
+        options: &MultiplyOptions,
+    ) -> Result<(Matrix, MultiplyReport), MatrixError> {
+        self.multiply_structured(other, options, &Structure::default(), None)
+    }
+
+    // `structure` is that of `self`, possibly computed by an earlier call,
+    // and `panels` its rows as `PreparedMatrix` packs them, if it has.
+    fn multiply_structured(
+        &self,
+        other: &Matrix,
+        options: &MultiplyOptions,
+        structure: &Structure,
+        panels: Option<&Panels>,
+    ) -> Result<(Matrix, MultiplyReport), MatrixError> {
+        options.context.install(|| {
+            let (result, report) = self.dispatch(other, options, structure, panels)?;
+            if !report.row_progress {
+                options.context.report_progress(result.rows, result.rows);
+            }
+            Ok((result, report))
+        })
+    }
+
+    fn dispatch(
+        &self,
+        other: &Matrix,
+        options: &MultiplyOptions,
+        structure: &Structure,
+        panels: Option<&Panels>,
+    ) -> Result<(Matrix, MultiplyReport), MatrixError> {
+        let context = &options.context;
+        let mut report = MultiplyReport::default();
+        if self.cols != other.rows {
+            if options.broadcast_scalars {
+                let scaled = match (self.to_scalar(), other.to_scalar()) {
+                    (Some(s), _) => Some((s, other)),
+                    (None, Some(s)) => Some((s, self)),
+                    (None, None) => None,
+                };
+                if let Some((s, matrix)) = scaled {
+                    options.check_epilogues(matrix.shape())?;
+                    context.check_allocation(8 * matrix.data.len() as u64)?;
+                    let mut result = matrix.scale(s);
+                    options.apply_epilogues(&mut result);
+                    return Ok((result, report));
+                }
+            }
+            return Err(MatrixError::DimensionMismatch {
+                left: self.shape(),
+                right: other.shape(),
+            });
+        }
+
+        options.check_epilogues((self.rows, other.cols))?;
+        let result_bytes = 8u64.saturating_mul(self.rows as u64).saturating_mul(other.cols as u64);
+        context.check_allocation(result_bytes)?;
+
+        // The skinny kernels are a single pass over the output, so they only
+        // check for cancellation before starting.
+        if options.cancel.as_ref().is_some_and(CancelToken::is_cancelled) {
+            return Err(MatrixError::Cancelled { rows_completed: 0 });
+        }
+
+        if options.shortcuts {
+            if let Some(mut result) = self.multiply_shortcut(other, structure) {
+                report.shortcut = true;
+                context.emit(LogEvent::Shortcut);
+                options.apply_epilogues(&mut result);
+                return Ok((result, report));
+            }
+        }
+
+        let flops = 2u64
+            .saturating_mul(self.rows as u64)
+            .saturating_mul(self.cols as u64)
+            .saturating_mul(other.cols as u64);
+        let algorithm = if options.algorithm == Algorithm::Par && flops < options.inline_below {
+            report.inlined = true;
+            context.emit(LogEvent::RanInline { flops });
+            Algorithm::Seq
+        } else {
+            options.algorithm
+        };
+
+        if self.cols == 1 {
+            let mut result = match algorithm {
+                Algorithm::Seq => self.multiply_outer(other),
+                Algorithm::Par => self.multiply_outer_par(other),
+            };
+            options.apply_epilogues(&mut result);
+            return Ok((result, report));
+        }
+        if self.rows == 1 && other.cols == 1 {
+            let mut result = self.multiply_dot(other);
+            options.apply_epilogues(&mut result);
+            return Ok((result, report));
+        }
+
+        if options.auto_narrow {
+            if let Some(result) = self.multiply_narrowed(other, algorithm, options) {
+                report.narrowed = true;
+                report.row_progress = true;
+                context.emit(LogEvent::Narrowed { algorithm });
+                return result.map(|result| (result, report));
+            }
+        }
+
+        let plain = options.epilogues.is_empty() && options.cancel.is_none() && !context.needs_row_loop();
+        if let Some(panels) = panels.filter(|_| plain && options.orientation.is_none()) {
+            report.orientation = Some(Orientation::Packed);
+            context.emit(LogEvent::Kernel { algorithm, orientation: Orientation::Packed });
+            let packed_bytes = 8u64.saturating_mul(other.data.len() as u64);
+            context.check_allocation(result_bytes.saturating_add(packed_bytes))?;
+            return Ok((panels.multiply(other, algorithm), report));
+        }
+
+        // A view of `other` cannot be any cheaper than reading it in place.
+        let orientation = match options.orientation {
+            None | Some(Orientation::View) => view::choose(self.rows, other.shape()),
+            Some(orientation) => orientation,
+        };
+        report.orientation = Some(orientation);
+        context.emit(LogEvent::Kernel { algorithm, orientation });
+        if orientation == Orientation::Packed {
+            let packed_bytes = 8u64.saturating_mul(other.data.len() as u64);
+            context.check_allocation(result_bytes.saturating_add(packed_bytes))?;
+        }
+        if orientation == Orientation::Strided && plain {
+            let result = match algorithm {
+                Algorithm::Seq => self.multiply_plain(other),
+                Algorithm::Par => self.multiply_par_plain(other),
+            };
+            return Ok((result, report));
+        }
+
+        let token = options.cancel.clone().unwrap_or_default();
+        let packed = (orientation == Orientation::Packed).then(|| other.transpose());
+        let epilogues = &options.epilogues;
+        let result = match (algorithm, &packed) {
+            (Algorithm::Seq, None) => self.multiply_fused(other, &token, epilogues, context)?,
+            (Algorithm::Par, None) => self.multiply_par_fused(other, &token, epilogues, context)?,
+            (Algorithm::Seq, Some(packed)) => self.multiply_fused(&packed.t(), &token, epilogues, context)?,
+            (Algorithm::Par, Some(packed)) => self.multiply_par_fused(&packed.t(), &token, epilogues, context)?,
+        };
+        report.row_progress = true;
+        Ok((result, report))
+    }
+
+    // n x 1 times 1 x m. The `0.0 +` keeps the result bit-identical to the
+    // general kernel, which turns a -0.0 product into 0.0.
+    fn multiply_outer(&self, other: &Matrix) -> Matrix {
+        assert_eq!((self.cols, other.rows), (1, 1));
+
+        let mut data = Vec::with_capacity(self.rows * other.cols);
+        for &a in &self.data {
+            data.extend(other.data.iter().map(|&b| 0.0 + a * b));
+        }
+        Matrix::new_unchecked(self.rows, other.cols, data)
+    }
+
+    fn multiply_outer_par(&self, other: &Matrix) -> Matrix {
+        assert_eq!((self.cols, other.rows), (1, 1));
+
+        let mut result = Matrix::new_unchecked(self.rows, other.cols, vec![0.0; self.rows * other.cols]);
+        result
+            .data
+            .par_chunks_mut(other.cols.max(1))
+            .zip(self.data.par_iter())
+            .for_each(|(row, &a)| {
+                for (cell, &b) in row.iter_mut().zip(&other.data) {
+                    *cell = 0.0 + a * b;
+                }
+            });
+        result
+    }
+
+    // 1 x k times k x 1. Both operands are contiguous, and the sum runs in
+    // the same order as the general kernel.
+    fn multiply_dot(&self, other: &Matrix) -> Matrix {
+        assert_eq!((self.rows, other.cols), (1, 1));
+        assert_eq!(self.cols, other.rows);
+
+        let mut sum = 0.0;
+        for (a, b) in self.data.iter().zip(&other.data) {
+            sum += a * b;
+        }
+        Matrix::new_unchecked(1, 1, vec![sum])
+    }
+}
+
+// `f` of each element of `row` and the matching one of row `i` of `other`,
+// in order, from a slice when that row is contiguous.
+fn zip_row(row: &[f64], other: &impl MatrixView, i: usize, f: impl Fn(f64, f64) -> f64, mut out: impl FnMut(f64)) {
+    match other.row(i) {
+        Some(other) => row.iter().zip(other).for_each(|(&a, &b)| out(f(a, b))),
+        None => row.iter().enumerate().for_each(|(j, &a)| out(f(a, other.get(i, j)))),
+    }
+}
+
+// Elementwise operations.
+impl Matrix {
+    pub fn transpose(&self) -> Matrix {
+        let mut result = Matrix::zeros(self.cols, self.rows);
+        for i in 0..self.rows {
+            for j in 0..self.cols {
+                result.set(j, i, self.get(i, j));
+            }
+        }
+        result
+    }
+
+    /// Whether the matrix is square and equal to its transpose, exactly.
+    pub fn is_symmetric(&self) -> bool {
+        self.rows == self.cols
+            && (0..self.rows).all(|i| (0..i).all(|j| self.get(i, j) == self.get(j, i)))
+    }
+
+    fn check_same_shape(&self, other: &impl MatrixView) -> Result<(), MatrixError> {
+        if self.shape() != other.shape() {
+            return Err(MatrixError::DimensionMismatch {
+                left: self.shape(),
+                right: other.shape(),
+            });
+        }
+        Ok(())
+    }
+
+    /// `f` of each element and the one in the same place in `other`, which
+    /// may be any view of the same shape, such as a flipped one.
+    pub fn zip_map(&self, other: &impl MatrixView, f: impl Fn(f64, f64) -> f64) -> Result<Matrix, MatrixError> {
+        self.check_same_shape(other)?;
+
+        let mut data = Vec::with_capacity(self.data.len());
+        for i in 0..self.rows {
+            zip_row(self.row(i), other, i, &f, |x| data.push(x));
+        }
+
+        Ok(Matrix::new_unchecked(self.rows, self.cols, data))
+    }
+
+    /// `zip_map`, a row at a time on the rayon pool.
+    pub fn zip_map_par(
+        &self,
+        other: &impl MatrixView,
+        f: impl Fn(f64, f64) -> f64 + Sync + Send,
+    ) -> Result<Matrix, MatrixError> {
+        self.check_same_shape(other)?;
+
+        let mut result = Matrix::new_unchecked(self.rows, self.cols, vec![0.0; self.data.len()]);
+        result.data.par_chunks_mut(self.cols.max(1)).enumerate().for_each(|(i, out)| {
+            let mut cells = out.iter_mut();
+            zip_row(self.row(i), other, i, &f, |x| *cells.next().unwrap() = x);
+        });
+
+        Ok(result)
+    }
+
+    /// The elementwise sum; the shapes must match.
+    pub fn add(&self, other: &Matrix) -> Result<Matrix, MatrixError> {
+        self.zip_map(other, |a, b| a + b)
+    }
+
+    /// The elementwise difference; the shapes must match.
+    pub fn sub(&self, other: &Matrix) -> Result<Matrix, MatrixError> {
+        self.zip_map(other, |a, b| a - b)
+    }
+
+    /// The elementwise product; the shapes must match.
+    pub fn hadamard(&self, other: &Matrix) -> Result<Matrix, MatrixError> {
+        self.zip_map(other, |a, b| a * b)
+    }
+
+    /// Every element times `factor`.
+    pub fn scale(&self, factor: f64) -> Matrix {
+        Matrix::new_unchecked(self.rows, self.cols, self.data.iter().map(|x| x * factor).collect())
+    }
+
+    /// The elementwise absolute difference; the shapes must match.
+    pub fn abs_diff(&self, other: &Matrix) -> Result<Matrix, MatrixError> {
+        self.zip_map(other, |a, b| (a - b).abs())
+    }
+
+    // Infinite when the shapes differ, NaN when one matrix has a NaN where
+    // the other does not. NaNs and equal infinities in the same place
+    // differ by nothing.
+    pub fn max_abs_diff(&self, other: &Matrix) -> f64 {
+        let diff = |a: f64, b: f64| if a == b || (a.is_nan() && b.is_nan()) { 0.0 } else { (a - b).abs() };
+        match self.zip_map(other, diff) {
+            Ok(diff) => diff.data.into_iter().fold(0.0, |max, d| if d.is_nan() || d > max { d } else { max }),
+            Err(_) => f64::INFINITY,
+        }
+    }
+
+    /// True when the shapes match and every pair of elements is within
+    /// `abs_tol` or within `rel_tol` of the larger magnitude. NaNs in the
+    /// same place count as equal, and infinities only equal themselves. Near
+    /// zero only `abs_tol` helps, since any relative tolerance of a value
+    /// that small is smaller still.
+    pub fn approx_eq(&self, other: &Matrix, rel_tol: f64, abs_tol: f64) -> bool {
+        self.shape() == other.shape()
+            && self.data.iter().zip(&other.data).all(|(&a, &b)| {
+                a == b
+                    || (a.is_nan() && b.is_nan())
+                    || (a.is_finite() && b.is_finite() && (a - b).abs() <= abs_tol.max(rel_tol * a.abs().max(b.abs())))
+            })
+    }
+
+    /// The bit patterns of the elements, for tests that compare results
+    /// exactly, NaNs and signed zeros included.
+    #[cfg(test)]
+    pub(crate) fn bits(&self) -> Vec<u64> {
+        self.data.iter().map(|x| x.to_bits()).collect()
+    }
+}
+
+// Input validation.
+impl Matrix {
+    pub fn validate_finite(&self) -> Result<(), NonFiniteAt> {
+        match self.data.par_iter().position_first(|x| !x.is_finite()) {
+            Some(index) => Err(NonFiniteAt {
+                row: index / self.cols,
+                col: index % self.cols,
+                value: self.data[index],
+            }),
+            None => Ok(()),
+        }
+    }
+
+    pub fn replace_nonfinite(&mut self, value: f64) -> usize {
+        self.data
+            .par_iter_mut()
+            .filter(|x| !x.is_finite())
+            .map(|x| *x = value)
+            .count()
+    }
+
+    /// The largest `|a_ij - a_ji|`: 0 for a symmetric matrix, and infinite
+    /// for one that is not square or where only one of a pair is NaN.
+    pub fn symmetry_error(&self) -> f64 {
+        if self.rows != self.cols {
+            return f64::INFINITY;
+        }
+        let mut error: f64 = 0.0;
+        for i in 0..self.rows {
+            for j in 0..i {
+                let (a, b) = (self.get(i, j), self.get(j, i));
+                let diff = if a == b || (a.is_nan() && b.is_nan()) { 0.0 } else { (a - b).abs() };
+                error = error.max(if diff.is_nan() { f64::INFINITY } else { diff });
+            }
+        }
+        error
+    }
+
+    /// (A + Aᵀ) / 2, rounded once per element, so the result is symmetric
+    /// to the bit and symmetrizing it again changes nothing.
+    pub fn symmetrize(&self) -> Result<Matrix, MatrixError> {
+        if self.rows != self.cols {
+            return Err(MatrixError::NotSquare { rows: self.rows, cols: self.cols });
+        }
+        let mut result = self.clone();
+        for i in 0..self.rows {
+            for j in 0..i {
+                let mid = self.get(i, j).midpoint(self.get(j, i));
+                result.set(i, j, mid);
+                result.set(j, i, mid);
+            }
+        }
+        Ok(result)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NonFiniteAt {
+    pub row: usize,
+    pub col: usize,
+    pub value: f64,
+}
+
+impl fmt::Display for NonFiniteAt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "non-finite value {} at row {}, column {}",
+            self.value, self.row, self.col
+        )
+    }
+}
+
+impl std::error::Error for NonFiniteAt {}
+
+/// How `Matrix::random_with` spreads elements over its range.
+#[derive(Clone, Copy, PartialEq, Eq, ArgEnum, Debug, Default)]
+pub enum Dist {
+    /// Evenly from the start of the range up to its end.
+    #[default]
+    Uniform,
+    /// Normally, centred on the middle of the range with a sixth of its
+    /// width as the standard deviation, so nearly all fall inside it.
+    Normal,
+    /// Whole numbers in the range, ends included, equally likely.
+    Integer,
+}
+
+/// What the text parser does with rows whose length differs from the rest.
+#[derive(Clone, Copy, PartialEq, Eq, ArgEnum, Debug, Default)]
+pub enum RaggedPolicy {
+    /// Reject the input, naming the first offending line.
+    #[default]
+    Error,
+    /// Extend short rows with zeros up to the widest row.
+    PadZero,
+    /// Cut long rows down to the width of the first row.
+    Truncate,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ParseOptions {
+    pub ragged: RaggedPolicy,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ParseReport {
+    pub padded_rows: usize,
+    pub truncated_rows: usize,
+    /// Integers an f64 cannot hold exactly.
+    pub inexact_integers: usize,
+}
+
+/// Errors compare equal when their variants and fields do, except that
+/// two `Io` errors need only share an `io::ErrorKind`.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum MatrixError {
+    DimensionMismatch {
+        left: (usize, usize),
+        right: (usize, usize),
+    },
+    /// `column` is the byte of the line the token starts at, from 1.
+    InvalidNumber {
+        line: usize,
+        column: usize,
+        token: String,
+    },
+    /// A number too large for the `--dtype` it is read as.
+    OutOfRange {
+        line: usize,
+        column: usize,
+        token: String,
+        dtype: &'static str,
+    },
+    RaggedRow {
+        line: usize,
+        expected: usize,
+        found: usize,
+    },
+    Cancelled {
+        rows_completed: usize,
+    },
+    NotSquare {
+        rows: usize,
+        cols: usize,
+    },
+    Singular {
+        pivot: usize,
+    },
+    /// A range `Matrix::random_with` cannot draw `dist` elements from.
+    InvalidRange {
+        start: f64,
+        end: f64,
+        dist: Dist,
+    },
+    NotStochastic {
+        row: usize,
+    },
+    LeadingDimension {
+        ld: usize,
+        cols: usize,
+    },
+    /// A binary layout whose bytes overflow a `usize`.
+    LayoutTooLarge {
+        rows: usize,
+        ld: usize,
+    },
+    SizeMismatch {
+        expected: u64,
+        found: u64,
+    },
+    /// A mask or index list with one entry per row or column that has too
+    /// many or too few; `what` names the entries.
+    WrongLength {
+        what: &'static str,
+        expected: usize,
+        found: usize,
+    },
+    /// Row `index` of a matrix with only `rows` rows.
+    RowOutOfRange {
+        index: usize,
+        rows: usize,
+    },
+    /// Elements of a product that a recomputation disagreed with.
+    SpotCheckFailed {
+        mismatches: usize,
+    },
+    /// A binary file whose header is not one this crate writes.
+    InvalidHeader(String),
+    /// Reading or writing failed. Shared so that the error stays `Clone`.
+    Io(Arc<io::Error>),
+    /// A failure no other variant describes.
+    Message(String),
+    NoConvergence {
+        iterations: usize,
+        residual: f64,
+    },
+    AllocationBudget {
+        requested: u64,
+        budget: u64,
+    },
+    /// A `rows x cols` matrix of f64 refused before it is allocated, for
+    /// having more than `max` elements.
+    TooManyElements {
+        rows: usize,
+        cols: usize,
+        max: u64,
+    },
+    /// Likewise, for needing more than `max` bytes.
+    TooManyBytes {
+        rows: usize,
+        cols: usize,
+        max: u64,
+    },
+    Overflow {
+        row: usize,
+        col: usize,
+    },
+    Shape(ShapeError),
+    /// Indices as written in the file.
+    IndexOutOfRange {
+        line: usize,
+        row: usize,
+        col: usize,
+        shape: Option<(usize, usize)>,
+    },
+    DuplicateEntry {
+        line: usize,
+        row: usize,
+        col: usize,
+    },
+    /// Matrices `index` and `index + 1` of a chain, counted from 0.
+    ChainMismatch {
+        index: usize,
+        left: (usize, usize),
+        right: (usize, usize),
+    },
+    EmptyChain,
+    /// A distributed multiply given an empty list of workers.
+    NoWorkers,
+    /// Every worker of a distributed multiply was dropped for failing;
+    /// `last` is the address of the last one and `reason` its last failure.
+    WorkersFailed {
+        last: String,
+        reason: String,
+    },
+    /// A worker answered a band with an error status other than 408 or 5xx,
+    /// which no other worker would answer differently. `message` is the body.
+    WorkerRejected {
+        worker: String,
+        status: u16,
+        message: String,
+    },
+}
+
+impl fmt::Display for MatrixError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MatrixError::DimensionMismatch { left, right } => write!(
+                f,
+                "dimension mismatch: {}x{} and {}x{}",
+                left.0, left.1, right.0, right.1
+            ),
+            MatrixError::InvalidNumber { line, column, token } => {
+                write!(f, "line {}, column {}: expected number, found '{}'", line, column, token)
+            }
+            MatrixError::OutOfRange { line, column, token, dtype } => {
+                write!(f, "line {}, column {}: {} is out of the range of {}", line, column, token, dtype)
+            }
+            MatrixError::RaggedRow { line, expected, found } => write!(
+                f,
+                "line {}: expected {} values, found {}",
+                line, expected, found
+            ),
+            MatrixError::Cancelled { rows_completed } => {
+                write!(f, "cancelled after {} rows", rows_completed)
+            }
+            MatrixError::NotSquare { rows, cols } => {
+                write!(f, "expected a square matrix, found {}x{}", rows, cols)
+            }
+            MatrixError::Singular { pivot } => {
+                write!(f, "matrix is singular: no nonzero pivot for column {}", pivot)
+            }
+            MatrixError::InvalidRange { start, end, dist } => {
+                let why = if start < end && !(end - start).is_finite() {
+                    "is wider than an f64 can hold"
+                } else if start < end && *dist == Dist::Integer {
+                    "holds no integer"
+                } else {
+                    "is empty"
+                };
+                write!(f, "the range {}..{} {}", start, end, why)
+            }
+            MatrixError::NotStochastic { row } => {
+                write!(f, "row {} is negative somewhere or does not sum to 1", row)
+            }
+            MatrixError::LeadingDimension { ld, cols } => {
+                write!(f, "leading dimension {} is less than the {} columns", ld, cols)
+            }
+            MatrixError::LayoutTooLarge { rows, ld } => {
+                write!(f, "{} rows {} elements apart do not fit in memory", rows, ld)
+            }
+            MatrixError::SizeMismatch { expected, found } => {
+                write!(f, "expected {} bytes of matrix data, found {}", expected, found)
+            }
+            MatrixError::WrongLength { what, expected, found } => {
+                write!(f, "expected {} {}, found {}", expected, what, found)
+            }
+            MatrixError::RowOutOfRange { index, rows } => {
+                write!(f, "row {} is out of range for {} rows", index, rows)
+            }
+            MatrixError::SpotCheckFailed { mismatches } => {
+                write!(f, "spot check found {} corrupted element{}", mismatches, if *mismatches == 1 { "" } else { "s" })
+            }
+            MatrixError::InvalidHeader(reason) => write!(f, "not a matrix binary file: {}", reason),
+            MatrixError::Io(err) => write!(f, "{}", err),
+            MatrixError::Message(message) => write!(f, "{}", message),
+            MatrixError::NoConvergence { iterations, residual } => write!(
+                f,
+                "no convergence after {} iterations (last change {:e})",
+                iterations, residual
+            ),
+            MatrixError::AllocationBudget { requested, budget } => write!(
+                f,
+                "multiply needs {} but the allocation budget is {}",
+                units::format_bytes(*requested),
+                units::format_bytes(*budget)
+            ),
+            MatrixError::TooManyElements { rows, cols, max } => {
+                let elements = (*rows as u64).saturating_mul(*cols as u64);
+                write!(f, "a {}x{} matrix has {} elements, over the limit of {}", rows, cols, elements, max)
+            }
+            MatrixError::TooManyBytes { rows, cols, max } => {
+                let bytes = (*rows as u64).saturating_mul(*cols as u64).saturating_mul(8);
+                write!(
+                    f,
+                    "a {}x{} matrix needs {}, over the limit of {}",
+                    rows,
+                    cols,
+                    units::format_bytes(bytes),
+                    units::format_bytes(*max)
+                )
+            }
+            MatrixError::Overflow { row, col } => {
+                write!(f, "integer overflow computing element ({}, {})", row, col)
+            }
+            MatrixError::Shape(err) => write!(f, "{}", err),
+            MatrixError::IndexOutOfRange { line, row, col, shape } => {
+                write!(f, "line {}: index ({}, {}) is out of range", line, row, col)?;
+                match shape {
+                    Some((rows, cols)) => write!(f, " for a {}x{} matrix", rows, cols),
+                    None => write!(f, ", indices start at 1"),
+                }
+            }
+            MatrixError::DuplicateEntry { line, row, col } => {
+                write!(f, "line {}: element ({}, {}) is listed twice", line, row, col)
+            }
+            MatrixError::ChainMismatch { index, left, right } => write!(
+                f,
+                "matrices {} and {} of the chain do not conform: {}x{} and {}x{}",
+                index + 1,
+                index + 2,
+                left.0,
+                left.1,
+                right.0,
+                right.1
+            ),
+            MatrixError::EmptyChain => write!(f, "a chain needs at least one matrix"),
+            MatrixError::NoWorkers => write!(f, "no workers to distribute to"),
+            MatrixError::WorkersFailed { last, reason } => {
+                write!(f, "every worker failed, the last one ({}) with: {}", last, reason)
+            }
+            MatrixError::WorkerRejected { worker, status, message } => {
+                write!(f, "worker {} answered {}: {}", worker, status, message)
+            }
+        }
+    }
+}
+
+impl PartialEq for MatrixError {
+    fn eq(&self, other: &MatrixError) -> bool {
+        use MatrixError::*;
+
+        match (self, other) {
+            (DimensionMismatch { left, right }, DimensionMismatch { left: l, right: r }) => (left, right) == (l, r),
+            (InvalidNumber { line, column, token }, InvalidNumber { line: l, column: c, token: t }) => {
+                (line, column, token) == (l, c, t)
+            }
+            (
+                OutOfRange { line, column, token, dtype },
+                OutOfRange { line: l, column: c, token: t, dtype: d },
+            ) => (line, column, token, dtype) == (l, c, t, d),
+            (RaggedRow { line, expected, found }, RaggedRow { line: l, expected: e, found: f }) => {
+                (line, expected, found) == (l, e, f)
+            }
+            (Cancelled { rows_completed }, Cancelled { rows_completed: r }) => rows_completed == r,
+            (NotSquare { rows, cols }, NotSquare { rows: r, cols: c }) => (rows, cols) == (r, c),
+            (Singular { pivot }, Singular { pivot: p }) => pivot == p,
+            (InvalidRange { start, end, dist }, InvalidRange { start: s, end: e, dist: d }) => {
+                (start, end, dist) == (s, e, d)
+            }
+            (NotStochastic { row }, NotStochastic { row: r }) => row == r,
+            (LeadingDimension { ld, cols }, LeadingDimension { ld: l, cols: c }) => (ld, cols) == (l, c),
+            (LayoutTooLarge { rows, ld }, LayoutTooLarge { rows: r, ld: l }) => (rows, ld) == (r, l),
+            (SizeMismatch { expected, found }, SizeMismatch { expected: e, found: f }) => (expected, found) == (e, f),
+            (
+                WrongLength { what, expected, found },
+                WrongLength { what: w, expected: e, found: f },
+            ) => (what, expected, found) == (w, e, f),
+            (RowOutOfRange { index, rows }, RowOutOfRange { index: i, rows: r }) => (index, rows) == (i, r),
+            (SpotCheckFailed { mismatches }, SpotCheckFailed { mismatches: m }) => mismatches == m,
+            (InvalidHeader(a), InvalidHeader(b)) | (Message(a), Message(b)) => a == b,
+            (Io(a), Io(b)) => a.kind() == b.kind(),
+            (NoConvergence { iterations, residual }, NoConvergence { iterations: i, residual: r }) => {
+                (iterations, residual) == (i, r)
+            }
+            (AllocationBudget { requested, budget }, AllocationBudget { requested: r, budget: b }) => {
+                (requested, budget) == (r, b)
+            }
+            (TooManyElements { rows, cols, max }, TooManyElements { rows: r, cols: c, max: m })
+            | (TooManyBytes { rows, cols, max }, TooManyBytes { rows: r, cols: c, max: m }) => {
+                (rows, cols, max) == (r, c, m)
+            }
+            (Overflow { row, col }, Overflow { row: r, col: c }) => (row, col) == (r, c),
+            (Shape(a), Shape(b)) => a == b,
+            (
+                IndexOutOfRange { line, row, col, shape },
+                IndexOutOfRange { line: l, row: r, col: c, shape: s },
+            ) => (line, row, col, shape) == (l, r, c, s),
+            (DuplicateEntry { line, row, col }, DuplicateEntry { line: l, row: r, col: c }) => {
+                (line, row, col) == (l, r, c)
+            }
+            (ChainMismatch { index, left, right }, ChainMismatch { index: i, left: l, right: r }) => {
+                (index, left, right) == (i, l, r)
+            }
+            (EmptyChain, EmptyChain) | (NoWorkers, NoWorkers) => true,
+            (WorkersFailed { last, reason }, WorkersFailed { last: l, reason: r }) => (last, reason) == (l, r),
+            (
+                WorkerRejected { worker, status, message },
+                WorkerRejected { worker: w, status: s, message: m },
+            ) => (worker, status, message) == (w, s, m),
+            _ => false,
+        }
+    }
+}
+
+impl std::error::Error for MatrixError {}
+
+impl From<io::Error> for MatrixError {
+    fn from(err: io::Error) -> MatrixError {
+        MatrixError::Io(Arc::new(err))
+    }
+}
+
+/// A buffer that does not fit the shape it was given.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ShapeError {
+    LengthMismatch { rows: usize, cols: usize, len: usize },
+    /// rows * cols does not fit in a usize.
+    Overflow { rows: usize, cols: usize },
+}
+
+impl fmt::Display for ShapeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShapeError::LengthMismatch { rows, cols, len } => {
+                write!(f, "a {}x{} matrix needs {} elements, found {}", rows, cols, rows * cols, len)
+            }
+            ShapeError::Overflow { rows, cols } => write!(f, "a {}x{} matrix has too many elements", rows, cols),
+        }
+    }
+}
+
+impl std::error::Error for ShapeError {}
+
+impl From<ShapeError> for MatrixError {
+    fn from(err: ShapeError) -> MatrixError {
+        MatrixError::Shape(err)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ThreadProfile {
+    busy: Vec<Duration>,
+}
+
+impl ThreadProfile {
+    fn from_tasks(threads: usize, tasks: &[(usize, Duration)]) -> ThreadProfile {
+        let mut busy = vec![Duration::ZERO; threads.max(1)];
+        for &(thread, elapsed) in tasks {
+            if thread >= busy.len() {
+                busy.resize(thread + 1, Duration::ZERO);
+            }
+            busy[thread] += elapsed;
+        }
+        ThreadProfile { busy }
+    }
+
+    fn min(&self) -> Duration {
+        self.busy.iter().copied().min().unwrap_or_default()
+    }
+
+    fn max(&self) -> Duration {
+        self.busy.iter().copied().max().unwrap_or_default()
+    }
+
+    fn mean(&self) -> Duration {
+        self.busy.iter().sum::<Duration>() / self.busy.len() as u32
+    }
+
+    // Share of the slowest thread's busy time that an average thread spent
+    // idle: 0% means perfectly balanced, values near 100% mean one thread did
+    // nearly all the work.
+    fn imbalance_percent(&self) -> f64 {
+        let max = self.max().as_secs_f64();
+        if max == 0.0 {
+            return 0.0;
+        }
+        (max - self.mean().as_secs_f64()) / max * 100.0
+    }
+}
+
+impl fmt::Display for ThreadProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Thread busy time over {} threads:", self.busy.len())?;
+        writeln!(
+            f,
+            "  min: {:?}, max: {:?}, mean: {:?}, imbalance: {:.1}%",
+            self.min(),
+            self.max(),
+            self.mean(),
+            self.imbalance_percent()
+        )
+    }
+}
+
+impl PartialEq for Matrix {
+    fn eq(&self, other: &Matrix) -> bool {
+        if self.rows != other.rows || self.cols != other.cols {
+            return false;
+        }
+
+        for i in 0..self.data.len() {
+            if self.data[i] != other.data[i] {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+#[macro_export]
+macro_rules! matrix {
+    () => {
+        {
+            $crate::Matrix::new_unchecked(0, 0, vec![])
+        }
+    };
+    ($( $( $x: expr ),*);*) => {
+        {
+            let data_as_nested_array = [ $( [ $($x),* ] ),* ];
+            let rows = data_as_nested_array.len();
+            let cols = data_as_nested_array[0].len();
+            let data_as_flat_array: Vec<f64> = data_as_nested_array.into_iter()
+                .flat_map(|row| row.into_iter())
+                .collect();
+            match $crate::Matrix::try_new(rows, cols, data_as_flat_array) {
+                Ok(matrix) => matrix,
+                Err(err) => panic!("matrix!: {}", err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn shared_reads_from_many_threads() {
+        let n = if cfg!(miri) { 8 } else { 96 };
+        let a = Arc::new(Matrix::random(n, n));
+        let v: Vec<f64> = (0..n).map(|i| i as f64).collect();
+        let expected = a.mul_vec(&v).unwrap();
+        let product = a.multiply(&a).unwrap();
+        let rounds = if cfg!(miri) { 2 } else { 50 };
+
+        std::thread::scope(|scope| {
+            for t in 0..16 {
+                let a = Arc::clone(&a);
+                let (v, expected, product) = (&v, &expected, &product);
+                scope.spawn(move || {
+                    for round in 0..rounds {
+                        assert_eq!(&a.mul_vec(v).unwrap(), expected);
+                        let i = (t + round) % n;
+                        assert_eq!(a.row(i), &a.data[i * n..(i + 1) * n]);
+                        assert_eq!(a.t().column(i).unwrap(), a.row(i));
+                        if !cfg!(miri) && round % 10 == 0 {
+                            assert_eq!(&a.multiply(&a).unwrap(), product);
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn mul_identity() {
+        let a = matrix![
+            1.0, 0.0;
+            0.0, 1.0
+        ];
+        let b = matrix![
+            1.0, 4.0;
+            2.0, 3.0
+        ];
+        let expected = matrix![
+            1.0, 4.0;
+            2.0, 3.0
+        ]; 
+
+        assert_eq!(a.multiply(&b).unwrap(), expected);
+    }
+
+    #[test]
+    fn mul_identity_par() {
+        let a = matrix![
+            1.0, 0.0;
+            0.0, 1.0
+        ];
+        let b = matrix![
+            1.0, 4.0;
+            2.0, 3.0
+        ];
+        let expected = matrix![
+            1.0, 4.0;
+            2.0, 3.0
+        ]; 
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        let c = pool.install(|| a.multiply_par(&b).unwrap());
+
+        assert_eq!(c, expected);
+    }
+
+    #[test]
+    fn mul_not_squared() {
+        let a = matrix![1.0, 0.0];
+        let b = matrix![1.0;
+                                2.0];
+        let expected = matrix![1.0]; 
+
+        assert_eq!(a.multiply(&b).unwrap(), expected);
+    }
+
+    #[test]
+    fn mul_not_squared_par() {
+        let a = matrix![1.0, 0.0];
+        let b = matrix![1.0;
+                                2.0];
+        let expected = matrix![1.0]; 
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        let c = pool.install(|| a.multiply_par(&b).unwrap());
+
+        assert_eq!(c, expected);
+    }
+
+    #[test]
+    fn par_matches_seq_on_any_pool_size() {
+        let a = Matrix::random(70, 50);
+        let b = Matrix::random(50, 60);
+        let expected = a.multiply(&b).unwrap();
+        for threads in [1, 8] {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            let c = pool.install(|| a.multiply_par(&b).unwrap());
+            assert_eq!(c, expected, "{} threads", threads);
+        }
+    }
+
+    #[test]
+    fn mul_squared() {
+        let a = matrix![1.0, 2.0;
+                                3.0, 4.0];
+        let b = matrix![1.0, 2.0;
+                                3.0, 4.0];
+        let expected = matrix![7.0, 10.0;
+                                       15.0, 22.0]; 
+
+        assert_eq!(a.multiply(&b).unwrap(), expected);
+    }
+
+    #[test]
+    fn mul_squared_par() {
+        let a = matrix![1.0, 2.0;
+                                3.0, 4.0];
+        let b = matrix![1.0, 2.0;
+                                3.0, 4.0];
+        let expected = matrix![7.0, 10.0;
+                                       15.0, 22.0];
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        let c = pool.install(|| a.multiply_par(&b).unwrap());
+
+        assert_eq!(c, expected);
+    }
+
+    #[test]
+    fn errors_instead_of_panics() {
+        let a = matrix![1.0, 2.0; 3.0, 4.0];
+        let mismatch = MatrixError::DimensionMismatch { left: (2, 2), right: (1, 2) };
+        assert_eq!(a.multiply(&matrix![1.0, 2.0]), Err(mismatch.clone()));
+        assert_eq!(a.multiply_par(&matrix![1.0, 2.0]), Err(mismatch.clone()));
+        assert_eq!(a.multiply_par_profiled(&matrix![1.0, 2.0]).unwrap_err(), mismatch);
+        assert_eq!(Matrix::try_from_str("1 2\n3 4").unwrap(), a);
+        assert_eq!(
+            Matrix::try_from_str("1 2\n3 4e"),
+            Err(MatrixError::InvalidNumber { line: 2, column: 3, token: "4e".to_owned() })
+        );
+    }
+
+    #[test]
+    fn io_errors_compare_by_kind() {
+        let missing = |message| MatrixError::from(io::Error::new(io::ErrorKind::NotFound, message));
+        assert_eq!(missing("a"), missing("b"));
+        assert_ne!(missing("a"), MatrixError::from(io::Error::from(io::ErrorKind::PermissionDenied)));
+        assert_ne!(missing("a"), MatrixError::Message("a".to_owned()));
+        assert_eq!(missing("cannot read a.txt").to_string(), "cannot read a.txt");
+    }
+
+    #[test]
+    fn mul_not_squared_par_random() {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        for (n, m, k) in [(200, 300, 150), (1, 300, 7), (33, 1, 65), (17, 29, 1)] {
+            let a = Matrix::random(n, m);
+            let b = Matrix::random(m, k);
+            let c = pool.install(|| a.multiply_par(&b).unwrap());
+            assert_eq!(c.bits(), a.multiply(&b).unwrap().bits(), "{}x{}x{}", n, m, k);
+        }
+    }
+
+    #[test]
+    fn profile_imbalanced() {
+        let mut tasks = vec![(0, Duration::from_millis(500))];
+        for i in 0..100 {
+            tasks.push((1 + i % 3, Duration::from_micros(10)));
+        }
+        let profile = ThreadProfile::from_tasks(4, &tasks);
+
+        assert_eq!(profile.max(), Duration::from_millis(500));
+        assert!(profile.imbalance_percent() > 70.0);
+    }
+
+    #[test]
+    fn profile_uniform() {
+        let tasks: Vec<(usize, Duration)> = (0..400)
+            .map(|i| (i % 4, Duration::from_micros(250)))
+            .collect();
+        let profile = ThreadProfile::from_tasks(4, &tasks);
+
+        assert_eq!(profile.min(), profile.max());
+        assert!(profile.imbalance_percent() < 1e-9);
+    }
+
+    #[test]
+    fn mul_squared_par_profiled() {
+        let a = Matrix::random(40, 30);
+        let b = Matrix::random(30, 20);
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        let (c, profile) = pool.install(|| a.multiply_par_profiled(&b)).unwrap();
+
+        assert_eq!(c, a.multiply(&b).unwrap());
+        assert_eq!(profile.busy.len(), 4);
+    }
+
+    #[test]
+    fn zip_map_shape_mismatch() {
+        let a = matrix![1.0, 2.0];
+        let b = matrix![1.0; 2.0];
+
+        assert_eq!(
+            a.zip_map(&b, |x, y| x + y),
+            Err(MatrixError::DimensionMismatch { left: (1, 2), right: (2, 1) })
+        );
+        assert!(a.zip_map_par(&b, |x, y| x + y).is_err());
+        assert!(a.add(&b).is_err());
+    }
+
+    #[test]
+    fn elementwise_ops() {
+        let a = matrix![1.0, 2.0;
+                        3.0, 4.0];
+        let b = matrix![4.0, 3.0;
+                        2.0, 1.0];
+
+        assert_eq!(a.add(&b).unwrap(), matrix![5.0, 5.0; 5.0, 5.0]);
+        assert_eq!(a.sub(&b).unwrap(), matrix![-3.0, -1.0; 1.0, 3.0]);
+        assert_eq!(a.hadamard(&b).unwrap(), matrix![4.0, 6.0; 6.0, 4.0]);
+        assert_eq!(a.abs_diff(&b).unwrap(), matrix![3.0, 1.0; 1.0, 3.0]);
+        assert_eq!(a.scale(-0.5), matrix![-0.5, -1.0; -1.5, -2.0]);
+        assert_eq!(Matrix::zeros(2, 3), matrix![0.0, 0.0, 0.0; 0.0, 0.0, 0.0]);
+        assert!(matches!(a.sub(&Matrix::zeros(2, 1)), Err(MatrixError::DimensionMismatch { .. })));
+    }
+
+    #[test]
+    fn structural_identities() {
+        for (n, m, k) in [(1, 1, 1), (3, 5, 2), (40, 70, 90), (0, 4, 3)] {
+            let (a, b) = (Matrix::random(n, m), Matrix::random(m, k));
+            assert_eq!(a.multiply(&Matrix::identity(m)).unwrap(), a);
+            assert_eq!(Matrix::identity(n).multiply(&a).unwrap(), a);
+            assert_eq!(a.transpose().shape(), (m, n));
+            assert_eq!(a.transpose().transpose(), a);
+
+            let product = a.multiply(&b).unwrap();
+            assert_eq!(a.multiply_transposed(&b.transpose()).unwrap().bits(), product.bits());
+            assert_eq!(a.multiply_par(&b).unwrap().bits(), product.bits());
+            assert_eq!(a.multiply_transposed_par(&b.transpose()).bits(), product.bits());
+        }
+        assert_eq!(
+            matrix![1.0, 2.0].multiply_transposed(&matrix![1.0, 2.0, 3.0]),
+            Err(MatrixError::DimensionMismatch { left: (1, 2), right: (3, 1) })
+        );
+    }
+
+    #[test]
+    fn zip_map_seq_and_par() {
+        let a = Matrix::random(37, 53);
+        let b = Matrix::random(37, 53);
+
+        assert_eq!(
+            a.zip_map(&b, |x, y| x * y - x).unwrap(),
+            a.zip_map_par(&b, |x, y| x * y - x).unwrap()
+        );
+    }
+
+    #[test]
+    fn zip_map_relative_difference() {
+        let eps = 1e-12;
+        let a = matrix![1.0, 0.0, 100.0];
+        let b = matrix![1.5, 0.0, 99.0];
+
+        let rel = a
+            .zip_map(&b, |x, y| (x - y).abs() / x.abs().max(y.abs()).max(eps))
+            .unwrap();
+
+        assert_eq!(rel, matrix![0.5 / 1.5, 0.0, 0.01]);
+    }
+
+    #[test]
+    fn random_with_seeds() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let random = |dist, range: Range<f64>, seed| {
+            Matrix::random_with(30, 20, range, dist, &mut StdRng::seed_from_u64(seed)).unwrap()
+        };
+        for dist in [Dist::Uniform, Dist::Normal, Dist::Integer] {
+            assert_eq!(random(dist, -5.0..5.0, 42), random(dist, -5.0..5.0, 42));
+            assert_ne!(random(dist, -5.0..5.0, 42), random(dist, -5.0..5.0, 43));
+        }
+        assert_eq!(random(Dist::Uniform, 0.0..1.0, 7), Matrix::random_seeded(30, 20, 7));
+
+        let uniform = random(Dist::Uniform, 2.0..3.0, 1);
+        assert!(uniform.data.iter().all(|x| (2.0..3.0).contains(x)));
+        let integers = random(Dist::Integer, -1.5..2.0, 1);
+        assert!(integers.data.iter().all(|x| [-1.0, 0.0, 1.0, 2.0].contains(x)));
+        assert!([-1.0, 2.0].iter().all(|end| integers.data.contains(end)));
+        let normal = random(Dist::Normal, 10.0..16.0, 1);
+        let mean = normal.data.iter().sum::<f64>() / 600.0;
+        assert!((mean - 13.0).abs() < 0.2, "{}", mean);
+        assert!(normal.data.iter().filter(|x| (10.0..16.0).contains(*x)).count() > 590);
+
+        let mut rng = StdRng::seed_from_u64(1);
+        let invalid = [
+            (Dist::Uniform, 1.0..1.0),
+            (Dist::Normal, 2.0..1.0),
+            (Dist::Integer, 0.2..0.8),
+            (Dist::Uniform, -1e308..1e308),
+            (Dist::Normal, f64::NEG_INFINITY..0.0),
+        ];
+        for (dist, range) in invalid {
+            let err = Matrix::random_with(2, 2, range.clone(), dist, &mut rng).unwrap_err();
+            assert_eq!(err, MatrixError::InvalidRange { start: range.start, end: range.end, dist });
+        }
+        let nan = Matrix::random_with(2, 2, f64::NAN..1.0, Dist::Uniform, &mut rng);
+        assert!(matches!(nan, Err(MatrixError::InvalidRange { .. })));
+        let err = Matrix::random_with(2, 2, 0.2..0.8, Dist::Integer, &mut rng).unwrap_err();
+        assert_eq!(err.to_string(), "the range 0.2..0.8 holds no integer");
+        let err = Matrix::random_with(2, 2, -1e308..1e308, Dist::Integer, &mut rng).unwrap_err();
+        assert!(err.to_string().ends_with(" is wider than an f64 can hold"), "{}", err);
+    }
+
+    #[test]
+    fn approx_eq() {
+        let a = matrix![1e6, 1.0, 1e-12];
+        let b = matrix![1e6 + 1e-4, 1.0 + 5e-10, 0.0];
+
+        // Relative: 1e-4 is nothing next to 1e6, but 1e-12 next to 0 is all
+        // of it, however small the absolute difference.
+        assert!(!a.approx_eq(&b, 1e-9, 0.0));
+        assert!(a.approx_eq(&b, 1e-9, 1e-12));
+        assert!(!a.approx_eq(&b, 1e-12, 1e-12));
+        assert!(!a.approx_eq(&b, 0.0, 1e-6));
+        assert!(a.approx_eq(&b, 0.0, 2e-4));
+        assert_eq!(a.max_abs_diff(&b), (1e6 + 1e-4) - 1e6);
+        assert!(!a.approx_eq(&matrix![1e6; 1.0; 1e-12], 1.0, 1.0));
+
+        // NaNs in the same place agree; a NaN against a number never does,
+        // and shows as a NaN difference rather than none.
+        let nan = matrix![f64::NAN, f64::INFINITY, 2.0];
+        assert!(nan.approx_eq(&nan.clone(), 0.0, 0.0));
+        assert_eq!(nan.max_abs_diff(&nan), 0.0);
+        let other = matrix![1.0, f64::INFINITY, 2.0];
+        assert!(!nan.approx_eq(&other, 1.0, f64::MAX));
+        assert!(nan.max_abs_diff(&other).is_nan());
+        assert!(!other.approx_eq(&matrix![1.0, f64::MAX, 2.0], 1.0, f64::MAX));
+        assert_eq!(other.max_abs_diff(&matrix![1.0, f64::MAX, 2.0]), f64::INFINITY);
+    }
+
+    #[test]
+    fn validate_finite_reports_first() {
+        let a = Matrix::try_from_str("1 2 3\n4 5 NaN\n7 inf 9").unwrap();
+        let err = a.validate_finite().unwrap_err();
+
+        assert_eq!((err.row, err.col), (1, 2));
+        assert!(err.value.is_nan());
+        assert!(Matrix::random(50, 50).validate_finite().is_ok());
+    }
+
+    #[test]
+    fn replace_nonfinite() {
+        let mut a = Matrix::try_from_str("1 NaN\n-inf 4").unwrap();
+        let b = matrix![1.0, 1.0;
+                        1.0, 1.0];
+
+        assert_eq!(a.replace_nonfinite(0.0), 2);
+        assert_eq!(a, matrix![1.0, 0.0; 0.0, 4.0]);
+        assert!(a.multiply(&b).unwrap().validate_finite().is_ok());
+    }
+
+    #[test]
+    #[should_panic(expected = "invariant: index (0, 2) outside 2x2 matrix")]
+    fn invariants_catch_corrupted_shape() {
+        let mut a = Matrix::random(2, 3);
+        a.cols = 2;
+        a.get(0, 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "invariant: 2x2 matrix backed by 3 elements")]
+    fn invariants_catch_bad_buffer() {
+        Matrix::new_unchecked(2, 2, vec![0.0; 3]);
+    }
+
+    #[test]
+    fn try_new_checks_shape() {
+        assert_eq!(Matrix::try_new(2, 3, vec![1.0; 6]).unwrap().shape(), (2, 3));
+        assert_eq!(Matrix::try_new(0, 5, vec![]).unwrap().shape(), (0, 5));
+        let err = Matrix::try_new(2, 2, vec![0.0; 3]).unwrap_err();
+        assert_eq!(err, ShapeError::LengthMismatch { rows: 2, cols: 2, len: 3 });
+        assert_eq!(err.to_string(), "a 2x2 matrix needs 4 elements, found 3");
+        assert_eq!(
+            Matrix::try_new(usize::MAX, 2, vec![]).unwrap_err(),
+            ShapeError::Overflow { rows: usize::MAX, cols: 2 }
+        );
+    }
+
+    const RAGGED: &str = "1 2 3\n4 5\n6 7 8 9\n1 1 1";
+
+    #[test]
+    fn ragged_error() {
+        let err = Matrix::from_string_with(RAGGED, &ParseOptions::default()).unwrap_err();
+        assert_eq!(err, MatrixError::RaggedRow { line: 2, expected: 3, found: 2 });
+
+        let err = Matrix::from_string_with("1 2\n3 x", &ParseOptions::default()).unwrap_err();
+        assert_eq!(err.to_string(), "line 2, column 3: expected number, found 'x'");
+    }
+
+    #[test]
+    fn ragged_pad_zero() {
+        let options = ParseOptions { ragged: RaggedPolicy::PadZero };
+        let (m, report) = Matrix::from_string_with(RAGGED, &options).unwrap();
+
+        assert_eq!(report, ParseReport { padded_rows: 3, ..ParseReport::default() });
+        assert_eq!(m, matrix![1.0, 2.0, 3.0, 0.0;
+                              4.0, 5.0, 0.0, 0.0;
+                              6.0, 7.0, 8.0, 9.0;
+                              1.0, 1.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn ragged_truncate() {
+        let options = ParseOptions { ragged: RaggedPolicy::Truncate };
+        let err = Matrix::from_string_with(RAGGED, &options).unwrap_err();
+        assert_eq!(err, MatrixError::RaggedRow { line: 2, expected: 3, found: 2 });
+
+        let (m, report) = Matrix::from_string_with("1 2 3\n6 7 8 9\n1 1 1", &options).unwrap();
+        assert_eq!(report, ParseReport { truncated_rows: 1, ..ParseReport::default() });
+        assert_eq!(m, matrix![1.0, 2.0, 3.0;
+                              6.0, 7.0, 8.0;
+                              1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn log1p_matches_post_hoc_map() {
+        let original = Matrix::random(20, 30);
+        let text = format!("{}", original);
+        let (m, _) = Matrix::from_string_map(text.trim(), &ParseOptions::default(), f64::ln_1p).unwrap();
+
+        let expected: Vec<f64> = original.data.iter().map(|x| x.ln_1p()).collect();
+        assert_eq!(m.data, expected);
+    }
+
+    #[test]
+    fn scalar_strict() {
+        let a = Matrix::random(3, 2);
+        let s = matrix![2.5];
+
+        assert!(s.is_scalar());
+        assert_eq!(s.to_scalar(), Some(2.5));
+        assert_eq!(a.to_scalar(), None);
+        assert_eq!(
+            a.multiply_with(&s, &MultiplyOptions::new()),
+            Err(MatrixError::DimensionMismatch { left: (3, 2), right: (1, 1) })
+        );
+        assert!(s.multiply_with(&a, &MultiplyOptions::new()).is_err());
+    }
+
+    #[test]
+    fn scalar_broadcast() {
+        let a = Matrix::random(3, 2);
+        let s = matrix![2.5];
+        let options = MultiplyOptions::new().broadcast_scalars(true);
+
+        assert_eq!(a.multiply_with(&s, &options).unwrap(), a.scale(2.5));
+        assert_eq!(s.multiply_with(&a, &options).unwrap(), a.scale(2.5));
+
+        let par = options.clone().algorithm(Algorithm::Par);
+        assert_eq!(s.multiply_with(&a, &par).unwrap(), a.scale(2.5));
+    }
+
+    #[test]
+    fn scalar_times_scalar() {
+        let a = matrix![3.0];
+        let b = matrix![-2.0];
+
+        for broadcast in [false, true] {
+            let options = MultiplyOptions::new().broadcast_scalars(broadcast);
+            assert_eq!(a.multiply_with(&b, &options).unwrap(), matrix![-6.0]);
+        }
+    }
+
+    #[test]
+    fn skinny_kernels_match_general() {
+        let mut a = Matrix::random(300, 1);
+        let b = Matrix::random(1, 200);
+        a.data[7] = -0.0;
+        assert_eq!(a.multiply_outer(&b).bits(), a.multiply(&b).unwrap().bits());
+        assert_eq!(a.multiply_outer_par(&b).bits(), a.multiply(&b).unwrap().bits());
+
+        let a = Matrix::random(1, 5000);
+        let b = Matrix::random(5000, 1);
+        assert_eq!(a.multiply_dot(&b).bits(), a.multiply(&b).unwrap().bits());
+
+        let seq = MultiplyOptions::new();
+        let par = MultiplyOptions::new().algorithm(Algorithm::Par);
+        assert_eq!(a.multiply_with(&b, &seq).unwrap(), a.multiply(&b).unwrap());
+        assert_eq!(b.multiply_with(&a, &par).unwrap(), b.multiply(&a).unwrap());
+    }
+
+    #[test]
+    fn cancel_from_other_thread() {
+        let a = Matrix::random(600, 60);
+        let b = Matrix::random(60, 60);
+
+        // Cancelled by whichever thread finishes row 99, so at a known row
+        // rather than after a guess at how long the rows take.
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        for algorithm in [Algorithm::Seq, Algorithm::Par] {
+            let token = CancelToken::new();
+            let canceller = token.clone();
+            let context = Context::new().rows(move |i, _| {
+                if i == 99 {
+                    canceller.cancel();
+                }
+            });
+            let options = MultiplyOptions::new().algorithm(algorithm).cancel_token(token).context(context);
+
+            match (algorithm, pool.install(|| a.multiply_with(&b, &options))) {
+                (Algorithm::Seq, Err(MatrixError::Cancelled { rows_completed })) => assert_eq!(rows_completed, 100),
+                (Algorithm::Par, Err(MatrixError::Cancelled { rows_completed })) => {
+                    assert!((100..600).contains(&rows_completed), "{}", rows_completed)
+                }
+                (_, other) => panic!("expected cancellation, got {:?}", other.map(|m| m.shape())),
+            }
+        }
+    }
+
+    #[test]
+    fn cancellable_kernels_match() {
+        let a = Matrix::random(20, 30);
+        let b = Matrix::random(30, 10);
+        let token = CancelToken::new();
+
+        assert_eq!(a.multiply_cancellable(&b, &token).unwrap(), a.multiply(&b).unwrap());
+        assert_eq!(a.multiply_par_cancellable(&b, &token).unwrap(), a.multiply(&b).unwrap());
+
+        let expired = CancelToken::with_timeout(Duration::ZERO);
+        let options = MultiplyOptions::new().cancel_token(expired);
+        assert_eq!(
+            a.multiply_with(&b, &options),
+            Err(MatrixError::Cancelled { rows_completed: 0 })
+        );
+    }
+
+    // The format parsed the simple way, a String per token, to check the
+    // streaming parser's results and error positions against.
+    fn reference_parse(s: &str) -> Result<Matrix, MatrixError> {
+        let mut rows = 0;
+        let mut cols = 0;
+        let mut data = Vec::new();
+
+        for (index, line) in s.lines().enumerate() {
+            let splitted: Vec<String> = line.split_whitespace().map(|x| x.to_owned()).collect();
+            if splitted.is_empty() {
+                continue;
+            }
+            let mut values = Vec::new();
+            let mut column = 1;
+            for num_str in &splitted {
+                column += line[column - 1..].find(num_str.as_str()).unwrap();
+                let num = num_str.parse::<f64>().map_err(|_| MatrixError::InvalidNumber {
+                    line: index + 1,
+                    column,
+                    token: num_str.clone(),
+                })?;
+                values.push(num);
+                column += num_str.len();
+            }
+            if rows == 0 {
+                cols = values.len();
+            }
+            rows += 1;
+            data.push((index + 1, values));
+        }
+
+        let mut flat = Vec::new();
+        for (line, values) in data {
+            if values.len() != cols {
+                return Err(MatrixError::RaggedRow { line, expected: cols, found: values.len() });
+            }
+            flat.extend(values);
+        }
+        Ok(Matrix::try_new(rows, cols, flat)?)
+    }
+
+    #[test]
+    fn parser_matches_reference() {
+        let fixtures = [
+            "1 2\n3 4",
+            "1.5e-3 -2 inf\nNaN 0 1e300\n4 5 6",
+            "1 2 3\n4 5\n6 x",
+            "1 2\n3 4 5\n",
+            "1  2\n3 4",
+            "1 2 \n3 4",
+            "1\t2\n\t3  4\t\n",
+            "\n1 2\r\n\r\n   \n3 4\r\n\n",
+            "1 2\n\n3 4\n 5\t abc",
+            "1 2\n \n3",
+            "",
+            " \n\t",
+            "7",
+        ];
+        for fixture in fixtures {
+            let new = Matrix::from_string_with(fixture, &ParseOptions::default()).map(|(m, _)| m);
+            match (new, reference_parse(fixture)) {
+                (Ok(a), Ok(b)) => assert_eq!(a.bits(), b.bits(), "{:?}", fixture),
+                (a, b) => assert_eq!(a.map(|m| m.shape()), b.map(|m| m.shape()), "{:?}", fixture),
+            }
+        }
+
+        let big = format!("{}", Matrix::random(40, 70));
+        let new = Matrix::from_string_with(big.trim(), &ParseOptions::default()).unwrap().0;
+        assert_eq!(new.bits(), reference_parse(big.trim()).unwrap().bits());
+    }
+
+    // Where the parallel kernel starts to beat the sequential one, which is
+    // what INLINE_BELOW_FLOPS should track:
+    // cargo test --release -- --ignored small_crossover --nocapture
+    #[test]
+    #[ignore]
+    fn small_crossover() {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let time = |f: &dyn Fn() -> Matrix| {
+            let start = Instant::now();
+            let mut runs = 0;
+            while start.elapsed() < Duration::from_millis(200) {
+                f();
+                runs += 1;
+            }
+            start.elapsed() / runs
+        };
+
+        for n in [4, 8, 16, 24, 32, 48, 64, 96, 128] {
+            let a = Matrix::random(n, n);
+            let b = Matrix::random(n, n);
+            let seq = time(&|| a.multiply(&b).unwrap());
+            let par = time(&|| pool.install(|| a.multiply_par(&b).unwrap()));
+            let par_rows = time(&|| pool.install(|| a.multiply_par_cancellable(&b, &CancelToken::new()).unwrap()));
+            println!(
+                "{:>4} ({:>8} flops): seq {:>10.2?}, par {:>10.2?}, par by row {:>10.2?}",
+                n,
+                2 * n * n * n,
+                seq,
+                par,
+                par_rows
+            );
+        }
+    }
+
+    // cargo test --release -- --ignored parse_throughput --nocapture
+    #[test]
+    #[ignore]
+    fn parse_throughput() {
+        let text = format!("{}", Matrix::random(2000, 2000));
+        let text = text.trim();
+        let mb = text.len() as f64 / 1e6;
+
+        let start = Instant::now();
+        let old = reference_parse(text).unwrap();
+        let old_time = start.elapsed();
+        let start = Instant::now();
+        let new = Matrix::from_string_with(text, &ParseOptions::default()).unwrap().0;
+        let new_time = start.elapsed();
+
+        assert_eq!(old, new);
+        println!(
+            "{:.1} MB: old {:.1} MB/s, new {:.1} MB/s ({:.2}x)",
+            mb,
+            mb / old_time.as_secs_f64(),
+            mb / new_time.as_secs_f64(),
+            old_time.as_secs_f64() / new_time.as_secs_f64()
+        );
+    }
+
+    #[test]
+    fn parallel_writer_is_byte_identical() {
+        let m = Matrix::random(53, 17);
+        let expected = format!("{}", m);
+
+        for (band_rows, in_flight) in [(1, 1), (5, 2), (7, 100), (53, 1), (1000, 3)] {
+            let mut out = Vec::new();
+            m.write_text_par(&mut out, band_rows, in_flight, Precision::Full).unwrap();
+            assert_eq!(String::from_utf8(out).unwrap(), expected);
+        }
+
+        // Never more than the cap formatted and waiting to be written.
+        for (band_rows, in_flight, peak) in [(1, 1, 1), (1, 4, 4), (5, 3, 3), (7, 100, 8), (1000, 3, 1)] {
+            let (mut out, count) = (Vec::new(), BandCount::default());
+            m.write_bands_counted(&mut out, band_rows, in_flight, Precision::Full, b' ', &count).unwrap();
+            assert_eq!(String::from_utf8(out).unwrap(), expected);
+            assert_eq!(count.peak.into_inner(), peak, "{} rows, {} in flight", band_rows, in_flight);
+            assert_eq!(count.held.into_inner(), 0);
+        }
+
+        let mut out = Vec::new();
+        matrix![].write_text_par(&mut out, 4, 4, Precision::Full).unwrap();
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn rounded_output() {
+        let m = matrix![-0.0, 0.1 + 0.2; 1e-17, 12345678.9];
+        let expected = "0 0.3\n1e-17 1.23457e7\n";
+        assert_eq!(m.display(Precision::Significant(6)).to_string(), expected);
+
+        let mut out = Vec::new();
+        m.write_text_par(&mut out, 1, 2, Precision::Significant(6)).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+        assert_eq!(m.to_string(), "-0 0.30000000000000004\n1e-17 12345678.9\n");
+    }
+
+    #[test]
+    fn write_to_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("m.txt");
+        let m = matrix![-0.0, 0.1 + 0.2, 1e-300; f64::MAX, -7.0, 12345678.9];
+        m.write_to(&path, Precision::Full).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        let back = Matrix::try_from_str(text.trim_end()).unwrap();
+        assert_eq!(back.bits(), m.bits());
+
+        // An existing file is replaced.
+        let small = Matrix::random(2, 2);
+        small.write_to(&path, Precision::Full).unwrap();
+        assert_eq!(Matrix::try_from_str(std::fs::read_to_string(&path).unwrap().trim_end()).unwrap(), small);
+        assert!(m.write_to(&dir.path().join("missing").join("m.txt"), Precision::Full).is_err());
+    }
+
+    #[test]
+    fn parallel_writer_band_cap() {
+        struct Recorder(Vec<usize>);
+        impl Write for Recorder {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.push(buf.len());
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let m = Matrix::new_unchecked(100, 10, vec![1.0; 1000]);
+        let mut out = Recorder(Vec::new());
+        m.write_text_par(&mut out, 8, 3, Precision::Full).unwrap();
+
+        // "1 1 1 1 1 1 1 1 1 1\n" is 20 bytes per row.
+        assert_eq!(out.0.len(), 13);
+        assert!(out.0.iter().all(|&len| len <= 8 * 20));
+    }
+
+    // cargo test --release -- --ignored write_throughput --nocapture
+    #[test]
+    #[ignore]
+    fn write_throughput() {
+        let m = Matrix::random(4000, 4000);
+
+        let start = Instant::now();
+        let single = format!("{}", m).into_bytes();
+        let single_time = start.elapsed();
+        let start = Instant::now();
+        let mut parallel = Vec::new();
+        m.write_text_par(&mut parallel, WRITE_BAND_ELEMENTS / m.cols, 2 * rayon::current_num_threads(), Precision::Full)
+            .unwrap();
+        let parallel_time = start.elapsed();
+
+        assert!(single == parallel);
+        println!(
+            "{:.1} MB: single-threaded {:?}, parallel {:?} ({:.2}x)",
+            single.len() as f64 / 1e6,
+            single_time,
+            parallel_time,
+            single_time.as_secs_f64() / parallel_time.as_secs_f64()
+        );
+    }
+
+    fn load_fixture(name: &str) -> Matrix {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join(name);
+        let bytes = std::fs::read(&path).unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+        let (rows, cols, data) = npy::read(&bytes).unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+        Matrix::new_unchecked(rows, cols, data)
+    }
+
+    // Every element must lie within the usual floating-point dot product
+    // bound of the reference, k * eps * sum(|a_ip * b_pj|), doubled to allow
+    // for the rounding in the reference itself.
+    fn matches_oracle(a: &Matrix, b: &Matrix, expected: &Matrix, actual: &Matrix) -> Result<(), String> {
+        if actual.rows != expected.rows || actual.cols != expected.cols {
+            return Err(format!(
+                "shape {}x{}, expected {}x{}",
+                actual.rows, actual.cols, expected.rows, expected.cols
+            ));
+        }
+        let k = a.cols as f64;
+        for i in 0..expected.rows {
+            for j in 0..expected.cols {
+                let magnitude: f64 = (0..a.cols).map(|p| (a.get(i, p) * b.get(p, j)).abs()).sum();
+                let bound = 2.0 * k * f64::EPSILON * magnitude;
+                let (x, y) = (actual.get(i, j), expected.get(i, j));
+                if (x - y).abs() > bound {
+                    return Err(format!("({}, {}): {} differs from {} by more than {:e}", i, j, x, y, bound));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn small_problems_run_inline() {
+        let a = Matrix::random(16, 16);
+        let b = Matrix::random(16, 16);
+        let flops = 2 * 16 * 16 * 16;
+        let par = MultiplyOptions::new().algorithm(Algorithm::Par);
+
+        let (m, report) = a.multiply_with_report(&b, &par.clone().inline_below(flops + 1)).unwrap();
+        assert!(report.inlined);
+        assert_eq!(m, a.multiply(&b).unwrap());
+
+        let (m, report) = a.multiply_with_report(&b, &par.clone().inline_below(flops)).unwrap();
+        assert!(!report.inlined);
+        assert_eq!(m, a.multiply(&b).unwrap());
+
+        let (_, report) = a.multiply_with_report(&b, &par.clone().inline_below(0)).unwrap();
+        assert!(!report.inlined);
+
+        // Only a parallel request can be shortcut.
+        let seq = MultiplyOptions::new().inline_below(u64::MAX);
+        assert!(!a.multiply_with_report(&b, &seq).unwrap().1.inlined);
+
+        // The skinny kernels and cancellation go through the same decision.
+        let col = Matrix::random(16, 1);
+        let row = Matrix::random(1, 16);
+        let (m, report) = col.multiply_with_report(&row, &par).unwrap();
+        assert!(report.inlined);
+        assert_eq!(m, col.multiply(&row).unwrap());
+
+        let token = CancelToken::new();
+        let cancellable = par.cancel_token(token.clone()).inline_below(u64::MAX);
+        let (m, report) = a.multiply_with_report(&b, &cancellable).unwrap();
+        assert!(report.inlined);
+        assert_eq!(m, a.multiply(&b).unwrap());
+    }
+
+    #[test]
+    fn fused_epilogues() {
+        let a = Matrix::random(9, 6);
+        let b = Matrix::random(6, 7);
+        let bias = Arc::new(Matrix::random(9, 7));
+        let mask = Arc::new(Matrix::new_unchecked(9, 7, (0..63).map(|i| (i % 3 == 0) as u8 as f64).collect()));
+        let two_step = a.multiply(&b).unwrap().add(&bias).unwrap().hadamard(&mask).unwrap();
+
+        let options = MultiplyOptions::new()
+            .epilogue(Epilogue::AddMatrix(Arc::clone(&bias)))
+            .epilogue(Epilogue::Mask(Arc::clone(&mask)));
+        for algorithm in [Algorithm::Seq, Algorithm::Par] {
+            let options = options.clone().algorithm(algorithm).inline_below(0);
+            assert_eq!(a.multiply_with(&b, &options).unwrap(), two_step);
+            let cancellable = options.cancel_token(CancelToken::new());
+            assert_eq!(a.multiply_with(&b, &cancellable).unwrap(), two_step);
+        }
+
+        // Shortcut and skinny products get the epilogue too.
+        let identity = Matrix::identity(6);
+        let options = MultiplyOptions::new().epilogue(Epilogue::Apply(f64::abs));
+        let abs = Matrix::new_unchecked(9, 6, a.data.iter().map(|x| x.abs()).collect());
+        assert_eq!(a.multiply_with(&identity, &options).unwrap(), abs);
+        let col = Matrix::random(9, 1);
+        let row = Matrix::random(1, 7);
+        let options = MultiplyOptions::new().epilogue(Epilogue::AddMatrix(Arc::clone(&bias)));
+        assert_eq!(col.multiply_with(&row, &options).unwrap(), col.multiply(&row).unwrap().add(&bias).unwrap());
+    }
+
+    #[test]
+    fn epilogue_shape_checked_first() {
+        let a = Matrix::random(4, 3);
+        let b = Matrix::random(3, 5);
+        let options = MultiplyOptions::new()
+            .epilogue(Epilogue::Mask(Arc::new(Matrix::random(5, 4))))
+            .cancel_token(CancelToken::new());
+        assert_eq!(
+            a.multiply_with(&b, &options),
+            Err(MatrixError::DimensionMismatch { left: (4, 5), right: (5, 4) })
+        );
+
+        // Also for a scalar broadcast, whose product is shaped like `b`.
+        let options = MultiplyOptions::new()
+            .broadcast_scalars(true)
+            .epilogue(Epilogue::AddMatrix(Arc::new(Matrix::random(4, 5))));
+        assert!(Matrix::new_unchecked(1, 1, vec![2.0]).multiply_with(&b, &options).is_err());
+    }
+
+    // The pass over the result that fusing saves:
+    // cargo test --release -- --ignored epilogue_fusion --nocapture
+    #[test]
+    #[ignore]
+    fn epilogue_fusion() {
+        let a = Matrix::random(512, 64);
+        let b = Matrix::random(64, 2048);
+        let bias = Arc::new(Matrix::random(512, 2048));
+        let epilogue = [Epilogue::AddMatrix(Arc::clone(&bias))];
+        let token = CancelToken::new();
+
+        // The same row kernel both times, so only the extra pass differs.
+        let start = Instant::now();
+        let two_step = a.multiply_par_fused(&b, &token, &[], &Context::new()).unwrap().add(&bias).unwrap();
+        let two_step_time = start.elapsed();
+        let start = Instant::now();
+        let one_step = a.multiply_par_fused(&b, &token, &epilogue, &Context::new()).unwrap();
+        let fused_time = start.elapsed();
+        assert_eq!(one_step, two_step);
+        println!("multiply then add: {:?}, fused: {:?}", two_step_time, fused_time);
+    }
+
+    #[test]
+    fn zero_and_identity_shortcuts() {
+        let mut a = Matrix::random(6, 4);
+        a.set(1, 2, -0.0);
+        let full = MultiplyOptions::new().shortcuts(false);
+        let check = |left: &Matrix, right: &Matrix, shortcut: bool| {
+            let (m, report) = left.multiply_with_report(right, &MultiplyOptions::new()).unwrap();
+            assert_eq!(report.shortcut, shortcut);
+            let (expected, report) = left.multiply_with_report(right, &full).unwrap();
+            assert!(!report.shortcut);
+            assert_eq!((m.rows, m.cols), (expected.rows, expected.cols));
+            assert_eq!(m.bits(), expected.bits());
+        };
+
+        check(&a, &Matrix::identity(4), true);
+        check(&Matrix::identity(6), &a, true);
+        check(&a, &Matrix::identity(4).scale(-2.5), true);
+        check(&Matrix::identity(6).scale(3.0), &a, true);
+        check(&a, &Matrix::new_unchecked(4, 3, vec![0.0; 12]), true);
+        check(&Matrix::new_unchecked(2, 6, vec![-0.0; 12]), &a, true);
+
+        // Near misses do the full multiply.
+        let mut near = Matrix::identity(4);
+        near.set(3, 3, 1.0 + f64::EPSILON);
+        assert_eq!(near.as_scaled_identity(), None);
+        check(&a, &near, false);
+        let mut near = Matrix::identity(4);
+        near.set(0, 3, 1e-300);
+        check(&a, &near, false);
+        check(&a, &Matrix::random(4, 4), false);
+
+        // inf * 0 is NaN in the full multiply, so no shortcut.
+        let mut inf = a.clone();
+        inf.set(0, 0, f64::INFINITY);
+        check(&inf, &Matrix::identity(4), false);
+        check(&inf, &Matrix::new_unchecked(4, 2, vec![0.0; 8]), false);
+    }
+
+    #[test]
+    fn numpy_oracle() {
+        let options = MultiplyOptions::new();
+        let token = CancelToken::new();
+
+        for name in ["square", "rectangular", "ill_conditioned"] {
+            let a = load_fixture(&format!("{}_a.npy", name));
+            let b = load_fixture(&format!("{}_b.npy", name));
+            let expected = load_fixture(&format!("{}_c.npy", name));
+
+            let results = [
+                ("seq", a.multiply(&b).unwrap()),
+                ("par", a.multiply_par(&b).unwrap()),
+                ("seq cancellable", a.multiply_cancellable(&b, &token).unwrap()),
+                ("par cancellable", a.multiply_par_cancellable(&b, &token).unwrap()),
+                ("par profiled", a.multiply_par_profiled(&b).unwrap().0),
+                ("multiply_with seq", a.multiply_with(&b, &options).unwrap()),
+                (
+                    "multiply_with par",
+                    a.multiply_with(&b, &options.clone().algorithm(Algorithm::Par)).unwrap(),
+                ),
+            ];
+            for (algo, actual) in &results {
+                if let Err(err) = matches_oracle(&a, &b, &expected, actual) {
+                    panic!("{} fixture, {}: {}", name, algo, err);
+                }
+            }
+        }
+
+        // The check is tight enough to notice swapped operands.
+        let a = load_fixture("square_a.npy");
+        let b = load_fixture("square_b.npy");
+        let expected = load_fixture("square_c.npy");
+        assert!(matches_oracle(&a, &b, &expected, &b.multiply(&a).unwrap()).is_err());
+        assert!(matches_oracle(&a, &b, &expected, &a.multiply(&b).unwrap().transpose()).is_err());
+    }
+
+    #[test]
+    fn symmetrize() {
+        let mut m = Matrix::random(6, 6).multiply_by_own_transpose(Algorithm::Seq);
+        assert_eq!(m.symmetry_error(), 0.0);
+        m.set(4, 1, m.get(1, 4) + 3e-14);
+        m.set(0, 5, m.get(5, 0) - 1e-14);
+        let error = m.symmetry_error();
+        assert!((2.9e-14..3.1e-14).contains(&error), "{:e}", error);
+
+        let repaired = m.symmetrize().unwrap();
+        assert_eq!(repaired.symmetry_error(), 0.0);
+        assert!(repaired.is_symmetric());
+        assert!(repaired.max_abs_diff(&m) < error);
+        assert_eq!(repaired.symmetrize().unwrap(), repaired);
+        assert_eq!(repaired.symmetrize().unwrap().bits(), repaired.bits());
+
+        // No overflow halfway between huge values; NaN pairs are no error,
+        // half-NaN ones are infinite.
+        let huge = matrix![1.0, f64::MAX; f64::MAX * 0.5, f64::NAN];
+        assert_eq!(huge.symmetrize().unwrap().get(0, 1), f64::MAX * 0.75);
+        assert_eq!(huge.symmetry_error(), f64::MAX * 0.5);
+        assert_eq!(matrix![f64::NAN, 1.0; f64::NAN, 2.0].symmetry_error(), f64::INFINITY);
+        assert_eq!(matrix![1.0, f64::NAN; f64::NAN, 2.0].symmetry_error(), 0.0);
+        assert_eq!(Matrix::random(2, 3).symmetry_error(), f64::INFINITY);
+        assert!(matches!(Matrix::random(2, 3).symmetrize(), Err(MatrixError::NotSquare { rows: 2, cols: 3 })));
+    }
+
+    #[test]
+    fn seq_and_par() {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+
+        let a = Matrix::random(10, 5);
+        let b = Matrix::random(5, 7);
+        let c = a.multiply(&b).unwrap();
+        let d = pool.install(|| a.multiply_par(&b).unwrap());
+        assert_eq!(c, d);
+
+        let a = Matrix::random(50, 13);
+        let b = Matrix::random(13, 70);
+        let c = a.multiply(&b).unwrap();
+        let d = pool.install(|| a.multiply_par(&b).unwrap());
+        assert_eq!(c, d);
+
+        let a = Matrix::random(150, 400);
+        let b = Matrix::random(400, 3);
+        let c = a.multiply(&b).unwrap();
+        let d = pool.install(|| a.multiply_par(&b).unwrap());
+        assert_eq!(c, d);
+
+        let a = Matrix::random(500, 700);
+        let b = Matrix::random(700, 345);
+        let c = a.multiply(&b).unwrap();
+        let d = pool.install(|| a.multiply_par(&b).unwrap());
+        assert_eq!(c, d);
+
+        let a = Matrix::random(1000, 1000);
+        let b = Matrix::random(1000, 1000);
+        let c = a.multiply(&b).unwrap();
+        let d = pool.install(|| a.multiply_par(&b).unwrap());
+        assert_eq!(c, d);
+    }
+}
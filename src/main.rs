@@ -1,16 +1,61 @@
-use crossbeam::atomic::AtomicCell;
 use std::{
+    borrow::Cow,
     fmt,
-    sync::Arc,
-    time::{Instant}, path::PathBuf, io::{Read, Write}, fs::File,
+    io::IsTerminal,
+    sync::{atomic::{AtomicBool, Ordering}, Arc},
+    time::{Instant, Duration}, path::{Path, PathBuf}, io::{self, Read, Write}, fs::File, net::SocketAddr,
 };
 use clap::{Parser, clap_derive::ArgEnum};
+use rand::{rngs::StdRng, SeedableRng};
+use matrix_mul::{
+    accuracy, bench, blocked, cancellation, distribute, element, format, int, interval, preview, scheduler, server, sparse,
+    spot_check, npy, strassen, quote_json, text_reader, tiles, trace, units, watch, Algorithm, CancelToken, ChainPlan, Context, CooTarget, Dist, Epilogue, EventSink, Matrix,
+    MatrixError, MultiplyOptions, MultiplyReport, Number, Orientation, ParseOptions, Precision, RaggedPolicy, Transform,
+    Warning,
+};
+#[cfg(feature = "plugins")]
+use matrix_mul::plugin;
+
+mod wizard;
+
+// Set when the result goes to stdout, so that a pipeline gets only the
+// matrix there.
+static STATUS_ON_STDERR: AtomicBool = AtomicBool::new(false);
+
+// Progress, timings and other lines about the run, as println! would print
+// them, or on stderr when the result is on stdout. `status!(@print ...)` is
+// the print! form, for text that ends in its own newline.
+macro_rules! status {
+    (@print $($arg:tt)*) => {
+        if STATUS_ON_STDERR.load(Ordering::Relaxed) {
+            eprint!($($arg)*)
+        } else {
+            print!($($arg)*)
+        }
+    };
+    ($($arg:tt)*) => {
+        if STATUS_ON_STDERR.load(Ordering::Relaxed) {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
 
 #[derive(Parser, Debug)]
-#[clap(author, version, about)]
+#[clap(
+    author,
+    version,
+    about,
+    after_help = "Run `matrix-mul wizard` to be asked for the inputs instead, or `matrix-mul shape FILE` for the shapes of \
+                  the matrices in a file."
+)]
 struct Args {
+    /// The input matrices, separated by X in one file, or one file each:
+    /// -f a.csv -f b.csv. `-` is stdin, which is also the default when no
+    /// sizes are given and stdin is not a terminal.
     #[clap(short, long, value_parser, value_name = "FILE")]
-    file: Option<PathBuf>,
+    file: Vec<PathBuf>,
 
     #[clap(short, long)]
     n: Option<usize>,
@@ -21,379 +66,3018 @@ struct Args {
     #[clap(short, long)]
     k: Option<usize>,
 
-    #[clap(short, long, arg_enum, value_parser)]
-    mode: Mode
+    /// Shorthand for the random sizes: "N" (n = m = k = N) or "NxMxK".
+    /// Any of -n, -m, -k given explicitly overrides its component.
+    #[clap(long, value_parser = parse_size, value_name = "SIZE")]
+    size: Option<(usize, usize, usize)>,
+
+    #[clap(long, arg_enum, value_parser)]
+    mode: Mode,
+
+    /// Rows, columns and inner length of the tiles --mode blocked and
+    /// --mode all compute at a time.
+    #[clap(
+        long,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        default_value_t = blocked::DEFAULT_BLOCK_SIZE,
+        value_name = "N"
+    )]
+    block_size: usize,
+
+    /// The size below which --mode strassen stops recursing: once any
+    /// dimension of the quadrants is at most this, they are multiplied
+    /// directly.
+    #[clap(
+        long,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        default_value_t = strassen::DEFAULT_CUTOFF,
+        value_name = "N"
+    )]
+    strassen_cutoff: usize,
+
+    /// Threads for the parallel modes, and for everything else that runs
+    /// on rayon. All cores when omitted.
+    #[clap(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..), value_name = "N")]
+    threads: Option<usize>,
+
+    #[clap(long, arg_enum, value_parser, default_value = "multiply")]
+    op: Op,
+
+    /// Multiply over the semiring exported by this shared library instead of
+    /// (+, *). See plugins/fuzzy-max-min.
+    #[cfg(feature = "plugins")]
+    #[clap(long, value_parser, value_name = "PATH")]
+    plugin: Option<PathBuf>,
+
+    /// Walk length for --op paths.
+    #[clap(long, required_if_eq("op", "paths"))]
+    length: Option<u32>,
+
+    /// With --op stationary, also estimate the mixing time, which takes up
+    /// to 1000 n x n multiplies.
+    #[clap(long)]
+    mix_time: bool,
+
+    #[clap(long)]
+    profile_threads: bool,
+
+    /// Give up on the multiplication after this long, e.g. "90s" or "2m";
+    /// a bare number is seconds.
+    #[clap(long, value_parser = units::parse_duration, value_name = "DURATION")]
+    timeout: Option<Duration>,
+
+    /// Treat a 1x1 operand as a scalar when the shapes do not conform.
+    #[clap(long)]
+    broadcast_scalars: bool,
+
+    /// The shape the result must have, as ROWSxCOLS, checked once the
+    /// inputs are loaded and before anything is computed. Given once per
+    /// input and once more, the inputs are checked too, in order. Shapes
+    /// that do not match exit with code 3.
+    #[clap(long, value_parser = sparse::parse_shape, value_name = "RxC")]
+    expect_shape: Vec<(usize, usize)>,
+
+    /// Write the result to this file instead of stdout.
+    #[clap(short, long, value_parser, value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// Write the result nowhere, so that timed runs do not pay for
+    /// formatting it.
+    #[clap(short, long, conflicts_with_all = &["output", "write-all-results"])]
+    quiet: bool,
+
+    /// Replace result files that already exist instead of refusing to.
+    #[clap(long)]
+    force: bool,
+
+    /// Which result to write in All mode.
+    #[clap(long, arg_enum, value_parser, default_value = "par")]
+    write: WriteChoice,
+
+    /// Also write every algorithm's result to <output>.<algo>.txt, named
+    /// after output.txt without --output.
+    #[clap(long)]
+    write_all_results: bool,
+
+    /// With --mode all, results may differ from SEQ's by this much relative
+    /// to the larger element, or by --abs-tolerance, and still agree. NaNs
+    /// agree with NaNs in the same place. STRASSEN's error is bounded by the
+    /// largest elements rather than each one, so it may differ by this much
+    /// relative to the largest element of the result.
+    #[clap(long, default_value_t = 1e-9, value_name = "REL")]
+    tolerance: f64,
+
+    /// With --mode all, the difference allowed whatever the elements'
+    /// magnitude, for elements that cancel to near zero.
+    #[clap(long, default_value_t = 0.0, value_name = "ABS")]
+    abs_tolerance: f64,
+
+    /// Round values in output files to this many significant digits. By
+    /// default they are written in full and read back exactly.
+    #[clap(long, value_name = "N")]
+    digits: Option<usize>,
+
+    #[clap(long, value_parser, value_name = "PATH")]
+    control_socket: Option<PathBuf>,
+
+    /// Serve POST /multiply over HTTP on this address instead of running
+    /// once. --timeout and --max-elements apply to every request.
+    #[clap(long, value_parser, value_name = "ADDR")]
+    serve: Option<SocketAddr>,
+
+    /// Split the multiply into bands of rows and send them to the --serve
+    /// workers listed in this file, one HOST:PORT per line.
+    #[clap(long, value_parser, value_name = "FILE", conflicts_with = "serve")]
+    distribute: Option<PathBuf>,
+
+    /// Largest request body --serve accepts, e.g. "64MiB".
+    #[clap(long, value_parser = units::parse_bytes, value_name = "SIZE", requires = "serve")]
+    max_request_size: Option<u64>,
+
+    /// Requests --serve handles at the same time; others wait their turn.
+    #[clap(long, value_name = "N", requires = "serve")]
+    max_concurrent: Option<usize>,
+
+    /// Products --serve computes at the same time, sharing the thread pool
+    /// in proportion to their size with older ones first. auto allows one
+    /// per thread.
+    #[clap(long, value_parser, value_name = "auto|N", requires = "serve")]
+    concurrent_jobs: Option<scheduler::ConcurrentJobs>,
+
+    /// Memory --serve keeps for uploaded operands, e.g. "1GiB"; the least
+    /// recently used ones are dropped beyond it.
+    #[clap(long, value_parser = units::parse_bytes, value_name = "SIZE", requires = "serve")]
+    operand_cache: Option<u64>,
+
+    #[clap(long, arg_enum, value_parser, default_value = "error")]
+    ragged_policy: RaggedPolicy,
+
+    /// Transform applied to every element while parsing, before any
+    /// validation: replace-sentinel:<SENTINEL>:<VALUE>, log1p or
+    /// clamp:<MIN>:<MAX>. May be repeated; transforms run in order.
+    #[clap(long, value_parser = Transform::parse, value_name = "TRANSFORM")]
+    map_input: Vec<Transform>,
+
+    #[clap(long, conflicts_with = "replace-nonfinite")]
+    strict_finite: bool,
+
+    /// Replace NaN and infinite input elements by VALUE, which must itself
+    /// be finite.
+    #[clap(long, value_parser = parse_finite, value_name = "VALUE")]
+    replace_nonfinite: Option<f64>,
+
+    /// Replace every input by (A + Aᵀ) / 2 if no |a_ij - a_ji| is above
+    /// TOL, and refuse it otherwise, for inputs symmetric up to rounding.
+    #[clap(long, value_name = "TOL")]
+    symmetrize: Option<f64>,
+
+    /// Upper bound on memory for both operands and the result, e.g. "512M" or "2GiB".
+    #[clap(long, value_parser = units::parse_bytes, value_name = "SIZE")]
+    max_memory: Option<u64>,
+
+    /// Upper bound on the element count of any single matrix, e.g. "100k".
+    #[clap(long, value_parser = units::parse_count, value_name = "COUNT")]
+    max_elements: Option<u64>,
+
+    /// Run PAR on the calling thread when the multiply needs fewer than this
+    /// many floating-point operations, e.g. "100k". 0 disables the shortcut.
+    #[clap(long, value_parser = units::parse_count, value_name = "FLOPS")]
+    inline_below: Option<u64>,
+
+    /// Repeat the multiply for this long, e.g. "60s", and report sustained
+    /// and peak GFLOPS and whether later iterations slowed down.
+    #[clap(long, value_parser = units::parse_duration, value_name = "DURATION")]
+    soak: Option<Duration>,
+
+    /// While multiplying, rewrite output.preview.txt this often, e.g.
+    /// "30s", with the number of rows finished, their min, max and mean,
+    /// and the first few of them. Only the seq and par kernels report rows
+    /// as they finish, so --mode blocked and strassen refuse it, and --mode
+    /// all previews those two.
+    #[clap(long, value_parser = units::parse_duration, value_name = "DURATION")]
+    preview_every: Option<Duration>,
+
+    /// Stay running and multiply every matrix-pair file that appears in this
+    /// directory. Inputs are moved to done/ or failed/ when processed.
+    #[clap(long, value_parser, value_name = "DIR", conflicts_with = "serve")]
+    watch: Option<PathBuf>,
+
+    /// Where --watch writes products; defaults to out/ in the watched
+    /// directory.
+    #[clap(long, value_parser, value_name = "DIR", requires = "watch")]
+    watch_output: Option<PathBuf>,
+
+    /// Compute the product tile by tile, keeping each finished tile in this
+    /// directory so an interrupted run can continue with --resume-tiles.
+    #[clap(long, value_parser, value_name = "DIR")]
+    tile_dir: Option<PathBuf>,
+
+    /// Side of an output tile for --tile-dir.
+    #[clap(long, value_name = "N", requires = "tile-dir")]
+    tile_size: Option<usize>,
+
+    /// Reuse the tiles an earlier run with the same inputs finished.
+    #[clap(long, requires = "tile-dir")]
+    resume_tiles: bool,
+
+    /// Flush every tile to disk before counting it as done.
+    #[clap(long, requires = "tile-dir")]
+    fsync_tiles: bool,
+
+    /// Add this matrix to the product while it is computed, e.g. a bias.
+    #[clap(long, value_parser, value_name = "FILE")]
+    add_after: Option<PathBuf>,
+
+    /// Multiply the product elementwise by this matrix, e.g. a 0/1 mask.
+    /// Applied after --add-after.
+    #[clap(long, value_parser, value_name = "FILE")]
+    mask_after: Option<PathBuf>,
+
+    /// Exit with an error after the run if there were any warnings.
+    #[clap(long)]
+    deny_warnings: bool,
+
+    /// Always do the full multiply, even when one operand is a zero matrix
+    /// or a multiple of the identity.
+    #[clap(long)]
+    no_shortcuts: bool,
+
+    /// Multiply f32 copies of the operands when every element of both is
+    /// exactly an f32 (small integers, for one). The result is the same to
+    /// the bit; the check costs a pass over the operands.
+    #[clap(long)]
+    auto_narrow: bool,
+
+    /// With --op accuracy-report, also report on inputs of the same shape
+    /// generated in this regime. May be repeated.
+    #[clap(long, arg_enum, value_name = "REGIME")]
+    regime: Vec<accuracy::Regime>,
+
+    /// Seed for the random inputs, and for --regime, --spot-check and
+    /// --verify-exact. Without it the random inputs differ on every run and
+    /// the others use 1.
+    #[clap(long)]
+    seed: Option<u64>,
+
+    /// The range random inputs are drawn from, MIN included.
+    #[clap(long, default_value_t = 0.0, allow_hyphen_values = true)]
+    min: f64,
+
+    #[clap(long, default_value_t = 1.0, allow_hyphen_values = true)]
+    max: f64,
+
+    /// How random inputs spread over --min to --max; integer is easier to
+    /// read when debugging.
+    #[clap(long, arg_enum, default_value = "uniform")]
+    dist: Dist,
+
+    /// Make the whole run reproducible to the bit: random inputs, --regime,
+    /// --spot-check and --verify-exact all use seeds derived from SEED,
+    /// recorded next to the output as <output>.seeds.txt. Options whose
+    /// results depend on timing are refused.
+    #[clap(long, value_name = "SEED", conflicts_with = "seed")]
+    deterministic_run: Option<u64>,
+
+    #[clap(long, arg_enum, default_value = "f64")]
+    dtype: Dtype,
+
+    /// How the input files are written. By default .csv files are CSV, .bin
+    /// files binary, .npy files NumPy arrays and other files text. Results go to .csv, .txt and .bin
+    /// outputs in those formats, and otherwise in the input's format.
+    #[clap(long, visible_alias = "format", arg_enum)]
+    input_format: Option<InputFormat>,
+
+    /// The leading dimension of the first matrix's binary --file: its rows
+    /// are N elements apart, and the elements after the columns of each row
+    /// are padding. N must be at least the columns, and the file must hold
+    /// rows x N elements after its header.
+    #[clap(long, value_name = "N", requires = "file")]
+    lda: Option<usize>,
+
+    /// As --lda, for the second matrix.
+    #[clap(long, value_name = "N", requires = "file")]
+    ldb: Option<usize>,
+
+    /// Write binary results with their rows N elements apart, padded with
+    /// zeros, for readers that need them aligned.
+    #[clap(long, value_name = "N")]
+    ldc: Option<usize>,
+
+    /// Whether --input-format coo indices count from 0 or 1. --write-coo
+    /// writes them the same way.
+    #[clap(long, arg_enum, default_value = "zero")]
+    coo_base: sparse::Base,
+
+    #[clap(long, arg_enum, default_value = "sum")]
+    coo_duplicates: sparse::Duplicates,
+
+    /// With csr, --op multiply multiplies the first operand as a sparse
+    /// matrix.
+    #[clap(long, arg_enum, default_value = "dense")]
+    coo_target: CooTarget,
+
+    /// Shape of --input-format coo operands, as ROWSxCOLS. Without it each
+    /// operand is as large as its largest indices.
+    #[clap(long, value_parser = sparse::parse_shape, value_name = "SHAPE")]
+    coo_shape: Option<(usize, usize)>,
+
+    /// Leave the rows of the first matrix that are all zero out of the
+    /// multiply. The output has only the other rows; output.rows.txt lists
+    /// their indices in the first matrix, one per line.
+    #[clap(long)]
+    drop_zero_rows: bool,
+
+    /// With --drop-zero-rows, elements of magnitude at most TOLERANCE count
+    /// as zero.
+    #[clap(long, default_value_t = 0.0, value_name = "TOLERANCE", requires = "drop-zero-rows")]
+    zero_tolerance: f64,
+
+    /// Write the output as triplets, leaving out elements whose magnitude
+    /// is at most TOLERANCE.
+    #[clap(long, value_name = "TOLERANCE")]
+    write_coo: Option<f64>,
+
+    /// With --dtype i64, recompute a few random output elements with a
+    /// wider accumulator and fail if any differs.
+    #[clap(long)]
+    verify_exact: bool,
+
+    /// Timed runs of each algorithm for --op bench.
+    #[clap(long, default_value_t = 10, value_name = "N")]
+    runs: usize,
+
+    /// Untimed runs of each algorithm before the --runs timed ones.
+    #[clap(long, default_value_t = 1, value_name = "N")]
+    warmup: usize,
+
+    /// What --op bench times, comma-separated. parse and format time each
+    /// file format on an in-memory --phase-size matrix.
+    #[clap(long, arg_enum, value_parser, use_value_delimiter = true, default_value = "multiply", value_name = "PHASES")]
+    phases: Vec<Phase>,
+
+    /// The rows and columns of the matrix --phases parse,format use.
+    #[clap(long, default_value_t = 500, value_name = "N")]
+    phase_size: usize,
+
+    /// Store the --op bench timings as the baseline NAME, under
+    /// .matrix-mul/baselines/.
+    #[clap(long, value_name = "NAME")]
+    save_baseline: Option<String>,
+
+    /// Compare the --op bench timings with the baseline NAME and exit with an
+    /// error if any case regressed.
+    #[clap(long, value_name = "NAME")]
+    compare_baseline: Option<String>,
+
+    /// A case regresses when its median grows by more than this percentage
+    /// plus --regression-mads times the baseline's median absolute deviation.
+    #[clap(long, default_value_t = 5.0, value_name = "PERCENT")]
+    regression_percent: f64,
+
+    #[clap(long, default_value_t = 2.0, value_name = "N")]
+    regression_mads: f64,
+
+    /// After --op multiply, recompute N random elements of each result
+    /// with a compensated dot product and fail if any differs by more than
+    /// rounding. Uses --seed.
+    #[clap(long, value_name = "N")]
+    spot_check: Option<usize>,
+
+    /// After --op multiply, multiply again keeping the sum of the
+    /// magnitudes of each element's products, and warn about elements more
+    /// than --cancellation-threshold times smaller than that sum. With
+    /// --op bench, time that multiply as well.
+    #[clap(long)]
+    cancellation_check: bool,
+
+    #[clap(long, default_value_t = cancellation::DEFAULT_THRESHOLD, value_name = "RATIO")]
+    cancellation_threshold: f64,
+
+    /// Record the run's phases, and the bands of rows each thread of the
+    /// multiply computed, in this file, for chrome://tracing or Perfetto.
+    #[clap(long, value_parser, value_name = "FILE")]
+    trace_file: Option<PathBuf>,
+
+    /// Print the decisions the multiply dispatcher made.
+    #[clap(short, long)]
+    verbose: bool,
+
+    // Forces how the multiply reads the second matrix, for testing.
+    #[clap(long, arg_enum, hide = true)]
+    right_layout: Option<Orientation>,
+}
+
+/// `matrix-mul shape FILE`: the shapes of the matrices in a file, one per
+/// line. Without --input-format or an extension, as for stdin, binary and
+/// .npy files are told apart by their magic bytes, and anything else is
+/// text. Binary and .npy files are read up to the end of their header;
+/// text and CSV are scanned for the lengths of their rows without parsing
+/// any number, and COO is parsed in full with the default --coo-* options.
+#[derive(Parser, Debug)]
+#[clap(name = "matrix-mul shape")]
+struct ShapeArgs {
+    #[clap(value_parser, value_name = "FILE")]
+    file: PathBuf,
+
+    /// Print one JSON object instead.
+    #[clap(long)]
+    json: bool,
+
+    /// As for the multiply; by default the extension decides.
+    #[clap(long, visible_alias = "format", arg_enum)]
+    input_format: Option<InputFormat>,
+
+    /// The shape each matrix in the file must have, in order. Shapes that
+    /// do not match exit with code 3.
+    #[clap(long, value_parser = sparse::parse_shape, value_name = "RxC")]
+    expect_shape: Vec<(usize, usize)>,
 }
 
+// The exit code for inputs of the wrong shape, apart from 1 for every
+// other error and clap's 2 for bad arguments.
+const DIMENSION_EXIT_CODE: i32 = 3;
+
 #[derive(Clone, Copy, PartialEq, Eq, ArgEnum, Debug)]
 enum Mode {
     Seq,
     Par,
+    /// Parallel, a tile of --block-size at a time.
+    Blocked,
+    /// Parallel, by Strassen's recursion down to --strassen-cutoff. Sums in
+    /// another order, so agrees with the others only to a tolerance.
+    Strassen,
     All
 }
 
 #[tokio::main]
 async fn main() {
-    let args = Args::parse();
-
-    let matrix1;
-    let matrix2;
-
-    if let Some(file_path) = args.file {
-        let mut data = String::new();
-        let mut file = File::open(file_path).expect("Unable to open file");
-        file.read_to_string(&mut data).expect("Unable to read string");
-        let splitted: Vec<String> = data.split("X").map(|x| x.trim().to_owned()).collect();
-        matrix1 = Matrix::from_string(&splitted[0]);
-        matrix2 = Matrix::from_string(&splitted[1]);
+    if std::env::args().nth(1).as_deref() == Some("shape") {
+        print_shapes(&ShapeArgs::parse_from(std::env::args().skip(1)));
+        return;
+    }
+    let mut args = if std::env::args().nth(1).as_deref() == Some("wizard") {
+        wizard_args()
     } else {
-        let n = args.n.expect("No n found");
-        let m = args.m.expect("No m found");
-        let k = args.k.expect("No k found");
-        matrix1 = Matrix::random(n, m);
-        matrix2 = Matrix::random(m, k);
+        Args::parse()
+    };
+    if args.deterministic_run.is_some() {
+        if let Err(err) = check_deterministic(&args) {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+    }
+    if args.preview_every.is_some() && matches!(args.mode, Mode::Blocked | Mode::Strassen) {
+        eprintln!("Error: --preview-every needs --mode seq, par or all; blocked and strassen report no rows as they go");
+        std::process::exit(1);
+    }
+    if let Some(threads) = args.threads {
+        // Only fails if something already used the global pool.
+        let _ = rayon::ThreadPoolBuilder::new().num_threads(threads).build_global();
     }
 
-    if args.mode == Mode::Seq {
-        let start = Instant::now();
-        let matrix_res = matrix1.multiply(&matrix2);
-        let elapsed = start.elapsed();
-        println!("Done! Elapsed time: {:?}", elapsed);
-        matrix_res.write_to_file();
-    } else if args.mode == Mode::Par {
-        let pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(4)
-            .build()
-            .unwrap();
-        let start = Instant::now();
-        let matrix_res = pool.install(|| matrix1.multiply_par(&matrix2));
-        let elapsed = start.elapsed();
-        println!("Done! Elapsed time: {:?}", elapsed);
-        matrix_res.write_to_file();
-    } else {
-        let start = Instant::now();
-        let matrix_res_seq = matrix1.multiply(&matrix2);
-        let elapsed = start.elapsed();
-        println!("Done! Elapsed time for SEQ: {:?}", elapsed);
+    let mut events = match &args.control_socket {
+        Some(path) => EventSink::connect(path).unwrap_or_else(|err| {
+            eprintln!("Error: cannot connect to control socket {}: {}", path.display(), err);
+            std::process::exit(1);
+        }),
+        None => EventSink::disabled(),
+    };
+    if args.trace_file.is_some() {
+        events.set_trace(Arc::new(trace::Trace::new()));
+    }
 
-        let pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(4)
-            .build()
-            .unwrap();
-        let start = Instant::now();
-        let matrix_res_par = pool.install(|| matrix1.multiply_par(&matrix2));
-        let elapsed = start.elapsed();
-        println!("Done! Elapsed time for PAR: {:?}", elapsed);
+    if let Some(addr) = args.serve {
+        let mut limits = server::Limits::default();
+        if let Some(bytes) = args.max_request_size {
+            limits.max_body_bytes = bytes;
+        }
+        if let Some(elements) = args.max_elements {
+            limits.max_elements = elements;
+        }
+        if let Some(timeout) = args.timeout {
+            limits.compute_timeout = timeout;
+        }
+        if let Some(n) = args.max_concurrent {
+            limits.max_concurrent = n;
+        }
+        if let Some(jobs) = args.concurrent_jobs {
+            limits.concurrent_jobs = jobs;
+        }
+        if let Some(bytes) = args.operand_cache {
+            limits.operand_cache_bytes = bytes;
+        }
+        let algorithm = match args.mode {
+            Mode::Seq => Algorithm::Seq,
+            Mode::Par | Mode::Blocked | Mode::Strassen | Mode::All => Algorithm::Par,
+        };
+        if let Err(err) = Arc::new(server::Server::new(limits, algorithm)).serve(addr).await {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
 
-        assert_eq!(matrix_res_seq, matrix_res_par);
+    if let Some(dir) = &args.watch {
+        if let Err(err) = watch_dir(&args, dir).await {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
 
-        matrix_res_par.write_to_file();
+    // `matrix-mul --mode par < in.txt > out.txt`.
+    if args.file.is_empty() && args.size.is_none() && args.n.is_none() && !io::stdin().is_terminal() {
+        args.file.push(PathBuf::from("-"));
     }
-}
+    if args.file.iter().filter(|path| is_stdin(path)).count() > 1 {
+        eprintln!("Error: stdin holds one input; give --file - once");
+        std::process::exit(1);
+    }
+    STATUS_ON_STDERR.store(result_target(&args) == Some(Target::Stdout) && args.write != WriteChoice::None, Ordering::Relaxed);
 
-#[derive(Clone, Debug)]
-struct Matrix {
-    rows: usize,
-    cols: usize,
-    data: Vec<f64>,
-}
+    // Refused now rather than after the multiply.
+    if let (Some(path), false) = (&args.output, args.force) {
+        if path.exists() {
+            write_failed(&Target::File(path.clone()), io::ErrorKind::AlreadyExists.into());
+        }
+    }
+    if args.deterministic_run.is_some() {
+        record_seeds(&args);
+    }
 
-impl fmt::Display for Matrix {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        for i in 0..self.rows {
-            for j in 0..self.cols {
-                write!(f, "{} ", self.data[i * self.cols + j])?;
+    // These read their own inputs, never as f64.
+    let typed = match args.dtype {
+        Dtype::F32 => Some(run_f32(&args, &mut events)),
+        Dtype::I64 => Some(run_i64(&args, &mut events)),
+        Dtype::F64 | Dtype::Interval => None,
+    };
+    if let Some(result) = typed {
+        if let Err(err) = result {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let operands = args.op.operands();
+    if args.mix_time && args.op != Op::Stationary {
+        eprintln!("Error: --mix-time only applies to --op stationary");
+        std::process::exit(1);
+    }
+    if args.op == Op::Chain && args.expect_shape.len() > 1 {
+        eprintln!("Error: --op chain takes --expect-shape once, for the result");
+        std::process::exit(1);
+    }
+    if args.expect_shape.len() > 1 && args.expect_shape.len() != operands + 1 {
+        eprintln!(
+            "Error: --op {:?} takes --expect-shape once for the result, or {} times for the inputs and the result",
+            args.op,
+            operands + 1
+        );
+        std::process::exit(1);
+    }
+    let mut inputs = Vec::with_capacity(operands);
+
+    let mut left_csr = None;
+    events.phase_started("load");
+    let start = Instant::now();
+    if args.op == Op::Chain {
+        if args.file.is_empty() {
+            eprintln!("Error: --op chain multiplies the matrices of its --file inputs; give at least one");
+            std::process::exit(1);
+        }
+        inputs = load_chain(&args, &mut events);
+    } else if !args.file.is_empty() {
+        if args.file.len() > 1 && args.file.len() != operands {
+            eprintln!("Error: expected {} matrices, one per --file, but got {} files", operands, args.file.len());
+            std::process::exit(1);
+        }
+        // One file holds every operand, separated by X, or each has its own.
+        let per_file = if args.file.len() == 1 { operands } else { 1 };
+        for (path, names) in args.file.iter().zip(OPERAND_NAMES[..operands].chunks(per_file)) {
+            let first = inputs.is_empty();
+            inputs.extend(load_file(&args, path, names, first, &mut left_csr, &mut events));
+        }
+    } else {
+        let (n, m, k) = match resolve_dims(&args) {
+            Ok(Dims::Given(n, m, k)) => (n, m, k),
+            Ok(Dims::InferredK(n, m, k)) => {
+                if operands == 2 {
+                    status!("No k given, using k = n = {}", k);
+                }
+                (n, m, k)
+            }
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                std::process::exit(1);
+            }
+        };
+        // Single-operand operations work on an n x n matrix, except A Aᵀ
+        // and Aᵀ A, which take n x m.
+        let (m, k) = if operands == 1 && !args.op.rectangular() { (n, n) } else { (m, k) };
+        let (ln, lm, lk) = args.op.limit_dims((n, m), k);
+        if let Err(err) = check_limits(&args, ln, lm, lk) {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+        if let Err(err) = check_range(&args) {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+        // --seed draws both operands from one generator, so that they differ.
+        let mut seeded = args.seed.map(StdRng::seed_from_u64);
+        let mut random = |rows, cols, purpose| {
+            let range = args.min..args.max;
+            match (args.deterministic_run, &mut seeded) {
+                (Some(_), _) => {
+                    Matrix::random_with(rows, cols, range, args.dist, &mut StdRng::seed_from_u64(seed(&args, purpose)))
+                }
+                (None, Some(rng)) => Matrix::random_with(rows, cols, range, args.dist, rng),
+                (None, None) => Matrix::random_with(rows, cols, range, args.dist, &mut rand::thread_rng()),
+            }
+        };
+        let mut random = |rows, cols, purpose| {
+            random(rows, cols, purpose).unwrap_or_else(|err| {
+                eprintln!("Error: {}", err);
+                std::process::exit(1);
+            })
+        };
+        inputs.push(random(n, m, "first"));
+        if operands == 2 {
+            inputs.push(random(m, k, "second"));
+        }
+    }
+    // A chain is checked product by product, once its order is planned.
+    if !args.file.is_empty() && args.op != Op::Chain {
+        let (n, m, k) = args.op.limit_dims(inputs[0].shape(), inputs.last().unwrap().cols());
+        if let Err(err) = check_limits(&args, n, m, k) {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+    }
+    events.phase_finished("load", start.elapsed());
+    if !args.expect_shape.is_empty() {
+        let shapes: Vec<(usize, usize)> = inputs.iter().map(Matrix::shape).collect();
+        if let Err(err) = check_expected_shapes(&args, &shapes) {
+            eprintln!("Error: {}", err);
+            std::process::exit(DIMENSION_EXIT_CODE);
+        }
+    }
+    // A --ldc too large to write is refused before multiplying, not after.
+    if let Some(ld) = args.ldc {
+        let shapes: Vec<(usize, usize)> = inputs.iter().map(Matrix::shape).collect();
+        if let Ok((rows, _)) = args.op.result_shape(&shapes, args.broadcast_scalars) {
+            if ld.checked_mul(std::mem::size_of::<f64>()).and_then(|row| row.checked_mul(rows)).is_none() {
+                eprintln!("Error: {}", MatrixError::LayoutTooLarge { rows, ld });
+                std::process::exit(1);
             }
-            write!(f, "\n")?;
         }
-        Ok(())
     }
-}
 
-impl Matrix {
-    fn new(rows: usize, cols: usize, vec: Vec<f64>) -> Matrix {
-        Matrix {
-            rows,
-            cols,
-            data: vec,
+    if args.strict_finite {
+        for (name, matrix) in OPERAND_NAMES.iter().zip(&inputs) {
+            if let Err(err) = matrix.validate_finite() {
+                eprintln!("Error in {} matrix: {}", name, err);
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(value) = args.replace_nonfinite {
+        replace_nonfinite_inputs(&mut inputs, value, &mut events);
+    }
+    if let Some(tolerance) = args.symmetrize {
+        if let Err(err) = symmetrize_inputs(&mut inputs, tolerance) {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
         }
     }
 
-    fn from_string(s: &String) -> Matrix {
-        let mut rows = 0;
-        let mut cols = 0;
-        let mut data = Vec::new();
+    if args.dtype == Dtype::Interval {
+        if let Err(err) = run_interval(&args, &inputs, &mut events) {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
 
-        for line in s.lines() {
-            let splitted: Vec<String> = line.split(" ").map(|x| x.to_owned()).collect();
+    let mut kept_rows = None;
+    if args.drop_zero_rows {
+        if args.op != Op::Multiply || left_csr.is_some() {
+            eprintln!("Error: --drop-zero-rows only applies to a dense --op multiply");
+            std::process::exit(1);
+        }
+        let (compressed, indices) = inputs[0].drop_zero_rows(args.zero_tolerance);
+        status!("Dropped {} zero rows of {} from the first matrix", inputs[0].rows() - indices.len(), inputs[0].rows());
+        inputs[0] = compressed;
+        kept_rows = Some(indices);
+    }
 
-            if splitted.len() == 0 {
-                continue;
+    let cancel = match args.timeout {
+        Some(timeout) => CancelToken::with_timeout(timeout),
+        None => CancelToken::new(),
+    };
+    let on_ctrl_c = cancel.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            on_ctrl_c.cancel();
+        }
+    });
+
+    let outcome = match args.op {
+        Op::Multiply if args.tile_dir.is_some() => run_tiled(&args, &inputs[0], &inputs[1], &mut events),
+        Op::Multiply if left_csr.is_some() => run_csr(&args, left_csr.as_ref().unwrap(), &inputs[1], &mut events),
+        Op::Multiply if args.soak.is_some() => run_soak(&args, &inputs[0], &inputs[1], &cancel, &mut events),
+        Op::Multiply if args.distribute.is_some() => {
+            run_distributed(&args, &inputs[0], &inputs[1], &cancel, &mut events).await
+        }
+        Op::Multiply => run(&args, &inputs[0], &inputs[1], &cancel, &mut events),
+        Op::Solve => run_solve(&args, &inputs[0], &inputs[1], &mut events),
+        Op::AccuracyReport => run_accuracy_report(&args, &inputs[0], &inputs[1], &mut events),
+        Op::Bench => run_bench(&args, &inputs[0], &inputs[1], &mut events),
+        Op::Chain => run_chain(&args, &inputs, &mut events),
+        Op::Convert => Ok(vec![AlgoResult {
+            algo: "convert",
+            matrix: inputs.swap_remove(0),
+            elapsed: Duration::ZERO,
+        }]),
+        _ => run_single(&args, &inputs[0], &mut events),
+    };
+    let results = match outcome {
+        Ok(results) => results,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            if let MatrixError::DimensionMismatch { left, right } = err {
+                if left == (1, 1) || right == (1, 1) {
+                    eprintln!("Hint: pass --broadcast-scalars to treat a 1x1 operand as a scalar");
+                }
+                std::process::exit(DIMENSION_EXIT_CODE);
             }
-
-            if cols == 0 {
-                cols = splitted.len();
-            } else if cols != splitted.len() {
-                panic!("Cannot read matrix");
+            if let MatrixError::ChainMismatch { .. } = err {
+                std::process::exit(DIMENSION_EXIT_CODE);
             }
+            std::process::exit(1);
+        }
+    };
+    if args.op == Op::Multiply {
+        if let Err(err) = spot_check_results(&args, &inputs[0], &inputs[1], &results) {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+        if args.cancellation_check {
+            check_cancellation(&args, &inputs[0], &inputs[1], &mut events);
+        }
+    }
+    write_results(&results, &args, &mut events);
+    if let Some(indices) = &kept_rows {
+        let target = Target::File(algo_output_path(output_base(&args), "rows"));
+        let lines: String = indices.iter().map(|i| format!("{}\n", i)).collect();
+        if let Err(err) = target.open(args.force).and_then(|mut out| out.write_all(lines.as_bytes())) {
+            write_failed(&target, err);
+        }
+    }
 
-            rows += 1;
+    if let (Some(path), Some(trace)) = (&args.trace_file, events.trace()) {
+        if let Err(err) = trace.write(path) {
+            eprintln!("Error: cannot write {}: {}", path.display(), err);
+            std::process::exit(1);
+        }
+    }
 
-            for num_str in splitted {
-                let num = num_str.parse::<f64>().expect("Not a number");
-                data.push(num);
-            }
+    let warnings = events.warnings().len();
+    if warnings > 0 {
+        status!("{} warning{}", warnings, if warnings == 1 { "" } else { "s" });
+    }
+    let code = exit_code(&events, args.deny_warnings);
+    if code != 0 {
+        if events.warnings().iter().any(Warning::is_fatal) {
+            eprintln!("Error: the benchmark regressed against its baseline");
+        } else {
+            eprintln!("Error: --deny-warnings given and the run had warnings");
         }
+        std::process::exit(code);
+    }
+}
 
-        Matrix { rows, cols, data }
+// --spot-check, and the automatic one after experimental kernels. Results
+// over another semiring (--plugin) or of a broadcast scalar are not ordinary
+// products and are left alone.
+fn spot_check_results(args: &Args, a: &Matrix, b: &Matrix, results: &[AlgoResult]) -> Result<(), MatrixError> {
+    let cells = |result: &AlgoResult| match args.spot_check {
+        Some(n) => n,
+        None if accuracy::is_experimental(result.algo) => spot_check::EXPERIMENTAL_CELLS,
+        None => 0,
+    };
+    let checked: Vec<&AlgoResult> = results
+        .iter()
+        .filter(|result| cells(result) > 0 && result.algo != "plugin" && a.cols() == b.rows())
+        .collect();
+    if checked.is_empty() {
+        return Ok(());
     }
 
-    fn random(rows: usize, cols: usize) -> Matrix {
-        let mut m = Matrix::new(rows, cols, vec![0.0; rows * cols]);
-        for i in 0..m.data.len() {
-            m.data[i] = rand::random::<f64>();
+    let options = multiply_options(args)?;
+    let mut corrupted = 0;
+    for result in checked {
+        let bound = if result.algo == "strassen" {
+            let growth = strassen::error_growth(a.rows(), a.cols(), b.cols(), args.strassen_cutoff);
+            spot_check::Bound::Normwise { growth }
+        } else {
+            spot_check::Bound::Elementwise
+        };
+        let (cells, seed) = (cells(result), seed(args, "spot-check"));
+        let mismatches = spot_check::spot_check(a, b, options.epilogues(), &result.matrix, bound, cells, seed);
+        let name = result.algo.to_uppercase();
+        if mismatches.is_empty() {
+            status!("{}: spot check of {} elements passed", name, cells.min(result.matrix.data().len()));
         }
-        m
+        for mismatch in &mismatches {
+            eprintln!("{}: spot check failed, element {}", name, mismatch);
+        }
+        corrupted += mismatches.len();
     }
-
-    fn get(&self, row: usize, col: usize) -> f64 {
-        self.data[row * self.cols + col]
+    if corrupted > 0 {
+        return Err(MatrixError::SpotCheckFailed { mismatches: corrupted });
     }
+    Ok(())
+}
 
-    fn set(&mut self, row: usize, col: usize, value: f64) {
-        self.data[row * self.cols + col] = value;
+// --cancellation-check, for the same products as the spot check.
+fn check_cancellation(args: &Args, a: &Matrix, b: &Matrix, events: &mut EventSink) {
+    #[cfg(feature = "plugins")]
+    if args.plugin.is_some() {
+        return;
+    }
+    if a.cols() != b.rows() {
+        return;
     }
 
-    fn multiply(&self, other: &Matrix) -> Matrix {
-        assert_eq!(self.cols, other.rows);
+    events.phase_started("cancellation-check");
+    let start = Instant::now();
+    let (_, report) = a.multiply_checking_cancellation(b, args.cancellation_threshold).unwrap();
+    let elapsed = start.elapsed();
+    events.phase_finished("cancellation-check", elapsed);
+    status!(
+        "Cancellation check: {} of {} elements over {:e} times smaller than their products ({:?})",
+        report.flagged,
+        a.rows() * b.cols(),
+        args.cancellation_threshold,
+        elapsed
+    );
+    for hotspot in &report.worst {
+        status!("  {}", hotspot);
+    }
+    if report.flagged > 0 {
+        events.warn(Warning::Cancellation { elements: report.flagged, threshold: args.cancellation_threshold });
+    }
+}
 
-        let mut result = Matrix::new(self.rows, other.cols, vec![0.0; self.rows * other.cols]);
+// The operands `names` from one --file, the first of them the left operand
+// if `left`. Exits on errors.
+fn load_file(
+    args: &Args,
+    path: &Path,
+    names: &[&'static str],
+    left: bool,
+    left_csr: &mut Option<sparse::Csr>,
+    events: &mut EventSink,
+) -> Vec<Matrix> {
+    let format = input_format(args, path);
+    let mut inputs = Vec::with_capacity(names.len());
+    let first = OPERAND_NAMES.iter().position(|&name| name == names[0]).unwrap();
+    if format != InputFormat::Bin && (first..first + names.len()).any(|index| leading_dimension(args, index).is_some()) {
+        eprintln!("Error: --lda and --ldb apply only to binary files");
+        std::process::exit(1);
+    }
+    if matches!(format, InputFormat::Bin | InputFormat::Npy) {
+        if names.len() > 1 {
+            eprintln!("Error: a binary file holds one matrix; give one --file each");
+            std::process::exit(1);
+        }
+        match read_binary_operand(path, format, args, leading_dimension(args, first)) {
+            Ok(matrix) => inputs.push(matrix),
+            Err(err) => {
+                eprintln!("Error in {} matrix: {}", names[0], err);
+                std::process::exit(1);
+            }
+        }
+        return inputs;
+    }
+    if format != InputFormat::Coo {
+        // Parsed as it is read, so a file with huge lines is never held in
+        // memory as text.
+        let mut reader = io::BufReader::new(open_input(path));
+        for (index, &name) in names.iter().enumerate() {
+            let separated = index == 0 || text_reader::skip_separator(&mut reader).unwrap_or_else(|err| {
+                eprintln!("Error: cannot read {}: {}", path.display(), err);
+                std::process::exit(1);
+            });
+            if !separated {
+                eprintln!("Error: expected {} matrices separated by X, or one --file each", names.len());
+                std::process::exit(1);
+            }
+            match parse_text_operand(name, &mut reader, format, args, events) {
+                Ok(matrix) => inputs.push(matrix),
+                Err(err) => {
+                    eprintln!("Error in {} matrix: {}", name, err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        return inputs;
+    }
 
-        for i in 0..self.rows {
-            for j in 0..other.cols {
-                let mut sum = 0.0;
+    let mut data = String::new();
+    if let Err(err) = open_input(path).read_to_string(&mut data) {
+        eprintln!("Error: cannot read {}: {}", path.display(), err);
+        std::process::exit(1);
+    }
+    let splitted = text_reader::split_operands(&data);
+    if splitted.len() < names.len() {
+        eprintln!("Error: expected {} matrices separated by X, or one --file each", names.len());
+        std::process::exit(1);
+    }
+    for (index, (&name, text)) in names.iter().zip(&splitted).enumerate() {
+        let sparse_left = left && index == 0 && args.coo_target == CooTarget::Csr;
+        let parsed = if sparse_left && args.op == Op::Multiply {
+            // The dense copy still goes through the checks below.
+            parse_coo(text, args).and_then(|coo| {
+                let dense = coo_to_dense(&coo, args)?;
+                *left_csr = Some(coo.to_csr()?);
+                Ok(dense)
+            })
+        } else {
+            parse_operand(name, text, format, args, events)
+        };
+        match parsed {
+            Ok(matrix) => inputs.push(matrix),
+            Err(err) => {
+                eprintln!("Error in {} matrix: {}", name, err);
+                std::process::exit(1);
+            }
+        }
+    }
+    inputs
+}
 
-                for k in 0..self.cols {
-                    sum += self.get(i, k) * other.get(k, j);
+// --op chain: every matrix of every --file, however many there are.
+// Exits on errors.
+fn load_chain(args: &Args, events: &mut EventSink) -> Vec<Matrix> {
+    let mut inputs = Vec::new();
+    let fail = |name: &str, err: MatrixError| -> ! {
+        eprintln!("Error in {} matrix: {}", name, err);
+        std::process::exit(1);
+    };
+    for path in &args.file {
+        let format = input_format(args, path);
+        match format {
+            InputFormat::Bin | InputFormat::Npy => {
+                let name = chain_name(inputs.len());
+                let ld = leading_dimension(args, inputs.len());
+                inputs.push(read_binary_operand(path, format, args, ld).unwrap_or_else(|err| fail(&name, err)));
+            }
+            InputFormat::Coo => {
+                let mut data = String::new();
+                if let Err(err) = open_input(path).read_to_string(&mut data) {
+                    eprintln!("Error: cannot read {}: {}", path.display(), err);
+                    std::process::exit(1);
+                }
+                for text in text_reader::split_operands(&data) {
+                    let name = chain_name(inputs.len());
+                    inputs.push(parse_operand(&name, text, format, args, events).unwrap_or_else(|err| fail(&name, err)));
+                }
+            }
+            InputFormat::Text | InputFormat::Csv => {
+                let mut reader = io::BufReader::new(open_input(path));
+                loop {
+                    let name = chain_name(inputs.len());
+                    let parsed = parse_text_operand(&name, &mut reader, format, args, events);
+                    inputs.push(parsed.unwrap_or_else(|err| fail(&name, err)));
+                    match text_reader::skip_separator(&mut reader) {
+                        Ok(true) => {}
+                        Ok(false) => break,
+                        Err(err) => {
+                            eprintln!("Error: cannot read {}: {}", path.display(), err);
+                            std::process::exit(1);
+                        }
+                    }
                 }
-
-                result.set(i, j, sum);
             }
         }
+    }
+    inputs
+}
+
+// "3rd" for the matrix at index 2 of a chain.
+fn chain_name(index: usize) -> String {
+    let n = index + 1;
+    let suffix = match (n % 10, n % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    };
+    format!("{}{}", n, suffix)
+}
 
-        result
+// The format a .csv, .txt, .bin or .npy extension names.
+fn extension_format(path: &Path) -> Option<InputFormat> {
+    match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+        "csv" => Some(InputFormat::Csv),
+        "txt" => Some(InputFormat::Text),
+        "bin" => Some(InputFormat::Bin),
+        "npy" => Some(InputFormat::Npy),
+        _ => None,
     }
+}
 
-    fn multiply_par(&self, other: &Matrix) -> Matrix {
-        assert_eq!(self.cols, other.rows);
+// --input-format, or else the one the extension names, or text.
+fn input_format(args: &Args, path: &Path) -> InputFormat {
+    args.input_format.or_else(|| extension_format(path)).unwrap_or(InputFormat::Text)
+}
 
-        let result = Arc::new(AtomicCell::new(Matrix::new(
-            self.rows,
-            other.cols,
-            vec![0.0; self.rows * other.cols],
-        )));
+// --lda for the first matrix and --ldb for the second.
+fn leading_dimension(args: &Args, index: usize) -> Option<usize> {
+    [args.lda, args.ldb].get(index).copied().flatten()
+}
 
-        rayon::scope(|s| {
-            for i in 0..self.rows {
-                for j in 0..other.cols {
-                    let result = Arc::clone(&result);
-                    s.spawn(move |_| {
-                        let mut sum = 0.0;
+// A binary or .npy --file, with rows `ld` elements apart and --map-input
+// applied as the text parser would.
+fn read_binary_operand(path: &Path, format: InputFormat, args: &Args, ld: Option<usize>) -> Result<Matrix, MatrixError> {
+    let matrix = if format == InputFormat::Npy {
+        if ld.is_some() {
+            return Err(MatrixError::Message("--lda and --ldb apply only to binary files, not .npy".to_owned()));
+        }
+        let mut bytes = Vec::new();
+        open_input(path).read_to_end(&mut bytes)?;
+        let (rows, cols, data) = npy::read(&bytes).map_err(MatrixError::InvalidHeader)?;
+        Matrix::try_new(rows, cols, data)?
+    } else if is_stdin(path) || !path.is_file() {
+        Matrix::read_binary_ld(open_input(path), ld)?
+    } else {
+        // Mapped, so that a file of the wrong size fails before anything
+        // is copied out of it.
+        Matrix::map_binary(path, ld)?
+    };
+    if args.map_input.is_empty() {
+        return Ok(matrix);
+    }
+    let mut changed = vec![0usize; args.map_input.len()];
+    let data = matrix.data().iter().map(|&x| apply_transforms(&args.map_input, &mut changed, x)).collect();
+    Ok(Matrix::try_new(matrix.rows(), matrix.cols(), data)?)
+}
 
-                        for k in 0..self.cols {
-                            sum += self.get(i, k) * other.get(k, j);
-                        }
+// Bin or Npy for input that starts with their magic bytes, else text,
+// with the bytes looked at put back in front of the rest.
+fn sniff_format(mut input: Box<dyn Read>) -> io::Result<(InputFormat, Box<dyn Read>)> {
+    const BINARY_MAGIC: &[u8] = b"MMULBIN\0";
+    const NPY_MAGIC: &[u8] = b"\x93NUMPY";
+
+    let mut start = Vec::with_capacity(BINARY_MAGIC.len());
+    (&mut input).take(BINARY_MAGIC.len() as u64).read_to_end(&mut start)?;
+    let format = if start.starts_with(BINARY_MAGIC) {
+        InputFormat::Bin
+    } else if start.starts_with(NPY_MAGIC) {
+        InputFormat::Npy
+    } else {
+        InputFormat::Text
+    };
+    Ok((format, Box::new(io::Cursor::new(start).chain(input))))
+}
 
-                        unsafe {
-                            (*result.as_ptr()).set(i, j, sum);
-                        }
-                    });
-                }
-            }
-        });
+// `--file -`.
+fn is_stdin(path: &Path) -> bool {
+    path == Path::new("-")
+}
 
-        unsafe { (*result.as_ptr()).clone() }
+// --file, or stdin for `-`, exiting with an error if it cannot be opened.
+fn open_input(path: &Path) -> Box<dyn Read> {
+    if is_stdin(path) {
+        return Box::new(io::stdin().lock());
     }
-
-    fn write_to_file(&self) {
-        let mut file = File::create("output.txt").expect("Unable to create file");
-        file.write_all(format!("{}", self).as_bytes()).expect("Unable to write data");
+    match File::open(path) {
+        Ok(file) => Box::new(file),
+        Err(err) => {
+            eprintln!("Error: cannot open {}: {}", path.display(), err);
+            std::process::exit(1);
+        }
     }
 }
 
-impl PartialEq for Matrix {
-    fn eq(&self, other: &Matrix) -> bool {
-        if self.rows != other.rows || self.cols != other.cols {
-            return false;
+// `err` with what was being done put in front, keeping its kind.
+fn io_error(what: impl fmt::Display, err: io::Error) -> MatrixError {
+    io::Error::new(err.kind(), format!("{}: {}", what, err)).into()
+}
+
+// `matrix-mul shape`: prints the shapes, then exits if they are not the
+// expected ones.
+fn print_shapes(args: &ShapeArgs) {
+    let mut input = open_input(&args.file);
+    let format = match args.input_format.or_else(|| extension_format(&args.file)) {
+        Some(format) => format,
+        None => {
+            let (sniffed, rest) = sniff_format(input).unwrap_or_else(|err| {
+                eprintln!("Error: cannot read {}: {}", args.file.display(), err);
+                std::process::exit(1);
+            });
+            input = rest;
+            sniffed
+        }
+    };
+    let shapes = match format {
+        InputFormat::Bin => Matrix::read_binary_shape(input).map(|shape| vec![shape]),
+        InputFormat::Npy => npy::read_shape(input).map(|shape| vec![shape]).map_err(MatrixError::InvalidHeader),
+        InputFormat::Text | InputFormat::Csv => {
+            text_reader::scan_shapes(io::BufReader::new(input), format == InputFormat::Csv)
+        }
+        InputFormat::Coo => {
+            let mut text = String::new();
+            input
+                .read_to_string(&mut text)
+                .map_err(MatrixError::from)
+                .and_then(|_| {
+                    let options = sparse::CooOptions::default();
+                    text_reader::split_operands(&text).iter().map(|text| Ok(sparse::Coo::parse(text, &options)?.shape())).collect()
+                })
+        }
+    };
+    let shapes = shapes.unwrap_or_else(|err| {
+        eprintln!("Error in {}: {}", args.file.display(), err);
+        std::process::exit(1);
+    });
+
+    if args.json {
+        let matrices: Vec<String> =
+            shapes.iter().map(|(rows, cols)| format!("{{\"rows\":{},\"cols\":{}}}", rows, cols)).collect();
+        println!("{{\"file\":{},\"matrices\":[{}]}}", quote_json(&args.file.to_string_lossy()), matrices.join(","));
+    } else {
+        for (rows, cols) in &shapes {
+            println!("{}x{}", rows, cols);
+        }
+    }
+
+    if args.expect_shape.is_empty() {
+        return;
+    }
+    if args.expect_shape.len() != shapes.len() {
+        eprintln!("Error: {} holds {} matrices, but --expect-shape gave {}", args.file.display(), shapes.len(), args.expect_shape.len());
+        std::process::exit(DIMENSION_EXIT_CODE);
+    }
+    for (index, (found, expected)) in shapes.iter().zip(&args.expect_shape).enumerate() {
+        if found != expected {
+            eprintln!(
+                "Error: matrix {} of {} is {}x{}, not {}x{} as --expect-shape says",
+                index + 1,
+                args.file.display(),
+                found.0,
+                found.1,
+                expected.0,
+                expected.1
+            );
+            std::process::exit(DIMENSION_EXIT_CODE);
         }
+    }
+}
 
-        for i in 0..self.data.len() {
-            if self.data[i] != other.data[i] {
-                return false;
-            }
+// `matrix-mul wizard`: the arguments the user's answers amount to.
+fn wizard_args() -> Args {
+    if !std::io::stdin().is_terminal() {
+        eprintln!("Error: matrix-mul wizard asks questions, so it needs a terminal; pass the flags directly instead (see --help)");
+        std::process::exit(1);
+    }
+    let stdin = std::io::stdin();
+    let answers = match wizard::Wizard::new(stdin.lock(), std::io::stdout()).run() {
+        Ok(answers) => answers,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
         }
+    };
+    status!("Running: {}", answers.command_line());
+    Args::parse_from(std::iter::once("matrix-mul".to_owned()).chain(answers.to_args()))
+}
+
+// For a run that got as far as writing its results.
+fn exit_code(events: &EventSink, deny_warnings: bool) -> i32 {
+    let warnings = events.warnings();
+    if warnings.iter().any(Warning::is_fatal) || (deny_warnings && !warnings.is_empty()) {
+        1
+    } else {
+        0
+    }
+}
 
-        true
+const OPERAND_NAMES: [&str; 2] = ["first", "second"];
+
+// Parses one operand from the input file with --map-input and
+// --ragged-policy applied.
+fn parse_operand(
+    name: &str,
+    text: &str,
+    format: InputFormat,
+    args: &Args,
+    events: &mut EventSink,
+) -> Result<Matrix, MatrixError> {
+    match format {
+        InputFormat::Coo => coo_to_dense(&parse_coo(text, args)?, args),
+        InputFormat::Text | InputFormat::Csv => parse_text_operand(name, &mut text.as_bytes(), format, args, events),
+        InputFormat::Bin | InputFormat::Npy => unreachable!("binary files are not text"),
     }
 }
 
-#[macro_export]
-macro_rules! matrix {
-    () => {
-        {
-            Matrix::new(0, 0, vec![])
+// parse_operand for the text and CSV formats, reading up to the next X.
+fn parse_text_operand(
+    name: &str,
+    reader: &mut impl io::BufRead,
+    format: InputFormat,
+    args: &Args,
+    events: &mut EventSink,
+) -> Result<Matrix, MatrixError> {
+    let options = ParseOptions { ragged: args.ragged_policy };
+    let mut changed = vec![0usize; args.map_input.len()];
+    let transform = |x| apply_transforms(&args.map_input, &mut changed, x);
+    let parsed = match format {
+        InputFormat::Csv => text_reader::read_csv_operand(reader, &options, transform),
+        _ => text_reader::read_operand(reader, &options, transform),
+    };
+    for (transform, &count) in args.map_input.iter().zip(&changed) {
+        if count > 0 {
+            status!("{} changed {} values in {} matrix", transform, count, name);
+        }
+    }
+
+    let (matrix, report) = parsed?;
+    if report.inexact_integers > 0 {
+        events.warn(Warning::InexactIntegers { operand: name.to_owned().into(), count: report.inexact_integers });
+    }
+    for (rows, padded) in [(report.padded_rows, true), (report.truncated_rows, false)] {
+        if rows > 0 {
+            events.warn(Warning::RaggedRows { operand: name.to_owned().into(), rows, padded });
         }
+    }
+    Ok(matrix)
+}
+
+fn parse_coo(text: &str, args: &Args) -> Result<sparse::Coo, MatrixError> {
+    let options = sparse::CooOptions {
+        base: args.coo_base,
+        duplicates: args.coo_duplicates,
+        shape: args.coo_shape,
     };
-    ($( $( $x: expr ),*);*) => {
-        {
-            let data_as_nested_array = [ $( [ $($x),* ] ),* ];
-            let rows = data_as_nested_array.len();
-            let cols = data_as_nested_array[0].len();
-            let data_as_flat_array: Vec<f64> = data_as_nested_array.into_iter()
-                .flat_map(|row| row.into_iter())
-                .collect();
-            Matrix::new(rows, cols, data_as_flat_array)
+    sparse::Coo::parse(text, &options)
+}
+
+// The shape of a COO file is whatever its indices or --coo-shape say, so
+// the limits are checked before the zeros are allocated, not after.
+fn coo_to_dense(coo: &sparse::Coo, args: &Args) -> Result<Matrix, MatrixError> {
+    let (rows, cols) = coo.shape();
+    let elements = (rows as u64).saturating_mul(cols as u64);
+    if let Some(max) = args.max_elements.filter(|&max| elements > max) {
+        return Err(MatrixError::TooManyElements { rows, cols, max });
+    }
+    let bytes = elements.saturating_mul(std::mem::size_of::<f64>() as u64);
+    if let Some(max) = args.max_memory.filter(|&max| bytes > max) {
+        return Err(MatrixError::TooManyBytes { rows, cols, max });
+    }
+    coo.to_dense()
+}
+
+// The two operands of a --dtype that parses its own input: both from the
+// one --file, as text.
+fn read_typed_operands<T: element::Parse>(args: &Args, events: &mut EventSink) -> Result<Vec<Matrix<T>>, String> {
+    let dtype = T::NAME;
+    if !matches!(args.mode, Mode::Seq | Mode::Par) {
+        return Err(format!("--dtype {} multiplies with --mode seq or par only", dtype));
+    }
+    match (args.file.len(), args.op) {
+        (1 | 2, Op::Multiply) => {}
+        (0, _) => return Err(format!("--dtype {} needs an input --file", dtype)),
+        (files, Op::Multiply) => return Err(format!("expected 2 matrices, one per --file, but got {} files", files)),
+        (_, op) => return Err(format!("--dtype {} only supports --op multiply, not {:?}", dtype, op)),
+    }
+
+    events.phase_started("load");
+    let start = Instant::now();
+    let mut texts = Vec::with_capacity(2);
+    for path in &args.file {
+        let mut text = String::new();
+        open_input(path).read_to_string(&mut text).map_err(|err| format!("cannot read {}: {}", path.display(), err))?;
+        texts.push(text);
+    }
+    // Both matrices in one file separated by X, or one per file.
+    let parts = match &texts[..] {
+        [text] => text_reader::split_operands(text),
+        _ => texts.iter().map(String::as_str).collect(),
+    };
+    if parts.len() < 2 {
+        return Err("expected 2 matrices separated by X".to_owned());
+    }
+    let mut inputs = Vec::with_capacity(2);
+    for (name, part) in OPERAND_NAMES.iter().zip(&parts) {
+        inputs.push(Matrix::<T>::parse(part).map_err(|err| format!("in {} matrix: {}", name, err))?);
+    }
+    events.phase_finished("load", start.elapsed());
+
+    let shapes: Vec<(usize, usize)> = inputs.iter().map(Matrix::shape).collect();
+    if !args.expect_shape.is_empty() {
+        if let Err(err) = check_expected_shapes(args, &shapes) {
+            eprintln!("Error: {}", err);
+            std::process::exit(DIMENSION_EXIT_CODE);
         }
     }
+    let ((n, m), k) = (shapes[0], shapes[1].1);
+    check_typed_limits(args, n, m, k, std::mem::size_of::<T>(), std::mem::size_of::<T::Sum>())?;
+    Ok(inputs)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+// --dtype f32: parsed, multiplied and written in single precision.
+fn run_f32(args: &Args, events: &mut EventSink) -> Result<(), String> {
+    let inputs = read_typed_operands::<f32>(args, events)?;
+
+    let algorithm = if args.mode == Mode::Seq { Algorithm::Seq } else { Algorithm::Par };
+    events.phase_started("multiply");
+    let start = Instant::now();
+    let product = inputs[0].multiply(&inputs[1], algorithm).map_err(|err| err.to_string())?;
+    let elapsed = start.elapsed();
+    events.phase_finished("multiply", elapsed);
+    status!("Done! Elapsed time: {:?}", elapsed);
+
+    if let Some(target) = result_target(args) {
+        if let Err(err) = target.open(args.force).and_then(|out| product.write(io::BufWriter::new(out))) {
+            write_failed(&target, err);
+        }
+    }
+    Ok(())
+}
 
-    #[test]
-    fn mul_identity() {
-        let a = matrix![
-            1.0, 0.0;
-            0.0, 1.0
-        ];
-        let b = matrix![
-            1.0, 4.0;
-            2.0, 3.0
-        ];
-        let expected = matrix![
-            1.0, 4.0;
-            2.0, 3.0
-        ]; 
+// --dtype i64: the integer inputs are multiplied exactly and never go
+// through f64.
+fn run_i64(args: &Args, events: &mut EventSink) -> Result<(), String> {
+    use rand::Rng;
+
+    let inputs = read_typed_operands::<i64>(args, events)?;
+
+    let algorithm = if args.mode == Mode::Seq { Algorithm::Seq } else { Algorithm::Par };
+    events.phase_started("multiply");
+    let start = Instant::now();
+    let product = inputs[0].multiply(&inputs[1], algorithm).map_err(|err| err.to_string())?;
+    let elapsed = start.elapsed();
+    events.phase_finished("multiply", elapsed);
+    status!("Done! Elapsed time: {:?}", elapsed);
+
+    if args.verify_exact && !product.data().is_empty() {
+        let mut rng = StdRng::seed_from_u64(seed(args, "verify-exact"));
+        let cells: Vec<(usize, usize)> = (0..16)
+            .map(|_| (rng.gen_range(0..product.rows()), rng.gen_range(0..product.cols())))
+            .collect();
+        if let Err((i, j)) = int::verify_exact(&inputs[0], &inputs[1], &product, &cells) {
+            return Err(format!("element ({}, {}) differs from the exact reference", i, j));
+        }
+        status!("Verified {} elements exactly", cells.len());
+    }
 
-        assert_eq!(a.multiply(&b), expected);
+    if let Some(target) = result_target(args) {
+        if let Err(err) = target.open(args.force).and_then(|out| product.write(io::BufWriter::new(out))) {
+            write_failed(&target, err);
+        }
     }
+    Ok(())
+}
 
-    #[test]
-    fn mul_identity_par() {
-        let a = matrix![
-            1.0, 0.0;
-            0.0, 1.0
-        ];
-        let b = matrix![
-            1.0, 4.0;
-            2.0, 3.0
-        ];
-        let expected = matrix![
-            1.0, 4.0;
-            2.0, 3.0
-        ]; 
+// --dtype interval: the inputs as degenerate intervals, and bounds on the
+// exact product written as two matrices.
+fn run_interval(args: &Args, inputs: &[Matrix], events: &mut EventSink) -> Result<(), String> {
+    if args.op != Op::Multiply {
+        return Err(format!("--dtype interval only supports --op multiply, not {:?}", args.op));
+    }
+    // Bounds are only guaranteed for finite inputs.
+    for (name, matrix) in OPERAND_NAMES.iter().zip(inputs) {
+        matrix.validate_finite().map_err(|err| format!("in {} matrix: {}", name, err))?;
+    }
+
+    let a = interval::IntervalMatrix::from_points(&inputs[0]);
+    let b = interval::IntervalMatrix::from_points(&inputs[1]);
+    let algorithm = if args.mode == Mode::Seq { Algorithm::Seq } else { Algorithm::Par };
+    events.phase_started("multiply");
+    let start = Instant::now();
+    let product = a.multiply(&b, algorithm).map_err(|err| err.to_string())?;
+    let elapsed = start.elapsed();
+    events.phase_finished("multiply", elapsed);
+    status!("Done! Elapsed time: {:?}", elapsed);
+    status!("Widest interval: {:e}", product.max_width());
+
+    if let Some(target) = result_target(args) {
+        if let Err(err) = target.open(args.force).and_then(|out| product.write(io::BufWriter::new(out))) {
+            write_failed(&target, err);
+        }
+    }
+    Ok(())
+}
 
-        let pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(4)
-            .build()
-            .unwrap();
-        let c = pool.install(|| a.multiply_par(&b));
+// Everything a run seeds. --deterministic-run gives each its own seed; it
+// never changes, so a new use only needs a new name here.
+const SEED_PURPOSES: [&str; 5] = ["first", "second", "regime", "spot-check", "verify-exact"];
 
-        assert_eq!(c, expected);
+// The seed for `purpose`: derived from --deterministic-run, or --seed.
+fn seed(args: &Args, purpose: &str) -> u64 {
+    debug_assert!(SEED_PURPOSES.contains(&purpose), "{}", purpose);
+    match args.deterministic_run {
+        Some(seed) => derive_seed(seed, purpose),
+        None => args.seed.unwrap_or(1),
     }
+}
 
-    #[test]
-    fn mul_not_squared() {
-        let a = matrix![1.0, 0.0];
-        let b = matrix![1.0;
-                                2.0];
-        let expected = matrix![1.0]; 
+// FNV-1a of `purpose` mixed into `seed`, then splitmix64's finalizer, so
+// that nearby seeds give unrelated sub-seeds.
+fn derive_seed(seed: u64, purpose: &str) -> u64 {
+    let hash = purpose.bytes().fold(0xcbf2_9ce4_8422_2325u64, |h, b| (h ^ b as u64).wrapping_mul(0x0100_0000_01b3));
+    let mut z = (seed ^ hash).wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
 
-        assert_eq!(a.multiply(&b), expected);
+// --deterministic-run: whatever would make the outputs depend on timing,
+// on the order of outside events, or on anything but the inputs and seed.
+// The kernels need nothing: each element is summed in the same order
+// whatever the thread count.
+fn check_deterministic(args: &Args) -> Result<(), String> {
+    let refused = [
+        (args.timeout.is_some(), "--timeout, which stops the multiply at a time rather than a point"),
+        (args.soak.is_some(), "--soak, which runs for a time rather than a number of iterations"),
+        (args.preview_every.is_some(), "--preview-every, whose previews show whatever rows are done"),
+        (args.trace_file.is_some(), "--trace-file, which records timings"),
+        (args.watch.is_some(), "--watch, which processes files in the order they arrive"),
+        (args.serve.is_some(), "--serve, whose results depend on its clients"),
+        (args.op == Op::Bench, "--op bench, which reports timings"),
+        (args.mode == Mode::All && args.write == WriteChoice::Fastest, "--write fastest, which picks a result by timing"),
+    ];
+    match refused.iter().find(|(given, _)| *given) {
+        Some((_, what)) => Err(format!("--deterministic-run cannot guarantee a reproducible run with {}", what)),
+        None => Ok(()),
     }
+}
 
-    #[test]
-    fn mul_not_squared_par() {
-        let a = matrix![1.0, 0.0];
-        let b = matrix![1.0;
-                                2.0];
-        let expected = matrix![1.0]; 
+// The sub-seeds of --deterministic-run, in <output>.seeds.txt, or on stdout
+// when the result goes there.
+fn record_seeds(args: &Args) {
+    let root = args.deterministic_run.unwrap();
+    let mut lines = format!("deterministic-run {}\n", root);
+    for purpose in SEED_PURPOSES {
+        lines.push_str(&format!("{} {}\n", purpose, seed(args, purpose)));
+    }
+    let target = match result_target(args) {
+        Some(Target::File(_)) => Target::File(algo_output_path(output_base(args), "seeds")),
+        // Not mixed into a result on stdout.
+        _ if STATUS_ON_STDERR.load(Ordering::Relaxed) => {
+            eprint!("{}", lines);
+            return;
+        }
+        _ => Target::Stdout,
+    };
+    if let Err(err) = target.open(args.force).and_then(|mut out| out.write_all(lines.as_bytes())) {
+        write_failed(&target, err);
+    }
+}
 
-        let pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(4)
-            .build()
-            .unwrap();
-        let c = pool.install(|| a.multiply_par(&b));
+fn replace_nonfinite_inputs(inputs: &mut [Matrix], value: f64, events: &mut EventSink) {
+    for (operand, matrix) in OPERAND_NAMES.iter().zip(inputs) {
+        let count = matrix.replace_nonfinite(value);
+        if count > 0 {
+            events.warn(Warning::NonFiniteReplaced { operand: Cow::Borrowed(operand), count, value });
+        }
+    }
+}
 
-        assert_eq!(c, expected);
+fn symmetrize_inputs(inputs: &mut [Matrix], tolerance: f64) -> Result<(), String> {
+    for (name, matrix) in OPERAND_NAMES.iter().zip(inputs) {
+        let symmetrized = matrix.symmetrize().map_err(|err| format!("in {} matrix: {}", name, err))?;
+        let error = matrix.symmetry_error();
+        if error > tolerance {
+            return Err(format!(
+                "{} matrix has a symmetry error of {:e}, above the --symmetrize tolerance {:e}",
+                name, error, tolerance
+            ));
+        }
+        if error > 0.0 {
+            status!("Symmetrized {} matrix (symmetry error {:e})", name, error);
+            *matrix = symmetrized;
+        }
     }
+    Ok(())
+}
 
-    #[test]
-    fn mul_squared() {
-        let a = matrix![1.0, 2.0;
-                                3.0, 4.0];
-        let b = matrix![1.0, 2.0;
-                                3.0, 4.0];
-        let expected = matrix![7.0, 10.0;
-                                       15.0, 22.0]; 
+// Applies `transforms` in order, counting how many values each one changed.
+fn apply_transforms(transforms: &[Transform], changed: &mut [usize], x: f64) -> f64 {
+    let mut x = x;
+    for (transform, count) in transforms.iter().zip(changed.iter_mut()) {
+        let y = transform.apply(x);
+        if y.to_bits() != x.to_bits() {
+            *count += 1;
+        }
+        x = y;
+    }
+    x
+}
+
+/// What to compute from the loaded matrices.
+#[derive(Clone, Copy, PartialEq, Eq, ArgEnum, Debug)]
+enum Op {
+    /// Multiply the two input matrices.
+    Multiply,
+    /// Reachability matrix of a square adjacency matrix.
+    Closure,
+    /// Number of walks of --length steps between each pair of vertices.
+    Paths,
+    /// Stationary distribution of a row-stochastic transition matrix.
+    Stationary,
+    /// Solve A X = B for X, with A and B the two input matrices.
+    Solve,
+    /// Compare every multiply kernel with an accurate reference product.
+    AccuracyReport,
+    /// Time each algorithm over --runs runs after --warmup untimed ones,
+    /// optionally against a saved baseline.
+    Bench,
+    /// The input matrix times its transpose, A Aᵀ.
+    Aat,
+    /// The transpose of the input matrix times the matrix, Aᵀ A.
+    Ata,
+    /// Write the input matrix in the format of --output's extension, for
+    /// example text or CSV to .bin once, to load quickly from then on.
+    Convert,
+    /// Multiply any number of matrices, every one in the --file inputs, in
+    /// the order that takes the fewest flops. Always in parallel; --verbose
+    /// prints the order.
+    Chain,
+}
 
-        assert_eq!(a.multiply(&b), expected);
+impl Op {
+    fn operands(self) -> usize {
+        match self {
+            // At least; a chain takes as many as its files hold.
+            Op::Multiply | Op::Solve | Op::AccuracyReport | Op::Bench | Op::Chain => 2,
+            Op::Closure | Op::Paths | Op::Stationary | Op::Aat | Op::Ata | Op::Convert => 1,
+        }
     }
 
-    #[test]
-    fn mul_squared_par() {
-        let a = matrix![1.0, 2.0;
-                                3.0, 4.0];
-        let b = matrix![1.0, 2.0;
-                                3.0, 4.0];
-        let expected = matrix![7.0, 10.0;
-                                       15.0, 22.0];
+    /// Whether the single operand may be rectangular.
+    fn rectangular(self) -> bool {
+        matches!(self, Op::Aat | Op::Ata)
+    }
+
+    /// The shape of the result for inputs of these shapes, or why they
+    /// have none.
+    fn result_shape(self, inputs: &[(usize, usize)], broadcast_scalars: bool) -> Result<(usize, usize), String> {
+        let square = |(rows, cols): (usize, usize)| {
+            if rows == cols {
+                Ok((rows, rows))
+            } else {
+                Err(format!("--op {:?} needs a square matrix, not {}x{}", self, rows, cols))
+            }
+        };
+        match (self, inputs) {
+            (Op::Multiply | Op::AccuracyReport | Op::Bench, &[a, b]) => match (a, b) {
+                (a, b) if a.1 == b.0 => Ok((a.0, b.1)),
+                ((1, 1), other) | (other, (1, 1)) if broadcast_scalars && self == Op::Multiply => Ok(other),
+                (a, b) => Err(format!("a {}x{} matrix cannot multiply a {}x{} one", a.0, a.1, b.0, b.1)),
+            },
+            (Op::Solve, &[a, b]) => match square(a)? {
+                (n, _) if n == b.0 => Ok((n, b.1)),
+                (n, _) => Err(format!("a {}x{} system has no solution for {} right-hand rows", n, n, b.0)),
+            },
+            (Op::Closure | Op::Paths, &[a]) => square(a),
+            (Op::Stationary, &[a]) => square(a).map(|(n, _)| (1, n)),
+            (Op::Aat, &[(n, _)]) => Ok((n, n)),
+            (Op::Ata, &[(_, m)]) => Ok((m, m)),
+            (Op::Convert, &[a]) => Ok(a),
+            (Op::Chain, shapes) => match ChainPlan::new(shapes) {
+                Ok(_) => Ok((shapes[0].0, shapes[shapes.len() - 1].1)),
+                Err(err) => Err(err.to_string()),
+            },
+            _ => unreachable!("{:?} takes {} operands", self, self.operands()),
+        }
+    }
 
-        let pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(4)
-            .build()
-            .unwrap();
-        let c = pool.install(|| a.multiply_par(&b));
+    /// (n, m, k) for --max-elements and --max-memory when the operands
+    /// are `a` and a matrix with `k` columns: the product is n x k.
+    fn limit_dims(self, (n, m): (usize, usize), k: usize) -> (usize, usize, usize) {
+        match self {
+            Op::Aat => (n, m, n),
+            Op::Ata => (m, n, m),
+            _ => (n, m, k),
+        }
+    }
+}
 
-        assert_eq!(c, expected);
+fn parse_size(s: &str) -> Result<(usize, usize, usize), String> {
+    let parts: Vec<&str> = s.split(['x', 'X']).collect();
+    if parts.len() != 1 && parts.len() != 3 {
+        return Err(format!(
+            "expected N or NxMxK, found {} components in '{}'",
+            parts.len(),
+            s
+        ));
     }
 
-    #[test]
-    fn seq_and_par() {
-        let pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(4)
-            .build()
-            .unwrap();
-
-        let a = Matrix::random(10, 5);
-        let b = Matrix::random(5, 7);
-        let c = a.multiply(&b);
-        let d = pool.install(|| a.multiply_par(&b));
-        assert_eq!(c, d);
-
-        let a = Matrix::random(50, 13);
-        let b = Matrix::random(13, 70);
-        let c = a.multiply(&b);
-        let d = pool.install(|| a.multiply_par(&b));
-        assert_eq!(c, d);
-
-        let a = Matrix::random(150, 400);
-        let b = Matrix::random(400, 3);
-        let c = a.multiply(&b);
-        let d = pool.install(|| a.multiply_par(&b));
-        assert_eq!(c, d);
-
-        let a = Matrix::random(500, 700);
-        let b = Matrix::random(700, 345);
-        let c = a.multiply(&b);
-        let d = pool.install(|| a.multiply_par(&b));
-        assert_eq!(c, d);
-
-        let a = Matrix::random(1000, 1000);
-        let b = Matrix::random(1000, 1000);
-        let c = a.multiply(&b);
-        let d = pool.install(|| a.multiply_par(&b));
-        assert_eq!(c, d);
-    }
-}
\ No newline at end of file
+    let mut dims = Vec::with_capacity(3);
+    for (i, part) in parts.iter().enumerate() {
+        let dim = part.trim().parse::<usize>().map_err(|_| {
+            format!(
+                "component {} of '{}' is not a valid dimension: '{}'",
+                i + 1,
+                s,
+                part
+            )
+        })?;
+        dims.push(dim);
+    }
+
+    if dims.len() == 1 {
+        Ok((dims[0], dims[0], dims[0]))
+    } else {
+        Ok((dims[0], dims[1], dims[2]))
+    }
+}
+
+fn parse_finite(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(x) if x.is_finite() => Ok(x),
+        Ok(_) => Err(format!("expected a finite number, found '{}'", s)),
+        Err(_) => Err(format!("'{}' is not a number", s)),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Dims {
+    Given(usize, usize, usize),
+    InferredK(usize, usize, usize),
+}
+
+fn resolve_dims(args: &Args) -> Result<Dims, String> {
+    let (size_n, size_m, size_k) = match args.size {
+        Some((n, m, k)) => (Some(n), Some(m), Some(k)),
+        None => (None, None, None),
+    };
+
+    let n = args.n.or(size_n).ok_or("No n found (use -n or --size)")?;
+    let m = args.m.or(size_m).ok_or("No m found (use -m or --size)")?;
+    match args.k.or(size_k) {
+        Some(k) => Ok(Dims::Given(n, m, k)),
+        None => Ok(Dims::InferredK(n, m, n)),
+    }
+}
+
+// --min and --max for random inputs, which --dist integer needs an integer
+// between.
+fn check_range(args: &Args) -> Result<(), String> {
+    if args.min.partial_cmp(&args.max) != Some(std::cmp::Ordering::Less) {
+        return Err(format!("--min {} must be below --max {}", args.min, args.max));
+    }
+    if !(args.max - args.min).is_finite() {
+        return Err(format!("--min {} to --max {} is wider than an f64 can hold", args.min, args.max));
+    }
+    if args.dist == Dist::Integer && args.min.ceil() > args.max.floor() {
+        return Err(format!("--dist integer needs an integer from --min {} to --max {}", args.min, args.max));
+    }
+    Ok(())
+}
+
+// --expect-shape against inputs of `shapes`: the result alone, or each
+// input and then the result.
+fn check_expected_shapes(args: &Args, shapes: &[(usize, usize)]) -> Result<(), String> {
+    let result = args.op.result_shape(shapes, args.broadcast_scalars)?;
+    let mut actual: Vec<(&str, (usize, usize))> = vec![("result", result)];
+    if args.expect_shape.len() > 1 {
+        actual.splice(0..0, ["first matrix", "second matrix"].into_iter().zip(shapes.iter().copied()));
+    }
+    for ((name, (rows, cols)), &(expected_rows, expected_cols)) in actual.into_iter().zip(&args.expect_shape) {
+        if (rows, cols) != (expected_rows, expected_cols) {
+            return Err(format!("the {} is {}x{}, not {}x{} as --expect-shape says", name, rows, cols, expected_rows, expected_cols));
+        }
+    }
+    Ok(())
+}
+
+// Checks an n x m by m x k multiply against --max-memory and --max-elements.
+fn check_limits(args: &Args, n: usize, m: usize, k: usize) -> Result<(), String> {
+    check_typed_limits(args, n, m, k, std::mem::size_of::<f64>(), std::mem::size_of::<f64>())
+}
+
+// check_limits for operands of `input_bytes` an element and a product of
+// `output_bytes`.
+fn check_typed_limits(
+    args: &Args,
+    n: usize,
+    m: usize,
+    k: usize,
+    input_bytes: usize,
+    output_bytes: usize,
+) -> Result<(), String> {
+    let sizes = [(n, m), (m, k), (n, k)].map(|(r, c)| (r as u64).saturating_mul(c as u64));
+
+    if let Some(max) = args.max_elements {
+        if let Some(&largest) = sizes.iter().max().filter(|&&largest| largest > max) {
+            return Err(format!(
+                "a {}x{} by {}x{} multiply needs a matrix of {} elements, over the limit of {}",
+                n, m, m, k, largest, max
+            ));
+        }
+    }
+
+    if let Some(max) = args.max_memory {
+        let bytes = sizes
+            .iter()
+            .zip([input_bytes, input_bytes, output_bytes])
+            .fold(0u64, |acc, (&s, bytes)| acc.saturating_add(s.saturating_mul(bytes as u64)));
+        if bytes > max {
+            return Err(format!(
+                "a {}x{} by {}x{} multiply needs {}, over the limit of {}",
+                n,
+                m,
+                m,
+                k,
+                units::format_bytes(bytes),
+                units::format_bytes(max)
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// Operations on a single matrix; the result is reported like an algorithm
+// named after the operation.
+fn run_single(args: &Args, matrix: &Matrix, events: &mut EventSink) -> Result<Vec<AlgoResult>, MatrixError> {
+    let (algo, phase) = match args.op {
+        Op::Closure => ("closure", "closure"),
+        Op::Paths => ("paths", "paths"),
+        Op::Stationary => ("stationary", "stationary"),
+        Op::Aat => ("aat", "multiply-aat"),
+        Op::Ata => ("ata", "multiply-ata"),
+        Op::Multiply | Op::Solve | Op::AccuracyReport | Op::Bench => unreachable!("takes two operands"),
+        Op::Chain => unreachable!("takes a chain"),
+        Op::Convert => unreachable!("computes nothing"),
+    };
+
+    events.phase_started(phase);
+    let start = Instant::now();
+    let result = match args.op {
+        Op::Closure => matrix.transitive_closure()?,
+        Op::Stationary => {
+            let pi = matrix.stationary_distribution(1e-12, 100_000)?;
+            Matrix::new_unchecked(1, pi.len(), pi)
+        }
+        Op::Aat | Op::Ata => {
+            let algorithm = if args.mode == Mode::Seq { Algorithm::Seq } else { Algorithm::Par };
+            if args.op == Op::Aat {
+                matrix.multiply_by_own_transpose(algorithm)
+            } else {
+                matrix.multiply_transpose_by_self(algorithm)
+            }
+        }
+        _ => matrix.count_paths(args.length.unwrap_or(1))?,
+    };
+    let elapsed = start.elapsed();
+    events.phase_finished(phase, elapsed);
+    status!("Done! Elapsed time: {:?}", elapsed);
+    if args.op == Op::Stationary {
+        for (state, p) in result.data().iter().enumerate() {
+            status!("state {}: {}", state, Number(*p, Precision::Significant(format::PREVIEW_DIGITS)));
+        }
+    }
+    if args.mix_time {
+        match matrix.mix_time_estimate(0.01, 1000)? {
+            Some(steps) => status!("Mixing time estimate (TV distance 0.01): {} steps", steps),
+            None => status!("Mixing time estimate (TV distance 0.01): over 1000 steps"),
+        }
+    }
+
+    Ok(vec![AlgoResult { algo, matrix: result, elapsed }])
+}
+
+// Runs --watch until SIGTERM or Ctrl-C.
+async fn watch_dir(args: &Args, dir: &Path) -> Result<(), String> {
+    let algorithm = if args.mode == Mode::Seq { Algorithm::Seq } else { Algorithm::Par };
+    let job = watch::Job {
+        parse: ParseOptions { ragged: args.ragged_policy },
+        multiply: multiply_options(args).map_err(|err| err.to_string())?.algorithm(algorithm),
+        precision: args.digits.map_or(Precision::Full, Precision::Significant),
+    };
+    let out_dir = args.watch_output.clone().unwrap_or_else(|| dir.join("out"));
+    let watcher = watch::Watcher::new(dir.to_path_buf(), out_dir).map_err(|err| err.to_string())?;
+
+    let stop = CancelToken::new();
+    let on_signal = stop.clone();
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut term = signal(SignalKind::terminate()).expect("Unable to listen for SIGTERM");
+            tokio::select! {
+                _ = term.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = tokio::signal::ctrl_c().await;
+        eprintln!("Stopping after the current file");
+        on_signal.cancel();
+    });
+
+    watch::watch(watcher, job, Duration::from_millis(500), stop)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+// The multiply options given on the command line, apart from the algorithm.
+fn multiply_options(args: &Args) -> Result<MultiplyOptions, MatrixError> {
+    let mut options = MultiplyOptions::new()
+        .broadcast_scalars(args.broadcast_scalars)
+        .shortcuts(!args.no_shortcuts)
+        .auto_narrow(args.auto_narrow);
+    if let Some(flops) = args.inline_below {
+        options = options.inline_below(flops);
+    }
+    if let Some(orientation) = args.right_layout {
+        options = options.orientation(orientation);
+    }
+    // --max-memory also bounds what each multiply allocates: the result and
+    // any packed copy of the second operand.
+    if let Some(bytes) = args.max_memory {
+        options = options.context(Context::new().allocation_budget(bytes));
+    }
+    if let Some(path) = &args.add_after {
+        options = options.epilogue(Epilogue::AddMatrix(load_epilogue_operand(path, args)?));
+    }
+    if let Some(path) = &args.mask_after {
+        options = options.epilogue(Epilogue::Mask(load_epilogue_operand(path, args)?));
+    }
+    Ok(options)
+}
+
+// The pool the parallel kernels run on: --threads of them, or one per core.
+fn thread_pool(args: &Args) -> Arc<rayon::ThreadPool> {
+    let mut builder = rayon::ThreadPoolBuilder::new();
+    if let Some(threads) = args.threads {
+        builder = builder.num_threads(threads);
+    }
+    Arc::new(builder.build().unwrap_or_else(|err| {
+        eprintln!("Error: cannot start {} threads: {}", args.threads.unwrap_or(0), err);
+        std::process::exit(1);
+    }))
+}
+
+// "on 4 threads" after an elapsed time.
+fn threads(pool: &rayon::ThreadPool) -> String {
+    match pool.current_num_threads() {
+        1 => "1 thread".to_owned(),
+        n => format!("{} threads", n),
+    }
+}
+
+// `options.get_context()`, printing the dispatcher's decisions under --verbose.
+fn verbose_context(args: &Args, options: &MultiplyOptions, label: &'static str) -> Context {
+    let context = options.get_context().clone();
+    if args.verbose {
+        context.log(move |event| status!("{}: {}", label, event))
+    } else {
+        context
+    }
+}
+
+// --preview-every: `context` with a row observer, and the thread writing
+// what it sees, which writes a last preview when dropped.
+fn preview_context(args: &Args, context: Context, a: &Matrix, b: &Matrix) -> (Context, Option<preview::PreviewWriter>) {
+    let Some(interval) = args.preview_every else {
+        return (context, None);
+    };
+    let preview = Arc::new(preview::Preview::new(a.rows(), b.cols()));
+    let writer = preview.write_every(algo_output_path(output_base(args), "preview"), interval);
+    (context.rows(move |i, row| preview.observe(i, row)), Some(writer))
+}
+
+fn load_epilogue_operand(path: &Path, args: &Args) -> Result<Arc<Matrix>, MatrixError> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|err| io_error(format!("cannot read {}", path.display()), err))?;
+    let options = ParseOptions { ragged: args.ragged_policy };
+    let (matrix, _) = Matrix::from_string_with(text.trim(), &options)?;
+    Ok(Arc::new(matrix))
+}
+
+// Repeats the multiply for --soak. Ctrl-C or --timeout ends the run early
+// with a report instead of an error.
+fn run_soak(
+    args: &Args,
+    matrix1: &Matrix,
+    matrix2: &Matrix,
+    cancel: &CancelToken,
+    events: &mut EventSink,
+) -> Result<Vec<AlgoResult>, MatrixError> {
+    let algorithm = if args.mode == Mode::Seq { Algorithm::Seq } else { Algorithm::Par };
+    let options = multiply_options(args)?.algorithm(algorithm);
+    let budget = args.soak.unwrap_or_default();
+    let pool = thread_pool(args);
+
+    events.phase_started("soak");
+    let start = Instant::now();
+    let (matrix, report) = pool.install(|| matrix1.soak(matrix2, &options, budget, cancel))?;
+    let elapsed = start.elapsed();
+    events.phase_finished("soak", elapsed);
+
+    // Only the first product is kept, and only it is checked.
+    if algorithm == Algorithm::Par && matrix1.cols() == matrix2.rows() {
+        status!("Max difference from SEQ: {:e}", matrix.max_abs_diff(&matrix1.multiply(matrix2)?));
+    }
+    status!("Iterations: {} in {:?}", report.iterations, report.total);
+    status!("Fastest: {:?}, slowest: {:?}", report.fastest, report.slowest);
+    status!(
+        "GFLOPS: {:.3} sustained, {:.3} peak",
+        report.sustained_gflops, report.peak_gflops
+    );
+    status!(
+        "First quarter mean {:?}, last quarter mean {:?} ({:+.1}%)",
+        report.first_quartile,
+        report.last_quartile,
+        report.slowdown_percent()
+    );
+    events.soak(&report);
+
+    Ok(vec![AlgoResult { algo: "soak", matrix, elapsed }])
+}
+
+// Multiplies through the tiles in --tile-dir, reported as "tiled".
+fn run_tiled(args: &Args, matrix1: &Matrix, matrix2: &Matrix, events: &mut EventSink) -> Result<Vec<AlgoResult>, MatrixError> {
+    let dir = args.tile_dir.clone().unwrap_or_default();
+    let mut store = tiles::TileDir::create(dir, args.fsync_tiles)
+        .map_err(|err| io_error("cannot create tile directory", err))?;
+    let mut options = tiles::TileOptions {
+        resume: args.resume_tiles,
+        ..tiles::TileOptions::default()
+    };
+    if let Some(tile) = args.tile_size {
+        options.tile = tile;
+    }
+    // The tiles hold the plain product, so that a resumed run can reuse
+    // them whatever the epilogues; these run on the assembled result.
+    let multiply = multiply_options(args)?;
+    if matrix1.cols() == matrix2.rows() {
+        multiply.check_epilogues((matrix1.rows(), matrix2.cols()))?;
+    }
+
+    events.phase_started("multiply-tiled");
+    let start = Instant::now();
+    let (mut matrix, report) = matrix1.multiply_tiled(matrix2, &mut store, &options)?;
+    multiply.apply_epilogues(&mut matrix);
+    let elapsed = start.elapsed();
+    events.phase_finished("multiply-tiled", elapsed);
+    status!("Done! Elapsed time: {:?}", elapsed);
+    status!(
+        "Tiles: {} computed, {} reused, {} corrupt and recomputed, {} writes retried",
+        report.computed, report.reused, report.corrupt, report.retries
+    );
+
+    Ok(vec![AlgoResult { algo: "tiled", matrix, elapsed }])
+}
+
+// --coo-target csr: the sparse first operand times the dense second one,
+// reported like an algorithm named "csr".
+fn run_csr(args: &Args, a: &sparse::Csr, b: &Matrix, events: &mut EventSink) -> Result<Vec<AlgoResult>, MatrixError> {
+    let total = a.rows * a.cols;
+    status!(
+        "First matrix: {} nonzeros of {} ({:.2}%)",
+        a.nonzeros(),
+        total,
+        100.0 * a.nonzeros() as f64 / total.max(1) as f64
+    );
+    let options = multiply_options(args)?;
+    if a.cols == b.rows() {
+        options.check_epilogues((a.rows, b.cols()))?;
+    }
+    events.phase_started("multiply");
+    let start = Instant::now();
+    let mut matrix = a.multiply_dense(b)?;
+    options.apply_epilogues(&mut matrix);
+    let elapsed = start.elapsed();
+    events.phase_finished("multiply", elapsed);
+    status!("CSR: Done! Elapsed time: {:?}", elapsed);
+    Ok(vec![AlgoResult { algo: "csr", matrix, elapsed }])
+}
+
+// The product of a chain, in the cheapest order, reported like an
+// algorithm named "chain".
+fn run_chain(args: &Args, inputs: &[Matrix], events: &mut EventSink) -> Result<Vec<AlgoResult>, MatrixError> {
+    let shapes: Vec<(usize, usize)> = inputs.iter().map(Matrix::shape).collect();
+    let plan = ChainPlan::new(&shapes)?;
+    if args.verbose {
+        status!("Chain order: {}", plan);
+        status!("Flops: {} in that order, {} left to right", plan.flops, plan.left_to_right_flops);
+    }
+    // Every intermediate, not just the first and last shapes, before any
+    // of them is formed.
+    for (n, m, k) in plan.products() {
+        check_limits(args, n, m, k).map_err(MatrixError::Message)?;
+    }
+
+    events.phase_started("multiply-chain");
+    let start = Instant::now();
+    let matrix = Matrix::multiply_chain(inputs)?;
+    let elapsed = start.elapsed();
+    events.phase_finished("multiply-chain", elapsed);
+    status!("Done! Elapsed time: {:?}", elapsed);
+
+    Ok(vec![AlgoResult { algo: "chain", matrix, elapsed }])
+}
+
+// Solves A X = B, reporting X like an algorithm named "solve".
+fn run_solve(args: &Args, a: &Matrix, b: &Matrix, events: &mut EventSink) -> Result<Vec<AlgoResult>, MatrixError> {
+    let algorithm = if args.mode == Mode::Seq { Algorithm::Seq } else { Algorithm::Par };
+
+    events.phase_started("solve");
+    let start = Instant::now();
+    let x = a.solve(b, algorithm)?;
+    let elapsed = start.elapsed();
+    events.phase_finished("solve", elapsed);
+    status!("Done! Elapsed time: {:?}", elapsed);
+    status!("Backward error: {:e}", a.backward_error(&x, b)?);
+
+    Ok(vec![AlgoResult { algo: "solve", matrix: x, elapsed }])
+}
+
+fn run_accuracy_report(
+    args: &Args,
+    a: &Matrix,
+    b: &Matrix,
+    events: &mut EventSink,
+) -> Result<Vec<AlgoResult>, MatrixError> {
+    if a.cols() != b.rows() {
+        return Err(MatrixError::DimensionMismatch {
+            left: a.shape(),
+            right: b.shape(),
+        });
+    }
+
+    events.phase_started("accuracy-report");
+    let start = Instant::now();
+    let (report, reference) = accuracy::AccuracyReport::run("Inputs".to_owned(), a, b);
+    print!("{}", report);
+    for regime in &args.regime {
+        let (x, y) = regime.generate(a.rows(), a.cols(), b.cols(), seed(args, "regime"));
+        let title = format!("Regime {} (seed {})", regime, seed(args, "regime"));
+        print!("\n{}", accuracy::AccuracyReport::run(title, &x, &y).0);
+    }
+    let elapsed = start.elapsed();
+    events.phase_finished("accuracy-report", elapsed);
+
+    Ok(vec![AlgoResult { algo: "reference", matrix: reference, elapsed }])
+}
+
+/// A part of a run that --op bench can time.
+#[derive(Clone, Copy, PartialEq, Eq, ArgEnum, Debug)]
+enum Phase {
+    /// Reading each file format.
+    Parse,
+    /// Writing each file format.
+    Format,
+    /// The multiply, with each algorithm of --mode.
+    Multiply,
+}
+
+fn run_bench(args: &Args, a: &Matrix, b: &Matrix, events: &mut EventSink) -> Result<Vec<AlgoResult>, MatrixError> {
+    let options = multiply_options(args)?;
+    let pool = thread_pool(args);
+    let shape = format!("{}x{}x{}", a.rows(), a.cols(), b.cols());
+    let mut algorithms = Vec::new();
+    if args.mode == Mode::Seq || args.mode == Mode::All {
+        algorithms.push(("seq", Algorithm::Seq, 1));
+    }
+    if args.mode == Mode::Par || args.mode == Mode::All {
+        algorithms.push(("par", Algorithm::Par, pool.current_num_threads()));
+    }
+    if args.mode == Mode::Blocked || args.mode == Mode::All {
+        algorithms.push(("blocked", Algorithm::Par, pool.current_num_threads()));
+    }
+    if args.mode == Mode::Strassen || args.mode == Mode::All {
+        algorithms.push(("strassen", Algorithm::Par, pool.current_num_threads()));
+    }
+    if args.cancellation_check {
+        algorithms.push(("cancellation-check", Algorithm::Par, pool.current_num_threads()));
+    }
+    if !args.phases.contains(&Phase::Multiply) {
+        algorithms.clear();
+    }
+
+    events.phase_started("bench");
+    let start = Instant::now();
+    let mut cases = Vec::new();
+    let mut rows = Vec::new();
+    let mut results = Vec::new();
+    for (algo, algorithm, threads) in algorithms {
+        let options = options.clone().algorithm(algorithm).context(options.get_context().clone().pool(Arc::clone(&pool)));
+        let multiply = || {
+            let mut m = match algo {
+                "blocked" => pool.install(|| a.multiply_blocked_par(b, args.block_size))?,
+                "strassen" => pool.install(|| a.multiply_strassen_par(b, args.strassen_cutoff))?,
+                "cancellation-check" => {
+                    pool.install(|| a.multiply_checking_cancellation(b, args.cancellation_threshold))?.0
+                }
+                _ => return a.multiply_with(b, &options),
+            };
+            options.apply_epilogues(&mut m);
+            Ok(m)
+        };
+        // A run that fails fails at once, and so do all the others, so only
+        // the last result is checked.
+        let (stats, matrix) = bench::bench(&mut bench::WallClock, args.runs, args.warmup, multiply);
+        cases.push(bench::BenchCase::new(shape.clone(), algo, threads, &stats));
+        rows.push((algo, stats));
+        results.push(AlgoResult { algo, matrix: matrix?, elapsed: start.elapsed() });
+    }
+    let (parse, format) = (args.phases.contains(&Phase::Parse), args.phases.contains(&Phase::Format));
+    let mut io_cases = Vec::new();
+    if parse || format {
+        let m = Matrix::random(args.phase_size, args.phase_size);
+        let threads = pool.current_num_threads();
+        io_cases = pool.install(|| {
+            bench::bench_io(&mut bench::WallClock, args.runs, args.warmup, &m, &bench::IO_FORMATS, parse, format)
+        })?;
+        let shape = format!("{}x{}", args.phase_size, args.phase_size);
+        cases.extend(io_cases.iter().map(|case| bench::BenchCase::new(shape.clone(), &case.name, threads, &case.stats)));
+    }
+    events.phase_finished("bench", start.elapsed());
+    let flops = 2.0 * a.rows() as f64 * a.cols() as f64 * b.cols() as f64;
+    if !rows.is_empty() {
+        status!(@print "{}", bench::table(&rows, flops));
+    }
+    if !io_cases.is_empty() {
+        status!(@print "{}", bench::io_table(&io_cases));
+    }
+
+    let current = bench::Baseline::new(cases);
+    if let Some(name) = &args.compare_baseline {
+        let path = bench::Baseline::path(name);
+        let baseline = bench::Baseline::load(&path)
+            .map_err(|err| io_error(format!("cannot read baseline {}", path.display()), err))?;
+        let threshold = bench::Threshold {
+            percent: args.regression_percent,
+            mads: args.regression_mads,
+        };
+        let comparison = bench::Comparison::new(&baseline, &current, threshold);
+        status!(@print "{}", comparison);
+        for difference in comparison.mismatches.clone() {
+            events.warn(Warning::BaselineMachine { difference });
+        }
+        let regressions = comparison.regressions();
+        if regressions > 0 {
+            events.warn(Warning::BenchRegression { baseline: name.clone(), regressions });
+        }
+    }
+    if let Some(name) = &args.save_baseline {
+        let path = bench::Baseline::path(name);
+        current
+            .save(&path)
+            .map_err(|err| io_error(format!("cannot write baseline {}", path.display()), err))?;
+        status!("Saved baseline {} to {}", name, path.display());
+    }
+    Ok(results)
+}
+
+struct AlgoResult {
+    algo: &'static str,
+    matrix: Matrix,
+    elapsed: Duration,
+}
+
+fn run(
+    args: &Args,
+    matrix1: &Matrix,
+    matrix2: &Matrix,
+    cancel: &CancelToken,
+    events: &mut EventSink,
+) -> Result<Vec<AlgoResult>, MatrixError> {
+    #[cfg(feature = "plugins")]
+    if let Some(path) = &args.plugin {
+        return run_plugin(path, matrix1, matrix2, events);
+    }
+
+    let total_rows = matrix1.rows();
+    let mut results = Vec::new();
+    let mut options = multiply_options(args)?.cancel_token(cancel.clone());
+    if let Some(trace) = events.trace() {
+        let context = options.get_context().clone().trace(trace);
+        options = options.context(context);
+    }
+    if matrix1.cols() == matrix2.rows() {
+        options.check_epilogues((matrix1.rows(), matrix2.cols()))?;
+    }
+
+    if args.mode == Mode::Seq || args.mode == Mode::All {
+        events.phase_started("multiply-seq");
+        let start = Instant::now();
+        let (context, preview) = preview_context(args, verbose_context(args, &options, "SEQ"), matrix1, matrix2);
+        let options = options.clone().context(context);
+        let (matrix, report) = matrix1.multiply_with_report(matrix2, &options)?;
+        drop(preview);
+        let elapsed = start.elapsed();
+        if report.shortcut {
+            status!("Zero or identity operand, SEQ skipped the multiply (see --no-shortcuts)");
+        }
+        if report.narrowed {
+            status!("Operands fit in f32, SEQ multiplied f32 copies (--auto-narrow)");
+        }
+        events.progress(total_rows, total_rows);
+        events.phase_finished("multiply-seq", elapsed);
+        if args.mode == Mode::Seq {
+            status!("Done! Elapsed time: {:?}", elapsed);
+        } else {
+            status!("Done! Elapsed time for SEQ: {:?}", elapsed);
+        }
+        results.push(AlgoResult { algo: "seq", matrix, elapsed });
+    }
+
+    let pool = thread_pool(args);
+    if args.mode == Mode::Par || args.mode == Mode::All {
+        events.phase_started("multiply-par");
+        let start = Instant::now();
+        let context = verbose_context(args, &options, "PAR").pool(Arc::clone(&pool));
+        let (context, preview) = preview_context(args, context, matrix1, matrix2);
+        let options = options.clone().algorithm(Algorithm::Par).context(context);
+        let (matrix, profile, report) = if args.profile_threads && matrix1.cols() == matrix2.rows() {
+            let (mut m, p) = pool.install(|| matrix1.multiply_par_profiled(matrix2))?;
+            options.apply_epilogues(&mut m);
+            (m, Some(p), MultiplyReport::default())
+        } else {
+            let (m, report) = matrix1.multiply_with_report(matrix2, &options)?;
+            (m, None, report)
+        };
+        drop(preview);
+        let elapsed = start.elapsed();
+        if report.inlined {
+            let flops = 2 * (matrix1.rows() * matrix1.cols() * matrix2.cols()) as u64;
+            events.warn(Warning::RanInline { flops });
+        }
+        if report.shortcut {
+            status!("Zero or identity operand, PAR skipped the multiply (see --no-shortcuts)");
+        }
+        if report.narrowed {
+            status!("Operands fit in f32, PAR multiplied f32 copies (--auto-narrow)");
+        }
+        events.progress(total_rows, total_rows);
+        events.phase_finished("multiply-par", elapsed);
+        if args.mode == Mode::Par {
+            status!("Done! Elapsed time: {:?} on {}", elapsed, threads(&pool));
+        } else {
+            status!("Done! Elapsed time for PAR: {:?} on {}", elapsed, threads(&pool));
+        }
+        if let Some(profile) = profile {
+            status!("{}", profile.to_string().trim_end());
+        }
+        results.push(AlgoResult { algo: "par", matrix, elapsed });
+    }
+
+    if args.mode == Mode::Blocked || args.mode == Mode::All {
+        events.phase_started("multiply-blocked");
+        let start = Instant::now();
+        let matrix = if matrix1.cols() == matrix2.rows() {
+            let mut m = pool.install(|| matrix1.multiply_blocked_with(matrix2, args.block_size, Algorithm::Par, cancel))?;
+            options.apply_epilogues(&mut m);
+            m
+        } else {
+            // A broadcast scalar, or the mismatch error.
+            matrix1.multiply_with(matrix2, &options)?
+        };
+        let elapsed = start.elapsed();
+        events.progress(total_rows, total_rows);
+        events.phase_finished("multiply-blocked", elapsed);
+        if args.mode == Mode::Blocked {
+            status!("Done! Elapsed time: {:?} on {}", elapsed, threads(&pool));
+        } else {
+            status!("Done! Elapsed time for BLOCKED: {:?} on {}", elapsed, threads(&pool));
+        }
+        results.push(AlgoResult { algo: "blocked", matrix, elapsed });
+    }
+
+    if args.mode == Mode::Strassen || args.mode == Mode::All {
+        events.phase_started("multiply-strassen");
+        let start = Instant::now();
+        let matrix = if matrix1.cols() == matrix2.rows() {
+            let mut m = pool.install(|| matrix1.multiply_strassen_par(matrix2, args.strassen_cutoff))?;
+            options.apply_epilogues(&mut m);
+            m
+        } else {
+            // A broadcast scalar, or the mismatch error.
+            matrix1.multiply_with(matrix2, &options)?
+        };
+        let elapsed = start.elapsed();
+        events.progress(total_rows, total_rows);
+        events.phase_finished("multiply-strassen", elapsed);
+        if args.mode == Mode::Strassen {
+            status!("Done! Elapsed time: {:?} on {}", elapsed, threads(&pool));
+        } else {
+            status!("Done! Elapsed time for STRASSEN: {:?} on {}", elapsed, threads(&pool));
+        }
+        results.push(AlgoResult { algo: "strassen", matrix, elapsed });
+    }
+
+    if args.mode == Mode::All {
+        let reference = &results[0].matrix;
+        for other in &results[1..] {
+            let (first, second) = (results[0].algo.to_uppercase(), other.algo.to_uppercase());
+            let difference = reference.max_abs_diff(&other.matrix);
+            if args.write_all_results {
+                status!("Max difference between {} and {}: {:e}", first, second, difference);
+            }
+            let abs_tolerance = if other.algo == "strassen" {
+                let largest = reference.data().iter().filter(|x| x.is_finite()).fold(0.0, |m: f64, x| m.max(x.abs()));
+                args.abs_tolerance.max(args.tolerance * largest)
+            } else {
+                args.abs_tolerance
+            };
+            if !reference.approx_eq(&other.matrix, args.tolerance, abs_tolerance) {
+                eprintln!(
+                    "Error: {} and {} disagree by up to {:e}, beyond --tolerance {:e} and --abs-tolerance {:e}",
+                    first, second, difference, args.tolerance, abs_tolerance
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+
+    Ok(results)
+}
+
+async fn run_distributed(
+    args: &Args,
+    a: &Matrix,
+    b: &Matrix,
+    cancel: &CancelToken,
+    events: &mut EventSink,
+) -> Result<Vec<AlgoResult>, MatrixError> {
+    let path = args.distribute.as_ref().unwrap();
+    let text = std::fs::read_to_string(path)
+        .map_err(|err| io_error(format!("cannot read {}", path.display()), err))?;
+    let workers =
+        distribute::parse_workers(&text).map_err(|err| MatrixError::Message(format!("{}: {}", path.display(), err)))?;
+    let options = multiply_options(args)?;
+    if a.cols() == b.rows() {
+        options.check_epilogues((a.rows(), b.cols()))?;
+    }
+
+    events.phase_started("multiply-distributed");
+    let start = Instant::now();
+    let distribute_options = distribute::DistributeOptions {
+        cancel: cancel.clone(),
+        ..distribute::DistributeOptions::default()
+    };
+    let (mut matrix, report) = distribute::distribute(a, b, &workers, &distribute_options).await?;
+    options.apply_epilogues(&mut matrix);
+    let elapsed = start.elapsed();
+    events.progress(a.rows(), a.rows());
+    events.phase_finished("multiply-distributed", elapsed);
+    status!("Done! Elapsed time: {:?}", elapsed);
+    status!(
+        "{} bands over {} workers, {} retried after a failure, {} sent again for being slow",
+        report.bands,
+        workers.len(),
+        report.retries,
+        report.redispatched
+    );
+
+    Ok(vec![AlgoResult { algo: "distributed", matrix, elapsed }])
+}
+
+#[cfg(feature = "plugins")]
+fn run_plugin(
+    path: &Path,
+    matrix1: &Matrix,
+    matrix2: &Matrix,
+    events: &mut EventSink,
+) -> Result<Vec<AlgoResult>, MatrixError> {
+    let plugin = plugin::Plugin::load(path).unwrap_or_else(|err| {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    });
+
+    events.phase_started("multiply-plugin");
+    let start = Instant::now();
+    let matrix = match plugin.multiply(matrix1, matrix2) {
+        Ok(matrix) => matrix,
+        Err(plugin::PluginError::Matrix(err)) => return Err(err),
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let elapsed = start.elapsed();
+    events.phase_finished("multiply-plugin", elapsed);
+    status!("Done! Elapsed time: {:?}", elapsed);
+
+    Ok(vec![AlgoResult { algo: "plugin", matrix, elapsed }])
+}
+
+/// Element type of the input matrices.
+#[derive(Clone, Copy, PartialEq, Eq, ArgEnum, Debug)]
+enum Dtype {
+    /// Single precision throughout: parsed, multiplied and written as f32.
+    F32,
+    F64,
+    /// Exact 64-bit integers, accumulated in 128 bits.
+    I64,
+    /// f64 inputs, and lower and upper bounds on the exact product, written
+    /// as two matrices separated by X.
+    Interval,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ArgEnum, Debug)]
+enum InputFormat {
+    /// Values separated by single spaces.
+    #[clap(alias = "txt")]
+    Text,
+    /// Values separated by commas, which may be quoted.
+    Csv,
+    /// "row col value" triplets, one per line.
+    Coo,
+    /// The native binary format: a header, then little-endian f64 in row
+    /// order. One matrix per file.
+    Bin,
+    /// A NumPy .npy file of a 2-D float64 array. One matrix per file, and
+    /// only read.
+    Npy,
+}
+
+/// Which result gets written to the output file.
+#[derive(Clone, Copy, PartialEq, Eq, ArgEnum, Debug)]
+enum WriteChoice {
+    /// The result of the algorithm that finished first.
+    Fastest,
+    /// The sequential result.
+    Reference,
+    /// The parallel result.
+    Par,
+    /// Nothing.
+    None,
+}
+
+fn choose_result(results: &[AlgoResult], choice: WriteChoice) -> Option<&AlgoResult> {
+    let by_algo = |algo| results.iter().find(|r| r.algo == algo);
+    match choice {
+        WriteChoice::Fastest => results.iter().min_by_key(|r| r.elapsed),
+        WriteChoice::Reference => by_algo("seq").or_else(|| results.first()),
+        WriteChoice::Par => by_algo("par").or_else(|| results.last()),
+        WriteChoice::None => None,
+    }
+}
+
+/// Where a result is written.
+#[derive(Clone, Debug, PartialEq)]
+enum Target {
+    File(PathBuf),
+    Stdout,
+}
+
+impl Target {
+    /// Refuses to replace an existing file without --force.
+    fn open(&self, force: bool) -> io::Result<Box<dyn Write>> {
+        match self {
+            Target::File(path) if force => Ok(Box::new(File::create(path)?)),
+            Target::File(path) => Ok(Box::new(File::options().write(true).create_new(true).open(path)?)),
+            Target::Stdout => Ok(Box::new(io::stdout().lock())),
+        }
+    }
+
+    /// For `result` events: the file, or "-".
+    fn event_path(&self) -> String {
+        match self {
+            Target::File(path) => path.to_string_lossy().into_owned(),
+            Target::Stdout => "-".to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::File(path) => write!(f, "{}", path.display()),
+            Target::Stdout => write!(f, "stdout"),
+        }
+    }
+}
+
+// The result's target: --output, stdout without it, nothing with --quiet.
+fn result_target(args: &Args) -> Option<Target> {
+    match &args.output {
+        _ if args.quiet => None,
+        Some(path) => Some(Target::File(path.clone())),
+        None => Some(Target::Stdout),
+    }
+}
+
+// What the other files a run writes are named after.
+fn output_base(args: &Args) -> &Path {
+    args.output.as_deref().unwrap_or(Path::new("output.txt"))
+}
+
+fn write_failed(target: &Target, err: io::Error) -> ! {
+    if err.kind() == io::ErrorKind::AlreadyExists {
+        eprintln!("Error: {} already exists; pass --force to replace it", target);
+    } else {
+        eprintln!("Error: cannot write {}: {}", target, err);
+    }
+    std::process::exit(1);
+}
+
+// "output.txt" and "par" give "output.par.txt".
+fn algo_output_path(output: &Path, algo: &str) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match output.extension() {
+        Some(ext) => format!("{}.{}.{}", stem, algo, ext.to_string_lossy()),
+        None => format!("{}.{}", stem, algo),
+    };
+    output.with_file_name(name)
+}
+
+// The format a .csv, .txt or .bin output names; otherwise the first input
+// file's, except that COO is written as text and stdout never gets binary.
+fn output_format(args: &Args, target: &Target) -> InputFormat {
+    if let Target::File(path) = target {
+        if let Some(format) = extension_format(path) {
+            return format;
+        }
+    }
+    match args.file.first().map(|path| input_format(args, path)) {
+        Some(InputFormat::Bin) if *target != Target::Stdout => InputFormat::Bin,
+        Some(InputFormat::Csv) => InputFormat::Csv,
+        _ => InputFormat::Text,
+    }
+}
+
+fn write_results(results: &[AlgoResult], args: &Args, events: &mut EventSink) -> Vec<Target> {
+    let mut targets: Vec<(Target, &Matrix)> = Vec::new();
+    if args.write_all_results {
+        for result in results {
+            targets.push((Target::File(algo_output_path(output_base(args), result.algo)), &result.matrix));
+        }
+    }
+    if let (Some(target), Some(result)) = (result_target(args), choose_result(results, args.write)) {
+        targets.push((target, &result.matrix));
+    }
+
+    let binary = |target: &Target| args.write_coo.is_none() && output_format(args, target) == InputFormat::Bin;
+    if args.ldc.is_some() && !targets.iter().all(|(target, _)| binary(target)) {
+        eprintln!("Error: --ldc applies only to binary outputs");
+        std::process::exit(1);
+    }
+
+    let precision = args.digits.map_or(Precision::Full, Precision::Significant);
+    events.phase_started("write");
+    let start = Instant::now();
+    for (target, matrix) in &targets {
+        let written = target.open(args.force).and_then(|out| match args.write_coo {
+            Some(tolerance) => sparse::write_coo(matrix, tolerance, args.coo_base, precision, io::BufWriter::new(out)),
+            None => match output_format(args, target) {
+                InputFormat::Csv => matrix.write_csv(out, precision),
+                InputFormat::Bin => matrix.write_binary_ld(out, args.ldc.unwrap_or(matrix.cols())),
+                _ => matrix.write_text(out, precision),
+            },
+        });
+        if let Err(err) = written {
+            write_failed(target, err);
+        }
+    }
+    events.phase_finished("write", start.elapsed());
+
+    for (target, matrix) in &targets {
+        events.result(&target.event_path(), matrix.checksum());
+    }
+    targets.into_iter().map(|(target, _)| target).collect()
+}
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use matrix_mul::matrix;
+
+    use super::*;
+
+    #[test]
+    fn verify_cli() {
+        use clap::CommandFactory;
+        Args::command().debug_assert();
+        ShapeArgs::command().debug_assert();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn control_socket_events() {
+        use std::io::{BufRead, BufReader};
+        use std::os::unix::net::UnixListener;
+
+        let path = std::env::temp_dir().join(format!("matrix-mul-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let reader = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            BufReader::new(stream)
+                .lines()
+                .map(|line| line.unwrap())
+                .collect::<Vec<String>>()
+        });
+
+        // A problem this small would also warn that PAR ran inline.
+        let args = Args::parse_from(["matrix-mul", "--mode", "all", "--inline-below", "0"]);
+        let a = Matrix::random(6, 4);
+        let b = Matrix::random(4, 5);
+        let mut events = EventSink::connect(&path).unwrap();
+        let c = run(&args, &a, &b, &CancelToken::new(), &mut events).unwrap().pop().unwrap().matrix;
+        events.result("output.txt", c.checksum());
+        drop(events);
+
+        let lines = reader.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let kinds: Vec<&str> = lines
+            .iter()
+            .map(|line| {
+                assert!(line.starts_with("{\"v\":1,\"event\":"), "{}", line);
+                line.split('"').nth(5).unwrap()
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                "phase_started",
+                "progress",
+                "phase_finished",
+                "phase_started",
+                "progress",
+                "phase_finished",
+                "phase_started",
+                "progress",
+                "phase_finished",
+                "phase_started",
+                "progress",
+                "phase_finished",
+                "result"
+            ]
+        );
+        assert!(lines[1].contains("\"done_rows\":6,\"total_rows\":6"));
+        assert!(lines[2].contains("\"phase\":\"multiply-seq\",\"elapsed_ms\":"));
+        assert!(lines[4].contains("\"done_rows\":6,\"total_rows\":6"));
+        assert!(lines[8].contains("\"phase\":\"multiply-blocked\",\"elapsed_ms\":"));
+        assert!(lines[11].contains("\"phase\":\"multiply-strassen\",\"elapsed_ms\":"));
+        assert!(lines[12].contains(&format!("\"checksum\":\"{:016x}\"", c.checksum())));
+    }
+
+    #[test]
+    fn warnings_are_collected() {
+        #[derive(Clone, Default)]
+        struct Shared(Arc<std::sync::Mutex<Vec<u8>>>);
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let args = Args::parse_from(["matrix-mul", "--mode", "par", "--ragged-policy", "pad-zero"]);
+        let out = Shared::default();
+        let mut events = EventSink::from_writer(out.clone());
+
+        let mut inputs = vec![
+            parse_operand("first", "1 2\n3", InputFormat::Text, &args, &mut events).unwrap(),
+            parse_operand("second", "NaN 1\n2 inf", InputFormat::Text, &args, &mut events).unwrap(),
+        ];
+        replace_nonfinite_inputs(&mut inputs, 0.0, &mut events);
+        run(&args, &inputs[0], &inputs[1], &CancelToken::new(), &mut events).unwrap();
+
+        let codes: Vec<&str> = events.warnings().iter().map(Warning::code).collect();
+        assert_eq!(codes, ["ragged_rows", "nonfinite_replaced", "ran_inline"]);
+        let json = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let warning_lines: Vec<&str> = json.lines().filter(|line| line.contains("\"event\":\"warning\"")).collect();
+        assert_eq!(warning_lines.len(), 3);
+        assert!(warning_lines[0].contains("\"code\":\"ragged_rows\",\"message\":\"1 ragged rows padded in first matrix\",\"count\":1"));
+        assert!(warning_lines[1].contains("\"code\":\"nonfinite_replaced\""));
+        assert!(warning_lines[1].contains("\"count\":2"));
+        assert!(warning_lines[2].contains("\"code\":\"ran_inline\""));
+
+        assert_eq!(exit_code(&events, false), 0);
+        assert_eq!(exit_code(&events, true), 1);
+        assert_eq!(exit_code(&EventSink::disabled(), true), 0);
+    }
+
+    #[test]
+    fn size_shorthand() {
+        assert_eq!(parse_size("1000"), Ok((1000, 1000, 1000)));
+        assert_eq!(parse_size("2000x500x800"), Ok((2000, 500, 800)));
+        assert_eq!(parse_size("2X3X4"), Ok((2, 3, 4)));
+
+        let err = parse_size("2000xabcx800").unwrap_err();
+        assert!(err.contains("component 2") && err.contains("'abc'"), "{}", err);
+        assert!(parse_size("20x30").unwrap_err().contains("2 components"));
+        assert!(parse_size("-5").is_err());
+    }
+
+    #[test]
+    fn replacements_are_finite() {
+        assert_eq!(parse_finite("-1.5"), Ok(-1.5));
+        for s in ["nan", "inf", "-infinity", "1e400"] {
+            assert!(parse_finite(s).unwrap_err().contains("finite"), "{}", s);
+        }
+        assert!(Args::try_parse_from(["matrix-mul", "--replace-nonfinite", "NaN"]).is_err());
+    }
+
+    #[test]
+    fn all_mode_includes_blocked() {
+        let args = Args::parse_from(["matrix-mul", "--mode", "all", "--block-size", "7"]);
+        let (a, b) = (Matrix::random(65, 130), Matrix::random(130, 67));
+        let results = run(&args, &a, &b, &CancelToken::new(), &mut EventSink::disabled()).unwrap();
+        let algos: Vec<&str> = results.iter().map(|r| r.algo).collect();
+        assert_eq!(algos, ["seq", "par", "blocked", "strassen"]);
+        assert_eq!(results[2].matrix, a.multiply(&b).unwrap());
+
+        // Recursing, Strassen only agrees to a tolerance.
+        let args = Args::parse_from(["matrix-mul", "--mode", "strassen", "--strassen-cutoff", "16"]);
+        let results = run(&args, &a, &b, &CancelToken::new(), &mut EventSink::disabled()).unwrap();
+        assert_eq!(results[0].algo, "strassen");
+        assert!(results[0].matrix.approx_eq(&a.multiply(&b).unwrap(), 1e-12, 0.0));
+
+        assert_eq!(Args::parse_from(["matrix-mul", "--mode", "blocked"]).block_size, 64);
+        assert!(Args::try_parse_from(["matrix-mul", "--mode", "blocked", "--block-size", "0"]).is_err());
+    }
+
+    #[test]
+    fn single_thread_pool() {
+        // --mode all fails the run unless PAR, BLOCKED and STRASSEN match SEQ.
+        let args = Args::parse_from(["matrix-mul", "--mode", "all", "--threads", "1", "--inline-below", "0"]);
+        assert_eq!(thread_pool(&args).current_num_threads(), 1);
+        let (a, b) = (Matrix::random(40, 30), Matrix::random(30, 20));
+        let results = run(&args, &a, &b, &CancelToken::new(), &mut EventSink::disabled()).unwrap();
+        assert_eq!(results.len(), 4);
+
+        assert!(Args::try_parse_from(["matrix-mul", "--mode", "par", "--threads", "0"]).is_err());
+        let cores = rayon::ThreadPoolBuilder::new().build().unwrap().current_num_threads();
+        assert_eq!(thread_pool(&Args::parse_from(["matrix-mul", "--mode", "par"])).current_num_threads(), cores);
+    }
+
+    #[test]
+    fn size_precedence() {
+        let dims = |argv: &[&str]| {
+            let mut full = vec!["matrix-mul", "--mode", "seq"];
+            full.extend_from_slice(argv);
+            resolve_dims(&Args::parse_from(full))
+        };
+
+        assert_eq!(dims(&["--size", "5"]), Ok(Dims::Given(5, 5, 5)));
+        assert_eq!(dims(&["--size", "5x6x7", "-m", "2"]), Ok(Dims::Given(5, 2, 7)));
+        assert_eq!(dims(&["-n", "3", "-m", "4"]), Ok(Dims::InferredK(3, 4, 3)));
+        assert_eq!(dims(&["-n", "3", "-m", "4", "-k", "9"]), Ok(Dims::Given(3, 4, 9)));
+        assert!(dims(&["-n", "3"]).is_err());
+    }
+
+    #[test]
+    fn memory_limits() {
+        let limits = |argv: &[&str]| {
+            let mut full = vec!["matrix-mul", "--mode", "seq"];
+            full.extend_from_slice(argv);
+            Args::parse_from(full)
+        };
+
+        // 3 * 100 * 100 * 8 bytes = 240 kB
+        assert!(check_limits(&limits(&[]), 100, 100, 100).is_ok());
+        assert!(check_limits(&limits(&["--max-memory", "240kB"]), 100, 100, 100).is_ok());
+        assert!(check_limits(&limits(&["--max-memory", "235KiB"]), 100, 100, 100).is_ok());
+        let err = check_limits(&limits(&["--max-memory", "200K"]), 100, 100, 100).unwrap_err();
+        assert!(err.contains("234.38 KiB"), "{}", err);
+
+        assert!(check_limits(&limits(&["--max-elements", "10k"]), 100, 100, 100).is_ok());
+        assert!(check_limits(&limits(&["--max-elements", "10k"]), 100, 101, 100).is_err());
+        assert!(check_limits(&limits(&["--max-elements", "5k"]), 10, 10, 500).is_ok());
+        assert!(check_limits(&limits(&["--max-elements", "5k"]), 11, 10, 500).is_err());
+    }
+
+    #[test]
+    fn expected_shapes() {
+        let expect = |argv: &[&str], shapes: &[(usize, usize)]| {
+            let mut full = vec!["matrix-mul", "--mode", "seq"];
+            full.extend_from_slice(argv);
+            check_expected_shapes(&Args::parse_from(full), shapes)
+        };
+        assert!(expect(&["--expect-shape", "2x5"], &[(2, 3), (3, 5)]).is_ok());
+        assert!(expect(&["--expect-shape", "2x3", "--expect-shape", "3x5", "--expect-shape", "2x5"], &[(2, 3), (3, 5)]).is_ok());
+        let err = expect(&["--expect-shape", "2x3", "--expect-shape", "3x4", "--expect-shape", "2x5"], &[(2, 3), (3, 5)]);
+        assert_eq!(err.unwrap_err(), "the second matrix is 3x5, not 3x4 as --expect-shape says");
+        assert!(expect(&["--expect-shape", "2x5"], &[(2, 3), (4, 5)]).unwrap_err().contains("cannot multiply"));
+        assert!(expect(&["--expect-shape", "2x3", "--broadcast-scalars"], &[(1, 1), (2, 3)]).is_ok());
+
+        // The result of each op, predicted from the shapes alone.
+        let op = |op: &str, shapes: &[(usize, usize)]| {
+            let args = Args::parse_from(["matrix-mul", "--mode", "seq", "--op", op, "--length", "2"]);
+            args.op.result_shape(shapes, false)
+        };
+        assert_eq!(op("solve", &[(4, 4), (4, 2)]), Ok((4, 2)));
+        assert!(op("solve", &[(4, 3), (4, 2)]).unwrap_err().contains("square"));
+        assert_eq!(op("aat", &[(4, 3)]), Ok((4, 4)));
+        assert_eq!(op("ata", &[(4, 3)]), Ok((3, 3)));
+        assert_eq!(op("stationary", &[(5, 5)]), Ok((1, 5)));
+        assert_eq!(op("paths", &[(5, 5)]), Ok((5, 5)));
+        assert_eq!(op("convert", &[(2, 7)]), Ok((2, 7)));
+    }
+
+    #[test]
+    fn timeouts_are_durations() {
+        let parse = |timeout: &str| Args::try_parse_from(["matrix-mul", "--mode", "seq", "--timeout", timeout]);
+        assert_eq!(parse("1.5").unwrap().timeout, Some(Duration::from_millis(1500)));
+        assert_eq!(parse("2m").unwrap().timeout, Some(Duration::from_secs(120)));
+        for bad in ["-1", "nan", "inf", "1e300"] {
+            assert!(parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn parse_with_transforms() {
+        let text = "1 -999 3\n-999 5 -999";
+        let transforms = [Transform::parse("replace-sentinel:-999:0").unwrap()];
+        let mut changed = [0];
+        let (m, _) =
+            Matrix::from_string_map(text, &ParseOptions::default(), |x| apply_transforms(&transforms, &mut changed, x))
+                .unwrap();
+
+        assert_eq!(changed, [3]);
+        assert_eq!(m, matrix![1.0, 0.0, 3.0; 0.0, 5.0, 0.0]);
+    }
+
+    #[test]
+    fn transforms_run_before_finite_check() {
+        let options = ParseOptions::default();
+        let replace_inf = [Transform::parse("replace-sentinel:inf:0").unwrap()];
+        let mut changed = [0];
+        let (m, _) =
+            Matrix::from_string_map("1 inf\n2 3", &options, |x| apply_transforms(&replace_inf, &mut changed, x))
+                .unwrap();
+        assert!(m.validate_finite().is_ok());
+
+        // log1p of a value below -1 is NaN, which the check then reports.
+        let (m, _) = Matrix::from_string_map("1 -2\n2 3", &options, f64::ln_1p).unwrap();
+        let err = m.validate_finite().unwrap_err();
+        assert_eq!((err.row, err.col), (0, 1));
+    }
+
+    #[test]
+    fn write_all_results() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("output.txt");
+        let name = |algo: &str| Target::File(dir.path().join(format!("output.{}.txt", algo)));
+
+        let args = Args::parse_from(["matrix-mul", "--mode", "all", "--write-all-results", "-o", output.to_str().unwrap()]);
+        let a = Matrix::random(7, 3);
+        let b = Matrix::random(3, 4);
+        let results = run(&args, &a, &b, &CancelToken::new(), &mut EventSink::disabled()).unwrap();
+        let written = write_results(&results, &args, &mut EventSink::disabled());
+
+        assert_eq!(written, [name("seq"), name("par"), name("blocked"), name("strassen"), Target::File(output.clone())]);
+        for path in [dir.path().join("output.seq.txt"), output.clone()] {
+            let m = Matrix::try_from_str(std::fs::read_to_string(path).unwrap().trim()).unwrap();
+            assert_eq!((m.rows(), m.cols()), (7, 4));
+        }
+
+        // Files already there are only replaced with --force.
+        let err = Target::File(output.clone()).open(false).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert!(Target::File(output).open(true).is_ok());
+    }
+
+    #[test]
+    fn output_targets() {
+        let args = |argv: &[&str]| {
+            let mut full = vec!["matrix-mul", "--mode", "par"];
+            full.extend_from_slice(argv);
+            Args::try_parse_from(full)
+        };
+        let target = |argv: &[&str]| result_target(&args(argv).unwrap());
+        assert_eq!(target(&[]), Some(Target::Stdout));
+        assert_eq!(target(&["--output", "c.txt"]), Some(Target::File(PathBuf::from("c.txt"))));
+        assert_eq!(target(&["--quiet"]), None);
+        assert!(args(&["--quiet", "--output", "c.txt"]).is_err());
+        assert!(args(&["--quiet", "--write-all-results"]).is_err());
+
+        assert_eq!(output_base(&args(&[]).unwrap()), Path::new("output.txt"));
+        assert_eq!(algo_output_path(output_base(&args(&["-o", "out/c.csv"]).unwrap()), "preview"), Path::new("out/c.preview.csv"));
+        assert_eq!(Target::Stdout.event_path(), "-");
+    }
+
+    #[test]
+    fn csv_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a.csv"), dir.path().join("b.CSV"));
+        std::fs::write(&a, "1,2\n3,4\n").unwrap();
+        std::fs::write(&b, "\"1.5e-3\"\n2\n").unwrap();
+        let paths = [a.to_str().unwrap(), b.to_str().unwrap()];
+        let args = Args::parse_from(["matrix-mul", "--mode", "seq", "-f", paths[0], "-f", paths[1]]);
+        let mut events = EventSink::disabled();
+        let mut inputs = Vec::new();
+        for (path, name) in args.file.iter().zip(OPERAND_NAMES) {
+            inputs.extend(load_file(&args, path, &[name], inputs.is_empty(), &mut None, &mut events));
+        }
+        assert_eq!(inputs, [matrix![1.0, 2.0; 3.0, 4.0], matrix![1.5e-3; 2.0]]);
+
+        // Results follow the input's format unless the output names another.
+        let file = |name: &str| Target::File(PathBuf::from(name));
+        assert_eq!(output_format(&args, &Target::Stdout), InputFormat::Csv);
+        assert_eq!(output_format(&args, &file("c.out")), InputFormat::Csv);
+        assert_eq!(output_format(&args, &file("c.txt")), InputFormat::Text);
+        let text = Args::parse_from(["matrix-mul", "--mode", "seq", "-f", paths[0], "--format", "txt"]);
+        assert_eq!(input_format(&text, &a), InputFormat::Text);
+        assert_eq!(output_format(&text, &Target::Stdout), InputFormat::Text);
+        assert_eq!(output_format(&text, &file("c.Csv")), InputFormat::Csv);
+    }
+
+    #[test]
+    fn binary_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (text, bin) = (dir.path().join("a.mat"), dir.path().join("a.bin"));
+        std::fs::write(&text, "1 2\n3 4\nX\n5\n6\n").unwrap();
+        let (text, bin) = (text.to_str().unwrap(), bin.to_str().unwrap());
+        let args = Args::parse_from(["matrix-mul", "--mode", "seq", "--op", "convert", "-f", text, "-o", bin]);
+        let a = load_file(&args, Path::new(text), &["first"], true, &mut None, &mut EventSink::disabled());
+        let results = [AlgoResult { algo: "convert", matrix: a[0].clone(), elapsed: Duration::ZERO }];
+        write_results(&results, &args, &mut EventSink::disabled());
+        let bytes = std::fs::read(bin).unwrap();
+        assert_eq!((&bytes[..8], bytes.len()), (&b"MMULBIN\0"[..], 32 + 4 * 8));
+
+        // Read back by extension; binary input still prints text.
+        let args = Args::parse_from(["matrix-mul", "--mode", "seq", "-f", bin, "-f", bin, "--map-input", "clamp:0:3"]);
+        let mut events = EventSink::disabled();
+        let read = load_file(&args, Path::new(bin), &["first"], true, &mut None, &mut events);
+        assert_eq!(read, [matrix![1.0, 2.0; 3.0, 3.0]]);
+        assert_eq!(output_format(&args, &Target::Stdout), InputFormat::Text);
+        assert_eq!(output_format(&args, &Target::File(PathBuf::from("c"))), InputFormat::Bin);
+    }
+
+    #[test]
+    fn symmetrize_within_tolerance() {
+        let mut inputs = vec![matrix![1.0, 2.0; 2.0 + 1e-13, 3.0], matrix![1.0, 5.0; 5.0, 0.0]];
+        symmetrize_inputs(&mut inputs, 1e-12).unwrap();
+        assert_eq!(inputs[0].symmetry_error(), 0.0);
+        assert_eq!(inputs[1], matrix![1.0, 5.0; 5.0, 0.0]);
+
+        let mut gross = vec![matrix![1.0, 2.0; 2.5, 3.0]];
+        let err = symmetrize_inputs(&mut gross, 1e-12).unwrap_err();
+        assert!(err.contains("symmetry error of 5e-1"), "{}", err);
+        assert_eq!(gross[0], matrix![1.0, 2.0; 2.5, 3.0]);
+        let err = symmetrize_inputs(&mut [Matrix::random(2, 3)], 1.0).unwrap_err();
+        assert!(err.contains("expected a square matrix"), "{}", err);
+    }
+
+    #[test]
+    fn deterministic_seeds() {
+        let args = |argv: &[&str]| {
+            let mut full = vec!["matrix-mul", "--mode", "all"];
+            full.extend_from_slice(argv);
+            Args::try_parse_from(full).unwrap()
+        };
+        let plain = args(&["--seed", "9"]);
+        assert!(SEED_PURPOSES.iter().all(|purpose| seed(&plain, purpose) == 9));
+
+        let run = args(&["--deterministic-run", "9"]);
+        let seeds: Vec<u64> = SEED_PURPOSES.iter().map(|purpose| seed(&run, purpose)).collect();
+        let again: Vec<u64> = SEED_PURPOSES.iter().map(|purpose| derive_seed(9, purpose)).collect();
+        assert_eq!(seeds, again);
+        assert!(seeds.iter().enumerate().all(|(i, s)| !seeds[..i].contains(s)), "{:?}", seeds);
+        assert_ne!(derive_seed(10, "first"), derive_seed(9, "first"));
+        assert_eq!(check_deterministic(&run), Ok(()));
+
+        let err = check_deterministic(&args(&["--deterministic-run", "9", "--timeout", "5"])).unwrap_err();
+        assert!(err.contains("--timeout"), "{}", err);
+        assert!(check_deterministic(&args(&["--deterministic-run", "9", "--write", "fastest"])).is_err());
+        assert!(check_deterministic(&args(&["--deterministic-run", "9", "--op", "bench"])).is_err());
+        assert!(Args::try_parse_from(["matrix-mul", "--mode", "seq", "--deterministic-run", "1", "--seed", "2"]).is_err());
+    }
+
+    #[test]
+    fn bench_phases() {
+        let phases = |argv: &[&str]| {
+            let mut full = vec!["matrix-mul", "--mode", "par", "--op", "bench"];
+            full.extend_from_slice(argv);
+            Args::try_parse_from(full).map(|args| args.phases)
+        };
+        assert_eq!(phases(&[]).unwrap(), [Phase::Multiply]);
+        assert_eq!(phases(&["--phases", "parse,format"]).unwrap(), [Phase::Parse, Phase::Format]);
+        assert!(phases(&["--phases", "parse,load"]).is_err());
+    }
+
+    #[test]
+    fn write_choice() {
+        let result = |algo, ms| AlgoResult {
+            algo,
+            matrix: matrix![],
+            elapsed: Duration::from_millis(ms),
+        };
+        let results = [result("seq", 20), result("par", 30)];
+
+        assert_eq!(choose_result(&results, WriteChoice::Fastest).unwrap().algo, "seq");
+        assert_eq!(choose_result(&results, WriteChoice::Reference).unwrap().algo, "seq");
+        assert_eq!(choose_result(&results, WriteChoice::Par).unwrap().algo, "par");
+        assert!(choose_result(&results, WriteChoice::None).is_none());
+        assert_eq!(choose_result(&results[..1], WriteChoice::Par).unwrap().algo, "seq");
+    }
+}
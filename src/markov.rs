@@ -0,0 +1,152 @@
+//! Markov chain utilities for row-stochastic transition matrices, where
+//! entry (i, j) is the probability of moving from state i to state j.
+
+use crate::{Matrix, MatrixError};
+
+impl Matrix {
+    /// Scales every row to sum to 1. Rows summing to 0 are left unchanged.
+    pub fn normalize_rows(&self) -> Matrix {
+        let mut result = self.clone();
+        for row in result.data.chunks_mut(self.cols.max(1)) {
+            let sum: f64 = row.iter().sum();
+            if sum != 0.0 {
+                row.iter_mut().for_each(|x| *x /= sum);
+            }
+        }
+        result
+    }
+
+    /// Square, with no entry below `-tol` and every row summing to 1 within
+    /// `tol`.
+    pub fn is_stochastic(&self, tol: f64) -> bool {
+        self.check_stochastic(tol).is_ok()
+    }
+
+    fn check_stochastic(&self, tol: f64) -> Result<(), MatrixError> {
+        self.check_square()?;
+        let bad_row = |row: &[f64]| {
+            row.iter().any(|&x| x < -tol) || (row.iter().sum::<f64>() - 1.0).abs() > tol
+        };
+        match self.data.chunks(self.cols.max(1)).position(bad_row) {
+            Some(row) => Err(MatrixError::NotStochastic { row }),
+            None => Ok(()),
+        }
+    }
+
+    /// The distribution `pi` with `pi * P = pi`, found by repeatedly stepping
+    /// a distribution that starts with all mass on state 0 until it moves by
+    /// less than `tol` (in L1 norm) per step. Periodic chains never settle and
+    /// return `NoConvergence`.
+    pub fn stationary_distribution(
+        &self,
+        tol: f64,
+        max_iters: usize,
+    ) -> Result<Vec<f64>, MatrixError> {
+        self.check_stochastic(STOCHASTIC_TOL)?;
+
+        // pi * P is P^T * pi, and mul_vec wants the matrix on the left.
+        let transposed = self.transpose();
+        let mut pi = vec![0.0; self.rows];
+        if let Some(first) = pi.first_mut() {
+            *first = 1.0;
+        }
+
+        let mut residual = f64::INFINITY;
+        for _ in 0..max_iters {
+            let next = transposed.mul_vec(&pi)?;
+            residual = l1_distance(&next, &pi);
+            pi = next;
+            if residual < tol {
+                return Ok(pi);
+            }
+        }
+
+        Err(MatrixError::NoConvergence {
+            iterations: max_iters,
+            residual,
+        })
+    }
+
+    /// Smallest number of steps after which every starting state's
+    /// distribution is within `eps` of the stationary distribution in total
+    /// variation distance, or `None` if that takes more than `max_steps`.
+    pub fn mix_time_estimate(
+        &self,
+        eps: f64,
+        max_steps: usize,
+    ) -> Result<Option<usize>, MatrixError> {
+        let pi = self.stationary_distribution(eps / 10.0, max_steps.max(1000) * 10)?;
+
+        // Row i of P^t is the distribution after t steps from state i.
+        let mut power = Matrix::identity(self.rows);
+        for step in 1..=max_steps {
+            power = power.multiply_plain(self);
+            let worst = power
+                .data
+                .chunks(self.cols.max(1))
+                .map(|row| l1_distance(row, &pi) / 2.0)
+                .fold(0.0, f64::max);
+            if worst <= eps {
+                return Ok(Some(step));
+            }
+        }
+        Ok(None)
+    }
+}
+
+// Tolerance on row sums when checking that a matrix is stochastic.
+const STOCHASTIC_TOL: f64 = 1e-9;
+
+fn l1_distance(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| (x - y).abs()).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matrix;
+
+    #[test]
+    fn three_state_chain() {
+        // Detailed balance gives pi proportional to (1, 2, 2):
+        // pi0 * 0.5 = pi1 * 0.25 and pi1 * 0.25 = pi2 * 0.25.
+        let p = matrix![0.5, 0.5, 0.0;
+                        0.25, 0.5, 0.25;
+                        0.0, 0.25, 0.75];
+        assert!(p.is_stochastic(1e-12));
+
+        let pi = p.stationary_distribution(1e-13, 10_000).unwrap();
+        for (got, want) in pi.iter().zip([0.2, 0.4, 0.4]) {
+            assert!((got - want).abs() < 1e-10, "{:?}", pi);
+        }
+
+        let steps = p.mix_time_estimate(0.01, 1000).unwrap().unwrap();
+        assert!(steps > 1 && steps < 100, "{}", steps);
+    }
+
+    #[test]
+    fn periodic_chain_does_not_converge() {
+        let p = matrix![0.0, 1.0;
+                        1.0, 0.0];
+        assert!(matches!(
+            p.stationary_distribution(1e-12, 500),
+            Err(MatrixError::NoConvergence { iterations: 500, .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_non_stochastic() {
+        let p = matrix![0.5, 0.4;
+                        0.5, 0.5];
+        assert!(!p.is_stochastic(1e-9));
+        assert!(!matrix![1.5, -0.5; 0.5, 0.5].is_stochastic(1e-9));
+        assert_eq!(
+            p.stationary_distribution(1e-9, 100),
+            Err(MatrixError::NotStochastic { row: 0 })
+        );
+
+        let normalized = p.normalize_rows();
+        assert!(normalized.is_stochastic(1e-12));
+        assert!(normalized.stationary_distribution(1e-12, 10_000).is_ok());
+    }
+}
@@ -0,0 +1,118 @@
+//! `--auto-narrow`: the general multiply on f32 copies of operands whose
+//! elements are all exactly f32 values, such as small integers or data that
+//! started out in half or single precision.
+//!
+//! The condition is only that every element converts to f32 and back to the
+//! same f64 bits. Nothing is needed of the products: the kernel widens each
+//! element to the f64 it came from and multiplies and sums in f64, in the
+//! order the f64 kernels use, so it does exactly the same arithmetic on
+//! exactly the same values. (Products formed in f32 would round whenever
+//! they need more than 24 bits, which is why they are not.) What is saved is
+//! memory traffic: the kernel streams 4 bytes per element instead of 8.
+
+use crate::{fused_rows, par_fused_rows, Algorithm, Matrix, MatrixError, MultiplyOptions};
+
+impl Matrix {
+    /// The elements as f32, if every one of them is one: it converts back
+    /// to the same bits, which also keeps the sign of zero and NaN payloads.
+    pub(crate) fn narrow(&self) -> Option<Vec<f32>> {
+        self.data
+            .iter()
+            .map(|&x| {
+                let narrow = x as f32;
+                (f64::from(narrow).to_bits() == x.to_bits()).then_some(narrow)
+            })
+            .collect()
+    }
+
+    /// `self * other` from f32 copies, or `None` when an operand does not
+    /// narrow or the copies would break the allocation budget. The shapes
+    /// must conform.
+    pub(crate) fn multiply_narrowed(
+        &self,
+        other: &Matrix,
+        algorithm: Algorithm,
+        options: &MultiplyOptions,
+    ) -> Option<Result<Matrix, MatrixError>> {
+        assert_eq!(self.cols, other.rows);
+        let (rows, inner, cols) = (self.rows, self.cols, other.cols);
+        let context = &options.context;
+        let copies = 4 * (self.data.len() + other.data.len()) as u64;
+        let result_bytes = 8u64.saturating_mul(rows as u64).saturating_mul(cols as u64);
+        context.check_allocation(result_bytes.saturating_add(copies)).ok()?;
+
+        let a = self.narrow()?;
+        // Transposed, so that the columns the kernel reads are contiguous.
+        let b = other.narrow()?;
+        let mut bt = vec![0.0f32; b.len()];
+        for (k, row) in b.chunks(cols.max(1)).enumerate() {
+            for (j, &y) in row.iter().enumerate() {
+                bt[j * inner + k] = y;
+            }
+        }
+
+        let kernel = |i: usize, out: &mut [f64]| {
+            let a = &a[i * inner..(i + 1) * inner];
+            for (j, cell) in out.iter_mut().enumerate() {
+                let mut sum = 0.0;
+                for (&x, &y) in a.iter().zip(&bt[j * inner..(j + 1) * inner]) {
+                    sum += f64::from(x) * f64::from(y);
+                }
+                *cell = sum;
+            }
+        };
+        let token = options.cancel.clone().unwrap_or_default();
+        let epilogues = &options.epilogues;
+        Some(match algorithm {
+            Algorithm::Seq => fused_rows(rows, cols, &token, epilogues, context, kernel),
+            Algorithm::Par => par_fused_rows(rows, cols, &token, epilogues, context, kernel),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Algorithm, Context, Epilogue, Matrix, MultiplyOptions};
+
+    #[test]
+    fn integers_narrow_exactly() {
+        // Up to 2^24 every integer is an f32, and their products need more
+        // than f32's 24 bits.
+        let a = Matrix::new_unchecked(30, 20, (0..600).map(|x| (x * 27_961 % 16_777_216) as f64 - 8e6).collect());
+        let b = Matrix::new_unchecked(20, 25, (0..500).map(|x| (x * 7_919 % 65_536) as f64).collect());
+        assert!(a.narrow().is_some() && b.narrow().is_some());
+        let expected = a.multiply(&b).unwrap();
+        for algorithm in [Algorithm::Seq, Algorithm::Par] {
+            let options = MultiplyOptions::new().algorithm(algorithm).inline_below(0);
+            let (product, report) = a.multiply_with_report(&b, &options.clone().auto_narrow(true)).unwrap();
+            assert!(report.narrowed);
+            assert_eq!(product.bits(), expected.bits());
+
+            // Epilogues run on the f64 rows as usual.
+            let options = options.epilogue(Epilogue::Apply(f64::sqrt));
+            let plain = a.multiply_with(&b, &options).unwrap();
+            let narrowed = a.multiply_with(&b, &options.auto_narrow(true)).unwrap();
+            assert_eq!(narrowed.bits(), plain.bits());
+        }
+    }
+
+    #[test]
+    fn inexact_elements_stay_f64() {
+        let mut a = Matrix::new_unchecked(4, 3, (0..12).map(f64::from).collect());
+        let b = Matrix::new_unchecked(3, 5, (0..15).map(f64::from).collect());
+        let options = MultiplyOptions::new().auto_narrow(true);
+        assert!(a.multiply_with_report(&b, &options).unwrap().1.narrowed);
+        for x in [0.1, 16_777_217.0, 1e300, f64::MIN_POSITIVE] {
+            a.set(2, 1, x);
+            assert!(a.narrow().is_none(), "{}", x);
+            let (product, report) = a.multiply_with_report(&b, &options).unwrap();
+            assert!(!report.narrowed);
+            assert_eq!(product, a.multiply(&b).unwrap());
+        }
+
+        // Nor when the copies do not fit the budget; 160 bytes is the result.
+        a.set(2, 1, 0.5);
+        let options = options.context(Context::new().allocation_budget(160));
+        assert!(!a.multiply_with_report(&b, &options).unwrap().1.narrowed);
+    }
+}
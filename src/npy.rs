@@ -0,0 +1,197 @@
+//! Minimal reader and writer for NumPy `.npy` files holding a 2-D float64
+//! array, used by the oracle fixtures under `testdata/`, and by the `shape`
+//! subcommand, which reads only the header.
+//!
+//! Only format versions 1.0 and 2.0 with dtype `<f8` are supported. Arrays
+//! stored in Fortran order are converted to row-major on read.
+
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8] = b"\x93NUMPY";
+
+pub fn write(out: &mut impl Write, rows: usize, cols: usize, data: &[f64]) -> io::Result<()> {
+    assert_eq!(rows * cols, data.len());
+    let mut header = format!(
+        "{{'descr': '<f8', 'fortran_order': False, 'shape': ({}, {}), }}",
+        rows, cols
+    );
+    // Magic, version and the length field take 10 bytes; the header is
+    // padded so the data starts on a 64-byte boundary.
+    let unpadded = MAGIC.len() + 4 + header.len() + 1;
+    header.push_str(&" ".repeat((64 - unpadded % 64) % 64));
+    header.push('\n');
+
+    out.write_all(MAGIC)?;
+    out.write_all(&[1, 0])?;
+    out.write_all(&(header.len() as u16).to_le_bytes())?;
+    out.write_all(header.as_bytes())?;
+    for x in data {
+        out.write_all(&x.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Returns `(rows, cols, data)` with `data` in row-major order.
+pub fn read(bytes: &[u8]) -> Result<(usize, usize, Vec<f64>), String> {
+    if !bytes.starts_with(MAGIC) || bytes.len() < 10 {
+        return Err("not an .npy file".to_owned());
+    }
+    let (header_len, header_start) = match bytes[6] {
+        1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+        2 if bytes.len() >= 12 => (u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize, 12),
+        version => return Err(format!("unsupported .npy version {}", version)),
+    };
+    let body = header_start + header_len;
+    let header = bytes
+        .get(header_start..body)
+        .and_then(|h| std::str::from_utf8(h).ok())
+        .ok_or("truncated .npy header")?;
+    let (rows, cols, fortran_order) = parse_header(header)?;
+
+    let payload = &bytes[body..];
+    let needed = rows
+        .checked_mul(cols)
+        .and_then(|elements| elements.checked_mul(8))
+        .ok_or_else(|| format!("{}x{} array is too large", rows, cols))?;
+    if payload.len() != needed {
+        return Err(format!("{}x{} array needs {} bytes, found {}", rows, cols, needed, payload.len()));
+    }
+    let values: Vec<f64> = payload
+        .chunks_exact(8)
+        .map(|b| f64::from_le_bytes(b.try_into().unwrap()))
+        .collect();
+
+    let data = if fortran_order {
+        (0..rows * cols).map(|i| values[(i % cols) * rows + i / cols]).collect()
+    } else {
+        values
+    };
+    Ok((rows, cols, data))
+}
+
+/// `(rows, cols)` from the header, reading up to its end and no further.
+pub fn read_shape(mut reader: impl Read) -> Result<(usize, usize), String> {
+    let mut exact = |len: usize| {
+        let mut buf = vec![0; len];
+        reader.read_exact(&mut buf).map(|()| buf).map_err(|err| match err.kind() {
+            io::ErrorKind::UnexpectedEof => "truncated .npy header".to_owned(),
+            _ => err.to_string(),
+        })
+    };
+    let start = exact(8)?;
+    if !start.starts_with(MAGIC) {
+        return Err("not an .npy file".to_owned());
+    }
+    let header_len = match start[6] {
+        1 => u16::from_le_bytes(exact(2)?.try_into().unwrap()) as usize,
+        2 => u32::from_le_bytes(exact(4)?.try_into().unwrap()) as usize,
+        version => return Err(format!("unsupported .npy version {}", version)),
+    };
+    let header = String::from_utf8(exact(header_len)?).map_err(|_| "truncated .npy header")?;
+    parse_header(&header).map(|(rows, cols, _)| (rows, cols))
+}
+
+// `(rows, cols, fortran_order)` from the header dict.
+fn parse_header(header: &str) -> Result<(usize, usize, bool), String> {
+    if field(header, "descr")? != "'<f8'" {
+        return Err(format!("expected dtype '<f8' in header {}", header.trim()));
+    }
+    let fortran_order = match field(header, "fortran_order")? {
+        "True" => true,
+        "False" => false,
+        other => return Err(format!("bad fortran_order {}", other)),
+    };
+    let shape = field(header, "shape")?;
+    let dims: Vec<usize> = shape
+        .trim_matches(|c| c == '(' || c == ')')
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .map(|d| d.parse().map_err(|_| format!("bad shape {}", shape)))
+        .collect::<Result<_, _>>()?;
+    let (rows, cols) = match dims[..] {
+        [rows, cols] => (rows, cols),
+        _ => return Err(format!("expected a 2-D array, found shape {}", shape)),
+    };
+    Ok((rows, cols, fortran_order))
+}
+
+// Value of `key` in the header dict, e.g. "(3, 4)" for 'shape'.
+fn field<'a>(header: &'a str, key: &str) -> Result<&'a str, String> {
+    let start = header
+        .find(&format!("'{}':", key))
+        .ok_or_else(|| format!("no '{}' in .npy header", key))?
+        + key.len()
+        + 3;
+    let rest = header[start..].trim_start();
+    let end = if rest.starts_with('(') {
+        rest.find(')').map(|i| i + 1)
+    } else {
+        rest.find(',')
+    }
+    .ok_or_else(|| format!("unterminated '{}' in .npy header", key))?;
+    Ok(rest[..end].trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let data: Vec<f64> = (0..12).map(|x| x as f64 * 0.5 - 1.0).collect();
+        let mut bytes = Vec::new();
+        write(&mut bytes, 3, 4, &data).unwrap();
+
+        assert_eq!((bytes.len() - data.len() * 8) % 64, 0);
+        assert_eq!(read(&bytes), Ok((3, 4, data)));
+    }
+
+    #[test]
+    fn fortran_order() {
+        // Column-major storage of [[1, 2, 3], [4, 5, 6]].
+        let mut bytes = Vec::new();
+        write(&mut bytes, 2, 3, &[1.0, 4.0, 2.0, 5.0, 3.0, 6.0]).unwrap();
+        let header_end = bytes.len() - 6 * 8;
+        let header = String::from_utf8(bytes[10..header_end].to_vec()).unwrap();
+        bytes[10..header_end].copy_from_slice(header.replace("False", "True ").as_bytes());
+
+        assert_eq!(read(&bytes), Ok((2, 3, vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0])));
+    }
+
+    #[test]
+    fn overflowing_shape() {
+        // 2^61 x 1 takes 2^64 bytes, which wraps to 0 unchecked and would
+        // match the empty payload.
+        let header = b"{'descr': '<f8', 'fortran_order': False, 'shape': (2305843009213693952, 1), }\n";
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&[1, 0]);
+        bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(header);
+
+        assert_eq!(read(&bytes), Err("2305843009213693952x1 array is too large".to_owned()));
+        assert_eq!(read_shape(&bytes[..]), Ok((2305843009213693952, 1)));
+    }
+
+    #[test]
+    fn shape_reads_only_the_header() {
+        let mut bytes = Vec::new();
+        write(&mut bytes, 5, 2, &[0.5; 10]).unwrap();
+        let mut rest = &bytes[..];
+        assert_eq!(read_shape(&mut rest), Ok((5, 2)));
+        assert_eq!(rest.len(), 10 * 8);
+
+        let mut rest = &bytes[..bytes.len() - 10 * 8 - 1];
+        assert_eq!(read_shape(&mut rest), Err("truncated .npy header".to_owned()));
+        assert_eq!(read_shape(&b"1 2\n3 4\n"[..]), Err("not an .npy file".to_owned()));
+    }
+
+    #[test]
+    fn rejects_other_dtypes() {
+        let mut bytes = Vec::new();
+        write(&mut bytes, 1, 1, &[1.0]).unwrap();
+        let patched = String::from_utf8(bytes[10..bytes.len() - 8].to_vec()).unwrap().replace("<f8", "<i8");
+        bytes[10..10 + patched.len()].copy_from_slice(patched.as_bytes());
+        assert!(read(&bytes).unwrap_err().contains("dtype"));
+    }
+}
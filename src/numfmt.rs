@@ -0,0 +1,184 @@
+//! The one place elements are turned into text and back.
+//!
+//! Every writer that has to be read back exactly (output files, COO
+//! triplets, baselines) goes through `format_f64_roundtrip`, and every
+//! parser goes through `parse_f64`, or `parse_f32` for `--dtype f32`. All
+//! are locale-independent: Rust's float formatting never uses a decimal
+//! comma or digit grouping.
+//!
+//! `parser_survives_noise` stands in for a fuzzer on the parsers alone;
+//! fuzz/ has a cargo-fuzz target for the whole text format.
+
+/// The shortest text that `parse_f64` reads back to the same bits: the
+/// shorter of plain decimal and scientific notation, each with the fewest
+/// digits that round-trip. So 1e-21 is "1e-21", not 22 decimal places,
+/// and 0.5 is "0.5", not "5e-1". -0 is "-0".
+pub(crate) fn format_f64_roundtrip(x: f64) -> String {
+    let plain = x.to_string();
+    let scientific = format!("{:e}", x);
+    if scientific.len() < plain.len() {
+        scientific
+    } else {
+        plain
+    }
+}
+
+/// Parses an element as Rust's `f64::from_str` does: optional sign,
+/// decimal or scientific notation, "inf", "infinity" and "NaN" in any case.
+/// No surrounding whitespace, hex or digit separators.
+#[cfg(feature = "fast-float")]
+pub(crate) fn parse_f64(s: &str) -> Option<f64> {
+    fast_float::parse(s).ok()
+}
+
+#[cfg(not(feature = "fast-float"))]
+pub(crate) fn parse_f64(s: &str) -> Option<f64> {
+    s.parse().ok()
+}
+
+/// `parse_f64`, rounded once to the nearest f32.
+#[cfg(feature = "fast-float")]
+pub(crate) fn parse_f32(s: &str) -> Option<f32> {
+    fast_float::parse(s).ok()
+}
+
+#[cfg(not(feature = "fast-float"))]
+pub(crate) fn parse_f32(s: &str) -> Option<f32> {
+    s.parse().ok()
+}
+
+/// Whether `token`, which parsed to `x`, is an integer (an optional sign
+/// and decimal digits, as many as there are) that `x` does not equal. The
+/// digits are compared with the exact decimal expansion of `x`, so there
+/// is no limit to the integers this knows about.
+pub(crate) fn is_inexact_integer(token: &str, x: f64) -> bool {
+    let digits = token.strip_prefix(['+', '-']).unwrap_or(token);
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return false;
+    }
+    // 2^53 has 16 digits; any shorter integer is exact.
+    let digits = digits.trim_start_matches('0');
+    if digits.len() < 16 {
+        return false;
+    }
+    !x.is_finite() || format!("{:.0}", x.abs()) != digits
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    fn round_trips(x: f64) {
+        let s = format_f64_roundtrip(x);
+        assert_eq!(parse_f64(&s).map(f64::to_bits), Some(x.to_bits()), "{:?} wrote {}", x, s);
+    }
+
+    #[test]
+    fn shortest_form() {
+        assert_eq!(format_f64_roundtrip(1e-21), "1e-21");
+        assert_eq!(format_f64_roundtrip(1e21), "1e21");
+        assert_eq!(format_f64_roundtrip(0.5), "0.5");
+        assert_eq!(format_f64_roundtrip(100.0), "100");
+        assert_eq!(format_f64_roundtrip(1000.0), "1e3");
+        assert_eq!(format_f64_roundtrip(-0.0), "-0");
+        assert_eq!(format_f64_roundtrip(0.1 + 0.2), "0.30000000000000004");
+        assert_eq!(format_f64_roundtrip(f64::NAN), "NaN");
+        assert_eq!(format_f64_roundtrip(f64::NEG_INFINITY), "-inf");
+        for x in [f64::MAX, f64::MIN_POSITIVE, 5e-324, -f64::MIN_POSITIVE / 3.0, f64::INFINITY] {
+            round_trips(x);
+        }
+    }
+
+    #[test]
+    fn inexact_integers_of_any_length() {
+        let inexact = |token: &str| is_inexact_integer(token, parse_f64(token).unwrap());
+        for token in ["9007199254740993", "-9007199254740993", "+9007199254740995", "18446744073709551617"] {
+            assert!(inexact(token), "{}", token);
+        }
+        for token in ["9007199254740992", "18446744073709551616", "0000000000000000001", "1.5", "1e300", "-1"] {
+            assert!(!inexact(token), "{}", token);
+        }
+        // Past i128, and past f64 altogether.
+        let two_to_200 = "1606938044258990275541962092341162602522202993782792835301376";
+        assert!(!inexact(two_to_200));
+        assert!(inexact(&two_to_200.replace("376", "377")));
+        assert!(inexact(&format!("1{}", "0".repeat(300))));
+        assert!(inexact(&format!("1{}", "0".repeat(400))));
+
+        // Against i128 arithmetic, around 2^53 and 2^64.
+        let mut rng = StdRng::seed_from_u64(238);
+        for _ in 0..10_000 {
+            let x: i128 = rng.gen_range(-(1i128 << 66)..1 << 66);
+            let token = x.to_string();
+            assert_eq!(inexact(&token), x as f64 as i128 != x, "{}", token);
+        }
+    }
+
+    // A million random bit patterns, a quarter of them subnormal.
+    #[test]
+    fn random_bits_round_trip() {
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+        let mut subnormals = 0;
+        for i in 0..1_000_000 {
+            let mut bits: u64 = rng.gen();
+            if i % 4 == 0 {
+                bits &= !(0x7ff << 52);
+            }
+            let x = f64::from_bits(bits);
+            if x.is_finite() {
+                subnormals += x.is_subnormal() as usize;
+                round_trips(x);
+            }
+        }
+        assert!(subnormals > 200_000);
+    }
+
+    // Random strings over the characters a number can contain: the parser
+    // must not panic, and whatever it accepts must round-trip.
+    #[test]
+    fn parser_survives_noise() {
+        const ALPHABET: &[u8] = b"0123456789+-.eEinfaNIxX_ ";
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut accepted = 0;
+        for _ in 0..100_000 {
+            let len = rng.gen_range(0..12);
+            let s: String = (0..len).map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char).collect();
+            if let Some(x) = parse_f64(&s) {
+                accepted += 1;
+                // NaN is written without its sign or payload.
+                if x.is_nan() {
+                    assert!(parse_f64(&format_f64_roundtrip(x)).unwrap().is_nan());
+                } else {
+                    round_trips(x);
+                }
+            }
+        }
+        assert!(accepted > 1000, "accepted {}", accepted);
+    }
+
+    #[cfg(feature = "fast-float")]
+    #[test]
+    fn fast_float_matches_std() {
+        let tokens = [
+            "0", "-0", "1", "+1", "1.", ".5", "1e5", "1E-5", "1e", "e5", ".", "", "inf", "-inf",
+            "infinity", "NaN", "nan", "0x10", "1_000", "2.2250738585072014e-308", "4.9e-324",
+            "1.7976931348623157e308", "1e400", "12abc", " 1",
+        ];
+        for token in tokens {
+            let std = token.parse::<f64>().ok();
+            let fast = parse_f64(token);
+            assert_eq!(std.map(f64::to_bits), fast.map(f64::to_bits), "{:?}", token);
+            let std = token.parse::<f32>().ok();
+            let fast = parse_f32(token);
+            assert_eq!(std.map(f32::to_bits), fast.map(f32::to_bits), "{:?}", token);
+        }
+        for _ in 0..10_000 {
+            let x = f64::from_bits(rand::random::<u64>());
+            if x.is_finite() {
+                assert_eq!(parse_f64(&x.to_string()), Some(x));
+            }
+        }
+    }
+}
@@ -0,0 +1,231 @@
+//! Parsed operands kept by the server between requests.
+//!
+//! Operands are keyed by a hash of the text they were parsed from, so
+//! uploading the same matrix twice parses it once. The hash is keyed with
+//! random keys chosen when the process starts, so no client can make
+//! another's text collide with its own, and each entry also keeps the
+//! length and a second, independently keyed hash of its text: a different
+//! text with the same handle is refused rather than given the cached
+//! matrix or allowed to replace it. Each is kept as a
+//! `PreparedMatrix`, so multiplies with it on the left reuse its prepared
+//! structure and panels, which count towards its size. When the matrices held exceed the memory budget, the least
+//! recently used ones are dropped.
+
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::BuildHasher,
+    sync::{Arc, OnceLock},
+};
+
+use crate::prepared::PreparedMatrix;
+
+/// Identifies a cached operand; formatted as 16 hex digits.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub(crate) struct Handle(pub(crate) u64);
+
+// The keys of Handle::of and Fingerprint::of.
+fn keys() -> &'static (RandomState, RandomState) {
+    static KEYS: OnceLock<(RandomState, RandomState)> = OnceLock::new();
+    KEYS.get_or_init(|| (RandomState::new(), RandomState::new()))
+}
+
+impl Handle {
+    /// The same for the same text for as long as the process runs.
+    pub(crate) fn of(text: &str) -> Handle {
+        Handle(keys().0.hash_one(text))
+    }
+
+    pub(crate) fn parse(s: &str) -> Option<Handle> {
+        if s.len() != 16 {
+            return None;
+        }
+        u64::from_str_radix(s, 16).ok().map(Handle)
+    }
+}
+
+impl std::fmt::Display for Handle {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// What an entry remembers of its text, to tell it from another text
+/// with the same handle.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) struct Fingerprint {
+    len: usize,
+    hash: u64,
+}
+
+impl Fingerprint {
+    pub(crate) fn of(text: &str) -> Fingerprint {
+        Fingerprint {
+            len: text.len(),
+            hash: keys().1.hash_one(text),
+        }
+    }
+}
+
+/// A cache lookup by handle and text.
+pub(crate) enum Lookup {
+    Hit(Arc<PreparedMatrix>),
+    Miss,
+    /// The handle holds a different text.
+    Collision,
+}
+
+struct Entry {
+    matrix: Arc<PreparedMatrix>,
+    fingerprint: Fingerprint,
+    last_used: u64,
+}
+
+pub(crate) struct OperandCache {
+    budget_bytes: u64,
+    used_bytes: u64,
+    clock: u64,
+    entries: HashMap<Handle, Entry>,
+}
+
+fn size_of(matrix: &PreparedMatrix) -> u64 {
+    matrix.bytes()
+}
+
+impl OperandCache {
+    pub(crate) fn new(budget_bytes: u64) -> OperandCache {
+        OperandCache {
+            budget_bytes,
+            used_bytes: 0,
+            clock: 0,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn budget_bytes(&self) -> u64 {
+        self.budget_bytes
+    }
+
+    pub(crate) fn get(&mut self, handle: Handle) -> Option<Arc<PreparedMatrix>> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(&handle)?;
+        entry.last_used = self.clock;
+        Some(Arc::clone(&entry.matrix))
+    }
+
+    /// `get`, if the operand under `handle` was parsed from the text with
+    /// `fingerprint`.
+    pub(crate) fn find(&mut self, handle: Handle, fingerprint: Fingerprint) -> Lookup {
+        match self.entries.get(&handle) {
+            None => Lookup::Miss,
+            Some(entry) if entry.fingerprint != fingerprint => Lookup::Collision,
+            Some(_) => Lookup::Hit(self.get(handle).unwrap()),
+        }
+    }
+
+    /// Stores `matrix`, parsed from the text with `fingerprint`, evicting
+    /// older operands to stay within the budget. A matrix larger than the
+    /// whole budget is not stored.
+    pub(crate) fn insert(&mut self, handle: Handle, fingerprint: Fingerprint, matrix: Arc<PreparedMatrix>) -> bool {
+        let size = size_of(&matrix);
+        if size > self.budget_bytes {
+            return false;
+        }
+        if let Some(old) = self.entries.remove(&handle) {
+            self.used_bytes -= size_of(&old.matrix);
+        }
+        while self.used_bytes + size > self.budget_bytes {
+            let oldest = *self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(handle, _)| handle)
+                .expect("used bytes are accounted to entries");
+            let evicted = self.entries.remove(&oldest).unwrap();
+            self.used_bytes -= size_of(&evicted.matrix);
+        }
+
+        self.clock += 1;
+        self.used_bytes += size;
+        self.entries.insert(
+            handle,
+            Entry {
+                matrix,
+                fingerprint,
+                last_used: self.clock,
+            },
+        );
+        true
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Matrix, MultiplyOptions};
+
+    const NONE: Fingerprint = Fingerprint { len: 0, hash: 0 };
+
+    fn square(n: usize) -> Arc<PreparedMatrix> {
+        let matrix = Matrix::new_unchecked(n, n, vec![1.0; n * n]);
+        Arc::new(PreparedMatrix::prepare(&matrix, &MultiplyOptions::new()))
+    }
+
+    #[test]
+    fn handles() {
+        let handle = Handle::of("1 2\n3 4");
+        assert_eq!(handle, Handle::of("1 2\n3 4"));
+        assert_ne!(handle, Handle::of("1 2\n3 5"));
+        assert_eq!(Handle::parse(&handle.to_string()), Some(handle));
+        assert_eq!(Handle::parse("12"), None);
+        assert_eq!(Handle::parse("zzzzzzzzzzzzzzzz"), None);
+    }
+
+    #[test]
+    fn collisions_are_told_apart() {
+        let mut cache = OperandCache::new(1024);
+        let (first, second) = (Fingerprint::of("1 2\n3 4"), Fingerprint::of("5 6\n7 8"));
+        assert_ne!(first, second);
+        assert!(matches!(cache.find(Handle(7), first), Lookup::Miss));
+        assert!(cache.insert(Handle(7), first, square(2)));
+        assert!(matches!(cache.find(Handle(7), first), Lookup::Hit(_)));
+        assert!(matches!(cache.find(Handle(7), second), Lookup::Collision));
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        // Room for exactly four 2x2 matrices, of 32 bytes and a 64-byte
+        // panel each.
+        let mut cache = OperandCache::new(4 * 96);
+        for i in 0..4 {
+            assert!(cache.insert(Handle(i), NONE, square(2)));
+        }
+        assert!(cache.get(Handle(0)).is_some());
+
+        assert!(cache.insert(Handle(4), NONE, square(2)));
+        assert_eq!(cache.len(), 4);
+        assert!(cache.get(Handle(1)).is_none());
+        for i in [0, 2, 3, 4] {
+            assert!(cache.get(Handle(i)).is_some());
+        }
+
+        // 168 bytes for a 3x3 matrix displaces the two least recently used.
+        assert!(cache.insert(Handle(5), NONE, square(3)));
+        assert_eq!(cache.len(), 3);
+        assert!(cache.get(Handle(0)).is_none());
+        assert!(cache.get(Handle(3)).is_some());
+        assert!(cache.get(Handle(4)).is_some());
+        assert!(cache.get(Handle(5)).is_some());
+    }
+
+    #[test]
+    fn too_large_for_budget() {
+        let mut cache = OperandCache::new(16);
+        assert!(!cache.insert(Handle(0), NONE, square(2)));
+        assert_eq!(cache.len(), 0);
+    }
+}
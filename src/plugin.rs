@@ -0,0 +1,176 @@
+//! Semirings loaded from a shared library at runtime (`--plugin PATH`,
+//! behind the `plugins` feature).
+//!
+//! A plugin exports one symbol, `matrix_mul_plugin_v1`, returning a
+//! `PluginV1` by value. A panic cannot cross the boundary (the host cannot
+//! catch another library's unwinding), so `combine` and `reduce` catch their
+//! own panics and return a nonzero status instead; the host then fails the
+//! multiply with `PluginError::Failed`. See plugins/fuzzy-max-min for an
+//! example.
+
+use std::{
+    fmt,
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{semiring::Semiring, Matrix, MatrixError};
+
+pub(crate) const ABI_VERSION: u32 = 1;
+const ENTRY_POINT: &[u8] = b"matrix_mul_plugin_v1";
+
+/// Layout shared with plugins. Fields may only be added at the end, with
+/// `abi_version` bumped.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct PluginV1 {
+    pub(crate) abi_version: u32,
+    pub(crate) identity: f64,
+    /// Writes the result to the third argument and returns 0, or returns
+    /// nonzero on failure.
+    pub(crate) combine: extern "C" fn(f64, f64, *mut f64) -> i32,
+    pub(crate) reduce: extern "C" fn(f64, f64, *mut f64) -> i32,
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum PluginError {
+    Load(libloading::Error),
+    Version { found: u32 },
+    Failed,
+    Matrix(MatrixError),
+}
+
+impl fmt::Display for PluginError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PluginError::Load(err) => write!(f, "cannot load plugin: {}", err),
+            PluginError::Version { found } => write!(
+                f,
+                "plugin ABI version {} is not supported, expected {}",
+                found, ABI_VERSION
+            ),
+            PluginError::Failed => write!(f, "plugin reported a failure"),
+            PluginError::Matrix(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for PluginError {}
+
+pub struct Plugin {
+    table: PluginV1,
+    // Keeps the function pointers in `table` valid.
+    _library: libloading::Library,
+}
+
+impl Plugin {
+    pub fn load(path: &Path) -> Result<Plugin, PluginError> {
+        // Loading runs the library's initializers; the user asked for it.
+        let library = unsafe { libloading::Library::new(path) }.map_err(PluginError::Load)?;
+        let table = unsafe {
+            let entry: libloading::Symbol<extern "C" fn() -> PluginV1> =
+                library.get(ENTRY_POINT).map_err(PluginError::Load)?;
+            entry()
+        };
+        if table.abi_version != ABI_VERSION {
+            return Err(PluginError::Version {
+                found: table.abi_version,
+            });
+        }
+        Ok(Plugin {
+            table,
+            _library: library,
+        })
+    }
+
+    pub fn multiply(&self, a: &Matrix, b: &Matrix) -> Result<Matrix, PluginError> {
+        let call = Call {
+            table: &self.table,
+            failed: AtomicBool::new(false),
+        };
+        let result = a.multiply_semiring(b, &call).map_err(PluginError::Matrix)?;
+        if call.failed.into_inner() {
+            return Err(PluginError::Failed);
+        }
+        Ok(result)
+    }
+}
+
+// One multiply through the plugin. A failed call yields NaN and marks the
+// whole product as failed.
+struct Call<'a> {
+    table: &'a PluginV1,
+    failed: AtomicBool,
+}
+
+impl Call<'_> {
+    fn checked(&self, f: extern "C" fn(f64, f64, *mut f64) -> i32, a: f64, b: f64) -> f64 {
+        let mut out = f64::NAN;
+        if f(a, b, &mut out) != 0 {
+            self.failed.store(true, Ordering::Relaxed);
+        }
+        out
+    }
+}
+
+impl Semiring for Call<'_> {
+    fn identity(&self) -> f64 {
+        self.table.identity
+    }
+
+    fn combine(&self, a: f64, b: f64) -> f64 {
+        self.checked(self.table.combine, a, b)
+    }
+
+    fn reduce(&self, acc: f64, x: f64) -> f64 {
+        self.checked(self.table.reduce, acc, x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::semiring::MaxMin;
+    use std::{path::PathBuf, process::Command};
+
+    // Builds plugins/fuzzy-max-min into its own target directory, so this
+    // does not wait on the lock held by the outer build.
+    fn build_example() -> PathBuf {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let target = root.join("target").join("plugin-test");
+        let status = Command::new(env!("CARGO"))
+            .args(["build", "--quiet", "-p", "fuzzy-max-min", "--target-dir"])
+            .arg(&target)
+            .current_dir(root)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        target.join("debug").join(libloading::library_filename("fuzzy_max_min"))
+    }
+
+    #[test]
+    fn example_plugin_matches_native() {
+        let plugin = Plugin::load(&build_example()).unwrap();
+        let a = Matrix::random(17, 11);
+        let b = Matrix::random(11, 5);
+        assert_eq!(plugin.multiply(&a, &b).unwrap(), a.multiply_semiring(&b, &MaxMin).unwrap());
+
+        // The example panics on NaN, catches it and reports a failure.
+        let mut nan = a.clone();
+        nan.set(3, 4, f64::NAN);
+        assert!(matches!(plugin.multiply(&nan, &b), Err(PluginError::Failed)));
+        assert!(matches!(
+            plugin.multiply(&a, &a),
+            Err(PluginError::Matrix(MatrixError::DimensionMismatch { .. }))
+        ));
+    }
+
+    #[test]
+    fn missing_library() {
+        assert!(matches!(
+            Plugin::load(Path::new("/nonexistent/libnothing.so")),
+            Err(PluginError::Load(_))
+        ));
+    }
+}
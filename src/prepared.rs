@@ -0,0 +1,253 @@
+//! One left operand multiplied by a stream of right ones.
+//!
+//! The work that depends only on the left operand is done once, by
+//! `PreparedMatrix::prepare`: the zero and identity checks of the shortcut
+//! path, each a full pass over the matrix, and packing its rows into
+//! panels. A panel interleaves four rows, so that their elements for one
+//! `k` are adjacent and one pass down a column of the right operand gives
+//! four sums at once. The per-row kernels have one sum in flight and wait
+//! on each addition; packing costs a copy of the left operand, so only a
+//! prepared one is packed. The sums are taken in the same order, and the
+//! products are identical to `Matrix::multiply_with`.
+
+use std::sync::{Arc, OnceLock};
+
+use rayon::prelude::*;
+
+use crate::{invariants, Algorithm, Matrix, MatrixError, MultiplyOptions};
+
+/// Facts about an operand that the shortcut path checks, each computed the
+/// first time it is needed. Several threads may share one.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Structure {
+    scaled_identity: OnceLock<Option<f64>>,
+    zero: OnceLock<bool>,
+    finite: OnceLock<bool>,
+}
+
+impl Structure {
+    /// With every fact already computed.
+    pub(crate) fn of(m: &Matrix) -> Structure {
+        let structure = Structure::default();
+        structure.scaled_identity(m);
+        structure.is_zero(m);
+        structure.is_finite(m);
+        structure
+    }
+
+    pub(crate) fn scaled_identity(&self, m: &Matrix) -> Option<f64> {
+        *self.scaled_identity.get_or_init(|| m.as_scaled_identity())
+    }
+
+    pub(crate) fn is_zero(&self, m: &Matrix) -> bool {
+        *self.zero.get_or_init(|| m.is_zero())
+    }
+
+    pub(crate) fn is_finite(&self, m: &Matrix) -> bool {
+        *self.finite.get_or_init(|| m.data.iter().all(|x| x.is_finite()))
+    }
+}
+
+const PANEL: usize = 4;
+
+/// The rows of a matrix, `PANEL` at a time and interleaved: element `k` of
+/// row `PANEL * p + r` is at `(p * cols + k) * PANEL + r`. The last panel is
+/// padded with zero rows.
+#[derive(Debug)]
+pub(crate) struct Panels {
+    rows: usize,
+    cols: usize,
+    data: Vec<f64>,
+}
+
+impl Panels {
+    fn of(a: &Matrix) -> Panels {
+        let mut data = vec![0.0; a.rows.div_ceil(PANEL) * PANEL * a.cols];
+        for (i, row) in a.data.chunks_exact(a.cols.max(1)).enumerate() {
+            let (p, r) = (i / PANEL, i % PANEL);
+            for (k, &x) in row.iter().enumerate() {
+                data[(p * a.cols + k) * PANEL + r] = x;
+            }
+        }
+        Panels { rows: a.rows, cols: a.cols, data }
+    }
+
+    /// The packed matrix times `b`, whose shape the caller has checked.
+    /// Nothing is reported per row, so this is only for plain products.
+    pub(crate) fn multiply(&self, b: &Matrix, algorithm: Algorithm) -> Matrix {
+        assert_eq!(self.cols, b.rows);
+        let columns = b.transpose();
+        let mut result = Matrix::zeros(self.rows, b.cols);
+        let band = PANEL * b.cols.max(1);
+        let panel = |(p, out): (usize, &mut [f64])| self.multiply_panel(p, &columns, out);
+        match algorithm {
+            Algorithm::Seq => result.data.chunks_mut(band).enumerate().for_each(panel),
+            Algorithm::Par => result.data.par_chunks_mut(band).enumerate().for_each(panel),
+        }
+        result
+    }
+
+    // Rows `PANEL * p..` of the product into `out`, which holds as many of
+    // them as there are. `columns` is the right operand transposed.
+    fn multiply_panel(&self, p: usize, columns: &Matrix, out: &mut [f64]) {
+        invariants::row_band(self.rows, columns.rows, p * PANEL, out.len());
+        invariants::buffer_len(self.rows.div_ceil(PANEL) * PANEL, self.cols, self.data.len());
+        let panel = &self.data[p * PANEL * self.cols..(p + 1) * PANEL * self.cols];
+        for j in 0..columns.rows {
+            let mut sums = [0.0; PANEL];
+            for (a, b) in panel.chunks_exact(PANEL).zip(columns.row(j)) {
+                for (sum, a) in sums.iter_mut().zip(a) {
+                    *sum += a * b;
+                }
+            }
+            for (row, sum) in out.chunks_exact_mut(columns.rows).zip(sums) {
+                row[j] = sum;
+            }
+        }
+    }
+
+    fn bytes(&self) -> u64 {
+        (self.data.len() * std::mem::size_of::<f64>()) as u64
+    }
+}
+
+/// A left operand packed once for many products.
+///
+/// ```
+/// use matrix_mul::{Matrix, MultiplyOptions, PreparedMatrix};
+///
+/// let a = Matrix::random(64, 64);
+/// let prepared = PreparedMatrix::prepare(&a, &MultiplyOptions::new());
+/// for _ in 0..3 {
+///     let b = Matrix::random(64, 8);
+///     assert_eq!(prepared.multiply(&b)?, a.multiply(&b)?);
+/// }
+/// # Ok::<(), matrix_mul::Error>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct PreparedMatrix {
+    matrix: Arc<Matrix>,
+    structure: Structure,
+    panels: Arc<Panels>,
+    options: MultiplyOptions,
+}
+
+impl PreparedMatrix {
+    /// Prepares a copy of `a`, to be multiplied with `options`.
+    pub fn prepare(a: &Matrix, options: &MultiplyOptions) -> PreparedMatrix {
+        PreparedMatrix::from_arc(Arc::new(a.clone()), options)
+    }
+
+    // The server and --watch own their matrices.
+    pub(crate) fn from_arc(a: Arc<Matrix>, options: &MultiplyOptions) -> PreparedMatrix {
+        PreparedMatrix {
+            structure: Structure::of(&a),
+            panels: Arc::new(Panels::of(&a)),
+            matrix: a,
+            options: options.clone(),
+        }
+    }
+
+    pub(crate) fn matrix(&self) -> &Arc<Matrix> {
+        &self.matrix
+    }
+
+    /// The matrix and its panels.
+    pub(crate) fn bytes(&self) -> u64 {
+        (self.matrix.data.len() * std::mem::size_of::<f64>()) as u64 + self.panels.bytes()
+    }
+
+    /// `a * b` with the options given to `prepare`.
+    pub fn multiply(&self, b: &Matrix) -> Result<Matrix, MatrixError> {
+        self.multiply_with(b, &self.options)
+    }
+
+    /// `a * b` with other options, such as a per-call cancel token.
+    pub fn multiply_with(&self, b: &Matrix, options: &MultiplyOptions) -> Result<Matrix, MatrixError> {
+        self.matrix.multiply_structured(b, options, &self.structure, Some(&self.panels)).map(|(m, _)| m)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::{Algorithm, Epilogue};
+
+    #[test]
+    fn prepared_equals_plain() {
+        let cases = [
+            (Matrix::random(20, 30), Matrix::random(30, 25)),
+            (Matrix::random(7, 30), Matrix::random(30, 9)),
+            (Matrix::random(6, 0), Matrix::random(0, 3)),
+            (Matrix::random(5, 3), Matrix::random(3, 0)),
+            (Matrix::identity(6).scale(3.0), Matrix::random(6, 4)),
+            (Matrix::new_unchecked(3, 3, vec![0.0; 9]), Matrix::random(3, 2)),
+            (Matrix::random(5, 1), Matrix::random(1, 7)),
+            (Matrix::random(1, 5), Matrix::random(5, 1)),
+        ];
+        for (a, b) in cases {
+            for options in [
+                MultiplyOptions::new(),
+                MultiplyOptions::new().algorithm(Algorithm::Par).inline_below(0),
+                MultiplyOptions::new().epilogue(Epilogue::Apply(|x| x.max(0.0))),
+            ] {
+                let prepared = PreparedMatrix::prepare(&a, &options);
+                let expected = a.multiply_with(&b, &options).unwrap();
+                for _ in 0..2 {
+                    assert_eq!(prepared.multiply(&b).unwrap().bits(), expected.bits());
+                }
+                let mut infinite = b.clone();
+                if let Some(x) = infinite.data.first_mut() {
+                    *x = f64::INFINITY;
+                }
+                assert_eq!(
+                    prepared.multiply(&infinite).unwrap().bits(),
+                    a.multiply_with(&infinite, &options).unwrap().bits()
+                );
+            }
+        }
+
+        let prepared = PreparedMatrix::prepare(&Matrix::random(4, 4), &MultiplyOptions::new());
+        assert!(matches!(
+            prepared.multiply(&Matrix::random(3, 3)),
+            Err(MatrixError::DimensionMismatch { .. })
+        ));
+    }
+
+    // Per-call time for 50 successive multiplies against one 2048x2048
+    // matrix, prepared and not:
+    // cargo test --release prepared_bench -- --ignored --nocapture
+    //
+    // A random matrix is faster prepared because of its panels. One that is
+    // zero but for its last row also saves the structure checks, each of
+    // which reads the whole matrix. On one core, the random one took 31ms
+    // a call plain and 13ms prepared.
+    #[test]
+    #[ignore]
+    fn prepared_bench() {
+        let options = MultiplyOptions::new().algorithm(Algorithm::Par);
+        let bs: Vec<Matrix> = (0..50).map(|_| Matrix::random(2048, 8)).collect();
+        let mut last_row = Matrix::zeros(2048, 2048);
+        for j in 0..2048 {
+            last_row.set(2047, j, 1.0);
+        }
+
+        for (name, a) in [("random", Matrix::random(2048, 2048)), ("zero but the last row", last_row)] {
+            let prepared = PreparedMatrix::prepare(&a, &options);
+            let start = Instant::now();
+            for b in &bs {
+                a.multiply_with(b, &options).unwrap();
+            }
+            let plain = start.elapsed() / 50;
+            let start = Instant::now();
+            for b in &bs {
+                prepared.multiply(b).unwrap();
+            }
+            let reused = start.elapsed() / 50;
+            println!("{}: per call, plain {:?}, prepared {:?}", name, plain, reused);
+            assert!(reused < plain, "{:?} against {:?}", reused, plain);
+        }
+    }
+}
@@ -0,0 +1,237 @@
+//! `--preview-every`: a summary of the rows finished so far, rewritten
+//! periodically while a long multiply runs.
+//!
+//! The kernels hand each output row to `Preview::observe` once it is
+//! final, through `Context::rows`, and the preview keeps only what it
+//! shows: running statistics and a copy of the first few rows. The writer
+//! thread never touches the result buffer, so it cannot see a row that is
+//! still being computed, and the kernels never wait for it beyond a short
+//! lock per row.
+
+use std::{
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{format, Matrix, Precision};
+
+/// Rows shown in a preview, in the order they finished.
+const ROWS: usize = 4;
+/// Columns shown of each of those rows.
+const COLS: usize = 8;
+
+#[derive(Debug)]
+struct Summary {
+    elements: u64,
+    min: f64,
+    max: f64,
+    sum: f64,
+    /// (row index, its first `COLS` elements)
+    rows: Vec<(usize, Vec<f64>)>,
+}
+
+#[derive(Debug)]
+pub struct Preview {
+    total_rows: usize,
+    cols: usize,
+    completed: AtomicUsize,
+    summary: Mutex<Summary>,
+}
+
+impl Preview {
+    pub fn new(total_rows: usize, cols: usize) -> Preview {
+        Preview {
+            total_rows,
+            cols,
+            completed: AtomicUsize::new(0),
+            summary: Mutex::new(Summary {
+                elements: 0,
+                min: f64::INFINITY,
+                max: f64::NEG_INFINITY,
+                sum: 0.0,
+                rows: Vec::new(),
+            }),
+        }
+    }
+
+    /// Records finished row `i`. Called from the kernel threads.
+    pub fn observe(&self, i: usize, row: &[f64]) {
+        let (min, max) = row.iter().fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), &x| (lo.min(x), hi.max(x)));
+        let sum: f64 = row.iter().sum();
+        let mut summary = self.summary.lock().unwrap();
+        summary.elements += row.len() as u64;
+        summary.min = summary.min.min(min);
+        summary.max = summary.max.max(max);
+        summary.sum += sum;
+        if summary.rows.len() < ROWS {
+            summary.rows.push((i, row[..row.len().min(COLS)].to_vec()));
+        }
+        // Under the lock, so that `render` sees a count and statistics of
+        // the same rows.
+        self.completed.fetch_add(1, Ordering::Release);
+    }
+
+    pub(crate) fn completed(&self) -> usize {
+        self.completed.load(Ordering::Acquire)
+    }
+
+    /// The preview file's contents.
+    pub(crate) fn render(&self) -> String {
+        let summary = self.summary.lock().unwrap();
+        let completed = self.completed();
+        let precision = Precision::Significant(format::PREVIEW_DIGITS);
+        let number = |x| format::Number(x, precision);
+
+        let mut text = format!("rows completed: {} of {}\n", completed, self.total_rows);
+        if summary.elements > 0 {
+            let mean = summary.sum / summary.elements as f64;
+            let _ = writeln!(text, "min: {}", number(summary.min));
+            let _ = writeln!(text, "max: {}", number(summary.max));
+            let _ = writeln!(text, "mean: {}", number(mean));
+        }
+        if !summary.rows.is_empty() {
+            let shown = self.cols.min(COLS);
+            let indices: Vec<String> = summary.rows.iter().map(|(i, _)| i.to_string()).collect();
+            let _ = writeln!(text, "\nrows {}, first {} of {} columns:", indices.join(", "), shown, self.cols);
+            let data = summary.rows.iter().flat_map(|(_, row)| row.iter().copied()).collect();
+            let m = Matrix::new_unchecked(summary.rows.len(), shown, data);
+            let _ = write!(text, "{}", m.display(precision));
+        }
+        text
+    }
+
+    /// Writes `render` to `path` every `interval` until the returned writer
+    /// is dropped, and once more then.
+    pub fn write_every(self: &Arc<Preview>, path: PathBuf, interval: Duration) -> PreviewWriter {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let preview = Arc::clone(self);
+        let thread = thread::spawn(move || loop {
+            let last = !matches!(stopped.recv_timeout(interval), Err(mpsc::RecvTimeoutError::Timeout));
+            if let Err(err) = write_atomically(&path, &preview.render()) {
+                eprintln!("Warning: cannot write preview {}: {}", path.display(), err);
+            }
+            if last {
+                break;
+            }
+        });
+        PreviewWriter {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
+/// Readers of `path` see the old preview or the new one, never half of one.
+fn write_atomically(path: &Path, text: &str) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, text)?;
+    fs::rename(tmp, path)
+}
+
+pub struct PreviewWriter {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+/// Writes the final preview and waits for the thread.
+impl Drop for PreviewWriter {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::{Algorithm, Context, MultiplyOptions};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("matrix-mul-preview-{}-{}", std::process::id(), name))
+    }
+
+    // Finishes one row of `product` every few milliseconds, in a scrambled
+    // order, and returns each distinct preview seen meanwhile with the rows
+    // that had finished when it was read.
+    fn throttled_kernel(product: &Matrix, order: &[usize], preview: &Preview, path: &Path) -> Vec<(String, usize)> {
+        let mut previews: Vec<(String, usize)> = Vec::new();
+        for (done, &i) in order.iter().enumerate() {
+            preview.observe(i, product.row(i));
+            let start = Instant::now();
+            while start.elapsed() < Duration::from_millis(15) {
+                if let Ok(text) = fs::read_to_string(path) {
+                    if previews.last().map(|p| &p.0) != Some(&text) {
+                        previews.push((text, done + 1));
+                    }
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        previews
+    }
+
+    // What a preview of the first `n` rows of `order` must say.
+    fn expected(product: &Matrix, order: &[usize], n: usize) -> String {
+        let finished = &order[..n];
+        let elements: Vec<f64> = finished.iter().flat_map(|&i| product.row(i).iter().copied()).collect();
+        let min = elements.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = elements.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let mean = elements.iter().sum::<f64>() / elements.len() as f64;
+        let shown: Vec<String> = finished.iter().take(ROWS).map(|i| i.to_string()).collect();
+        let mean = format::Number(mean, Precision::Significant(format::PREVIEW_DIGITS));
+        format!("rows completed: {} of 10\nmin: {}\nmax: {}\nmean: {}\n\nrows {}, first 3 of 3 columns:\n", n, min, max, mean, shown.join(", "))
+    }
+
+    #[test]
+    fn previews_show_only_completed_rows() {
+        let product = Matrix::new_unchecked(10, 3, (0..30).map(|x| x as f64).collect());
+        let order: Vec<usize> = (0..10).map(|i| (i * 7) % 10).collect();
+        let path = temp_path("throttled.txt");
+        let preview = Arc::new(Preview::new(10, 3));
+        let writer = preview.write_every(path.clone(), Duration::from_millis(5));
+        let previews = throttled_kernel(&product, &order, &preview, &path);
+        drop(writer);
+
+        assert!(previews.len() > 2, "{:?}", previews);
+        for (text, finished) in &previews {
+            let count: usize = text["rows completed: ".len()..].split(' ').next().unwrap().parse().unwrap();
+            assert!(count >= 1 && count <= *finished, "{} rows finished:\n{}", finished, text);
+            assert!(text.starts_with(&expected(&product, &order, count)), "{}", text);
+        }
+
+        let last = fs::read_to_string(&path).unwrap();
+        assert!(last.starts_with(&expected(&product, &order, 10)), "{}", last);
+        assert!(last.ends_with("rows 0, 7, 4, 1, first 3 of 3 columns:\n0 1 2\n21 22 23\n12 13 14\n3 4 5\n"), "{}", last);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn final_output_is_unaffected() {
+        let a = Matrix::random(60, 40);
+        let b = Matrix::random(40, 30);
+        for algorithm in [Algorithm::Seq, Algorithm::Par] {
+            let preview = Arc::new(Preview::new(60, 30));
+            let observer = Arc::clone(&preview);
+            let context = Context::new().rows(move |i, row| observer.observe(i, row));
+            let options = MultiplyOptions::new().algorithm(algorithm).inline_below(0);
+            let product = a.multiply_with(&b, &options.clone().context(context)).unwrap();
+            assert_eq!(product, a.multiply_with(&b, &options).unwrap());
+            assert_eq!(preview.completed(), 60);
+            let rendered = preview.render();
+            assert!(rendered.starts_with("rows completed: 60 of 60\n"), "{}", rendered);
+            assert!(rendered.contains("first 8 of 30 columns"), "{}", rendered);
+        }
+    }
+}
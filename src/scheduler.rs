@@ -0,0 +1,255 @@
+//! `--concurrent-jobs`: several multiplies sharing one rayon pool.
+//!
+//! Each job asks for as many tasks as it can use, about one per
+//! `MIN_TASK_FLOPS` of work and never more than its rows, and is admitted
+//! with as many of the pool's threads as are free, so the tasks of all
+//! running jobs roughly add up to the core count. Jobs are admitted in the
+//! order they arrived: a job waits while an older one is waiting, and the
+//! jobs already running keep the threads they were given. A lone large job
+//! gets the whole pool; small ones run side by side.
+//!
+//! Cancel tokens, progress and the other hooks stay with each job's own
+//! `MultiplyOptions`; the scheduler only adds `Context::tasks`.
+
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{Condvar, Mutex},
+};
+
+use crate::MultiplyOptions;
+
+/// The least work worth a task of its own, in floating-point operations.
+const MIN_TASK_FLOPS: u64 = 1 << 17;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ConcurrentJobs {
+    /// As many as the pool has threads.
+    #[default]
+    Auto,
+    Count(usize),
+}
+
+impl FromStr for ConcurrentJobs {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ConcurrentJobs, String> {
+        match s {
+            "auto" => Ok(ConcurrentJobs::Auto),
+            _ => match s.parse::<usize>() {
+                Ok(n) if n > 0 => Ok(ConcurrentJobs::Count(n)),
+                _ => Err(format!("expected auto or a positive number of jobs, found '{}'", s)),
+            },
+        }
+    }
+}
+
+impl fmt::Display for ConcurrentJobs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConcurrentJobs::Auto => write!(f, "auto"),
+            ConcurrentJobs::Count(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+/// Tasks a multiply of an n x m matrix by an m x k one can use.
+pub(crate) fn tasks_for(n: usize, m: usize, k: usize) -> usize {
+    let flops = 2 * (n as u64) * (m as u64) * (k as u64);
+    (flops / MIN_TASK_FLOPS).clamp(1, n.max(1) as u64) as usize
+}
+
+#[derive(Debug)]
+struct State {
+    /// Tickets are handed out in arrival order and admitted in that order.
+    next_ticket: u64,
+    next_admitted: u64,
+    running: usize,
+    free_threads: usize,
+}
+
+#[derive(Debug)]
+pub(crate) struct Scheduler {
+    threads: usize,
+    max_jobs: usize,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl Scheduler {
+    /// For a pool of `threads` threads.
+    pub(crate) fn new(threads: usize, jobs: ConcurrentJobs) -> Scheduler {
+        let threads = threads.max(1);
+        let max_jobs = match jobs {
+            ConcurrentJobs::Auto => threads,
+            ConcurrentJobs::Count(n) => n.max(1),
+        };
+        Scheduler {
+            threads,
+            max_jobs,
+            state: Mutex::new(State {
+                next_ticket: 0,
+                next_admitted: 0,
+                running: 0,
+                free_threads: threads,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Waits for a turn and returns it with up to `wanted` tasks. The tasks
+    /// go back to the pool when the slot is dropped.
+    pub(crate) fn admit(&self, wanted: usize) -> Slot<'_> {
+        let mut state = self.state.lock().unwrap();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state = self
+            .changed
+            .wait_while(state, |s| s.next_admitted != ticket || s.running == self.max_jobs || s.free_threads == 0)
+            .unwrap();
+        let tasks = wanted.clamp(1, state.free_threads);
+        state.next_admitted += 1;
+        state.running += 1;
+        state.free_threads -= tasks;
+        drop(state);
+        // The next ticket may fit in what is left.
+        self.changed.notify_all();
+        Slot { scheduler: self, tasks }
+    }
+}
+
+/// A running job's share of the pool.
+#[derive(Debug)]
+pub(crate) struct Slot<'a> {
+    scheduler: &'a Scheduler,
+    tasks: usize,
+}
+
+impl Slot<'_> {
+    #[cfg(test)]
+    pub(crate) fn tasks(&self) -> usize {
+        self.tasks
+    }
+
+    /// `options` limited to this slot's share.
+    pub(crate) fn options(&self, options: &MultiplyOptions) -> MultiplyOptions {
+        let context = options.context.clone().tasks(self.tasks);
+        options.clone().context(context)
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        let mut state = self.scheduler.state.lock().unwrap();
+        state.running -= 1;
+        state.free_threads = (state.free_threads + self.tasks).min(self.scheduler.threads);
+        drop(state);
+        self.scheduler.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{mpsc, Arc},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use rayon::prelude::*;
+
+    use super::*;
+    use crate::{cancel::CancelToken, Algorithm, Matrix, MatrixError};
+
+    // A job of `units` pieces of 20ms work on `pool`, split into the tasks
+    // the scheduler gives it.
+    fn fake_job(pool: &rayon::ThreadPool, scheduler: &Scheduler, units: usize) {
+        let slot = scheduler.admit(units);
+        let per_task = units.div_ceil(slot.tasks());
+        pool.install(|| {
+            (0..units).into_par_iter().with_min_len(per_task).for_each(|_| thread::sleep(Duration::from_millis(20)))
+        });
+    }
+
+    fn two_jobs(jobs: ConcurrentJobs) -> Duration {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let scheduler = Scheduler::new(4, jobs);
+        let start = Instant::now();
+        thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| fake_job(&pool, &scheduler, 2));
+            }
+        });
+        start.elapsed()
+    }
+
+    #[test]
+    fn small_jobs_share_the_pool() {
+        let serial = two_jobs(ConcurrentJobs::Count(1));
+        let concurrent = two_jobs(ConcurrentJobs::Auto);
+        assert!(serial >= Duration::from_millis(40), "{:?}", serial);
+        assert!(concurrent * 4 < serial * 3, "concurrent {:?}, serial {:?}", concurrent, serial);
+    }
+
+    #[test]
+    fn older_jobs_keep_their_threads() {
+        let scheduler = Arc::new(Scheduler::new(4, ConcurrentJobs::Auto));
+        let first = scheduler.admit(3);
+        let second = scheduler.admit(3);
+        assert_eq!((first.tasks(), second.tasks()), (3, 1));
+
+        // Nothing is free, so the third waits, and so does a fourth that
+        // arrives after it. When three threads come back the third takes
+        // all of them, and the fourth waits for the third to finish.
+        let (admitted, order) = mpsc::channel();
+        let waiters: Vec<_> = [(3, "third"), (1, "fourth")]
+            .into_iter()
+            .map(|(wanted, name)| {
+                let (scheduler, admitted) = (Arc::clone(&scheduler), admitted.clone());
+                let waiter = thread::spawn(move || {
+                    let slot = scheduler.admit(wanted);
+                    admitted.send((name, slot.tasks())).unwrap();
+                });
+                thread::sleep(Duration::from_millis(50));
+                waiter
+            })
+            .collect();
+        assert!(order.try_recv().is_err());
+        drop(first);
+        assert_eq!(order.recv().unwrap(), ("third", 3));
+        assert_eq!(order.recv().unwrap(), ("fourth", 1));
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+        drop(second);
+        assert_eq!(scheduler.admit(10).tasks(), 4);
+        assert_eq!(tasks_for(1, 1000, 1000), 1);
+        assert_eq!(tasks_for(512, 512, 512), 512);
+        assert_eq!(tasks_for(64, 64, 64), 4);
+    }
+
+    #[test]
+    fn jobs_are_independent() {
+        let scheduler = Scheduler::new(4, ConcurrentJobs::Auto);
+        let a = Matrix::random(80, 60);
+        let b = Matrix::random(60, 50);
+        let options = MultiplyOptions::new().algorithm(Algorithm::Par).inline_below(0);
+        let cancelled = CancelToken::new();
+        cancelled.cancel();
+        thread::scope(|s| {
+            let good = s.spawn(|| {
+                let slot = scheduler.admit(tasks_for(80, 60, 50));
+                a.multiply_with(&b, &slot.options(&options))
+            });
+            let bad = s.spawn(|| {
+                let slot = scheduler.admit(tasks_for(80, 60, 50));
+                a.multiply_with(&b, &slot.options(&options.clone().cancel_token(cancelled.clone())))
+            });
+            assert_eq!(good.join().unwrap().unwrap(), a.multiply(&b).unwrap());
+            assert!(matches!(bad.join().unwrap(), Err(MatrixError::Cancelled { rows_completed: 0 })));
+        });
+        assert_eq!("auto".parse::<ConcurrentJobs>(), Ok(ConcurrentJobs::Auto));
+        assert_eq!("3".parse::<ConcurrentJobs>(), Ok(ConcurrentJobs::Count(3)));
+        assert!("0".parse::<ConcurrentJobs>().is_err());
+    }
+}
@@ -0,0 +1,98 @@
+//! Matrix products over other semirings.
+//!
+//! `multiply_semiring` computes `c_ij = reduce(... reduce(identity,
+//! combine(a_i0, b_0j)) ..., combine(a_ik, b_kj))`. With `Arithmetic` that
+//! is the ordinary product, summed in the same order as `Matrix::multiply`.
+
+use rayon::prelude::*;
+
+use crate::{Matrix, MatrixError};
+
+pub(crate) trait Semiring: Sync {
+    /// Identity of `reduce`, the value of an empty reduction.
+    fn identity(&self) -> f64;
+    fn combine(&self, a: f64, b: f64) -> f64;
+    fn reduce(&self, acc: f64, x: f64) -> f64;
+}
+
+/// The usual (+, *).
+pub(crate) struct Arithmetic;
+
+impl Semiring for Arithmetic {
+    fn identity(&self) -> f64 {
+        0.0
+    }
+
+    fn combine(&self, a: f64, b: f64) -> f64 {
+        a * b
+    }
+
+    fn reduce(&self, acc: f64, x: f64) -> f64 {
+        acc + x
+    }
+}
+
+/// (max, min), the composition of fuzzy relations with values in [0, 1].
+pub(crate) struct MaxMin;
+
+impl Semiring for MaxMin {
+    fn identity(&self) -> f64 {
+        0.0
+    }
+
+    fn combine(&self, a: f64, b: f64) -> f64 {
+        a.min(b)
+    }
+
+    fn reduce(&self, acc: f64, x: f64) -> f64 {
+        acc.max(x)
+    }
+}
+
+impl Matrix {
+    pub(crate) fn multiply_semiring(&self, other: &Matrix, ring: &impl Semiring) -> Result<Matrix, MatrixError> {
+        if self.cols != other.rows {
+            return Err(MatrixError::DimensionMismatch {
+                left: self.shape(),
+                right: other.shape(),
+            });
+        }
+
+        let mut result = Matrix::new_unchecked(self.rows, other.cols, vec![0.0; self.rows * other.cols]);
+        result
+            .data
+            .par_chunks_mut(other.cols.max(1))
+            .enumerate()
+            .for_each(|(i, row)| {
+                for (j, cell) in row.iter_mut().enumerate() {
+                    let mut acc = ring.identity();
+                    for k in 0..self.cols {
+                        acc = ring.reduce(acc, ring.combine(self.get(i, k), other.get(k, j)));
+                    }
+                    *cell = acc;
+                }
+            });
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arithmetic_is_multiply() {
+        let a = Matrix::random(13, 7);
+        let b = Matrix::random(7, 9);
+        assert_eq!(a.multiply_semiring(&b, &Arithmetic).unwrap(), a.multiply(&b).unwrap());
+        assert!(a.multiply_semiring(&a, &Arithmetic).is_err());
+    }
+
+    #[test]
+    fn max_min() {
+        let a = Matrix::new_unchecked(2, 2, vec![0.2, 0.9, 1.0, 0.0]);
+        let b = Matrix::new_unchecked(2, 2, vec![0.5, 0.3, 0.7, 0.8]);
+        let expected = Matrix::new_unchecked(2, 2, vec![0.7, 0.8, 0.5, 0.3]);
+        assert_eq!(a.multiply_semiring(&b, &MaxMin).unwrap(), expected);
+    }
+}
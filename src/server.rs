@@ -0,0 +1,1069 @@
+//! A small HTTP multiply service, started with `--serve ADDR`.
+//!
+//! `POST /multiply` takes a body in the input file format (two matrices
+//! separated by `X`) and answers `200` with the product in the output file
+//! format. `PUT /operands` takes a single matrix, keeps it parsed and
+//! answers `201` with `{"handle":"0123456789abcdef","rows":2,"cols":3}`;
+//! either operand of a multiply may then be given as `@0123456789abcdef`.
+//! Failures are answered with a JSON object such as
+//! `{"status":422,"error":"dimension_mismatch","message":"...","left":[2,3],"right":[4,5]}`:
+//!
+//! - `400` malformed request or matrix text
+//! - `404` / `405` unknown path or method, or an operand handle that is not
+//!   cached (`"error":"unknown_operand"`)
+//! - `408` request not received, or product not computed, within the limits
+//! - `409` an upload whose handle already holds a different matrix
+//! - `411` no Content-Length
+//! - `413` body or a matrix above the configured limits
+//! - `422` shapes that cannot be multiplied
+//! - `431` request head too large
+//! - `500` the computation panicked
+//!
+//! A multiply sent with `Accept: application/octet-stream` or
+//! `Accept: application/x-ndjson` is answered with the product streamed in
+//! chunks, a band of rows at a time, instead of formatted whole: in the
+//! native binary format, or as JSON lines, `{"rows":2,"cols":3}` and then
+//! one array per row, with `null` for elements that are not finite. Only a
+//! few bands are ever held: the bands go through a bounded channel to the
+//! connection, so a client that reads slowly pauses the computation, and
+//! one that goes away cancels it. Errors found before the first band are
+//! answered as above; after it, the status has been sent, and a failure
+//! ends the body without its last, empty chunk.
+//!
+//! Limits are checked before anything proportional to them is allocated:
+//! the body size from Content-Length, and every matrix size from a scan of
+//! the body before parsing. One connection carries one request.
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    sync::Semaphore,
+};
+
+use crate::{
+    binary::{self, ByteOrder},
+    cancel::CancelToken,
+    events::quote,
+    operand_cache::{Fingerprint, Handle, Lookup, OperandCache},
+    prepared::PreparedMatrix,
+    scheduler::{self, ConcurrentJobs, Scheduler},
+    text_reader, Algorithm, Matrix, MatrixError, MultiplyOptions, ParseOptions,
+};
+
+const MAX_HEAD_BYTES: usize = 8 * 1024;
+
+#[derive(Clone, Debug)]
+pub struct Limits {
+    pub max_body_bytes: u64,
+    /// Per matrix, including the product.
+    pub max_elements: u64,
+    /// For receiving the whole request.
+    pub read_timeout: Duration,
+    /// For computing the product, enforced through a CancelToken. A
+    /// streamed product has until its last band is sent, however slowly
+    /// the client reads.
+    pub compute_timeout: Duration,
+    /// Requests handled at the same time; further requests wait for a slot.
+    pub max_concurrent: usize,
+    /// Of those, products computed at the same time on the shared pool.
+    pub concurrent_jobs: ConcurrentJobs,
+    /// Memory for operands uploaded with PUT /operands.
+    pub operand_cache_bytes: u64,
+    /// Elements in each band of a streamed product.
+    pub stream_band_elements: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_body_bytes: 64 << 20,
+            max_elements: 16 << 20,
+            read_timeout: Duration::from_secs(10),
+            compute_timeout: Duration::from_secs(30),
+            max_concurrent: 4,
+            concurrent_jobs: ConcurrentJobs::Auto,
+            operand_cache_bytes: 256 << 20,
+            stream_band_elements: 64 * 1024,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Route {
+    Multiply { stream: Option<StreamFormat> },
+    PutOperand,
+}
+
+/// How a streamed product is written, from the request's Accept header.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum StreamFormat {
+    Binary,
+    JsonLines,
+}
+
+impl StreamFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            StreamFormat::Binary => "application/octet-stream",
+            StreamFormat::JsonLines => "application/x-ndjson",
+        }
+    }
+
+    fn from_accept(accept: &str) -> Option<StreamFormat> {
+        [StreamFormat::Binary, StreamFormat::JsonLines]
+            .into_iter()
+            .find(|format| accept.split(',').any(|media| media.split(';').next().unwrap().trim() == format.content_type()))
+    }
+
+    fn write_head(self, rows: usize, cols: usize, out: &mut Vec<u8>) {
+        match self {
+            StreamFormat::Binary => out.extend_from_slice(&binary::header(rows, cols, ByteOrder::Little)),
+            StreamFormat::JsonLines => out.extend_from_slice(format!("{{\"rows\":{},\"cols\":{}}}\n", rows, cols).as_bytes()),
+        }
+    }
+
+    fn write_band(self, band: &Matrix, out: &mut Vec<u8>) {
+        match self {
+            StreamFormat::Binary => band.data.iter().for_each(|x| out.extend_from_slice(&x.to_le_bytes())),
+            StreamFormat::JsonLines => {
+                for row in band.data.chunks(band.cols.max(1)).take(band.rows) {
+                    out.push(b'[');
+                    for (j, x) in row.iter().enumerate() {
+                        if j > 0 {
+                            out.push(b',');
+                        }
+                        if x.is_finite() {
+                            out.extend_from_slice(format!("{:?}", x).as_bytes());
+                        } else {
+                            out.extend_from_slice(b"null");
+                        }
+                    }
+                    out.extend_from_slice(b"]\n");
+                }
+            }
+        }
+    }
+}
+
+// Encoded bands waiting for the connection; the computation stops until
+// one of them is sent.
+const STREAM_IN_FLIGHT: usize = 2;
+
+pub struct Server {
+    limits: Limits,
+    algorithm: Algorithm,
+    slots: Semaphore,
+    scheduler: Scheduler,
+    operands: Mutex<OperandCache>,
+    // Matrices parsed so far, so tests can see what the cache saved, and
+    // bands of streamed products computed, so they can see a stream stop.
+    pub(crate) parses: AtomicUsize,
+    bands: AtomicUsize,
+}
+
+// Either operand of a multiply.
+enum Operand<'a> {
+    Cached(Arc<PreparedMatrix>),
+    Inline(&'a str),
+}
+
+impl Operand<'_> {
+    fn shape(&self) -> (usize, usize) {
+        match self {
+            Operand::Cached(prepared) => prepared.matrix().shape(),
+            Operand::Inline(text) => scan_shape(text),
+        }
+    }
+}
+
+impl Server {
+    pub fn new(limits: Limits, algorithm: Algorithm) -> Server {
+        let slots = Semaphore::new(limits.max_concurrent.max(1));
+        let operands = Mutex::new(OperandCache::new(limits.operand_cache_bytes));
+        let scheduler = Scheduler::new(rayon::current_num_threads(), limits.concurrent_jobs);
+        Server {
+            limits,
+            algorithm,
+            slots,
+            scheduler,
+            operands,
+            parses: AtomicUsize::new(0),
+            bands: AtomicUsize::new(0),
+        }
+    }
+
+    pub async fn serve(self: Arc<Self>, addr: SocketAddr) -> io::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        println!("Listening on http://{}", listener.local_addr()?);
+        self.serve_on(listener).await
+    }
+
+    /// `serve` on a listener already bound, such as one on port 0.
+    pub async fn serve_on(self: Arc<Self>, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let server = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(err) = server.handle_connection(stream).await {
+                    eprintln!("Connection from {}: {}", peer, err);
+                }
+            });
+        }
+    }
+
+    pub(crate) async fn handle_connection(
+        self: Arc<Self>,
+        mut stream: impl AsyncRead + AsyncWrite + Unpin,
+    ) -> io::Result<()> {
+        let request =
+            tokio::time::timeout(self.limits.read_timeout, read_request(&mut stream, &self.limits)).await;
+        let response = match request {
+            Err(_) => Response::error(408, "request_timeout", "request not received in time"),
+            Ok(Err(response)) => response,
+            Ok(Ok((Route::Multiply { stream: Some(format) }, body))) => {
+                match self.stream_multiply(format, body, &mut stream).await? {
+                    Some(response) => response,
+                    None => return stream.shutdown().await,
+                }
+            }
+            Ok(Ok((route, body))) => self.respond(route, body).await,
+        };
+        stream.write_all(&response.to_bytes()).await?;
+        stream.shutdown().await
+    }
+
+    // A multiply answered in chunks. Returns the response to send instead
+    // if it fails before the first band.
+    async fn stream_multiply(
+        self: Arc<Self>,
+        format: StreamFormat,
+        body: Vec<u8>,
+        stream: &mut (impl AsyncWrite + Unpin),
+    ) -> io::Result<Option<Response>> {
+        let _permit = match self.slots.acquire().await {
+            Ok(permit) => permit,
+            Err(_) => return Ok(Some(Response::error(500, "internal", "server is shutting down"))),
+        };
+        let server = Arc::clone(&self);
+        let operands = tokio::task::spawn_blocking(move || {
+            let (a, b) = server.multiply_operands(&body)?;
+            Ok::<_, Response>((server.resolve(a)?, server.resolve(b)?))
+        });
+        let (a, b) = match operands.await {
+            Ok(Ok(operands)) => operands,
+            Ok(Err(response)) => return Ok(Some(response)),
+            Err(_) => return Ok(Some(Response::error(500, "internal", "the computation panicked"))),
+        };
+
+        let head = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n",
+            format.content_type()
+        );
+        stream.write_all(head.as_bytes()).await?;
+        let (bands, mut received) = tokio::sync::mpsc::channel(STREAM_IN_FLIGHT);
+        let (recycle, recycled) = std::sync::mpsc::channel();
+        let token = CancelToken::with_timeout(self.limits.compute_timeout);
+        let server = Arc::clone(&self);
+        let cancel = token.clone();
+        let producer =
+            tokio::task::spawn_blocking(move || server.stream_product(&a, &b, format, &cancel, &bands, &recycled));
+
+        let mut sent = Ok(());
+        while let Some(chunk) = received.recv().await {
+            sent = write_chunk(stream, &chunk).await;
+            if sent.is_err() {
+                // Gone: stop computing rather than at the next band.
+                token.cancel();
+                break;
+            }
+            let _ = recycle.send(chunk);
+        }
+        drop(received);
+        let computed = producer.await;
+        sent?;
+        if let Ok(Ok(())) = computed {
+            stream.write_all(b"0\r\n\r\n").await?;
+        }
+        Ok(None)
+    }
+
+    // Computes `a * b` a band of rows at a time, sending each band encoded
+    // in a buffer `recycled` hands back once it is written. Stops when the
+    // connection is gone or `token` is cancelled.
+    fn stream_product(
+        &self,
+        a: &Matrix,
+        b: &Matrix,
+        format: StreamFormat,
+        token: &CancelToken,
+        bands: &tokio::sync::mpsc::Sender<Vec<u8>>,
+        recycled: &std::sync::mpsc::Receiver<Vec<u8>>,
+    ) -> Result<(), MatrixError> {
+        let gone = || MatrixError::from(io::Error::new(io::ErrorKind::BrokenPipe, "the client went away"));
+        let mut buffer = Vec::new();
+        format.write_head(a.rows, b.cols, &mut buffer);
+        bands.blocking_send(buffer).map_err(|_| gone())?;
+
+        let band_rows = (self.limits.stream_band_elements / b.cols.max(1)).max(1);
+        for start in (0..a.rows).step_by(band_rows) {
+            let end = (start + band_rows).min(a.rows);
+            let band = Matrix::new_unchecked(end - start, a.cols, a.data[start * a.cols..end * a.cols].to_vec());
+            let wanted = match self.algorithm {
+                Algorithm::Seq => 1,
+                Algorithm::Par => scheduler::tasks_for(band.rows, band.cols, b.cols),
+            };
+            // Admitted a band at a time, so that a slow client does not keep
+            // a share of the pool it is not using.
+            let slot = self.scheduler.admit(wanted);
+            let options = slot.options(&MultiplyOptions::new().algorithm(self.algorithm).cancel_token(token.clone()));
+            let product = band.multiply_with(b, &options).map_err(|err| match err {
+                MatrixError::Cancelled { rows_completed } => MatrixError::Cancelled { rows_completed: start + rows_completed },
+                err => err,
+            })?;
+            drop(slot);
+            self.bands.fetch_add(1, Ordering::Relaxed);
+
+            let mut buffer = recycled.try_recv().unwrap_or_default();
+            buffer.clear();
+            format.write_band(&product, &mut buffer);
+            bands.blocking_send(buffer).map_err(|_| gone())?;
+        }
+        Ok(())
+    }
+
+    async fn respond(self: Arc<Self>, route: Route, body: Vec<u8>) -> Response {
+        // The permit is held until the computation returns, which the
+        // compute timeout bounds.
+        let _permit = match self.slots.acquire().await {
+            Ok(permit) => permit,
+            Err(_) => return Response::error(500, "internal", "server is shutting down"),
+        };
+        let server = Arc::clone(&self);
+        let handled = tokio::task::spawn_blocking(move || match route {
+            Route::Multiply { .. } => server.multiply_request(&body).map(|matrix| Response {
+                status: 200,
+                content_type: "text/plain",
+                body: format!("{}", matrix),
+            }),
+            Route::PutOperand => server.put_operand(&body).map(|(handle, (rows, cols))| Response {
+                status: 201,
+                content_type: "application/json",
+                body: format!(
+                    "{{\"handle\":\"{}\",\"rows\":{},\"cols\":{}}}",
+                    handle, rows, cols
+                ),
+            }),
+        });
+        match handled.await {
+            Ok(Ok(response)) | Ok(Err(response)) => response,
+            Err(_) => Response::error(500, "internal", "the computation panicked"),
+        }
+    }
+
+    fn multiply_request(&self, body: &[u8]) -> Result<Matrix, Response> {
+        let (a, b) = self.multiply_operands(body)?;
+        let (left, right) = (a.shape(), b.shape());
+        let wanted = match self.algorithm {
+            Algorithm::Seq => 1,
+            Algorithm::Par => scheduler::tasks_for(left.0, left.1, right.1),
+        };
+        let slot = self.scheduler.admit(wanted);
+        let options = MultiplyOptions::new()
+            .algorithm(self.algorithm)
+            .cancel_token(CancelToken::with_timeout(self.limits.compute_timeout));
+        let options = slot.options(&options);
+        match a {
+            Operand::Cached(prepared) => {
+                let b = self.resolve(b)?;
+                Ok(prepared.multiply_with(&b, &options)?)
+            }
+            a => {
+                let (a, b) = (self.resolve(a)?, self.resolve(b)?);
+                Ok(a.multiply_with(&b, &options)?)
+            }
+        }
+    }
+
+    // The operands of a multiply, checked against the limits and each
+    // other but not yet parsed.
+    fn multiply_operands<'a>(&self, body: &'a [u8]) -> Result<(Operand<'a>, Operand<'a>), Response> {
+        let text = utf8(body)?;
+        let texts = text_reader::split_operands(text);
+        if texts.len() != 2 {
+            return Err(Response::error(
+                400,
+                "bad_request",
+                "expected two matrices separated by X",
+            ));
+        }
+        let (a, b) = (self.operand(texts[0])?, self.operand(texts[1])?);
+
+        let (left, right) = (a.shape(), b.shape());
+        for (name, shape) in [("first", left), ("second", right), ("product", (left.0, right.1))] {
+            self.check_elements(name, shape)?;
+        }
+        if left.1 != right.0 {
+            return Err(MatrixError::DimensionMismatch { left, right }.into());
+        }
+        Ok((a, b))
+    }
+
+    // Under one lock from lookup to insert, so that the same text uploaded
+    // twice at once is parsed once; other uploads, and multiplies naming a
+    // cached operand, wait for the parse.
+    fn put_operand(&self, body: &[u8]) -> Result<(Handle, (usize, usize)), Response> {
+        let text = utf8(body)?.trim();
+        let (handle, fingerprint) = (Handle::of(text), Fingerprint::of(text));
+        let mut operands = self.operands.lock().unwrap();
+        match operands.find(handle, fingerprint) {
+            Lookup::Hit(prepared) => return Ok((handle, prepared.matrix().shape())),
+            Lookup::Collision => {
+                return Err(Response::error(
+                    409,
+                    "handle_collision",
+                    &format!("handle {} already holds a different matrix", handle),
+                ))
+            }
+            Lookup::Miss => {}
+        }
+
+        self.check_elements("uploaded", scan_shape(text))?;
+        let matrix = self.parse(text)?;
+        let shape = matrix.shape();
+        let prepared = PreparedMatrix::from_arc(matrix, &MultiplyOptions::new().algorithm(self.algorithm));
+        if !operands.insert(handle, fingerprint, Arc::new(prepared)) {
+            return Err(Response::error(
+                413,
+                "operand_too_large",
+                &format!(
+                    "{}x{} matrix does not fit the operand cache of {} bytes",
+                    shape.0,
+                    shape.1,
+                    operands.budget_bytes()
+                ),
+            ));
+        }
+        Ok((handle, shape))
+    }
+
+    // `@handle` refers to a cached operand; anything else is matrix text.
+    fn operand<'a>(&self, text: &'a str) -> Result<Operand<'a>, Response> {
+        let name = match text.strip_prefix('@') {
+            Some(name) => name.trim(),
+            None => return Ok(Operand::Inline(text)),
+        };
+        Handle::parse(name)
+            .and_then(|handle| self.operands.lock().unwrap().get(handle))
+            .map(Operand::Cached)
+            .ok_or_else(|| {
+                Response::error_with(
+                    404,
+                    "unknown_operand",
+                    &format!("no cached operand '{}'", name),
+                    &[("handle", quote(name))],
+                )
+            })
+    }
+
+    fn resolve(&self, operand: Operand) -> Result<Arc<Matrix>, Response> {
+        match operand {
+            Operand::Cached(prepared) => Ok(Arc::clone(prepared.matrix())),
+            Operand::Inline(text) => self.parse(text),
+        }
+    }
+
+    fn parse(&self, text: &str) -> Result<Arc<Matrix>, Response> {
+        self.parses.fetch_add(1, Ordering::Relaxed);
+        Ok(Arc::new(Matrix::from_string_with(text, &ParseOptions::default())?.0))
+    }
+
+    fn check_elements(&self, name: &str, shape: (usize, usize)) -> Result<(), Response> {
+        let elements = (shape.0 as u64).checked_mul(shape.1 as u64);
+        if elements.is_none_or(|e| e > self.limits.max_elements) {
+            return Err(Response::error(
+                413,
+                "too_many_elements",
+                &format!(
+                    "{} matrix is {}x{}, above the limit of {} elements",
+                    name, shape.0, shape.1, self.limits.max_elements
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+// One chunk of a chunked body.
+async fn write_chunk(stream: &mut (impl AsyncWrite + Unpin), chunk: &[u8]) -> io::Result<()> {
+    stream.write_all(format!("{:x}\r\n", chunk.len()).as_bytes()).await?;
+    stream.write_all(chunk).await?;
+    stream.write_all(b"\r\n").await
+}
+
+fn utf8(body: &[u8]) -> Result<&str, Response> {
+    std::str::from_utf8(body).map_err(|_| Response::error(400, "bad_request", "body is not UTF-8"))
+}
+
+// Shape of the matrix the parser would read from `text`, without
+// allocating. Ragged rows are left for the parser to report.
+fn scan_shape(text: &str) -> (usize, usize) {
+    let rows = text.lines().count();
+    let cols = text.lines().next().map_or(0, |line| line.split(' ').count());
+    (rows, cols)
+}
+
+async fn read_request(
+    stream: &mut (impl AsyncRead + Unpin),
+    limits: &Limits,
+) -> Result<(Route, Vec<u8>), Response> {
+    let io_error = |err: io::Error| Response::error(400, "bad_request", &err.to_string());
+
+    let mut buf = Vec::with_capacity(1024);
+    let head_end = loop {
+        let end = buf.windows(4).position(|w| w == b"\r\n\r\n");
+        if end.unwrap_or(buf.len()) > MAX_HEAD_BYTES {
+            return Err(Response::error(
+                431,
+                "head_too_large",
+                "request head is too large",
+            ));
+        }
+        if let Some(end) = end {
+            break end;
+        }
+        let mut chunk = [0; 1024];
+        let read = stream.read(&mut chunk).await.map_err(io_error)?;
+        if read == 0 {
+            return Err(Response::error(
+                400,
+                "bad_request",
+                "connection closed before the request ended",
+            ));
+        }
+        buf.extend_from_slice(&chunk[..read]);
+    };
+
+    let head = std::str::from_utf8(&buf[..head_end])
+        .map_err(|_| Response::error(400, "bad_request", "request head is not UTF-8"))?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or("").split(' ');
+    let (method, path) = (
+        request_line.next().unwrap_or(""),
+        request_line.next().unwrap_or(""),
+    );
+    let (mut route, allowed) = match path {
+        "/multiply" => (Route::Multiply { stream: None }, "POST"),
+        "/operands" => (Route::PutOperand, "PUT"),
+        _ => {
+            return Err(Response::error(
+                404,
+                "not_found",
+                &format!("no such path '{}'", path),
+            ))
+        }
+    };
+    if method != allowed {
+        return Err(Response::error(
+            405,
+            "method_not_allowed",
+            &format!("use {} for {}", allowed, path),
+        ));
+    }
+
+    let mut content_length = None;
+    for line in lines {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| Response::error(400, "bad_request", &format!("malformed header '{}'", line)))?;
+        if name.trim().eq_ignore_ascii_case("content-length") {
+            let length: u64 = value
+                .trim()
+                .parse()
+                .map_err(|_| Response::error(400, "bad_request", "malformed Content-Length"))?;
+            content_length = Some(length);
+        } else if name.trim().eq_ignore_ascii_case("accept") {
+            if let Route::Multiply { stream } = &mut route {
+                *stream = StreamFormat::from_accept(value);
+            }
+        }
+    }
+    let length = content_length
+        .ok_or_else(|| Response::error(411, "length_required", "Content-Length is required"))?;
+    if length > limits.max_body_bytes {
+        return Err(Response::error(
+            413,
+            "body_too_large",
+            &format!(
+                "body of {} bytes is above the limit of {}",
+                length, limits.max_body_bytes
+            ),
+        ));
+    }
+
+    let length = length as usize;
+    let mut body = buf.split_off(head_end + 4);
+    if body.len() > length {
+        return Err(Response::error(
+            400,
+            "bad_request",
+            "body is longer than Content-Length",
+        ));
+    }
+    body.reserve_exact(length - body.len());
+    let mut rest = stream.take((length - body.len()) as u64);
+    rest.read_to_end(&mut body).await.map_err(io_error)?;
+    if body.len() < length {
+        return Err(Response::error(
+            400,
+            "bad_request",
+            "connection closed before the body ended",
+        ));
+    }
+    Ok((route, body))
+}
+
+#[derive(Debug)]
+pub(crate) struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn error(status: u16, error: &str, message: &str) -> Response {
+        Response::error_with(status, error, message, &[])
+    }
+
+    fn error_with(status: u16, error: &str, message: &str, fields: &[(&str, String)]) -> Response {
+        let mut body = format!(
+            "{{\"status\":{},\"error\":{},\"message\":{}",
+            status,
+            quote(error),
+            quote(message)
+        );
+        for (key, value) in fields {
+            body.push_str(&format!(",{}:{}", quote(key), value));
+        }
+        body.push('}');
+        Response {
+            status,
+            content_type: "application/json",
+            body,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let reason = match self.status {
+            200 => "OK",
+            201 => "Created",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            408 => "Request Timeout",
+            411 => "Length Required",
+            413 => "Payload Too Large",
+            422 => "Unprocessable Entity",
+            431 => "Request Header Fields Too Large",
+            _ => "Internal Server Error",
+        };
+        format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.status,
+            reason,
+            self.content_type,
+            self.body.len(),
+            self.body
+        )
+        .into_bytes()
+    }
+}
+
+impl From<MatrixError> for Response {
+    fn from(err: MatrixError) -> Response {
+        let message = err.to_string();
+        match err {
+            MatrixError::DimensionMismatch { left, right } => Response::error_with(
+                422,
+                "dimension_mismatch",
+                &message,
+                &[
+                    ("left", format!("[{},{}]", left.0, left.1)),
+                    ("right", format!("[{},{}]", right.0, right.1)),
+                ],
+            ),
+            MatrixError::InvalidNumber { .. } | MatrixError::RaggedRow { .. } => {
+                Response::error(400, "invalid_matrix", &message)
+            }
+            MatrixError::Cancelled { .. } => Response::error(408, "compute_timeout", &message),
+            _ => Response::error(500, "internal", &message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc_counter;
+
+    fn request(body: &str) -> String {
+        format!(
+            "POST /multiply HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+
+    async fn send(server: &Arc<Server>, raw: &str) -> (u16, String) {
+        let (mut client, connection) = tokio::io::duplex(1 << 20);
+        let handler = tokio::spawn(Arc::clone(server).handle_connection(connection));
+        client.write_all(raw.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        handler.await.unwrap().unwrap();
+
+        let status = response[9..12].parse().unwrap();
+        let body = response.split_once("\r\n\r\n").unwrap().1.to_owned();
+        (status, body)
+    }
+
+    fn body_for(a: &Matrix, b: &Matrix) -> String {
+        format!("{}\nX\n{}", a, b)
+    }
+
+    #[tokio::test]
+    async fn multiplies() {
+        let server = Arc::new(Server::new(Limits::default(), Algorithm::Par));
+        let a = Matrix::random(3, 4);
+        let b = Matrix::random(4, 2);
+
+        let (status, body) = send(&server, &request(&body_for(&a, &b))).await;
+        assert_eq!(status, 200);
+        assert_eq!(body, format!("{}", a.multiply(&b).unwrap()));
+    }
+
+    #[tokio::test]
+    async fn body_over_limit() {
+        let limits = Limits {
+            max_body_bytes: 10,
+            ..Limits::default()
+        };
+        let server = Arc::new(Server::new(limits, Algorithm::Seq));
+
+        // Rejected from the header alone; the body is never sent.
+        let (status, body) = send(&server, "POST /multiply HTTP/1.1\r\nContent-Length: 11\r\n\r\n").await;
+        assert_eq!(status, 413);
+        assert!(body.contains("\"error\":\"body_too_large\""));
+    }
+
+    #[test]
+    fn elements_over_limit_are_not_allocated() {
+        let a = Matrix::random(64, 64);
+        let body = body_for(&a, &a);
+        let matrix_bytes = 64 * 64 * std::mem::size_of::<f64>();
+
+        let limits = Limits {
+            max_elements: 64 * 64 - 1,
+            ..Limits::default()
+        };
+        let server = Server::new(limits, Algorithm::Seq);
+        let (count, result) =
+            alloc_counter::count_allocations(matrix_bytes, || server.multiply_request(body.as_bytes()));
+        let response = result.unwrap_err();
+        assert_eq!(response.status, 413);
+        assert!(
+            response.body.contains("first matrix is 64x64"),
+            "{}",
+            response.body
+        );
+        assert_eq!(count, 0);
+
+        // The same request within the limits does allocate matrices that size.
+        let server = Server::new(Limits::default(), Algorithm::Seq);
+        let (count, result) =
+            alloc_counter::count_allocations(matrix_bytes, || server.multiply_request(body.as_bytes()));
+        assert!(result.is_ok());
+        assert!(count > 0);
+    }
+
+    #[tokio::test]
+    async fn incompatible_shapes() {
+        let server = Arc::new(Server::new(Limits::default(), Algorithm::Seq));
+        let body = body_for(&Matrix::random(2, 3), &Matrix::random(4, 5));
+
+        let (status, body) = send(&server, &request(&body)).await;
+        assert_eq!(status, 422);
+        assert!(body.contains("\"left\":[2,3],\"right\":[4,5]"), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn compute_timeout_frees_slot() {
+        let limits = Limits {
+            compute_timeout: Duration::from_nanos(1),
+            max_concurrent: 1,
+            ..Limits::default()
+        };
+        let server = Arc::new(Server::new(limits, Algorithm::Par));
+        let a = Matrix::random(300, 300);
+
+        let (status, body) = send(&server, &request(&body_for(&a, &a))).await;
+        assert_eq!(status, 408);
+        assert!(body.contains("compute_timeout"));
+        assert_eq!(server.slots.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn compute_timeout_may_be_unbounded() {
+        // --timeout takes durations too long for an Instant; they never
+        // expire.
+        let limits = Limits {
+            compute_timeout: Duration::MAX,
+            ..Limits::default()
+        };
+        let server = Arc::new(Server::new(limits, Algorithm::Par));
+        let (a, b) = (Matrix::random(3, 4), Matrix::random(4, 2));
+
+        let (status, body) = send(&server, &request(&body_for(&a, &b))).await;
+        assert_eq!(status, 200, "{}", body);
+    }
+
+    fn put(body: &str) -> String {
+        format!("PUT /operands HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body)
+    }
+
+    // The handle from a 201 response body.
+    fn handle_in(response: &str) -> String {
+        let start = response.find("\"handle\":\"").unwrap() + 10;
+        response[start..start + 16].to_owned()
+    }
+
+    #[tokio::test]
+    async fn cached_operands_parse_once() {
+        let server = Arc::new(Server::new(Limits::default(), Algorithm::Seq));
+        let weights = Matrix::random(4, 3);
+
+        let (status, body) = send(&server, &put(&format!("{}", weights))).await;
+        assert_eq!(status, 201, "{}", body);
+        assert!(body.ends_with("\"rows\":4,\"cols\":3}"), "{}", body);
+        let handle = handle_in(&body);
+        assert_eq!(server.parses.load(Ordering::Relaxed), 1);
+
+        // Uploading it again finds it in the cache.
+        let (status, body) = send(&server, &put(&format!("{}", weights))).await;
+        assert_eq!((status, handle_in(&body)), (201, handle.clone()));
+        assert_eq!(server.parses.load(Ordering::Relaxed), 1);
+
+        for _ in 0..2 {
+            let a = Matrix::random(2, 4);
+            let (status, body) = send(&server, &request(&format!("{}\nX\n@{}", a, handle))).await;
+            assert_eq!(status, 200);
+            assert_eq!(body, format!("{}", a.multiply(&weights).unwrap()));
+        }
+        // One parse per inline A, none for the cached B.
+        assert_eq!(server.parses.load(Ordering::Relaxed), 3);
+
+        let (status, body) = send(&server, &request(&format!("@{}\nX\n@{}", handle, handle))).await;
+        assert_eq!(status, 422, "{}", body);
+    }
+
+    #[tokio::test]
+    async fn operand_eviction() {
+        // Room for one 4x4 operand and its panel.
+        let limits = Limits {
+            operand_cache_bytes: 2 * 16 * 8,
+            ..Limits::default()
+        };
+        let server = Arc::new(Server::new(limits, Algorithm::Seq));
+        let first = handle_in(&send(&server, &put(&format!("{}", Matrix::random(4, 4)))).await.1);
+        let second = handle_in(&send(&server, &put(&format!("{}", Matrix::random(4, 4)))).await.1);
+
+        let identity = Matrix::identity(4);
+        let (status, body) = send(&server, &request(&format!("{}\nX\n@{}", identity, first))).await;
+        assert_eq!(status, 404);
+        assert!(body.contains("\"error\":\"unknown_operand\""), "{}", body);
+        let (status, _) = send(&server, &request(&format!("{}\nX\n@{}", identity, second))).await;
+        assert_eq!(status, 200);
+
+        let (status, body) = send(&server, &put(&format!("{}", Matrix::random(5, 5)))).await;
+        assert_eq!(status, 413, "{}", body);
+    }
+
+    #[tokio::test]
+    async fn dangling_handles() {
+        let server = Arc::new(Server::new(Limits::default(), Algorithm::Seq));
+        for handle in ["@0123456789abcdef", "@not-a-handle"] {
+            let (status, body) = send(&server, &request(&format!("1\nX\n{}", handle))).await;
+            assert_eq!(status, 404);
+            assert!(body.contains(&format!("\"handle\":\"{}\"", &handle[1..])), "{}", body);
+        }
+        assert_eq!(send(&server, "POST /operands HTTP/1.1\r\n\r\n").await.0, 405);
+    }
+
+    #[tokio::test]
+    async fn malformed_requests() {
+        let server = Arc::new(Server::new(Limits::default(), Algorithm::Seq));
+
+        let cases = [
+            ("garbage\r\n\r\n", 404),
+            ("GET /multiply HTTP/1.1\r\n\r\n", 405),
+            ("POST /multiply HTTP/1.1\r\n\r\n", 411),
+            ("POST /multiply HTTP/1.1\r\nContent-Length: -1\r\n\r\n", 400),
+            ("POST /multiply HTTP/1.1\r\nno colon\r\n\r\n", 400),
+            ("POST /multiply HTTP/1.1\r\nContent-Length: 3\r\n\r\nabcdef", 400),
+        ];
+        for (raw, expected) in cases {
+            assert_eq!(send(&server, raw).await.0, expected, "{:?}", raw);
+        }
+
+        for body in ["1\nX\n2\nX\n3", "1 2\n3\nX\n1\n2", "1 x\nX\n1\n2", "\u{1F600}"] {
+            let (status, response) = send(&server, &request(body)).await;
+            assert_eq!(status, 400, "{:?}: {}", body, response);
+        }
+
+        let huge_head = format!(
+            "POST /multiply HTTP/1.1\r\nX-Padding: {}\r\n\r\n",
+            "a".repeat(MAX_HEAD_BYTES)
+        );
+        assert_eq!(send(&server, &huge_head).await.0, 431);
+    }
+
+    fn streamed(accept: &str, body: &str) -> String {
+        format!(
+            "POST /multiply HTTP/1.1\r\nAccept: {}\r\nContent-Length: {}\r\n\r\n{}",
+            accept,
+            body.len(),
+            body
+        )
+    }
+
+    // The head, and the body put back together from its chunks, or `None`
+    // for the body if it ends before the last chunk.
+    fn dechunk(response: &[u8]) -> (String, Option<Vec<u8>>) {
+        let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let head = String::from_utf8(response[..end].to_vec()).unwrap();
+        let (mut rest, mut body) = (&response[end..], Vec::new());
+        while let Some(line) = rest.windows(2).position(|w| w == b"\r\n") {
+            let size = usize::from_str_radix(std::str::from_utf8(&rest[..line]).unwrap(), 16).unwrap();
+            if size == 0 {
+                return (head, Some(body));
+            }
+            body.extend_from_slice(&rest[line + 2..line + 2 + size]);
+            rest = &rest[line + 4 + size..];
+        }
+        (head, None)
+    }
+
+    #[tokio::test]
+    async fn streams_products() {
+        let limits = Limits {
+            stream_band_elements: 50,
+            ..Limits::default()
+        };
+        let server = Arc::new(Server::new(limits, Algorithm::Par));
+        let (a, b) = (Matrix::random(37, 12), Matrix::random(12, 20));
+        let product = a.multiply(&b).unwrap();
+
+        for accept in ["application/octet-stream", "text/plain, application/x-ndjson;q=0.9"] {
+            // A small pipe, so that the computation has to wait for the client.
+            let (mut client, connection) = tokio::io::duplex(256);
+            let handler = tokio::spawn(Arc::clone(&server).handle_connection(connection));
+            client.write_all(streamed(accept, &body_for(&a, &b)).as_bytes()).await.unwrap();
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            handler.await.unwrap().unwrap();
+
+            let (head, body) = dechunk(&response);
+            assert!(head.starts_with("HTTP/1.1 200 OK\r\n") && head.contains("Transfer-Encoding: chunked"), "{}", head);
+            let body = body.unwrap();
+            if accept.starts_with("application/octet-stream") {
+                assert_eq!(Matrix::read_binary(&body[..]).unwrap(), product);
+            } else {
+                let text = String::from_utf8(body).unwrap();
+                let mut lines = text.lines();
+                assert_eq!(lines.next(), Some("{\"rows\":37,\"cols\":20}"));
+                let rows: Vec<Vec<f64>> = lines
+                    .map(|line| line.trim_matches(['[', ']']).split(',').map(|x| x.parse().unwrap()).collect())
+                    .collect();
+                assert_eq!(rows.concat(), product.data);
+            }
+        }
+        // Two rows to a band of 50 elements.
+        assert_eq!(server.bands.load(Ordering::Relaxed), 2 * 19);
+
+        // Errors before the first band are answered as usual.
+        let body = body_for(&Matrix::random(2, 3), &Matrix::random(4, 5));
+        let (status, _) = send(&server, &streamed("application/x-ndjson", &body)).await;
+        assert_eq!(status, 422);
+    }
+
+    #[test]
+    fn streaming_holds_a_few_bands() {
+        let limits = Limits {
+            stream_band_elements: 4096,
+            ..Limits::default()
+        };
+        let server = Arc::new(Server::new(limits, Algorithm::Seq));
+        let (a, b) = (Matrix::random(512, 64), Matrix::random(64, 512));
+        let product_bytes = 512 * 512 * std::mem::size_of::<f64>();
+
+        let (bands, mut received) = tokio::sync::mpsc::channel(STREAM_IN_FLIGHT);
+        let (recycle, recycled) = std::sync::mpsc::channel();
+        let producer = std::thread::spawn({
+            let (server, a, b) = (Arc::clone(&server), a.clone(), b.clone());
+            move || {
+                alloc_counter::peak_bytes(|| {
+                    server.stream_product(&a, &b, StreamFormat::Binary, &CancelToken::new(), &bands, &recycled)
+                })
+            }
+        });
+        // A slow client: the producer is always waiting for it.
+        let mut streamed = Vec::new();
+        while let Some(chunk) = received.blocking_recv() {
+            std::thread::sleep(Duration::from_millis(1));
+            streamed.extend_from_slice(&chunk);
+            // Refused once the producer is done with its last band.
+            let _ = recycle.send(chunk);
+        }
+        let (peak, result) = producer.join().unwrap();
+        result.unwrap();
+        assert_eq!(Matrix::read_binary(&streamed[..]).unwrap(), a.multiply(&b).unwrap());
+        assert!(peak < product_bytes / 4, "{} of {}", peak, product_bytes);
+    }
+
+    #[tokio::test]
+    async fn disconnect_stops_streaming() {
+        let limits = Limits {
+            stream_band_elements: 1,
+            max_concurrent: 1,
+            ..Limits::default()
+        };
+        let server = Arc::new(Server::new(limits, Algorithm::Seq));
+        let a = Matrix::random(400, 64);
+
+        let (mut client, connection) = tokio::io::duplex(1024);
+        let handler = tokio::spawn(Arc::clone(&server).handle_connection(connection));
+        let body = body_for(&a, &Matrix::random(64, 400));
+        client.write_all(streamed("application/octet-stream", &body).as_bytes()).await.unwrap();
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(client.read_u8().await.unwrap());
+        }
+        drop(client);
+
+        let handled = tokio::time::timeout(Duration::from_secs(10), handler).await.unwrap().unwrap();
+        assert!(handled.is_err());
+        assert_eq!(server.slots.available_permits(), 1);
+        let bands = server.bands.load(Ordering::Relaxed);
+        assert!(bands < 400, "{}", bands);
+    }
+}
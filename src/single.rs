@@ -0,0 +1,75 @@
+//! `--dtype f32`: matrices stored, multiplied and written in single
+//! precision.
+//!
+//! Values are parsed straight to f32, so each is rounded once, to the
+//! nearest f32, rather than to an f64 and then again. Products and sums are
+//! f32 too, which is what makes this faster than the f64 kernels: half the
+//! bytes to stream and twice the elements per cache line. The price is
+//! 24-bit precision: each operation rounds with a relative error of up to
+//! 2^-24, and a sum of k terms can be off by about k times that. For f32
+//! data that needs the f64 result exactly, `--auto-narrow` is the option.
+//!
+//! Only the plain row-times-column kernel of `element` exists in f32; the
+//! blocked, fused and shortcut paths of the f64 multiply are not
+//! duplicated here. A value too large for f32 is an error when parsed
+//! rather than an infinity.
+
+use crate::Matrix;
+
+pub type F32Matrix = Matrix<f32>;
+
+impl F32Matrix {
+    /// The same values as f64, which holds every f32 exactly.
+    pub fn widen(&self) -> Matrix {
+        Matrix::new_unchecked(self.rows, self.cols, self.data.iter().map(|&x| f64::from(x)).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Algorithm, MatrixError};
+
+    #[test]
+    fn parses_to_nearest_f32() {
+        let m = F32Matrix::parse("0.1, 1e-3\n16777217 -2.5\n").unwrap();
+        assert_eq!(m.shape(), (2, 2));
+        // 2^24 + 1 is not an f32; it rounds to 2^24.
+        assert_eq!(m.data, [0.1f32, 1e-3, 16_777_216.0, -2.5]);
+        assert!(matches!(
+            F32Matrix::parse("1 2\n3"),
+            Err(MatrixError::RaggedRow { line: 2, expected: 2, found: 1 })
+        ));
+        assert!(matches!(F32Matrix::parse("1 x"), Err(MatrixError::InvalidNumber { line: 1, column: 3, .. })));
+        assert!(matches!(F32Matrix::parse("1 1e40"), Err(MatrixError::OutOfRange { line: 1, column: 3, .. })));
+
+        let mut out = Vec::new();
+        m.write(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "0.1 0.001\n16777216 -2.5\n");
+    }
+
+    #[test]
+    fn multiplies_in_single_precision() {
+        let (a, b) = (Matrix::random_seeded(23, 41, 1), Matrix::random_seeded(41, 17, 2));
+        let narrow = |m: &Matrix| F32Matrix {
+            rows: m.rows,
+            cols: m.cols,
+            data: m.data.iter().map(|&x| x as f32).collect(),
+        };
+        let (a32, b32) = (narrow(&a), narrow(&b));
+        let seq = a32.multiply(&b32, Algorithm::Seq).unwrap();
+        assert_eq!(seq, a32.multiply(&b32, Algorithm::Par).unwrap());
+        assert_eq!(seq.shape(), (23, 17));
+
+        // Within the f32 error bound of the f64 product of the same values,
+        // and not as close as f64 would be.
+        let exact = a32.widen().multiply(&b32.widen()).unwrap();
+        let error = seq.widen().max_abs_diff(&exact);
+        assert!(error > 0.0 && error < 41.0 * f64::from(f32::EPSILON) * 41.0, "{}", error);
+
+        // Integers are exact while the sums stay below 2^24.
+        let small = F32Matrix::parse("1 2\n3 4").unwrap();
+        assert_eq!(small.multiply(&small, Algorithm::Seq).unwrap().data, [7.0, 10.0, 15.0, 22.0]);
+        assert!(matches!(a32.multiply(&a32, Algorithm::Seq), Err(MatrixError::DimensionMismatch { .. })));
+    }
+}
@@ -0,0 +1,130 @@
+//! Sustained throughput: the same multiply repeated for a time budget.
+//!
+//! A machine that throttles under load shows it as later iterations taking
+//! longer than earlier ones, so the report compares the mean of the first
+//! and last quarter of the iterations as well as the usual peak and
+//! sustained rates.
+
+use std::time::{Duration, Instant};
+
+use crate::{CancelToken, Matrix, MatrixError, MultiplyOptions};
+
+pub struct SoakReport {
+    pub iterations: usize,
+    pub total: Duration,
+    pub fastest: Duration,
+    pub slowest: Duration,
+    /// Mean time of the first and last quarter of the iterations.
+    pub first_quartile: Duration,
+    pub last_quartile: Duration,
+    /// Over the whole run, and for the fastest iteration.
+    pub sustained_gflops: f64,
+    pub peak_gflops: f64,
+}
+
+impl SoakReport {
+    pub fn from_times(times: &[Duration], flops: u64) -> SoakReport {
+        assert!(!times.is_empty());
+        let total: Duration = times.iter().sum();
+        let fastest = *times.iter().min().unwrap();
+        let slowest = *times.iter().max().unwrap();
+        let quarter = (times.len() / 4).max(1);
+        let mean = |times: &[Duration]| times.iter().sum::<Duration>() / times.len() as u32;
+        let gflops = |elapsed: Duration| flops as f64 / elapsed.as_secs_f64().max(1e-9) / 1e9;
+        SoakReport {
+            iterations: times.len(),
+            total,
+            fastest,
+            slowest,
+            first_quartile: mean(&times[..quarter]),
+            last_quartile: mean(&times[times.len() - quarter..]),
+            sustained_gflops: gflops(total) * times.len() as f64,
+            peak_gflops: gflops(fastest),
+        }
+    }
+
+    /// How much slower the last quarter ran than the first, in percent.
+    pub fn slowdown_percent(&self) -> f64 {
+        (self.last_quartile.as_secs_f64() / self.first_quartile.as_secs_f64().max(1e-12) - 1.0) * 100.0
+    }
+}
+
+impl Matrix {
+    /// Multiplies `self` by `other` until `budget` has passed (running at
+    /// least twice, so there is a trend to report) or `stop` is cancelled.
+    /// Returns the first product, which is the only one kept.
+    pub fn soak(
+        &self,
+        other: &Matrix,
+        options: &MultiplyOptions,
+        budget: Duration,
+        stop: &CancelToken,
+    ) -> Result<(Matrix, SoakReport), MatrixError> {
+        let flops = 2u64
+            .saturating_mul(self.rows as u64)
+            .saturating_mul(self.cols as u64)
+            .saturating_mul(other.cols as u64);
+        let start = Instant::now();
+        let mut times = Vec::new();
+
+        let iteration = Instant::now();
+        let first = self.multiply_with(other, options)?;
+        times.push(iteration.elapsed());
+
+        while times.len() < 2 || (start.elapsed() < budget && !stop.is_cancelled()) {
+            let iteration = Instant::now();
+            self.multiply_with(other, options)?;
+            times.push(iteration.elapsed());
+        }
+
+        Ok((first, SoakReport::from_times(&times, flops)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn soak_report() {
+        let a = Matrix::random(12, 8);
+        let b = Matrix::random(8, 10);
+        let budget = Duration::from_millis(20);
+        let (first, report) = a
+            .soak(&b, &MultiplyOptions::new(), budget, &CancelToken::new())
+            .unwrap();
+
+        assert_eq!(first, a.multiply(&b).unwrap());
+        assert!(report.iterations >= 2);
+        assert!(report.total <= budget + report.slowest * 2);
+        assert!(report.fastest <= report.first_quartile && report.first_quartile <= report.slowest);
+        assert!(report.fastest <= report.last_quartile && report.last_quartile <= report.slowest);
+        assert!(report.fastest * report.iterations as u32 <= report.total);
+        assert!(report.sustained_gflops > 0.0 && report.sustained_gflops <= report.peak_gflops);
+    }
+
+    #[test]
+    fn quartiles() {
+        let ms = Duration::from_millis;
+        let times = [ms(1), ms(3), ms(2), ms(2), ms(4), ms(4), ms(5), ms(7)];
+        let report = SoakReport::from_times(&times, 2_000_000);
+        assert_eq!(report.iterations, 8);
+        assert_eq!(report.total, ms(28));
+        assert_eq!((report.fastest, report.slowest), (ms(1), ms(7)));
+        assert_eq!((report.first_quartile, report.last_quartile), (ms(2), ms(6)));
+        assert!((report.slowdown_percent() - 200.0).abs() < 1e-9);
+        assert!((report.peak_gflops - 2.0).abs() < 1e-9);
+        assert!((report.sustained_gflops - 8.0 * 2.0 / 28.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn stops_when_cancelled() {
+        let a = Matrix::random(4, 4);
+        let stop = CancelToken::new();
+        stop.cancel();
+        let (_, report) = a
+            .soak(&a, &MultiplyOptions::new(), Duration::from_secs(3600), &stop)
+            .unwrap();
+        assert_eq!(report.iterations, 2);
+    }
+}
@@ -0,0 +1,370 @@
+//! `--input-format coo`: sparse operands as "row col value" triplets.
+//!
+//! A triplet file has one entry per line, in any order. Entries the file
+//! does not list are zero. Without `--coo-shape` the matrix is just large
+//! enough for the largest indices. Blank lines and lines starting with `%`
+//! are skipped.
+
+use std::io::{self, Write};
+
+use clap::clap_derive::ArgEnum;
+
+use crate::{format::Number, format::Precision, numfmt, Matrix, MatrixError, ShapeError};
+
+/// Where the indices in a triplet file count from.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ArgEnum)]
+pub enum Base {
+    Zero,
+    One,
+}
+
+impl Base {
+    fn offset(self) -> usize {
+        match self {
+            Base::Zero => 0,
+            Base::One => 1,
+        }
+    }
+}
+
+/// What to do with two entries for the same element.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ArgEnum)]
+pub enum Duplicates {
+    /// Add them up.
+    Sum,
+    /// Fail with the line of the second one.
+    Error,
+}
+
+/// What a triplet file is turned into.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ArgEnum)]
+pub enum CooTarget {
+    Dense,
+    /// Compressed sparse rows, multiplied without densifying.
+    Csr,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CooOptions {
+    pub base: Base,
+    pub duplicates: Duplicates,
+    pub shape: Option<(usize, usize)>,
+}
+
+impl Default for CooOptions {
+    fn default() -> CooOptions {
+        CooOptions {
+            base: Base::Zero,
+            duplicates: Duplicates::Sum,
+            shape: None,
+        }
+    }
+}
+
+/// Parses "ROWSxCOLS", for --coo-shape.
+pub fn parse_shape(s: &str) -> Result<(usize, usize), String> {
+    let (rows, cols) = s.split_once('x').ok_or_else(|| format!("expected ROWSxCOLS, found '{}'", s))?;
+    let dim = |d: &str| d.trim().parse::<usize>().map_err(|_| format!("invalid dimension '{}'", d));
+    Ok((dim(rows)?, dim(cols)?))
+}
+
+/// Entries sorted by row, then column, with duplicates merged.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Coo {
+    pub(crate) rows: usize,
+    pub(crate) cols: usize,
+    pub(crate) entries: Vec<(usize, usize, f64)>,
+}
+
+impl Coo {
+    pub fn parse(s: &str, options: &CooOptions) -> Result<Coo, MatrixError> {
+        let offset = options.base.offset();
+        // (row, col, value, line), indices from zero.
+        let mut entries = Vec::new();
+        for (index, raw) in s.lines().enumerate() {
+            let line_no = index + 1;
+            let line = raw.trim();
+            if line.is_empty() || line.starts_with('%') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3 {
+                return Err(MatrixError::RaggedRow {
+                    line: line_no,
+                    expected: 3,
+                    found: fields.len(),
+                });
+            }
+            let number = |token: &str| MatrixError::InvalidNumber {
+                line: line_no,
+                column: token.as_ptr() as usize - raw.as_ptr() as usize + 1,
+                token: token.to_owned(),
+            };
+            let row = fields[0].parse::<usize>().map_err(|_| number(fields[0]))?;
+            let col = fields[1].parse::<usize>().map_err(|_| number(fields[1]))?;
+            let value = numfmt::parse_f64(fields[2]).ok_or_else(|| number(fields[2]))?;
+
+            let in_range = |index: usize, len: Option<usize>| index >= offset && len.is_none_or(|len| index - offset < len);
+            if !in_range(row, options.shape.map(|s| s.0)) || !in_range(col, options.shape.map(|s| s.1)) {
+                return Err(MatrixError::IndexOutOfRange {
+                    line: line_no,
+                    row,
+                    col,
+                    shape: options.shape,
+                });
+            }
+            entries.push((row - offset, col - offset, value, line_no));
+        }
+
+        let (rows, cols) = match options.shape {
+            Some(shape) => shape,
+            None => {
+                // One past the largest index, which may not fit.
+                let extent = |f: fn(&(usize, usize, f64, usize)) -> usize| {
+                    entries.iter().map(f).max().map_or(Some(0), |i| i.checked_add(1))
+                };
+                match (extent(|e| e.0), extent(|e| e.1)) {
+                    (Some(rows), Some(cols)) => (rows, cols),
+                    (rows, cols) => {
+                        let (rows, cols) = (rows.unwrap_or(usize::MAX), cols.unwrap_or(usize::MAX));
+                        return Err(ShapeError::Overflow { rows, cols }.into());
+                    }
+                }
+            }
+        };
+
+        // A stable sort keeps duplicates in file order, so the error names
+        // the later line.
+        entries.sort_by_key(|&(row, col, _, _)| (row, col));
+        let mut merged: Vec<(usize, usize, f64)> = Vec::with_capacity(entries.len());
+        for (row, col, value, line) in entries {
+            match merged.last_mut() {
+                Some(last) if (last.0, last.1) == (row, col) => {
+                    if options.duplicates == Duplicates::Error {
+                        return Err(MatrixError::DuplicateEntry {
+                            line,
+                            row: row + offset,
+                            col: col + offset,
+                        });
+                    }
+                    last.2 += value;
+                }
+                _ => merged.push((row, col, value)),
+            }
+        }
+        Ok(Coo {
+            rows,
+            cols,
+            entries: merged,
+        })
+    }
+
+    pub fn shape(&self) -> (usize, usize) {
+        (self.rows, self.cols)
+    }
+
+    /// Fails if the dense matrix cannot be allocated at all; limits below
+    /// that are the caller's to check first.
+    pub fn to_dense(&self) -> Result<Matrix, MatrixError> {
+        let overflow = ShapeError::Overflow { rows: self.rows, cols: self.cols };
+        let len = self.rows.checked_mul(self.cols).filter(|&len| fits_in_memory::<f64>(len)).ok_or(overflow)?;
+        let mut data = Vec::new();
+        data.try_reserve_exact(len).map_err(|_| overflow)?;
+        data.resize(len, 0.0);
+        let mut m = Matrix::new_unchecked(self.rows, self.cols, data);
+        for &(row, col, value) in &self.entries {
+            m.set(row, col, value);
+        }
+        Ok(m)
+    }
+
+    pub fn to_csr(&self) -> Result<Csr, MatrixError> {
+        let starts = self.rows.checked_add(1).filter(|&len| fits_in_memory::<usize>(len));
+        let mut row_starts = vec![0; starts.ok_or(ShapeError::Overflow { rows: self.rows, cols: self.cols })?];
+        for &(row, _, _) in &self.entries {
+            row_starts[row + 1] += 1;
+        }
+        for i in 0..self.rows {
+            row_starts[i + 1] += row_starts[i];
+        }
+        Ok(Csr {
+            rows: self.rows,
+            cols: self.cols,
+            row_starts,
+            col_indices: self.entries.iter().map(|e| e.1).collect(),
+            values: self.entries.iter().map(|e| e.2).collect(),
+        })
+    }
+}
+
+// Whether `len` elements of `T` are few enough for a Vec to hold.
+fn fits_in_memory<T>(len: usize) -> bool {
+    len.checked_mul(std::mem::size_of::<T>()).is_some_and(|bytes| bytes <= isize::MAX as usize)
+}
+
+/// Compressed sparse rows: the entries of row `i` are
+/// `row_starts[i]..row_starts[i + 1]` of `col_indices` and `values`, in
+/// column order.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Csr {
+    pub rows: usize,
+    pub cols: usize,
+    pub(crate) row_starts: Vec<usize>,
+    pub(crate) col_indices: Vec<usize>,
+    pub(crate) values: Vec<f64>,
+}
+
+impl Csr {
+    pub fn nonzeros(&self) -> usize {
+        self.values.len()
+    }
+
+    fn row(&self, i: usize) -> impl Iterator<Item = (usize, f64)> + '_ {
+        let range = self.row_starts[i]..self.row_starts[i + 1];
+        self.col_indices[range.clone()].iter().copied().zip(self.values[range].iter().copied())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn to_dense(&self) -> Matrix {
+        let mut m = Matrix::new_unchecked(self.rows, self.cols, vec![0.0; self.rows * self.cols]);
+        for i in 0..self.rows {
+            for (j, value) in self.row(i) {
+                m.set(i, j, value);
+            }
+        }
+        m
+    }
+
+    /// `self * other`, touching only the stored entries of `self`.
+    pub fn multiply_dense(&self, other: &Matrix) -> Result<Matrix, MatrixError> {
+        if self.cols != other.rows {
+            return Err(MatrixError::DimensionMismatch {
+                left: (self.rows, self.cols),
+                right: other.shape(),
+            });
+        }
+        let mut result = vec![0.0; self.rows * other.cols];
+        for (i, out) in result.chunks_mut(other.cols.max(1)).enumerate().take(self.rows) {
+            for (k, a) in self.row(i) {
+                for (cell, b) in out.iter_mut().zip(other.row(k)) {
+                    *cell += a * b;
+                }
+            }
+        }
+        Ok(Matrix::new_unchecked(self.rows, other.cols, result))
+    }
+}
+
+/// Writes the elements of `m` whose magnitude is above `tolerance` as
+/// triplets, in row order.
+pub fn write_coo(
+    m: &Matrix,
+    tolerance: f64,
+    base: Base,
+    precision: Precision,
+    mut writer: impl Write,
+) -> io::Result<()> {
+    let offset = base.offset();
+    for i in 0..m.rows {
+        for (j, &x) in m.row(i).iter().enumerate() {
+            if x.abs() > tolerance {
+                writeln!(writer, "{} {} {}", i + offset, j + offset, Number(x, precision))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicates_are_summed() {
+        let coo = Coo::parse("1 0 2.5\n0 1 1\n\n% comment\n1 0 0.5\n0 0 -1\n", &CooOptions::default()).unwrap();
+        assert_eq!((coo.rows, coo.cols), (2, 2));
+        assert_eq!(coo.entries, vec![(0, 0, -1.0), (0, 1, 1.0), (1, 0, 3.0)]);
+
+        let options = CooOptions {
+            duplicates: Duplicates::Error,
+            ..CooOptions::default()
+        };
+        assert!(matches!(
+            Coo::parse("1 0 2.5\n0 1 1\n1 0 0.5", &options),
+            Err(MatrixError::DuplicateEntry { line: 3, row: 1, col: 0 })
+        ));
+    }
+
+    #[test]
+    fn out_of_range_indices() {
+        let one_based = CooOptions {
+            base: Base::One,
+            ..CooOptions::default()
+        };
+        assert!(matches!(
+            Coo::parse("1 1 1\n0 2 1", &one_based),
+            Err(MatrixError::IndexOutOfRange { line: 2, row: 0, col: 2, shape: None })
+        ));
+        let shaped = CooOptions {
+            shape: Some((2, 3)),
+            ..CooOptions::default()
+        };
+        assert!(matches!(
+            Coo::parse("0 0 1\n\n1 3 1", &shaped),
+            Err(MatrixError::IndexOutOfRange { line: 3, .. })
+        ));
+        assert!(matches!(Coo::parse("0 -1 1", &shaped), Err(MatrixError::InvalidNumber { line: 1, .. })));
+        assert!(matches!(Coo::parse("0 1", &shaped), Err(MatrixError::RaggedRow { line: 1, .. })));
+        assert_eq!(Coo::parse("1 2 5", &shaped).unwrap().to_dense().unwrap().shape(), (2, 3));
+    }
+
+    #[test]
+    fn shapes_too_large_to_allocate() {
+        let options = CooOptions::default();
+        let dense = |text: &str| Coo::parse(text, &options).and_then(|coo| coo.to_dense());
+        let overflow = |rows, cols| Err(MatrixError::Shape(ShapeError::Overflow { rows, cols }));
+        assert_eq!(dense("4000000000 4000000000 1"), overflow(4_000_000_001, 4_000_000_001));
+        assert_eq!(dense("18446744073709551614 1 1"), overflow(usize::MAX, 2));
+        assert_eq!(dense(&format!("{} 0 1", usize::MAX)), overflow(usize::MAX, 1));
+
+        // Valid sparse, just not densifiable.
+        let coo = Coo::parse("4000000000 4000000000 1", &options).unwrap();
+        assert_eq!(coo.shape(), (4_000_000_001, 4_000_000_001));
+        let tall = CooOptions {
+            shape: Some((usize::MAX, 0)),
+            ..CooOptions::default()
+        };
+        assert_eq!(Coo::parse("", &tall).unwrap().to_dense().unwrap().shape(), (usize::MAX, 0));
+        assert!(Coo::parse("", &tall).unwrap().to_csr().is_err());
+    }
+
+    #[test]
+    fn one_based_round_trip() {
+        let m = Matrix::new_unchecked(3, 2, vec![0.0, 1.5, 0.0, 0.0, -2.0, 1e-12]);
+        let mut out = Vec::new();
+        write_coo(&m, 1e-9, Base::One, Precision::Full, &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert_eq!(text, "1 2 1.5\n3 1 -2\n");
+
+        let options = CooOptions {
+            base: Base::One,
+            shape: Some((3, 2)),
+            ..CooOptions::default()
+        };
+        let dropped = Matrix::new_unchecked(3, 2, vec![0.0, 1.5, 0.0, 0.0, -2.0, 0.0]);
+        assert_eq!(Coo::parse(&text, &options).unwrap().to_dense().unwrap(), dropped);
+    }
+
+    #[test]
+    fn dense_and_csr_agree() {
+        let text = "3 1 4\n0 2 1\n3 0 2\n1 1 -1\n0 2 1\n";
+        let coo = Coo::parse(text, &CooOptions::default()).unwrap();
+        let csr = coo.to_csr().unwrap();
+        assert_eq!((csr.row_starts.clone(), csr.nonzeros()), (vec![0, 1, 2, 2, 4], 4));
+        assert_eq!(csr.to_dense(), coo.to_dense().unwrap());
+
+        let b = Matrix::random(3, 5);
+        assert_eq!(csr.multiply_dense(&b).unwrap(), coo.to_dense().unwrap().multiply(&b).unwrap());
+        assert!(csr.multiply_dense(&Matrix::random(4, 2)).is_err());
+    }
+}
@@ -0,0 +1,165 @@
+//! `--spot-check N`: recomputes N randomly chosen elements of a product on
+//! their own and compares them with the result.
+//!
+//! Each element is recomputed with a compensated dot product, which is
+//! accurate to about the last bit, and must agree with the result within
+//! the error bound of the kernel: for the plain kernels that of a dot
+//! product of that length, for Strassen's a bound relative to the largest
+//! elements of the operands, which is all its error has. A bug that
+//! corrupts a fraction f of the elements is missed with probability about
+//! (1 - f)^N.
+
+use std::fmt;
+
+use rand::{rngs::StdRng, seq::index, SeedableRng};
+
+use crate::{accuracy::compensated_dot, Epilogue, Matrix};
+
+/// Elements checked after an experimental kernel when --spot-check is not
+/// given.
+pub const EXPERIMENTAL_CELLS: usize = 16;
+
+/// How far a result element may be from the recomputed one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Bound {
+    /// A plain dot product's: about n * eps times the sum of the magnitudes
+    /// of its terms.
+    Elementwise,
+    /// `growth * eps * max|a| * max|b|`, for kernels such as Strassen's
+    /// that cancel where the plain dot product does not.
+    Normwise { growth: f64 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mismatch {
+    pub(crate) row: usize,
+    pub(crate) col: usize,
+    pub(crate) found: f64,
+    pub(crate) expected: f64,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "({}, {}) is {:e}, recomputed as {:e}", self.row, self.col, self.found, self.expected)
+    }
+}
+
+/// Checks `cells` distinct random elements of `result`, which should be
+/// `a * b` with `epilogues` applied, and returns those that differ by more
+/// than `bound`. Every element is checked when `cells` is at least their
+/// number.
+pub fn spot_check(
+    a: &Matrix,
+    b: &Matrix,
+    epilogues: &[Epilogue],
+    result: &Matrix,
+    bound: Bound,
+    cells: usize,
+    seed: u64,
+) -> Vec<Mismatch> {
+    assert_eq!((a.rows, a.cols, b.cols), (result.rows, b.rows, result.cols));
+    let largest = |m: &Matrix| m.data.iter().filter(|x| x.is_finite()).fold(0.0, |max: f64, x| max.max(x.abs()));
+    let normwise = match bound {
+        Bound::Elementwise => 0.0,
+        Bound::Normwise { growth } => growth * f64::EPSILON * largest(a) * largest(b),
+    };
+    let total = result.data.len();
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut mismatches = Vec::new();
+    for index in index::sample(&mut rng, total, cells.min(total)) {
+        let (row, col) = (index / result.cols, index % result.cols);
+        let pairs = || (0..a.cols).map(|k| (a.get(row, k), b.get(k, col)));
+        let exact = compensated_dot(pairs());
+        let expected = epilogues.iter().fold(exact, |x, epilogue| epilogue.apply_at(row, col, x));
+
+        // A plain dot product of n terms is within about n * eps of the sum
+        // of their magnitudes, and so is each epilogue's input.
+        let bound = match bound {
+            Bound::Elementwise => {
+                let magnitude: f64 = pairs().map(|(x, y)| (x * y).abs()).sum();
+                2.0 * (a.cols + epilogues.len()) as f64 * f64::EPSILON * (magnitude + expected.abs())
+            }
+            Bound::Normwise { .. } => normwise + 2.0 * epilogues.len() as f64 * f64::EPSILON * expected.abs(),
+        };
+        let found = result.get(row, col);
+        let agrees = (found - expected).abs() <= bound || found == expected || (found.is_nan() && expected.is_nan());
+        if !agrees {
+            mismatches.push(Mismatch { row, col, found, expected });
+        }
+    }
+    mismatches.sort_by_key(|m| (m.row, m.col));
+    mismatches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clean_results_pass() {
+        let a = Matrix::random(30, 40);
+        let b = Matrix::random(40, 20);
+        let product = a.multiply_par(&b).unwrap();
+        for seed in 0..20 {
+            assert_eq!(spot_check(&a, &b, &[], &product, Bound::Elementwise, 50, seed), vec![]);
+        }
+        assert_eq!(spot_check(&a, &b, &[], &product, Bound::Elementwise, usize::MAX, 0), vec![]);
+
+        let mask = Matrix::new_unchecked(30, 20, (0..600).map(|i| (i % 2) as f64).collect());
+        let epilogues = [Epilogue::Mask(mask.into()), Epilogue::Apply(|x| x - 1.0)];
+        let options = crate::MultiplyOptions::new().epilogue(epilogues[0].clone()).epilogue(epilogues[1].clone());
+        let masked = a.multiply_with(&b, &options).unwrap();
+        assert_eq!(spot_check(&a, &b, &epilogues, &masked, Bound::Elementwise, usize::MAX, 0), vec![]);
+
+        let (x, y) = (Matrix::new_unchecked(1, 2, vec![f64::NAN, 1.0]), Matrix::new_unchecked(2, 1, vec![1.0, 1.0]));
+        assert_eq!(spot_check(&x, &y, &[], &x.multiply(&y).unwrap(), Bound::Elementwise, 1, 0), vec![]);
+    }
+
+    #[test]
+    fn catches_a_flipped_cell() {
+        let a = Matrix::random(20, 16);
+        let b = Matrix::random(16, 10);
+        let mut corrupted = a.multiply(&b).unwrap();
+        let original = corrupted.get(13, 7);
+        corrupted.set(13, 7, -original);
+
+        // Checking 3/4 of the cells misses the flipped one a quarter of the
+        // time.
+        let caught = (0..200)
+            .filter(|&seed| {
+                let mismatches = spot_check(&a, &b, &[], &corrupted, Bound::Elementwise, 150, seed);
+                assert!(mismatches.len() <= 1);
+                mismatches.first().is_some_and(|m| (m.row, m.col, m.found) == (13, 7, -original))
+            })
+            .count();
+        assert!(caught > 120, "caught {} of 200", caught);
+
+        let mismatches = spot_check(&a, &b, &[], &corrupted, Bound::Elementwise, 200, 0);
+        let expected = a.multiply_compensated(&b).get(13, 7);
+        assert_eq!(mismatches, vec![Mismatch { row: 13, col: 7, found: -original, expected }]);
+        assert!(mismatches[0].to_string().starts_with("(13, 7) is -"));
+        let growth = crate::strassen::error_growth(20, 16, 10, 1);
+        assert_eq!(spot_check(&a, &b, &[], &corrupted, Bound::Normwise { growth }, 200, 0), mismatches);
+    }
+
+    #[test]
+    fn strassen_is_checked_normwise() {
+        // Small blocks beside a large one: Strassen's sums mix them, and the
+        // small elements of the product lose digits no plain dot product
+        // would.
+        let scaled = |seed| {
+            let mut m = Matrix::random_seeded(256, 256, seed);
+            for i in 128..256 {
+                for j in 128..256 {
+                    m.set(i, j, m.get(i, j) * 1e6);
+                }
+            }
+            m
+        };
+        let (a, b) = (scaled(1), scaled(2));
+        let product = a.multiply_strassen_par(&b, crate::strassen::DEFAULT_CUTOFF).unwrap();
+        assert_ne!(spot_check(&a, &b, &[], &product, Bound::Elementwise, usize::MAX, 0), vec![]);
+        let growth = crate::strassen::error_growth(256, 256, 256, crate::strassen::DEFAULT_CUTOFF);
+        assert_eq!(spot_check(&a, &b, &[], &product, Bound::Normwise { growth }, usize::MAX, 0), vec![]);
+    }
+}
@@ -0,0 +1,213 @@
+//! `--mode strassen`: Strassen's seven-product recursion, for large
+//! operands that are not too far from square.
+//!
+//! Each level cuts both operands into quadrants and forms the product from
+//! seven quadrant products instead of eight, which is O(n^2.81) overall.
+//! The recursion stops once any dimension is at most `cutoff`, and the
+//! quadrants left are multiplied by the blocked kernel. So that every level
+//! halves evenly, the operands are padded with zeros once, at the top, to a
+//! multiple of 2^depth in each dimension, at most 2^depth - 1 extra rows or
+//! columns; the padding adds zero to every product and is cropped off.
+//!
+//! The quadrants, their sums and the seven products are all copies. A level
+//! holds its operands' quadrants (as much again as the operands), up to two
+//! quadrant sums at a time per product, the seven products (7/4 of the
+//! result) and the joined result; the levels below add a quarter as much at
+//! each step. The peak is therefore a few times the operands and result, on
+//! top of what the plain multiply needs. With `Algorithm::Par` the seven
+//! products run at once, and so do their temporaries.
+//!
+//! The products are summed in a different order from the plain kernels,
+//! and the sums of quadrants cancel where the plain kernel's terms do not:
+//! the error is bounded normwise, by the largest elements of the operands,
+//! not element by element. Results agree with the plain kernels only to a
+//! tolerance, and an element much smaller than the others can lose most of
+//! its digits.
+
+use rayon::prelude::*;
+
+use crate::{blocked::DEFAULT_BLOCK_SIZE, invariants, Algorithm, CancelToken, Matrix, MatrixError};
+
+/// The default `--strassen-cutoff`: below 128, the copies and additions
+/// cost more than the product they save.
+pub const DEFAULT_CUTOFF: usize = 128;
+
+// Levels of recursion for a rows x inner by inner x cols product.
+fn depth(rows: usize, inner: usize, cols: usize, cutoff: usize) -> usize {
+    let cutoff = cutoff.max(1);
+    let mut depth = 0;
+    while rows.min(inner).min(cols) >> depth > cutoff {
+        depth += 1;
+    }
+    depth
+}
+
+/// The factor f in Higham's bound on the error of `multiply_strassen`
+/// (Accuracy and Stability of Numerical Algorithms, theorem 23.3): every
+/// element is within f * eps * max|a| * max|b| of the exact product, to
+/// first order. It is [12^depth * (n0^2 + 5 * n0) - 5 * n] for a product of
+/// size n recursed down to quadrants of size n0, taking n as the largest
+/// padded dimension.
+pub fn error_growth(rows: usize, inner: usize, cols: usize, cutoff: usize) -> f64 {
+    let depth = depth(rows, inner, cols, cutoff);
+    let n = rows.max(inner).max(cols).div_ceil(1 << depth) << depth;
+    let leaf = (n >> depth) as f64;
+    12f64.powi(depth as i32) * (leaf * leaf + 5.0 * leaf) - 5.0 * n as f64
+}
+
+impl Matrix {
+    /// `self * other` by Strassen's recursion, down to quadrants with a
+    /// dimension of at most `cutoff` (taken as at least 1).
+    pub fn multiply_strassen(&self, other: &Matrix, cutoff: usize) -> Result<Matrix, MatrixError> {
+        self.multiply_strassen_with(other, cutoff, Algorithm::Seq)
+    }
+
+    /// `multiply_strassen` with the seven products of each level, and the
+    /// blocked products at the bottom, in parallel.
+    pub fn multiply_strassen_par(&self, other: &Matrix, cutoff: usize) -> Result<Matrix, MatrixError> {
+        self.multiply_strassen_with(other, cutoff, Algorithm::Par)
+    }
+
+    fn multiply_strassen_with(&self, other: &Matrix, cutoff: usize, algorithm: Algorithm) -> Result<Matrix, MatrixError> {
+        self.check_inner(other)?;
+        let (rows, inner, cols) = (self.rows, self.cols, other.cols);
+        let depth = depth(rows, inner, cols, cutoff);
+        if depth == 0 {
+            return Ok(leaf(self, other, algorithm));
+        }
+
+        let round = |x: usize| x.div_ceil(1 << depth) << depth;
+        let (a, b) = (self.padded(round(rows), round(inner)), other.padded(round(inner), round(cols)));
+        invariants::tile(a.shape(), 0, 0, rows, inner);
+        invariants::tile(b.shape(), 0, 0, inner, cols);
+        Ok(strassen(&a, &b, depth, algorithm).padded(rows, cols))
+    }
+
+    // The top-left `rows x cols` of `self`, with zeros wherever `self` is
+    // smaller.
+    pub(crate) fn padded(&self, rows: usize, cols: usize) -> Matrix {
+        if (rows, cols) == self.shape() {
+            return self.clone();
+        }
+        let mut data = vec![0.0; rows * cols];
+        let width = cols.min(self.cols);
+        invariants::buffer_len(self.rows, self.cols, self.data.len());
+        for (out, row) in data.chunks_mut(cols.max(1)).zip(self.data.chunks(self.cols.max(1))).take(rows) {
+            out[..width].copy_from_slice(&row[..width]);
+        }
+        Matrix::new_unchecked(rows, cols, data)
+    }
+
+    // Copies of the quadrants of a matrix with even dimensions: top left,
+    // top right, bottom left, bottom right.
+    pub(crate) fn quadrants(&self) -> [Matrix; 4] {
+        debug_assert!(self.rows.is_multiple_of(2) && self.cols.is_multiple_of(2));
+        invariants::buffer_len(self.rows, self.cols, self.data.len());
+        let (rows, cols) = (self.rows / 2, self.cols / 2);
+        let quadrant = |top: usize, left: usize| {
+            invariants::tile(self.shape(), top, left, rows, cols);
+            let mut data = Vec::with_capacity(rows * cols);
+            for i in top..top + rows {
+                data.extend_from_slice(&self.data[i * self.cols + left..i * self.cols + left + cols]);
+            }
+            Matrix::new_unchecked(rows, cols, data)
+        };
+        [quadrant(0, 0), quadrant(0, cols), quadrant(rows, 0), quadrant(rows, cols)]
+    }
+
+    // The inverse of `quadrants`.
+    pub(crate) fn join([top_left, top_right, bottom_left, bottom_right]: [Matrix; 4]) -> Matrix {
+        let (rows, cols) = (top_left.rows * 2, top_left.cols * 2);
+        let mut data = Vec::with_capacity(rows * cols);
+        for (left, right) in [(&top_left, &top_right), (&bottom_left, &bottom_right)] {
+            for i in 0..left.rows {
+                data.extend_from_slice(left.row(i));
+                data.extend_from_slice(right.row(i));
+            }
+        }
+        Matrix::new_unchecked(rows, cols, data)
+    }
+}
+
+fn leaf(a: &Matrix, b: &Matrix, algorithm: Algorithm) -> Matrix {
+    a.multiply_blocked_with(b, DEFAULT_BLOCK_SIZE, algorithm, &CancelToken::new()).unwrap()
+}
+
+// `a * b` for dimensions that halve evenly `depth` times.
+fn strassen(a: &Matrix, b: &Matrix, depth: usize, algorithm: Algorithm) -> Matrix {
+    if depth == 0 {
+        return leaf(a, b, algorithm);
+    }
+    let [a11, a12, a21, a22] = a.quadrants();
+    let [b11, b12, b21, b22] = b.quadrants();
+    let add = |x: &Matrix, y: &Matrix| x.add(y).unwrap();
+    let sub = |x: &Matrix, y: &Matrix| x.sub(y).unwrap();
+    let product = |x: &Matrix, y: &Matrix| strassen(x, y, depth - 1, algorithm);
+
+    let products: [&(dyn Fn() -> Matrix + Sync); 7] = [
+        &|| product(&add(&a11, &a22), &add(&b11, &b22)),
+        &|| product(&add(&a21, &a22), &b11),
+        &|| product(&a11, &sub(&b12, &b22)),
+        &|| product(&a22, &sub(&b21, &b11)),
+        &|| product(&add(&a11, &a12), &b22),
+        &|| product(&sub(&a21, &a11), &add(&b11, &b12)),
+        &|| product(&sub(&a12, &a22), &add(&b21, &b22)),
+    ];
+    let [m1, m2, m3, m4, m5, m6, m7] = match algorithm {
+        Algorithm::Seq => products.map(|product| product()),
+        Algorithm::Par => products.par_iter().map(|product| product()).collect::<Vec<_>>().try_into().unwrap(),
+    };
+
+    let c11 = add(&sub(&add(&m1, &m4), &m5), &m7);
+    let c12 = add(&m3, &m5);
+    let c21 = add(&m2, &m4);
+    let c22 = add(&add(&sub(&m1, &m2), &m3), &m6);
+    Matrix::join([c11, c12, c21, c22])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_naive_product() {
+        // Odd sizes pad at the top, and small cutoffs recurse several levels.
+        let cases = [(300, 300, 300, 32), (257, 123, 511, 16), (64, 64, 64, 8), (5, 7, 3, 1), (300, 300, 300, DEFAULT_CUTOFF)];
+        for (n, m, k, cutoff) in cases {
+            let (a, b) = (Matrix::random_seeded(n, m, 1), Matrix::random_seeded(m, k, 2));
+            let naive = a.multiply_plain(&b);
+            for product in [a.multiply_strassen(&b, cutoff).unwrap(), a.multiply_strassen_par(&b, cutoff).unwrap()] {
+                assert_eq!(product.shape(), (n, k));
+                assert!(product.approx_eq(&naive, 1e-12, 0.0), "{}x{}x{} cutoff {}", n, m, k, cutoff);
+            }
+        }
+    }
+
+    #[test]
+    fn quadrants_round_trip() {
+        let m = Matrix::new_unchecked(4, 6, (0..24).map(f64::from).collect());
+        let quadrants = m.quadrants();
+        assert_eq!(quadrants[1], Matrix::new_unchecked(2, 3, vec![3.0, 4.0, 5.0, 9.0, 10.0, 11.0]));
+        assert_eq!(Matrix::join(quadrants), m);
+
+        let padded = Matrix::new_unchecked(2, 2, vec![1.0, 2.0, 3.0, 4.0]).padded(3, 4);
+        assert_eq!(padded.data, [1.0, 2.0, 0.0, 0.0, 3.0, 4.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]);
+        assert_eq!(padded.padded(2, 2).data, [1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn errors() {
+        let (a, b) = (Matrix::random(4, 3), Matrix::random(4, 3));
+        assert!(matches!(a.multiply_strassen(&b, 1), Err(MatrixError::DimensionMismatch { .. })));
+        let empty = Matrix::new_unchecked(0, 3, vec![]);
+        assert_eq!(empty.multiply_strassen(&Matrix::random(3, 2), 1).unwrap().shape(), (0, 2));
+    }
+
+    #[test]
+    #[should_panic(expected = "invariant: 6x4 matrix backed by 16 elements")]
+    fn invariants_catch_corrupted_quadrants() {
+        let mut m = Matrix::random(4, 4);
+        m.rows = 6;
+        m.quadrants();
+    }
+}
@@ -0,0 +1,731 @@
+//! The text matrix parser, reading a buffer at a time from any `BufRead`.
+//!
+//! Lines are never collected: bytes are scanned as they arrive, and only
+//! the token in progress is carried from one buffer to the next. Parsing a
+//! file therefore takes the parsed elements, the reader's buffer and one
+//! token of memory, however long its lines are.
+//!
+//! The format is the one `str::lines` and `split_whitespace` give: rows end
+//! at `\n` or `\r\n`, the last row need not end at all, elements are
+//! separated by any run of spaces and tabs, and lines with nothing but
+//! whitespace are skipped. Line numbers in errors still count them.
+//!
+//! CSV is read by the same parser with commas between elements instead.
+//! There, elements may have whitespace around them and be quoted, as in
+//! `"1.5e-3"`, but an empty element is an error; a quoted comma is part of
+//! the element, which is then not a number. A row still ends at every
+//! newline, quoted or not.
+//!
+//! Input files hold several operands separated by a line with `X` or `x`
+//! and nothing else but whitespace. An `x` anywhere else is not one: in a
+//! COO comment it is text, and in a row it is a malformed number.
+
+use std::io::{self, BufRead};
+
+use crate::{numfmt, Matrix, MatrixError, ParseOptions, ParseReport, RaggedPolicy};
+
+/// Parses a whole matrix from `reader`, passing every element through
+/// `transform` before it is stored.
+pub(crate) fn read_matrix(
+    reader: impl BufRead,
+    options: &ParseOptions,
+    transform: impl FnMut(f64) -> f64,
+) -> Result<(Matrix, ParseReport), MatrixError> {
+    Tokenizer::new(false, false, transform).run(reader)?.into_matrix(options)
+}
+
+/// `read_matrix` for CSV. Whitespace at both ends is ignored, so trailing
+/// newlines are too.
+pub(crate) fn read_csv(
+    reader: impl BufRead,
+    options: &ParseOptions,
+    transform: impl FnMut(f64) -> f64,
+) -> Result<(Matrix, ParseReport), MatrixError> {
+    Tokenizer::new(true, true, transform).run(reader)?.into_matrix(options)
+}
+
+/// `read_matrix` for one operand of an input file: reads up to the next
+/// line starting with `X` or the end, and ignores whitespace at both ends,
+/// like parsing the trimmed text between separators.
+pub fn read_operand(
+    reader: &mut impl BufRead,
+    options: &ParseOptions,
+    transform: impl FnMut(f64) -> f64,
+) -> Result<(Matrix, ParseReport), MatrixError> {
+    Tokenizer::new(true, false, transform).run(UntilSeparator::new(reader))?.into_matrix(options)
+}
+
+/// `read_operand` for CSV.
+pub fn read_csv_operand(
+    reader: &mut impl BufRead,
+    options: &ParseOptions,
+    transform: impl FnMut(f64) -> f64,
+) -> Result<(Matrix, ParseReport), MatrixError> {
+    Tokenizer::new(true, true, transform).run(UntilSeparator::new(reader))?.into_matrix(options)
+}
+
+fn is_separator(b: u8) -> bool {
+    b == b'X' || b == b'x'
+}
+
+// Whitespace that does not end the line.
+fn is_blank(b: u8) -> bool {
+    b.is_ascii_whitespace() && b != b'\n'
+}
+
+// Whether a line that was at its start (or had only blanks) still is
+// after `bytes`.
+fn line_start_after(at_line_start: bool, bytes: &[u8]) -> bool {
+    bytes.iter().fold(at_line_start, |at_start, &b| b == b'\n' || (at_start && is_blank(b)))
+}
+
+/// The operands of a whole input file, trimmed.
+pub fn split_operands(text: &str) -> Vec<&str> {
+    let mut operands = Vec::new();
+    let (mut start, mut offset) = (0, 0);
+    for line in text.split_inclusive('\n') {
+        if matches!(line.trim(), "X" | "x") {
+            operands.push(text[start..offset].trim());
+            start = offset + line.len();
+        }
+        offset += line.len();
+    }
+    operands.push(text[start..].trim());
+    operands
+}
+
+/// Consumes the separator line after an operand, which `read_operand`
+/// stops at. False at the end of the input, and an error if the line has
+/// more than the `X`.
+pub fn skip_separator(reader: &mut impl BufRead) -> io::Result<bool> {
+    if !reader.fill_buf()?.first().is_some_and(|&b| is_separator(b)) {
+        return Ok(false);
+    }
+    reader.consume(1);
+    loop {
+        let buf = reader.fill_buf()?;
+        match buf.iter().position(|&b| !is_blank(b)) {
+            None if buf.is_empty() => return Ok(true),
+            None => {
+                let n = buf.len();
+                reader.consume(n);
+            }
+            Some(i) if buf[i] == b'\n' => {
+                reader.consume(i + 1);
+                return Ok(true);
+            }
+            Some(_) => {
+                let message = "a line starting with X must hold only the X, to separate operands";
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+            }
+        }
+    }
+}
+
+/// The shape of every operand of an input file, found by counting the
+/// elements of each row without parsing them, so a malformed number goes
+/// unnoticed. Rows must all be as long as the first, as for
+/// `RaggedPolicy::Error`; line numbers count from the start of the file.
+pub fn scan_shapes(mut reader: impl BufRead, csv: bool) -> Result<Vec<(usize, usize)>, MatrixError> {
+    let mut scan = ShapeScan {
+        csv,
+        shapes: Vec::new(),
+        rows: 0,
+        cols: 0,
+        line: 1,
+        elements: 0,
+        in_token: false,
+        in_line: false,
+        quoted: false,
+        at_line_start: true,
+        held: None,
+    };
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        for &b in buf {
+            scan.byte(b)?;
+        }
+        let n = buf.len();
+        reader.consume(n);
+    }
+    // A separator on the last line, with an empty operand after it.
+    if scan.held.take().is_some() {
+        scan.end_operand()?;
+    }
+    scan.end_operand()?;
+    Ok(scan.shapes)
+}
+
+struct ShapeScan {
+    csv: bool,
+    shapes: Vec<(usize, usize)>,
+    /// The operand so far.
+    rows: usize,
+    cols: usize,
+    /// The line so far: elements started, or commas in CSV.
+    line: usize,
+    elements: usize,
+    in_token: bool,
+    in_line: bool,
+    quoted: bool,
+    at_line_start: bool,
+    /// An `X` at the start of the line, and whether blanks followed it:
+    /// a separator if nothing else does.
+    held: Option<bool>,
+}
+
+impl ShapeScan {
+    fn byte(&mut self, b: u8) -> Result<(), MatrixError> {
+        if let Some(spaced) = self.held {
+            match b {
+                b'\n' => {
+                    self.held = None;
+                    self.end_operand()?;
+                    self.line += 1;
+                    return Ok(());
+                }
+                b if is_blank(b) => {
+                    self.held = Some(true);
+                    return Ok(());
+                }
+                // Not a separator after all, just an element.
+                _ => {
+                    self.held = None;
+                    self.step(b'X')?;
+                    if spaced {
+                        self.step(b' ')?;
+                    }
+                }
+            }
+        }
+        if self.at_line_start && is_separator(b) && !self.quoted {
+            self.held = Some(false);
+            return Ok(());
+        }
+        self.at_line_start = line_start_after(self.at_line_start, &[b]);
+        self.step(b)
+    }
+
+    fn step(&mut self, b: u8) -> Result<(), MatrixError> {
+        match b {
+            b'\n' => {
+                self.end_row()?;
+                self.line += 1;
+                self.quoted = false;
+            }
+            b'"' if self.csv => {
+                self.in_line = true;
+                self.quoted = !self.quoted;
+            }
+            b',' if self.csv && !self.quoted => {
+                self.in_line = true;
+                self.elements += 1;
+            }
+            b if b.is_ascii_whitespace() => self.in_token = false,
+            _ => {
+                self.in_line = true;
+                if !self.csv && !self.in_token {
+                    self.elements += 1;
+                }
+                self.in_token = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn end_row(&mut self) -> Result<(), MatrixError> {
+        if self.in_line {
+            let found = self.elements + self.csv as usize;
+            if self.rows == 0 {
+                self.cols = found;
+            } else if found != self.cols {
+                return Err(MatrixError::RaggedRow {
+                    line: self.line,
+                    expected: self.cols,
+                    found,
+                });
+            }
+            self.rows += 1;
+        }
+        self.elements = 0;
+        self.in_token = false;
+        self.in_line = false;
+        Ok(())
+    }
+
+    fn end_operand(&mut self) -> Result<(), MatrixError> {
+        self.end_row()?;
+        self.shapes.push((self.rows, self.cols));
+        self.rows = 0;
+        self.cols = 0;
+        Ok(())
+    }
+}
+
+// The bytes of `R` up to the next line starting with `X`.
+struct UntilSeparator<'a, R> {
+    reader: &'a mut R,
+    /// Only blanks since the last newline consumed, if any.
+    at_line_start: bool,
+}
+
+impl<'a, R: BufRead> UntilSeparator<'a, R> {
+    fn new(reader: &'a mut R) -> UntilSeparator<'a, R> {
+        UntilSeparator { reader, at_line_start: true }
+    }
+}
+
+impl<R: BufRead> io::Read for UntilSeparator<'_, R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let buf = self.fill_buf()?;
+        let n = buf.len().min(out.len());
+        out[..n].copy_from_slice(&buf[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for UntilSeparator<'_, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let buf = self.reader.fill_buf()?;
+        let mut at_line_start = self.at_line_start;
+        let end = buf.iter().position(|&b| {
+            let found = at_line_start && is_separator(b);
+            at_line_start = line_start_after(at_line_start, &[b]);
+            found
+        });
+        Ok(&buf[..end.unwrap_or(buf.len())])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        // What is consumed was just returned by fill_buf, so this reads
+        // nothing.
+        if let Ok(buf) = self.reader.fill_buf() {
+            self.at_line_start = line_start_after(self.at_line_start, &buf[..amount.min(buf.len())]);
+        }
+        self.reader.consume(amount);
+    }
+}
+
+struct Tokenizer<F> {
+    trim: bool,
+    /// Commas between elements, which may be quoted and padded.
+    csv: bool,
+    /// Inside a quoted CSV element.
+    quoted: bool,
+    transform: F,
+    data: Vec<f64>,
+    /// (line number, elements) of each finished row.
+    rows: Vec<(usize, usize)>,
+    token: Vec<u8>,
+    /// Whether anything but whitespace was read since the last `\n`.
+    in_line: bool,
+    row_start: usize,
+    line: usize,
+    /// Bytes of the line before the current one, and before the token in
+    /// progress.
+    column: usize,
+    token_start: usize,
+    /// With `trim`: whitespace not yet known to be followed by anything
+    /// else, and whether anything else was read at all.
+    pending: Vec<u8>,
+    started: bool,
+    inexact_integers: usize,
+}
+
+impl<F: FnMut(f64) -> f64> Tokenizer<F> {
+    fn new(trim: bool, csv: bool, transform: F) -> Tokenizer<F> {
+        Tokenizer {
+            trim,
+            csv,
+            quoted: false,
+            transform,
+            data: Vec::new(),
+            rows: Vec::new(),
+            token: Vec::new(),
+            in_line: false,
+            row_start: 0,
+            line: 1,
+            column: 0,
+            token_start: 0,
+            pending: Vec::new(),
+            started: false,
+            inexact_integers: 0,
+        }
+    }
+
+    fn run(mut self, mut reader: impl BufRead) -> Result<Tokenizer<F>, MatrixError> {
+        loop {
+            let buf = reader.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            for &b in buf {
+                self.byte(b)?;
+            }
+            let n = buf.len();
+            reader.consume(n);
+        }
+        if self.in_line {
+            self.end_token()?;
+            self.end_row();
+        }
+        Ok(self)
+    }
+
+    fn byte(&mut self, b: u8) -> Result<(), MatrixError> {
+        if self.trim {
+            if b.is_ascii_whitespace() {
+                if self.started {
+                    self.pending.push(b);
+                }
+                return Ok(());
+            }
+            self.started = true;
+            let pending = std::mem::take(&mut self.pending);
+            for &b in &pending {
+                self.step(b)?;
+            }
+            // Keep the allocation for the next run of whitespace.
+            self.pending = pending;
+            self.pending.clear();
+        }
+        self.step(b)
+    }
+
+    fn step(&mut self, b: u8) -> Result<(), MatrixError> {
+        self.column += 1;
+        match b {
+            b'\n' => {
+                self.quoted = false;
+                if !self.in_line {
+                    self.skip_line();
+                    return Ok(());
+                }
+                if self.token.last() == Some(&b'\r') {
+                    self.token.pop();
+                }
+                self.end_token()?;
+                self.end_row();
+                Ok(())
+            }
+            b if b.is_ascii_whitespace() && !self.csv => self.end_token(),
+            b',' if self.csv && !self.quoted => {
+                self.in_line = true;
+                self.end_token()
+            }
+            b'"' if self.csv => {
+                self.in_line = true;
+                self.quoted = !self.quoted;
+                Ok(())
+            }
+            _ => {
+                self.in_line |= !b.is_ascii_whitespace();
+                self.token.push(b);
+                Ok(())
+            }
+        }
+    }
+
+    fn end_token(&mut self) -> Result<(), MatrixError> {
+        let csv = self.csv;
+        // Runs of whitespace are one separator; in CSV, an empty element
+        // is an error like any other non-number.
+        if self.token.is_empty() && !csv {
+            self.token_start = self.column;
+            return Ok(());
+        }
+        let parsed = std::str::from_utf8(&self.token).ok().and_then(|token| {
+            let token = if csv { token.trim() } else { token };
+            let x = numfmt::parse_f64(token)?;
+            self.inexact_integers += numfmt::is_inexact_integer(token, x) as usize;
+            Some(x)
+        });
+        match parsed {
+            Some(x) => self.data.push((self.transform)(x)),
+            None => {
+                return Err(MatrixError::InvalidNumber {
+                    line: self.line,
+                    column: self.token_start + 1,
+                    token: String::from_utf8_lossy(&self.token).into_owned(),
+                })
+            }
+        }
+        self.token.clear();
+        self.token_start = self.column;
+        Ok(())
+    }
+
+    fn end_row(&mut self) {
+        self.rows.push((self.line, self.data.len() - self.row_start));
+        self.row_start = self.data.len();
+        self.skip_line();
+    }
+
+    fn skip_line(&mut self) {
+        self.token.clear();
+        self.line += 1;
+        self.column = 0;
+        self.token_start = 0;
+        self.in_line = false;
+    }
+
+    // Rows only need reshuffling if their lengths differ.
+    fn into_matrix(self, options: &ParseOptions) -> Result<(Matrix, ParseReport), MatrixError> {
+        let Tokenizer { data, rows, inexact_integers, .. } = self;
+        let first = rows.first().map_or(0, |&(_, len)| len);
+        let widest = rows.iter().map(|&(_, len)| len).max().unwrap_or(0);
+        let cols = match options.ragged {
+            RaggedPolicy::PadZero => widest,
+            RaggedPolicy::Error | RaggedPolicy::Truncate => first,
+        };
+
+        let mut report = ParseReport {
+            inexact_integers,
+            ..ParseReport::default()
+        };
+        if rows.iter().all(|&(_, len)| len == cols) {
+            return Ok((Matrix::try_new(rows.len(), cols, data)?, report));
+        }
+
+        let mut fixed = Vec::with_capacity(rows.len() * cols);
+        let mut offset = 0;
+        for &(line, len) in &rows {
+            let values = &data[offset..offset + len];
+            offset += len;
+
+            if len < cols && options.ragged == RaggedPolicy::PadZero {
+                fixed.extend_from_slice(values);
+                fixed.resize(fixed.len() + cols - len, 0.0);
+                report.padded_rows += 1;
+            } else if len > cols && options.ragged == RaggedPolicy::Truncate {
+                fixed.extend_from_slice(&values[..cols]);
+                report.truncated_rows += 1;
+            } else if len != cols {
+                return Err(MatrixError::RaggedRow {
+                    line,
+                    expected: cols,
+                    found: len,
+                });
+            } else {
+                fixed.extend_from_slice(values);
+            }
+        }
+
+        Ok((Matrix::try_new(rows.len(), cols, fixed)?, report))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Read};
+
+    use super::*;
+    use crate::alloc_counter;
+
+    fn parse(s: &str) -> Result<Matrix, MatrixError> {
+        read_matrix(s.as_bytes(), &ParseOptions::default(), |x| x).map(|(m, _)| m)
+    }
+
+    // `s` a few bytes at a time, so tokens straddle buffer fills.
+    fn parse_in_pieces(s: &str, capacity: usize) -> Result<Matrix, MatrixError> {
+        let reader = BufReader::with_capacity(capacity, s.as_bytes());
+        read_matrix(reader, &ParseOptions::default(), |x| x).map(|(m, _)| m)
+    }
+
+    #[test]
+    fn line_endings() {
+        for text in ["1 2\n3 4", "1 2\r\n3 4\r\n", "1 2\n3 4\n", "1 2\n3 4\r", "1 2\r\r\n3 4"] {
+            assert_eq!(parse(text).unwrap(), Matrix::new_unchecked(2, 2, vec![1.0, 2.0, 3.0, 4.0]), "{:?}", text);
+        }
+        assert_eq!(parse("").unwrap().shape(), (0, 0));
+        assert_eq!(parse("\r\n\n").unwrap().shape(), (0, 0));
+
+        let text = "0.25 -1e300 7\r\n1.5 2.5 3.5\n-0 inf 42";
+        for capacity in 1..12 {
+            assert_eq!(parse_in_pieces(text, capacity).unwrap(), parse(text).unwrap(), "capacity {}", capacity);
+        }
+        match parse_in_pieces("1 2\n3 12345x", 2) {
+            Err(MatrixError::InvalidNumber { line: 2, column: 3, token }) => assert_eq!(token, "12345x"),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn whitespace() {
+        let expected = Matrix::new_unchecked(2, 3, vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        for text in [
+            "1\t2\t3\n4\t5\t6",
+            "1  2   3\n  4 5\t \t6",
+            "1 2 3 \n4 5 6\t\n",
+            "1 2 3\r\n4 5 6 \r\n",
+            "\n\n1 2 3\n\n \t\n4 5 6\n\n",
+        ] {
+            assert_eq!(parse(text).unwrap(), expected, "{:?}", text);
+            assert_eq!(parse_in_pieces(text, 2).unwrap(), expected, "{:?}", text);
+        }
+
+        // Errors count the skipped lines, and columns are bytes from 1.
+        match parse("1 2\n\n3  abc") {
+            Err(err @ MatrixError::InvalidNumber { line: 3, column: 4, .. }) => {
+                assert_eq!(err.to_string(), "line 3, column 4: expected number, found 'abc'")
+            }
+            other => panic!("{:?}", other),
+        }
+        match parse("1 2 3\n\n4\t5\n") {
+            Err(MatrixError::RaggedRow { line: 3, expected: 3, found: 2 }) => {}
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn operands_are_trimmed() {
+        let mut reader = BufReader::with_capacity(3, "\n 1 2\n3 4 \r\n\nX\n\n5\n6\n".as_bytes());
+        let options = ParseOptions::default();
+        let (a, _) = read_operand(&mut reader, &options, |x| x).unwrap();
+        assert!(skip_separator(&mut reader).unwrap());
+        let (b, _) = read_operand(&mut reader, &options, |x| x * 10.0).unwrap();
+        assert!(!skip_separator(&mut reader).unwrap());
+        assert_eq!(a, Matrix::new_unchecked(2, 2, vec![1.0, 2.0, 3.0, 4.0]));
+        assert_eq!(b, Matrix::new_unchecked(2, 1, vec![50.0, 60.0]));
+
+        // Lowercase separators, with blank lines around them.
+        let text = "1 2\n\n x \n\n3\n4\n";
+        let mut reader = text.as_bytes();
+        assert_eq!(read_operand(&mut reader, &options, |x| x).unwrap().0.shape(), (1, 2));
+        assert!(skip_separator(&mut reader).unwrap());
+        assert_eq!(read_operand(&mut reader, &options, |x| x).unwrap().0.shape(), (2, 1));
+        assert_eq!(split_operands(text), ["1 2", "3\n4"]);
+    }
+
+    #[test]
+    fn separators_are_whole_lines() {
+        // COO comments mention x freely.
+        let coo = "% exported matrix\n0 0 1\nX\n% max 2x2\n1 1 2\n";
+        assert_eq!(split_operands(coo), ["% exported matrix\n0 0 1", "% max 2x2\n1 1 2"]);
+        assert_eq!(split_operands("1 2\nX\n"), ["1 2", ""]);
+
+        let options = ParseOptions::default();
+        let operands = |text: &str, capacity| {
+            let mut reader = BufReader::with_capacity(capacity, text.as_bytes());
+            let mut shapes = vec![read_operand(&mut reader, &options, |x| x).map(|(m, _)| m.shape())];
+            while skip_separator(&mut reader)? {
+                shapes.push(read_operand(&mut reader, &options, |x| x).map(|(m, _)| m.shape()));
+            }
+            shapes.into_iter().collect::<Result<Vec<_>, _>>()
+        };
+        for capacity in 1..6 {
+            assert_eq!(operands("1 2\n \tx\r\n3\n", capacity), Ok(vec![(1, 2), (1, 1)]), "capacity {}", capacity);
+            // An x inside a row is a malformed number, not a separator.
+            assert!(matches!(operands("1 x 2\n", capacity), Err(MatrixError::InvalidNumber { line: 1, column: 3, .. })));
+            let extra = operands("1 2\nx 3\n", capacity).unwrap_err();
+            assert!(extra.to_string().contains("only the X"), "{}", extra);
+        }
+
+        for capacity in 1..6 {
+            let shapes = |text: &str| scan_shapes(BufReader::with_capacity(capacity, text.as_bytes()), false).unwrap();
+            assert_eq!(shapes("1 x\n3 4\n  X  \n5\n"), [(2, 2), (1, 1)], "capacity {}", capacity);
+            assert_eq!(shapes("x 1\nx\n"), [(1, 2), (0, 0)], "capacity {}", capacity);
+            assert_eq!(shapes("1\nX"), [(1, 1), (0, 0)], "capacity {}", capacity);
+        }
+    }
+
+    #[test]
+    fn shapes_without_parsing() {
+        let text = "\n1 2 3\r\n4  5\t6\n\nX\n\n7\n8\n9\n";
+        for capacity in 1..6 {
+            let reader = BufReader::with_capacity(capacity, text.as_bytes());
+            assert_eq!(scan_shapes(reader, false).unwrap(), [(2, 3), (3, 1)], "capacity {}", capacity);
+        }
+        // The elements are only counted, so anything that is not a number
+        // is one too.
+        assert_eq!(scan_shapes("1 two\n3 4".as_bytes(), false).unwrap(), [(2, 2)]);
+        assert_eq!(scan_shapes("".as_bytes(), false).unwrap(), [(0, 0)]);
+        assert_eq!(scan_shapes("\"1,5\",2\n3,4\nx\n1,2".as_bytes(), true).unwrap(), [(2, 2), (1, 2)]);
+
+        // Lines count from the start of the file, not of the operand.
+        match scan_shapes("1 2\nX\n1 2\n\n3\n".as_bytes(), false) {
+            Err(MatrixError::RaggedRow { line: 5, expected: 2, found: 1 }) => {}
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn csv() {
+        let m = Matrix::new_unchecked(2, 3, vec![0.25, -0.0, f64::INFINITY, 1e300, 1.5e-3, -7.0]);
+        assert_eq!(m.to_csv(), "0.25,-0,inf\n1e300,0.0015,-7\n");
+        let parsed = Matrix::from_csv(&m.to_csv()).unwrap();
+        assert_eq!(parsed, m);
+        assert!(parsed.data[1].is_sign_negative());
+
+        let text = "\"1.5e-3\", 2 ,\" -4E2 \"\r\n3,4,5\n\n\n";
+        let expected = Matrix::new_unchecked(2, 3, vec![1.5e-3, 2.0, -400.0, 3.0, 4.0, 5.0]);
+        assert_eq!(Matrix::from_csv(text).unwrap(), expected);
+        for capacity in 1..8 {
+            let reader = BufReader::with_capacity(capacity, text.as_bytes());
+            let (m, _) = read_csv(reader, &ParseOptions::default(), |x| x).unwrap();
+            assert_eq!(m, expected, "capacity {}", capacity);
+        }
+        assert_eq!(Matrix::from_csv("").unwrap().shape(), (0, 0));
+
+        // Operands end at X as in the text format.
+        let mut reader = "1,2\n3,4\nX\n5\n6".as_bytes();
+        let (a, _) = read_csv_operand(&mut reader, &ParseOptions::default(), |x| x).unwrap();
+        assert!(skip_separator(&mut reader).unwrap());
+        let (b, _) = read_csv_operand(&mut reader, &ParseOptions::default(), |x| x).unwrap();
+        assert_eq!((a.shape(), b.shape()), ((2, 2), (2, 1)));
+    }
+
+    #[test]
+    fn malformed_csv() {
+        match Matrix::from_csv("1,2,3\n4,5\n6,7,8") {
+            Err(MatrixError::RaggedRow { line: 2, expected: 3, found: 2 }) => {}
+            other => panic!("{:?}", other),
+        }
+        match Matrix::from_csv("1,2\n3,abc") {
+            Err(MatrixError::InvalidNumber { line: 2, column: 3, token }) => assert_eq!(token, "abc"),
+            other => panic!("{:?}", other),
+        }
+        // A quoted comma is part of the element, and an empty one is not 0.
+        assert!(matches!(Matrix::from_csv("\"1,5\",2"), Err(MatrixError::InvalidNumber { line: 1, column: 1, .. })));
+        assert!(matches!(Matrix::from_csv("1,,2"), Err(MatrixError::InvalidNumber { line: 1, column: 3, .. })));
+        assert!(matches!(Matrix::from_csv("1,2,\n3,4,"), Err(MatrixError::InvalidNumber { line: 1, column: 5, .. })));
+    }
+
+    // Ten million elements on one line, read from a reader that never holds
+    // more than a small buffer of them.
+    struct OneLongRow {
+        remaining: usize,
+    }
+
+    impl Read for OneLongRow {
+        fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+            let mut n = 0;
+            while self.remaining > 0 && n + 4 <= out.len() {
+                out[n..n + 4].copy_from_slice(if self.remaining == 1 { b"1.5\n" } else { b"1.5 " });
+                n += 4;
+                self.remaining -= 1;
+            }
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn wide_row_memory() {
+        const ELEMENTS: usize = 10_000_000;
+        let reader = BufReader::with_capacity(64 * 1024, OneLongRow { remaining: ELEMENTS });
+        let (peak, parsed) =
+            alloc_counter::peak_bytes(|| read_matrix(reader, &ParseOptions::default(), |x| x).map(|(m, _)| m));
+        let m = parsed.unwrap();
+        assert_eq!(m.shape(), (1, ELEMENTS));
+        assert!(m.data.iter().all(|&x| x == 1.5));
+
+        // The element vector grows by doubling, so may be up to twice the
+        // data; a line-at-a-time parser would add the 40MB of text as well.
+        let data_bytes = 8 * ELEMENTS;
+        assert!(peak < 2 * data_bytes, "peak {} for {} bytes of data", peak, data_bytes);
+    }
+}
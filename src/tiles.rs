@@ -0,0 +1,438 @@
+//! Tiled multiply that records each output tile as it finishes, so a long
+//! run that fails part way can resume instead of starting over.
+//!
+//! Every tile is written, read back and checked before it counts as done,
+//! with a few retries on an IO error or a mismatched read. A completion
+//! bitmap is saved after each tile; with `resume`, tiles whose bit is set
+//! are read back instead of recomputed, and a tile that no longer matches
+//! its checksum is recomputed rather than trusted.
+//!
+//! The operands and the assembled product are still held in memory; only
+//! the work done so far is kept on disk.
+
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use rayon::prelude::*;
+
+use crate::{invariants, Matrix, MatrixError};
+
+/// Where tiles and the completion record are kept. Tests inject failures
+/// through it.
+pub trait TileStore {
+    fn write_tile(&mut self, index: usize, bytes: &[u8]) -> io::Result<()>;
+    fn read_tile(&mut self, index: usize) -> io::Result<Vec<u8>>;
+    /// The bytes last passed to save_progress, if any.
+    fn load_progress(&mut self) -> io::Result<Option<Vec<u8>>>;
+    fn save_progress(&mut self, bytes: &[u8]) -> io::Result<()>;
+}
+
+/// One file per tile in a directory, plus a `progress` file.
+pub struct TileDir {
+    dir: PathBuf,
+    fsync: bool,
+}
+
+impl TileDir {
+    pub fn create(dir: PathBuf, fsync: bool) -> io::Result<TileDir> {
+        fs::create_dir_all(&dir)?;
+        Ok(TileDir { dir, fsync })
+    }
+
+    fn write_file(&self, name: &str, bytes: &[u8]) -> io::Result<()> {
+        // Written under a temporary name so a crash never leaves half a file
+        // under the real one.
+        let tmp = self.dir.join(format!("{}.tmp", name));
+        let mut file = File::create(&tmp)?;
+        file.write_all(bytes)?;
+        if self.fsync {
+            file.sync_all()?;
+        }
+        fs::rename(tmp, self.dir.join(name))
+    }
+}
+
+impl TileStore for TileDir {
+    fn write_tile(&mut self, index: usize, bytes: &[u8]) -> io::Result<()> {
+        self.write_file(&format!("tile-{}.bin", index), bytes)
+    }
+
+    fn read_tile(&mut self, index: usize) -> io::Result<Vec<u8>> {
+        fs::read(self.dir.join(format!("tile-{}.bin", index)))
+    }
+
+    fn load_progress(&mut self) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.dir.join("progress")) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn save_progress(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.write_file("progress", bytes)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TileOptions {
+    /// Side of a square output tile.
+    pub tile: usize,
+    /// Extra attempts at writing a tile before giving up.
+    pub retries: usize,
+    /// Reuse tiles recorded as done by an earlier run.
+    pub resume: bool,
+}
+
+impl Default for TileOptions {
+    fn default() -> TileOptions {
+        TileOptions {
+            tile: 512,
+            retries: 3,
+            resume: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct TileReport {
+    pub tiles: usize,
+    pub computed: usize,
+    pub reused: usize,
+    /// Tiles recorded as done whose contents failed the check on resume.
+    pub corrupt: usize,
+    /// Write attempts that had to be repeated.
+    pub retries: usize,
+}
+
+const PROGRESS_MAGIC: &[u8; 8] = b"MMULTILE";
+
+// What a completion record describes: shapes, tile size and the operands'
+// checksums, so tiles from a different problem are never reused.
+struct Problem {
+    rows: usize,
+    cols: usize,
+    tile: usize,
+    checksums: (u64, u64),
+}
+
+impl Problem {
+    fn header(&self) -> Vec<u8> {
+        let mut bytes = PROGRESS_MAGIC.to_vec();
+        for x in [self.rows as u64, self.cols as u64, self.tile as u64, self.checksums.0, self.checksums.1] {
+            bytes.extend_from_slice(&x.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn progress(&self, done: &[bool]) -> Vec<u8> {
+        let mut bytes = self.header();
+        let mut bits = vec![0u8; done.len().div_ceil(8)];
+        for (index, _) in done.iter().enumerate().filter(|(_, &done)| done) {
+            bits[index / 8] |= 1 << (index % 8);
+        }
+        bytes.extend_from_slice(&bits);
+        bytes
+    }
+
+    fn parse_progress(&self, bytes: &[u8], tiles: usize) -> Result<Vec<bool>, MatrixError> {
+        let header = self.header();
+        if bytes.len() != header.len() + tiles.div_ceil(8) || !bytes.starts_with(&header) {
+            return Err(MatrixError::Message(
+                "the tile directory holds tiles for a different problem".to_owned(),
+            ));
+        }
+        let bits = &bytes[header.len()..];
+        Ok((0..tiles).map(|index| bits[index / 8] & (1 << (index % 8)) != 0).collect())
+    }
+}
+
+// A tile's elements in little-endian order followed by their checksum.
+fn encode(tile: &Matrix) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity((tile.data.len() + 1) * 8);
+    for x in &tile.data {
+        bytes.extend_from_slice(&x.to_le_bytes());
+    }
+    bytes.extend_from_slice(&tile.checksum().to_le_bytes());
+    bytes
+}
+
+fn decode(bytes: &[u8], rows: usize, cols: usize) -> Option<Matrix> {
+    if bytes.len() != (rows * cols + 1) * 8 {
+        return None;
+    }
+    let (data, checksum) = bytes.split_at(rows * cols * 8);
+    let data = data
+        .chunks_exact(8)
+        .map(|b| f64::from_le_bytes(b.try_into().unwrap()))
+        .collect();
+    let tile = Matrix::try_new(rows, cols, data).ok()?;
+    (tile.checksum().to_le_bytes() == checksum).then_some(tile)
+}
+
+fn io_error(what: &str, err: io::Error) -> MatrixError {
+    io::Error::new(err.kind(), format!("{}: {}", what, err)).into()
+}
+
+impl Matrix {
+    pub fn multiply_tiled(
+        &self,
+        other: &Matrix,
+        store: &mut impl TileStore,
+        options: &TileOptions,
+    ) -> Result<(Matrix, TileReport), MatrixError> {
+        if self.cols != other.rows {
+            return Err(MatrixError::DimensionMismatch {
+                left: self.shape(),
+                right: other.shape(),
+            });
+        }
+
+        let size = options.tile.max(1);
+        let grid = (self.rows.div_ceil(size), other.cols.div_ceil(size));
+        let tiles = grid.0 * grid.1;
+        let problem = Problem {
+            rows: self.rows,
+            cols: other.cols,
+            tile: size,
+            checksums: (self.checksum(), other.checksum()),
+        };
+
+        let mut done = vec![false; tiles];
+        if options.resume {
+            if let Some(bytes) = store.load_progress().map_err(|err| io_error("cannot read tile progress", err))? {
+                done = problem.parse_progress(&bytes, tiles)?;
+            }
+        }
+
+        let mut report = TileReport {
+            tiles,
+            ..TileReport::default()
+        };
+        let mut result = Matrix::new_unchecked(self.rows, other.cols, vec![0.0; self.rows * other.cols]);
+        for index in 0..tiles {
+            let row_start = index / grid.1 * size;
+            let col_start = index % grid.1 * size;
+            let rows = row_start..(row_start + size).min(self.rows);
+            let cols = col_start..(col_start + size).min(other.cols);
+
+            let stored = if done[index] {
+                let bytes = store.read_tile(index).ok();
+                let tile = bytes.and_then(|bytes| decode(&bytes, rows.len(), cols.len()));
+                if tile.is_none() {
+                    report.corrupt += 1;
+                }
+                tile
+            } else {
+                None
+            };
+            let tile = match stored {
+                Some(tile) => {
+                    report.reused += 1;
+                    tile
+                }
+                None => {
+                    let tile = self.multiply_tile(other, rows.clone(), cols.clone());
+                    report.retries += write_verified(store, index, &tile, options.retries)?;
+                    done[index] = true;
+                    store
+                        .save_progress(&problem.progress(&done))
+                        .map_err(|err| io_error("cannot save tile progress", err))?;
+                    report.computed += 1;
+                    tile
+                }
+            };
+
+            invariants::tile(result.shape(), row_start, col_start, tile.rows, tile.cols);
+            for (i, row) in rows.enumerate() {
+                let start = row * result.cols + col_start;
+                result.data[start..start + tile.cols].copy_from_slice(&tile.data[i * tile.cols..(i + 1) * tile.cols]);
+            }
+        }
+        Ok((result, report))
+    }
+
+    // Summed in the same order as Matrix::multiply, so an assembled product
+    // is identical to it.
+    fn multiply_tile(&self, other: &Matrix, rows: std::ops::Range<usize>, cols: std::ops::Range<usize>) -> Matrix {
+        let width = cols.len();
+        invariants::tile((self.rows, other.cols), rows.start, cols.start, rows.len(), width);
+        let mut tile = Matrix::new_unchecked(rows.len(), width, vec![0.0; rows.len() * width]);
+        tile.data
+            .par_chunks_mut(width.max(1))
+            .zip(rows)
+            .for_each(|(out, i)| {
+                for (cell, j) in out.iter_mut().zip(cols.clone()) {
+                    let mut sum = 0.0;
+                    for k in 0..self.cols {
+                        sum += self.get(i, k) * other.get(k, j);
+                    }
+                    *cell = sum;
+                }
+            });
+        tile
+    }
+}
+
+// Writes and reads back a tile until it round-trips, returning how many
+// attempts had to be repeated.
+fn write_verified(store: &mut impl TileStore, index: usize, tile: &Matrix, retries: usize) -> Result<usize, MatrixError> {
+    let bytes = encode(tile);
+    let mut last_error = String::new();
+    for attempt in 0..=retries {
+        let outcome = store
+            .write_tile(index, &bytes)
+            .and_then(|_| store.read_tile(index));
+        match outcome {
+            Ok(read) if read == bytes => return Ok(attempt),
+            Ok(_) => last_error = "read back different bytes".to_owned(),
+            Err(err) => last_error = err.to_string(),
+        }
+    }
+    Err(MatrixError::Message(format!(
+        "tile {} failed after {} attempts: {}",
+        index,
+        retries + 1,
+        last_error
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[derive(Default)]
+    struct MemoryTiles {
+        tiles: HashMap<usize, Vec<u8>>,
+        progress: Option<Vec<u8>>,
+        writes: usize,
+        // Writes (counting from 0) that fail, and ones that store damaged bytes.
+        fail_writes: Vec<usize>,
+        corrupt_writes: Vec<usize>,
+    }
+
+    impl TileStore for MemoryTiles {
+        fn write_tile(&mut self, index: usize, bytes: &[u8]) -> io::Result<()> {
+            let write = self.writes;
+            self.writes += 1;
+            if self.fail_writes.contains(&write) {
+                return Err(io::Error::other("disk on fire"));
+            }
+            let mut bytes = bytes.to_vec();
+            if self.corrupt_writes.contains(&write) {
+                bytes[0] ^= 1;
+            }
+            self.tiles.insert(index, bytes);
+            Ok(())
+        }
+
+        fn read_tile(&mut self, index: usize) -> io::Result<Vec<u8>> {
+            self.tiles
+                .get(&index)
+                .cloned()
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+        }
+
+        fn load_progress(&mut self) -> io::Result<Option<Vec<u8>>> {
+            Ok(self.progress.clone())
+        }
+
+        fn save_progress(&mut self, bytes: &[u8]) -> io::Result<()> {
+            self.progress = Some(bytes.to_vec());
+            Ok(())
+        }
+    }
+
+    fn options(resume: bool) -> TileOptions {
+        TileOptions {
+            tile: 4,
+            retries: 2,
+            resume,
+        }
+    }
+
+    #[test]
+    fn tiled_matches_multiply() {
+        let a = Matrix::random(10, 7);
+        let b = Matrix::random(7, 9);
+        let mut store = MemoryTiles::default();
+        let (m, report) = a.multiply_tiled(&b, &mut store, &options(false)).unwrap();
+        assert_eq!(m, a.multiply(&b).unwrap());
+        assert_eq!(report.tiles, 9);
+        assert_eq!(report.computed, 9);
+        assert!(a.multiply_tiled(&a, &mut store, &options(false)).is_err());
+    }
+
+    #[test]
+    fn failed_writes_are_retried() {
+        let a = Matrix::random(8, 5);
+        let b = Matrix::random(5, 8);
+
+        // One error and one damaged write on the third tile, then success.
+        let mut store = MemoryTiles {
+            fail_writes: vec![2],
+            corrupt_writes: vec![3],
+            ..MemoryTiles::default()
+        };
+        let (m, report) = a.multiply_tiled(&b, &mut store, &options(false)).unwrap();
+        assert_eq!(m, a.multiply(&b).unwrap());
+        assert_eq!(report.retries, 2);
+        assert_eq!(store.writes, 4 + 2);
+
+        // More failures than retries gives up.
+        let mut store = MemoryTiles {
+            fail_writes: vec![2, 3, 4],
+            ..MemoryTiles::default()
+        };
+        let err = a.multiply_tiled(&b, &mut store, &options(false)).unwrap_err();
+        assert!(err.to_string().contains("tile 2 failed after 3 attempts"), "{}", err);
+    }
+
+    #[test]
+    fn resume_recomputes_missing_tiles() {
+        let a = Matrix::random(12, 6);
+        let b = Matrix::random(6, 8);
+        let mut store = MemoryTiles::default();
+        a.multiply_tiled(&b, &mut store, &options(false)).unwrap();
+        assert_eq!(store.writes, 6);
+
+        // Clear the bits of tiles 1 and 4, and damage tile 5.
+        let progress = store.progress.as_mut().unwrap();
+        let bits = progress.len() - 1;
+        progress[bits] &= !(1 << 1 | 1 << 4);
+        store.tiles.get_mut(&5).unwrap()[3] ^= 0x40;
+
+        let (m, report) = a.multiply_tiled(&b, &mut store, &options(true)).unwrap();
+        assert_eq!(m, a.multiply(&b).unwrap());
+        assert_eq!((report.computed, report.reused, report.corrupt), (3, 3, 1));
+        assert_eq!(store.writes, 6 + 3);
+
+        // Everything is done now.
+        let (_, report) = a.multiply_tiled(&b, &mut store, &options(true)).unwrap();
+        assert_eq!((report.computed, report.reused), (0, 6));
+
+        // Different operands do not reuse the tiles.
+        let err = b.transpose().multiply_tiled(&a.transpose(), &mut store, &options(true)).unwrap_err();
+        assert!(err.to_string().contains("different problem"));
+    }
+
+    #[test]
+    fn tile_dir_round_trip() {
+        let dir = std::env::temp_dir().join(format!("matrix-mul-tiles-{}", std::process::id()));
+        let a = Matrix::random(9, 3);
+        let b = Matrix::random(3, 5);
+        let mut store = TileDir::create(dir.clone(), true).unwrap();
+        let (m, _) = a.multiply_tiled(&b, &mut store, &options(false)).unwrap();
+        assert_eq!(m, a.multiply(&b).unwrap());
+
+        let mut store = TileDir::create(dir.clone(), false).unwrap();
+        let (m, report) = a.multiply_tiled(&b, &mut store, &options(true)).unwrap();
+        assert_eq!(m, a.multiply(&b).unwrap());
+        assert_eq!(report.reused, 6);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
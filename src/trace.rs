@@ -0,0 +1,245 @@
+//! `--trace-file`: a timeline of the run in the Chrome trace-event format,
+//! for chrome://tracing or Perfetto.
+//!
+//! Phases come from `EventSink::phase_finished`, so every phase the run
+//! reports (load, which is the parse, the multiplies and write) is a span
+//! on the main thread. The row kernels add one span per band of rows: the
+//! rows one rayon task, or the sequential loop, went through, on the thread
+//! that ran it, with the time the band's epilogues took. Without a trace
+//! the kernels only check for one per band.
+
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    fs, io,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{events::quote, numfmt::format_f64_roundtrip};
+
+static NEXT_THREAD: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    // Chrome wants small integer thread ids, which std does not give out.
+    static THREAD: u64 = NEXT_THREAD.fetch_add(1, Ordering::Relaxed);
+}
+
+#[derive(Debug)]
+struct Span {
+    name: String,
+    category: &'static str,
+    start: Duration,
+    duration: Duration,
+    thread: u64,
+    args: Vec<(&'static str, String)>,
+}
+
+#[derive(Debug)]
+pub struct Trace {
+    epoch: Instant,
+    spans: Mutex<Vec<Span>>,
+    /// Names of the threads seen so far, by id.
+    threads: Mutex<HashMap<u64, String>>,
+}
+
+impl Trace {
+    pub fn new() -> Trace {
+        Trace {
+            epoch: Instant::now(),
+            spans: Mutex::new(Vec::new()),
+            threads: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records a span from `start` to `end` on the calling thread.
+    pub(crate) fn span(&self, name: &str, category: &'static str, start: Instant, end: Instant, args: Vec<(&'static str, String)>) {
+        let thread = THREAD.with(|id| *id);
+        self.threads.lock().unwrap().entry(thread).or_insert_with(|| match std::thread::current().name() {
+            Some(name) => name.to_owned(),
+            None => format!("worker {}", thread),
+        });
+        self.spans.lock().unwrap().push(Span {
+            name: name.to_owned(),
+            category,
+            start: start.saturating_duration_since(self.epoch),
+            duration: end.saturating_duration_since(start),
+            thread,
+            args,
+        });
+    }
+
+    /// A band of rows starting now, recorded when it is dropped.
+    pub(crate) fn band(&self) -> Band<'_> {
+        Band {
+            trace: self,
+            start: Instant::now(),
+            rows: None,
+            epilogue: Duration::ZERO,
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> String {
+        let micros = |d: Duration| format_f64_roundtrip(d.as_nanos() as f64 / 1000.0);
+        let mut events = Vec::new();
+        let mut threads: Vec<_> = self.threads.lock().unwrap().iter().map(|(&id, name)| (id, name.clone())).collect();
+        threads.sort();
+        for (id, name) in threads {
+            events.push(format!(
+                "{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":1,\"tid\":{},\"args\":{{\"name\":{}}}}}",
+                id,
+                quote(&name)
+            ));
+        }
+        for span in self.spans.lock().unwrap().iter() {
+            let mut event = format!(
+                "{{\"name\":{},\"cat\":\"{}\",\"ph\":\"X\",\"ts\":{},\"dur\":{},\"pid\":1,\"tid\":{}",
+                quote(&span.name),
+                span.category,
+                micros(span.start),
+                micros(span.duration),
+                span.thread
+            );
+            if !span.args.is_empty() {
+                let args: Vec<String> = span.args.iter().map(|(key, value)| format!("{}:{}", quote(key), value)).collect();
+                let _ = write!(event, ",\"args\":{{{}}}", args.join(","));
+            }
+            event.push('}');
+            events.push(event);
+        }
+        format!("{{\"displayTimeUnit\":\"ms\",\"traceEvents\":[\n{}\n]}}\n", events.join(",\n"))
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_json())
+    }
+}
+
+impl Default for Trace {
+    fn default() -> Trace {
+        Trace::new()
+    }
+}
+
+/// The rows one kernel task computed.
+pub(crate) struct Band<'a> {
+    trace: &'a Trace,
+    start: Instant,
+    rows: Option<(usize, usize)>,
+    epilogue: Duration,
+}
+
+impl Band<'_> {
+    /// Counts row `i`, timing `epilogues`, which apply its epilogues.
+    pub(crate) fn row(&mut self, i: usize, epilogues: impl FnOnce()) {
+        let start = Instant::now();
+        epilogues();
+        self.epilogue += start.elapsed();
+        let (first, last) = self.rows.get_or_insert((i, i));
+        *first = (*first).min(i);
+        *last = (*last).max(i);
+    }
+}
+
+impl Drop for Band<'_> {
+    fn drop(&mut self) {
+        // A task that was cancelled before its first row did nothing.
+        let Some((first, last)) = self.rows else {
+            return;
+        };
+        let args = vec![
+            ("first_row", first.to_string()),
+            ("rows", (last - first + 1).to_string()),
+            ("epilogue_us", format_f64_roundtrip(self.epilogue.as_nanos() as f64 / 1000.0)),
+        ];
+        self.trace.span("band", "compute", self.start, Instant::now(), args);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{bench::Json, events::EventSink, Algorithm, Context, Epilogue, Matrix, MultiplyOptions};
+
+    fn traced_multiply(algorithm: Algorithm) -> Json {
+        let trace = Arc::new(Trace::new());
+        let mut events = EventSink::disabled();
+        events.set_trace(Arc::clone(&trace));
+        let pool = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap());
+
+        let a = Matrix::random(200, 60);
+        let b = Matrix::random(60, 50);
+        let context = Context::new().pool(pool).trace(Arc::clone(&trace));
+        let options = MultiplyOptions::new()
+            .algorithm(algorithm)
+            .inline_below(0)
+            .epilogue(Epilogue::Apply(f64::abs))
+            .context(context);
+        events.phase_started("load");
+        events.phase_finished("load", Duration::from_micros(1));
+        events.phase_started("multiply");
+        let start = Instant::now();
+        let product = a.multiply_with(&b, &options).unwrap();
+        events.phase_finished("multiply", start.elapsed());
+        assert_eq!(product.shape(), (200, 50));
+        Json::parse(&trace.to_json()).unwrap()
+    }
+
+    fn spans(trace: &Json) -> Vec<&Json> {
+        let events = trace.get("traceEvents").and_then(Json::as_array).unwrap();
+        events.iter().filter(|event| event.get("ph").and_then(Json::as_str) == Some("X")).collect()
+    }
+
+    fn number(event: &Json, key: &str) -> f64 {
+        event.get(key).and_then(Json::as_f64).unwrap_or_else(|| panic!("no {} in {:?}", key, event))
+    }
+
+    #[test]
+    fn bands_fall_within_the_multiply() {
+        for algorithm in [Algorithm::Seq, Algorithm::Par] {
+            let trace = traced_multiply(algorithm);
+            let spans = spans(&trace);
+            for span in &spans {
+                for key in ["ts", "dur", "tid"] {
+                    assert!(number(span, key) >= 0.0);
+                }
+                assert!(span.get("name").and_then(Json::as_str).is_some());
+            }
+            let phase = |name| *spans.iter().find(|s| s.get("name").and_then(Json::as_str) == Some(name)).unwrap();
+            let multiply = phase("multiply");
+            let (start, end) = (number(multiply, "ts"), number(multiply, "ts") + number(multiply, "dur"));
+            assert!(number(phase("load"), "ts") <= start);
+
+            let bands: Vec<&Json> =
+                spans.iter().copied().filter(|s| s.get("name").and_then(Json::as_str) == Some("band")).collect();
+            assert!(!bands.is_empty());
+            let mut rows = 0.0;
+            for band in &bands {
+                let (ts, dur) = (number(band, "ts"), number(band, "dur"));
+                assert!(ts >= start && ts + dur <= end, "band {}+{} outside {}..{}", ts, dur, start, end);
+                let args = band.get("args").unwrap();
+                rows += number(args, "rows");
+                assert!(number(args, "epilogue_us") <= dur);
+            }
+            // Every row is in exactly one band.
+            assert_eq!(rows, 200.0);
+            if algorithm == Algorithm::Seq {
+                assert_eq!(bands.len(), 1);
+            }
+        }
+    }
+
+    #[test]
+    fn untraced_context_records_nothing() {
+        assert!(Context::new().band().is_none());
+        let trace = Trace::new();
+        let json = Json::parse(&trace.to_json()).unwrap();
+        assert_eq!(json.get("traceEvents").and_then(Json::as_array).map(<[Json]>::len), Some(0));
+    }
+}
@@ -0,0 +1,142 @@
+//! Per-element transforms applied while parsing input files.
+//!
+//! Accepted forms for `--map-input`:
+//!
+//! - `replace-sentinel:<SENTINEL>:<VALUE>` replaces every element equal to
+//!   SENTINEL (NaN matches NaN) with VALUE
+//! - `log1p` replaces x with ln(1 + x)
+//! - `clamp:<MIN>:<MAX>` limits elements to [MIN, MAX]
+
+use std::fmt;
+
+use crate::numfmt::parse_f64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transform {
+    ReplaceSentinel { sentinel: f64, value: f64 },
+    Log1p,
+    Clamp { min: f64, max: f64 },
+}
+
+impl Transform {
+    pub fn parse(s: &str) -> Result<Transform, String> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let number = |i: usize| -> Result<f64, String> {
+            let part = parts
+                .get(i)
+                .ok_or_else(|| format!("'{}' is missing argument {}", s, i))?;
+            parse_f64(part.trim())
+                .ok_or_else(|| format!("argument {} of '{}' is not a number: '{}'", i, s, part))
+        };
+
+        let transform = match parts[0] {
+            "replace-sentinel" => Transform::ReplaceSentinel {
+                sentinel: number(1)?,
+                value: number(2)?,
+            },
+            "log1p" => Transform::Log1p,
+            "clamp" => {
+                let (min, max) = (number(1)?, number(2)?);
+                // f64::clamp panics on either.
+                if min.is_nan() || max.is_nan() {
+                    return Err(format!("clamp bounds of '{}' must be numbers, not NaN", s));
+                }
+                if min > max {
+                    return Err(format!("clamp minimum {} is above maximum {}", min, max));
+                }
+                Transform::Clamp { min, max }
+            }
+            name => return Err(format!("unknown transform '{}'", name)),
+        };
+
+        let expected = match transform {
+            Transform::Log1p => 1,
+            _ => 3,
+        };
+        if parts.len() != expected {
+            return Err(format!("'{}' takes {} arguments", parts[0], expected - 1));
+        }
+        Ok(transform)
+    }
+
+    pub fn apply(&self, x: f64) -> f64 {
+        match *self {
+            Transform::ReplaceSentinel { sentinel, value } => {
+                if x == sentinel || (x.is_nan() && sentinel.is_nan()) {
+                    value
+                } else {
+                    x
+                }
+            }
+            Transform::Log1p => x.ln_1p(),
+            Transform::Clamp { min, max } => x.clamp(min, max),
+        }
+    }
+}
+
+impl fmt::Display for Transform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Transform::ReplaceSentinel { sentinel, value } => {
+                write!(f, "replace-sentinel:{}:{}", sentinel, value)
+            }
+            Transform::Log1p => write!(f, "log1p"),
+            Transform::Clamp { min, max } => write!(f, "clamp:{}:{}", min, max),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            Transform::parse("replace-sentinel:-999:0"),
+            Ok(Transform::ReplaceSentinel {
+                sentinel: -999.0,
+                value: 0.0
+            })
+        );
+        assert_eq!(Transform::parse("log1p"), Ok(Transform::Log1p));
+        assert_eq!(
+            Transform::parse("clamp:-1:1"),
+            Ok(Transform::Clamp {
+                min: -1.0,
+                max: 1.0
+            })
+        );
+
+        assert!(Transform::parse("clamp:1:-1")
+            .unwrap_err()
+            .contains("above"));
+        assert!(Transform::parse("clamp:1").unwrap_err().contains("missing"));
+        assert!(Transform::parse("clamp:nan:1").unwrap_err().contains("NaN"));
+        assert!(Transform::parse("clamp:0:NaN").unwrap_err().contains("NaN"));
+        assert!(Transform::parse("log1p:2").unwrap_err().contains("takes 0"));
+        assert!(Transform::parse("replace-sentinel:x:0")
+            .unwrap_err()
+            .contains("'x'"));
+        assert!(Transform::parse("sqrt").unwrap_err().contains("unknown"));
+    }
+
+    #[test]
+    fn apply() {
+        let sentinel = Transform::ReplaceSentinel {
+            sentinel: -999.0,
+            value: 0.0,
+        };
+        assert_eq!(sentinel.apply(-999.0), 0.0);
+        assert_eq!(sentinel.apply(-998.0), -998.0);
+
+        let nan = Transform::ReplaceSentinel {
+            sentinel: f64::NAN,
+            value: 1.0,
+        };
+        assert_eq!(nan.apply(f64::NAN), 1.0);
+
+        assert_eq!(Transform::Clamp { min: 0.0, max: 1.0 }.apply(7.0), 1.0);
+        assert_eq!(Transform::Log1p.apply(0.0), 0.0);
+    }
+}
@@ -0,0 +1,162 @@
+//! Parsing of human-friendly sizes used by the memory and element limits.
+//!
+//! Byte sizes accept a plain number of bytes ("1048576", "1.5e9"), decimal
+//! units ("512K", "512KB", "2M", "2MB", "1G", "1GB", "1T", "1TB") and binary
+//! units ("512KiB", "2MiB", "1GiB", "1TiB"). Units are case-insensitive
+//! except that a lone lowercase "m" is rejected, since it could mean milli.
+//! Element counts accept the decimal suffixes k, M, G and an optional
+//! trailing "elements" ("100k elements"). Durations accept a plain number
+//! of seconds or the suffixes ms, s, m (minutes) and h ("500ms", "1.5m").
+
+use std::time::Duration;
+
+use crate::numfmt::parse_f64;
+
+// Splits "1.5e9 GiB" into ("1.5e9", "GiB"). An 'e' only belongs to the
+// number when an exponent follows it.
+fn split_number(s: &str) -> (&str, &str) {
+    let s = s.trim();
+    let is_numeric = |c: char| c.is_ascii_digit() || c == '.' || c == '+' || c == '-';
+    let end = s
+        .char_indices()
+        .find(|&(i, c)| {
+            let exponent = (c == 'e' || c == 'E') && s[i + 1..].starts_with(is_numeric);
+            !is_numeric(c) && !exponent
+        })
+        .map(|(i, _)| i)
+        .unwrap_or(s.len());
+    (&s[..end], s[end..].trim())
+}
+
+fn scale(s: &str, number: &str, multiplier: f64, what: &str) -> Result<u64, String> {
+    let value = parse_f64(number).ok_or_else(|| format!("'{}' does not start with a number", s))?;
+    if value < 0.0 {
+        return Err(format!("{} cannot be negative: '{}'", what, s));
+    }
+
+    let scaled = value * multiplier;
+    if !scaled.is_finite() || scaled > u64::MAX as f64 {
+        return Err(format!("{} is too large: '{}'", what, s));
+    }
+    if scaled.fract() != 0.0 {
+        return Err(format!("'{}' is not a whole number of {}", s, what));
+    }
+    Ok(scaled as u64)
+}
+
+pub fn parse_bytes(s: &str) -> Result<u64, String> {
+    let (number, unit) = split_number(s);
+    if unit == "m" {
+        return Err(format!("ambiguous unit in '{}', use M, MB or MiB", s));
+    }
+
+    let multiplier = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1.0,
+        "k" | "kb" => 1e3,
+        "m" | "mb" => 1e6,
+        "g" | "gb" => 1e9,
+        "t" | "tb" => 1e12,
+        "kib" => 1024.0,
+        "mib" => 1024.0 * 1024.0,
+        "gib" => 1024.0 * 1024.0 * 1024.0,
+        "tib" => 1024.0 * 1024.0 * 1024.0 * 1024.0,
+        _ => return Err(format!("unknown size unit '{}' in '{}'", unit, s)),
+    };
+    scale(s, number, multiplier, "bytes")
+}
+
+pub fn parse_count(s: &str) -> Result<u64, String> {
+    let (number, unit) = split_number(s);
+    let unit = unit.strip_suffix("elements").unwrap_or(unit).trim();
+
+    let multiplier = match unit {
+        "" => 1.0,
+        "k" | "K" => 1e3,
+        "M" => 1e6,
+        "G" => 1e9,
+        _ => return Err(format!("unknown count suffix '{}' in '{}'", unit, s)),
+    };
+    scale(s, number, multiplier, "elements")
+}
+
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (number, unit) = split_number(s);
+    let seconds = match unit {
+        "ms" => 1e-3,
+        "" | "s" => 1.0,
+        "m" | "min" => 60.0,
+        "h" => 3600.0,
+        _ => return Err(format!("unknown duration unit '{}' in '{}'", unit, s)),
+    };
+    let value = parse_f64(number).ok_or_else(|| format!("'{}' does not start with a number", s))?;
+    Duration::try_from_secs_f64(value * seconds).map_err(|_| format!("'{}' is not a valid duration", s))
+}
+
+/// Formats a byte count with binary units, for reporting parsed limits.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.2} {} ({} bytes)", value, UNITS[unit], bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_decimal_and_binary() {
+        assert_eq!(parse_bytes("512"), Ok(512));
+        assert_eq!(parse_bytes("512M"), Ok(512_000_000));
+        assert_eq!(parse_bytes("512MB"), Ok(512_000_000));
+        assert_eq!(parse_bytes("2GiB"), Ok(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_bytes("2 gib"), Ok(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_bytes("1.5e9"), Ok(1_500_000_000));
+        assert_eq!(parse_bytes("1.5G"), Ok(1_500_000_000));
+        assert_eq!(parse_bytes("0.5KiB"), Ok(512));
+    }
+
+    #[test]
+    fn bytes_errors() {
+        assert!(parse_bytes("-1G").unwrap_err().contains("negative"));
+        assert!(parse_bytes("5m").unwrap_err().contains("ambiguous"));
+        assert!(parse_bytes("1.5").unwrap_err().contains("whole number"));
+        assert!(parse_bytes("12 parsecs").unwrap_err().contains("unknown"));
+        assert!(parse_bytes("GiB").is_err());
+    }
+
+    #[test]
+    fn counts() {
+        assert_eq!(parse_count("100k elements"), Ok(100_000));
+        assert_eq!(parse_count("100K"), Ok(100_000));
+        assert_eq!(parse_count("2.5M"), Ok(2_500_000));
+        assert_eq!(parse_count("1e6"), Ok(1_000_000));
+        assert!(parse_count("-3").is_err());
+        assert!(parse_count("3Ki").is_err());
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("60s"), Ok(Duration::from_secs(60)));
+        assert_eq!(parse_duration("60"), Ok(Duration::from_secs(60)));
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("1.5m"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert!(parse_duration("-1s").is_err());
+        assert!(parse_duration("5 fortnights").is_err());
+    }
+
+    #[test]
+    fn format() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(3 * 1024 * 1024), "3.00 MiB (3145728 bytes)");
+    }
+}
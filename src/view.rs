@@ -0,0 +1,510 @@
+//! Read-only views of a matrix, and how the multiply reads its right
+//! operand.
+//!
+//! Each output row is a dot product of a row of the left operand with every
+//! column of the right one. Reading those columns from a row-major matrix
+//! jumps a whole row per element, so for larger problems the dispatcher
+//! first packs the right operand: it copies out its transpose and reads that
+//! through a `TransposedView`, whose columns are the contiguous rows of the
+//! copy. A right operand that is already a transpose is read the same way
+//! without the copy. Every orientation sums in the same order, so the
+//! results are identical.
+//!
+//! A `FlippedView` reverses the rows, the columns or both of a matrix
+//! through an offset and signed strides, so that a flip before a multiply
+//! or an elementwise operation costs nothing. The kernels read a flipped
+//! right operand through it as they would the matrix itself; a flipped left
+//! operand keeps its rows contiguous only when just the rows are reversed,
+//! and is copied out otherwise.
+
+use std::time::Instant;
+
+use clap::clap_derive::ArgEnum;
+
+use crate::{
+    fused_rows, invariants, par_fused_rows, Algorithm, LogEvent, Matrix, MatrixError, MultiplyOptions, MultiplyReport,
+};
+
+pub trait MatrixView: Send + Sync {
+    fn shape(&self) -> (usize, usize);
+    fn get(&self, row: usize, col: usize) -> f64;
+
+    /// Column `col` as a slice, when it is stored contiguously.
+    fn column(&self, _col: usize) -> Option<&[f64]> {
+        None
+    }
+
+    /// Row `row` as a slice, when it is stored contiguously.
+    fn row(&self, _row: usize) -> Option<&[f64]> {
+        None
+    }
+}
+
+impl MatrixView for Matrix {
+    fn shape(&self) -> (usize, usize) {
+        Matrix::shape(self)
+    }
+
+    fn get(&self, row: usize, col: usize) -> f64 {
+        Matrix::<f64>::get(self, row, col)
+    }
+
+    fn row(&self, row: usize) -> Option<&[f64]> {
+        Some(Matrix::row(self, row))
+    }
+}
+
+/// The transpose of a matrix, reading the original buffer.
+#[derive(Clone, Copy)]
+pub(crate) struct TransposedView<'a> {
+    inner: &'a Matrix,
+}
+
+impl MatrixView for TransposedView<'_> {
+    fn shape(&self) -> (usize, usize) {
+        (self.inner.cols, self.inner.rows)
+    }
+
+    fn get(&self, row: usize, col: usize) -> f64 {
+        self.inner.get(col, row)
+    }
+
+    fn column(&self, col: usize) -> Option<&[f64]> {
+        Some(self.inner.row(col))
+    }
+}
+
+/// Which way a `FlippedView` reverses a matrix.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Flip {
+    /// Upside down: the last row first.
+    Rows,
+    /// Mirrored: the last column first.
+    Cols,
+    /// Both, which is a half turn.
+    Both,
+}
+
+/// A matrix flipped, reading the original buffer: element `(row, col)` is
+/// at `offset + row * row_stride + col * col_stride` in it.
+#[derive(Clone, Copy, Debug)]
+pub struct FlippedView<'a> {
+    inner: &'a Matrix,
+    flip: Flip,
+    offset: usize,
+    row_stride: isize,
+    col_stride: isize,
+}
+
+impl MatrixView for FlippedView<'_> {
+    fn shape(&self) -> (usize, usize) {
+        self.inner.shape()
+    }
+
+    fn get(&self, row: usize, col: usize) -> f64 {
+        let (rows, cols) = self.inner.shape();
+        invariants::in_bounds(rows, cols, row, col);
+        invariants::strided_view(rows, cols, self.offset, self.row_stride, self.col_stride, self.inner.data.len());
+        let index = self.offset as isize + row as isize * self.row_stride + col as isize * self.col_stride;
+        self.inner.data[index as usize]
+    }
+
+    fn row(&self, row: usize) -> Option<&[f64]> {
+        (self.flip == Flip::Rows).then(|| self.inner.row(self.inner.rows - 1 - row))
+    }
+}
+
+impl FlippedView<'_> {
+    pub fn flip(&self) -> Flip {
+        self.flip
+    }
+
+    /// The elements row by row, as the flipped matrix stores them.
+    pub fn iter(&self) -> impl Iterator<Item = f64> + '_ {
+        let (rows, cols) = self.shape();
+        (0..rows).flat_map(move |i| (0..cols).map(move |j| self.get(i, j)))
+    }
+
+    /// A copy of the flipped matrix.
+    pub fn materialize(&self) -> Matrix {
+        let (rows, cols) = self.shape();
+        let mut data = Vec::with_capacity(rows * cols);
+        data.extend(self.iter());
+        Matrix::new_unchecked(rows, cols, data)
+    }
+
+    // The transpose of the flipped matrix, which is what packing makes.
+    fn transpose(&self) -> Matrix {
+        let (rows, cols) = self.shape();
+        Matrix::new_unchecked(cols, rows, (0..cols).flat_map(|j| (0..rows).map(move |i| self.get(i, j))).collect())
+    }
+
+    /// `self * other`. With only the rows reversed, each output row is
+    /// computed from the matching row of the original; otherwise the
+    /// flipped matrix is copied out first, which `options`' context logs as
+    /// `LogEvent::Materialized` and traces as a "materialize" span.
+    pub fn multiply_with_report(
+        &self,
+        other: &Matrix,
+        options: &MultiplyOptions,
+    ) -> Result<(Matrix, MultiplyReport), MatrixError> {
+        let inner = self.inner;
+        if self.flip != Flip::Rows {
+            let context = &options.context;
+            context.check_allocation(8u64.saturating_mul(inner.data.len() as u64))?;
+            let start = Instant::now();
+            let copy = self.materialize();
+            context.span("materialize", start);
+            context.emit(LogEvent::Materialized {
+                rows: copy.rows,
+                cols: copy.cols,
+            });
+            return copy.multiply_with_report(other, options);
+        }
+        if inner.cols != other.rows {
+            return Err(MatrixError::DimensionMismatch {
+                left: inner.shape(),
+                right: other.shape(),
+            });
+        }
+        let last = inner.rows.saturating_sub(1);
+        multiply_rows(options, inner.rows, other.shape(), || other.transpose(), |packed, i, out| match packed {
+            Some(packed) => inner.multiply_row(&packed.t(), last - i, out),
+            None => inner.multiply_row(other, last - i, out),
+        })
+    }
+}
+
+impl Matrix {
+    pub fn flipped_view(&self, flip: Flip) -> FlippedView<'_> {
+        let (rows, cols) = (self.rows as isize, self.cols as isize);
+        let (row_stride, col_stride, corner) = match flip {
+            Flip::Rows => (-cols, 1, (rows - 1) * cols),
+            Flip::Cols => (cols, -1, cols - 1),
+            Flip::Both => (-cols, -1, rows * cols - 1),
+        };
+        FlippedView {
+            inner: self,
+            flip,
+            offset: corner.max(0) as usize,
+            row_stride,
+            col_stride,
+        }
+    }
+
+    /// `self * other`, reading `other` through the view. Packing, when the
+    /// dispatcher would pack, copies the flipped transpose out of it in one
+    /// pass, as it would from a matrix.
+    pub fn multiply_by_flipped(
+        &self,
+        other: &FlippedView,
+        options: &MultiplyOptions,
+    ) -> Result<(Matrix, MultiplyReport), MatrixError> {
+        if self.cols != other.shape().0 {
+            return Err(MatrixError::DimensionMismatch {
+                left: self.shape(),
+                right: other.shape(),
+            });
+        }
+        multiply_rows(options, self.rows, other.shape(), || other.transpose(), |packed, i, out| match packed {
+            Some(packed) => self.multiply_row(&packed.t(), i, out),
+            None => self.multiply_row(other, i, out),
+        })
+    }
+}
+
+// A general multiply with `rows` output rows and a right operand of
+// `right_shape`, orientated as the dispatcher would. `kernel(packed, i, out)`
+// computes output row `i`, reading the packed right operand when there is
+// one.
+fn multiply_rows(
+    options: &MultiplyOptions,
+    rows: usize,
+    right_shape: (usize, usize),
+    pack: impl FnOnce() -> Matrix,
+    kernel: impl Fn(Option<&Matrix>, usize, &mut [f64]) + Sync,
+) -> Result<(Matrix, MultiplyReport), MatrixError> {
+    let cols = right_shape.1;
+    options.check_epilogues((rows, cols))?;
+    let context = &options.context;
+    let result_bytes = 8u64.saturating_mul(rows as u64).saturating_mul(cols as u64);
+    let orientation = match options.orientation {
+        None | Some(Orientation::View) => choose(rows, right_shape),
+        Some(orientation) => orientation,
+    };
+    let packed = if orientation == Orientation::Packed {
+        let packed_bytes = 8u64.saturating_mul(right_shape.0 as u64).saturating_mul(cols as u64);
+        context.check_allocation(result_bytes.saturating_add(packed_bytes))?;
+        Some(pack())
+    } else {
+        context.check_allocation(result_bytes)?;
+        None
+    };
+    context.emit(LogEvent::Kernel {
+        algorithm: options.algorithm,
+        orientation,
+    });
+
+    let report = MultiplyReport {
+        orientation: Some(orientation),
+        row_progress: true,
+        ..MultiplyReport::default()
+    };
+    let token = options.cancel.clone().unwrap_or_default();
+    let epilogues = &options.epilogues;
+    let kernel = |i: usize, out: &mut [f64]| kernel(packed.as_ref(), i, out);
+    let result = context.install(|| match options.algorithm {
+        Algorithm::Seq => fused_rows(rows, cols, &token, epilogues, context, kernel),
+        Algorithm::Par => par_fused_rows(rows, cols, &token, epilogues, context, kernel),
+    })?;
+    Ok((result, report))
+}
+
+/// How the multiply reads its right operand.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ArgEnum)]
+pub enum Orientation {
+    /// In place, down its columns.
+    Strided,
+    /// In place, through a transposed view whose columns are contiguous.
+    #[clap(skip)]
+    View,
+    /// From a transposed copy made before the multiply.
+    Packed,
+}
+
+impl Orientation {
+    pub(crate) fn describe(self) -> &'static str {
+        match self {
+            Orientation::Strided => "read the right operand in place",
+            Orientation::View => "read the right operand through a transposed view",
+            Orientation::Packed => "packed the right operand into column order",
+        }
+    }
+}
+
+// Packing costs a pass over the right operand and a copy of it, which pays
+// off once enough output rows reuse each packed column.
+const PACK_MIN_ROWS: usize = 16;
+const PACK_MIN_ELEMENTS: usize = 4096;
+
+/// Whether a multiply with `left_rows` output rows should pack a right
+/// operand of `shape`.
+pub(crate) fn choose(left_rows: usize, shape: (usize, usize)) -> Orientation {
+    if left_rows >= PACK_MIN_ROWS && shape.0 * shape.1 >= PACK_MIN_ELEMENTS {
+        Orientation::Packed
+    } else {
+        Orientation::Strided
+    }
+}
+
+impl Matrix {
+    pub(crate) fn t(&self) -> TransposedView<'_> {
+        TransposedView { inner: self }
+    }
+
+    /// `self * other^T`. Reads `other` through a view instead of copying out
+    /// its transpose, unless `options` forces another orientation, in which
+    /// case the transpose is formed and multiplied as usual.
+    pub(crate) fn multiply_by_transpose(
+        &self,
+        other: &Matrix,
+        options: &MultiplyOptions,
+    ) -> Result<(Matrix, MultiplyReport), MatrixError> {
+        if !matches!(options.orientation, None | Some(Orientation::View)) {
+            return self.multiply_with_report(&other.transpose(), options);
+        }
+        if self.cols != other.cols {
+            return Err(MatrixError::DimensionMismatch {
+                left: self.shape(),
+                right: (other.cols, other.rows),
+            });
+        }
+        options.check_epilogues((self.rows, other.rows))?;
+
+        let context = &options.context;
+        context.check_allocation(8u64.saturating_mul(self.rows as u64).saturating_mul(other.rows as u64))?;
+        context.emit(LogEvent::Kernel {
+            algorithm: options.algorithm,
+            orientation: Orientation::View,
+        });
+
+        let report = MultiplyReport {
+            orientation: Some(Orientation::View),
+            row_progress: true,
+            ..MultiplyReport::default()
+        };
+        let token = options.cancel.clone().unwrap_or_default();
+        let epilogues = &options.epilogues;
+        let result = context.install(|| match options.algorithm {
+            Algorithm::Seq => self.multiply_fused(&other.t(), &token, epilogues, context),
+            Algorithm::Par => self.multiply_par_fused(&other.t(), &token, epilogues, context),
+        })?;
+        Ok((result, report))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{alloc_counter::count_allocations, CancelToken, Context};
+
+    #[test]
+    fn transposed_view() {
+        let m = Matrix::new_unchecked(2, 3, vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        let t = m.t();
+        assert_eq!(t.shape(), (3, 2));
+        assert_eq!(t.get(2, 1), 6.0);
+        assert_eq!(t.column(1), Some(&[4.0, 5.0, 6.0][..]));
+        assert_eq!(MatrixView::column(&m, 0), None);
+    }
+
+    #[test]
+    fn every_orientation_agrees() {
+        let a = Matrix::random(20, 80);
+        let b = Matrix::random(60, 80);
+        let bt = b.transpose();
+        let expected = a.multiply(&bt).unwrap();
+        for algorithm in [Algorithm::Seq, Algorithm::Par] {
+            for cancel in [None, Some(CancelToken::new())] {
+                let mut options = MultiplyOptions::new().algorithm(algorithm).inline_below(0);
+                if let Some(token) = cancel {
+                    options = options.cancel_token(token);
+                }
+                for orientation in [None, Some(Orientation::Strided), Some(Orientation::Packed)] {
+                    let options = match orientation {
+                        Some(orientation) => options.clone().orientation(orientation),
+                        None => options.clone(),
+                    };
+                    let (m, report) = a.multiply_with_report(&bt, &options).unwrap();
+                    assert_eq!(m, expected);
+                    assert_eq!(report.orientation, orientation.or(Some(Orientation::Packed)));
+
+                    let (m, report) = a.multiply_by_transpose(&b, &options).unwrap();
+                    assert_eq!(m, expected);
+                    assert_eq!(report.orientation, orientation.or(Some(Orientation::View)));
+                }
+            }
+        }
+        assert!(a.multiply_by_transpose(&bt, &MultiplyOptions::new()).is_err());
+    }
+
+    #[test]
+    fn view_is_not_copied() {
+        let a = Matrix::random(20, 80);
+        let b = Matrix::random(60, 80);
+        // Bytes in a copy of b, which is not the size of the result.
+        let copy = 60 * 80 * 8;
+        let options = MultiplyOptions::new();
+        let (copies, _) = count_allocations(copy, || a.multiply_by_transpose(&b, &options).unwrap());
+        assert_eq!(copies, 0);
+
+        let bt = b.transpose();
+        for (orientation, expected) in [(Orientation::Strided, 0), (Orientation::Packed, 1)] {
+            let options = options.clone().orientation(orientation);
+            let (copies, _) = count_allocations(copy, || a.multiply_with_report(&bt, &options).unwrap());
+            assert_eq!(copies, expected, "{:?}", orientation);
+        }
+    }
+
+    // The flip made by hand, element by element.
+    fn flipped(m: &Matrix, flip: Flip) -> Matrix {
+        let (rows, cols) = m.shape();
+        let mut out = Matrix::new_unchecked(rows, cols, vec![0.0; rows * cols]);
+        for i in 0..rows {
+            for j in 0..cols {
+                let (r, c) = match flip {
+                    Flip::Rows => (rows - 1 - i, j),
+                    Flip::Cols => (i, cols - 1 - j),
+                    Flip::Both => (rows - 1 - i, cols - 1 - j),
+                };
+                out.set(i, j, m.get(r, c));
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn flipped_views_read_in_flipped_order() {
+        let m = Matrix::new_unchecked(2, 3, vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        let order = |flip| m.flipped_view(flip).iter().collect::<Vec<_>>();
+        assert_eq!(order(Flip::Rows), [4.0, 5.0, 6.0, 1.0, 2.0, 3.0]);
+        assert_eq!(order(Flip::Cols), [3.0, 2.0, 1.0, 6.0, 5.0, 4.0]);
+        assert_eq!(order(Flip::Both), [6.0, 5.0, 4.0, 3.0, 2.0, 1.0]);
+
+        let a = Matrix::random(7, 11);
+        let b = Matrix::random(7, 11);
+        for flip in [Flip::Rows, Flip::Cols, Flip::Both] {
+            let view = a.flipped_view(flip);
+            let copy = flipped(&a, flip);
+            assert_eq!(view.materialize(), copy);
+            assert!(view.iter().eq(copy.data.iter().copied()));
+            assert_eq!(MatrixView::row(&view, 0).is_some(), flip == Flip::Rows);
+            assert_eq!(b.zip_map(&view, |x, y| x - y).unwrap(), b.sub(&copy).unwrap());
+            assert_eq!(b.zip_map_par(&view, |x, y| x * y).unwrap(), b.hadamard(&copy).unwrap());
+            assert!(b.transpose().zip_map(&view, |x, y| x + y).is_err());
+        }
+        let empty = Matrix::new_unchecked(0, 4, vec![]);
+        assert_eq!(empty.flipped_view(Flip::Both).materialize(), empty);
+    }
+
+    #[test]
+    fn flipped_multiply_matches_copy() {
+        use std::sync::{Arc, Mutex};
+
+        for (n, m, k) in [(3, 5, 2), (20, 80, 60), (1, 9, 17), (33, 1, 4)] {
+            let a = Matrix::random(n, m);
+            let b = Matrix::random(m, k);
+            for flip in [Flip::Rows, Flip::Cols, Flip::Both] {
+                let (fa, fb) = (a.flipped_view(flip), b.flipped_view(flip));
+                let (left, right) = (flipped(&a, flip).multiply(&b).unwrap(), a.multiply(&flipped(&b, flip)).unwrap());
+                for algorithm in [Algorithm::Seq, Algorithm::Par] {
+                    for orientation in [None, Some(Orientation::Strided), Some(Orientation::Packed)] {
+                        let mut options = MultiplyOptions::new().algorithm(algorithm);
+                        if let Some(orientation) = orientation {
+                            options = options.orientation(orientation);
+                        }
+                        assert_eq!(fa.multiply_with_report(&b, &options).unwrap().0, left, "{:?}", flip);
+                        let (product, report) = a.multiply_by_flipped(&fb, &options).unwrap();
+                        assert_eq!(product, right, "{:?} {:?}", flip, orientation);
+                        let expected = orientation.unwrap_or_else(|| choose(n, (m, k)));
+                        assert_eq!(report.orientation, Some(expected));
+                    }
+                }
+            }
+        }
+        let a = Matrix::random(4, 3);
+        assert!(a.multiply_by_flipped(&a.flipped_view(Flip::Rows), &MultiplyOptions::new()).is_err());
+        assert!(a.flipped_view(Flip::Cols).multiply_with_report(&a, &MultiplyOptions::new()).is_err());
+
+        // Reversed rows are read in place; reversed columns are copied out,
+        // and the log says so.
+        let a = Matrix::random(40, 50);
+        let b = Matrix::random(50, 30);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&events);
+        let options = MultiplyOptions::new().context(Context::new().log(move |event| sink.lock().unwrap().push(event)));
+        let copy = 40 * 50 * 8;
+        let (copies, _) = count_allocations(copy, || a.flipped_view(Flip::Rows).multiply_with_report(&b, &options).unwrap());
+        assert_eq!(copies, 0);
+        let (copies, _) = count_allocations(copy, || a.flipped_view(Flip::Cols).multiply_with_report(&b, &options).unwrap());
+        assert_eq!(copies, 1);
+        let materialized = events.lock().unwrap().iter().filter(|event| matches!(event, LogEvent::Materialized { .. })).count();
+        assert_eq!(materialized, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "invariant: element (2, 0) of a 3x4 view at 16 outside a buffer of 12")]
+    fn invariants_catch_corrupted_stride() {
+        let m = Matrix::random(3, 4);
+        let mut view = m.flipped_view(Flip::Rows);
+        view.row_stride = -view.row_stride;
+        view.get(0, 0);
+    }
+
+    #[test]
+    fn packs_large_operands() {
+        assert_eq!(choose(4, (100, 100)), Orientation::Strided);
+        assert_eq!(choose(64, (100, 100)), Orientation::Packed);
+        assert_eq!(choose(64, (10, 10)), Orientation::Strided);
+    }
+}
@@ -0,0 +1,103 @@
+//! Things worth telling the user that do not stop the run.
+//!
+//! Warnings are reported through EventSink::warn, which prints them to
+//! stderr, sends them as `warning` events and keeps them so the run can end
+//! with a count and, under `--deny-warnings`, a failing exit code.
+
+use std::{borrow::Cow, fmt};
+
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum Warning {
+    /// Rows of an operand padded or truncated under --ragged-policy.
+    RaggedRows {
+        operand: Cow<'static, str>,
+        rows: usize,
+        padded: bool,
+    },
+    /// NaN or infinite elements replaced under --replace-nonfinite.
+    NonFiniteReplaced {
+        operand: Cow<'static, str>,
+        count: usize,
+        value: f64,
+    },
+    /// Integers too large for f64 were rounded while parsing.
+    InexactIntegers { operand: Cow<'static, str>, count: usize },
+    /// --compare-baseline found cases slower than the baseline allows.
+    BenchRegression { baseline: String, regressions: usize },
+    /// The baseline was recorded on a different machine.
+    BaselineMachine { difference: String },
+    /// PAR was requested but the problem was small enough to run on the
+    /// calling thread.
+    RanInline { flops: u64 },
+    /// --cancellation-check found elements that cancellation left with few
+    /// or no correct digits.
+    Cancellation { elements: usize, threshold: f64 },
+}
+
+impl Warning {
+    /// Stable identifier for scripts.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Warning::RaggedRows { .. } => "ragged_rows",
+            Warning::NonFiniteReplaced { .. } => "nonfinite_replaced",
+            Warning::InexactIntegers { .. } => "inexact_integers",
+            Warning::BenchRegression { .. } => "bench_regression",
+            Warning::BaselineMachine { .. } => "baseline_machine",
+            Warning::RanInline { .. } => "ran_inline",
+            Warning::Cancellation { .. } => "cancellation",
+        }
+    }
+
+    /// Fails the run even without --deny-warnings.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, Warning::BenchRegression { .. })
+    }
+
+    /// How many elements or rows the warning is about, if it is about any.
+    pub fn count(&self) -> Option<usize> {
+        match *self {
+            Warning::RaggedRows { rows, .. } => Some(rows),
+            Warning::NonFiniteReplaced { count, .. } => Some(count),
+            Warning::InexactIntegers { count, .. } => Some(count),
+            Warning::BenchRegression { regressions, .. } => Some(regressions),
+            Warning::Cancellation { elements, .. } => Some(elements),
+            Warning::BaselineMachine { .. } | Warning::RanInline { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Warning::RaggedRows { operand, rows, padded } => {
+                let action = if *padded { "padded" } else { "truncated" };
+                write!(f, "{} ragged rows {} in {} matrix", rows, action, operand)
+            }
+            Warning::NonFiniteReplaced { operand, count, value } => {
+                write!(f, "replaced {} non-finite values in {} matrix with {}", count, operand, value)
+            }
+            Warning::InexactIntegers { operand, count } => write!(
+                f,
+                "{} integers in {} matrix are too large for f64 and were rounded (see --dtype i64)",
+                count, operand
+            ),
+            Warning::BenchRegression { baseline, regressions } => {
+                write!(f, "{} cases regressed against baseline {}", regressions, baseline)
+            }
+            Warning::BaselineMachine { difference } => {
+                write!(f, "baseline is from another machine: {}", difference)
+            }
+            Warning::RanInline { flops } => write!(
+                f,
+                "small problem ({} flops), PAR ran on the calling thread (see --inline-below)",
+                flops
+            ),
+            Warning::Cancellation { elements, threshold } => write!(
+                f,
+                "{} elements are over {:e} times smaller than their products and may be mostly rounding error",
+                elements, threshold
+            ),
+        }
+    }
+}
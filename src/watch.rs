@@ -0,0 +1,326 @@
+//! `--watch DIR`: multiply every matrix-pair file dropped into a directory.
+//!
+//! A file is picked up once it is complete: either a `NAME.ready` marker
+//! appears next to it, or its size and modification time stay the same
+//! between two scans. The product goes to the output directory under the
+//! input's name, and the input is moved to `done/` or `failed/`. Each file
+//! gets one JSON line on stdout:
+//!
+//! `{"file":"a.txt","status":"done","output":"out/a.txt","elapsed_ms":1.5}`
+//! `{"file":"b.txt","status":"failed","error":"..."}`
+//!
+//! The directory is scanned every `interval` rather than watched through OS
+//! notifications, since deciding that a file is complete needs repeated
+//! looks at it either way.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    events::quote, prepared::PreparedMatrix, text_reader, CancelToken, Matrix, MultiplyOptions, ParseOptions,
+    Precision,
+};
+
+const READY_SUFFIX: &str = ".ready";
+
+/// How each file is processed.
+#[derive(Clone, Debug)]
+pub struct Job {
+    pub parse: ParseOptions,
+    pub multiply: MultiplyOptions,
+    pub precision: Precision,
+}
+
+pub struct Watcher {
+    dir: PathBuf,
+    out_dir: PathBuf,
+    // Size and modification time at the last scan, for files not yet ready.
+    pending: HashMap<PathBuf, (u64, Option<SystemTime>)>,
+    // The first operand of the last file, and its text, kept for the next
+    // file with the same one.
+    last_left: Option<(String, PreparedMatrix)>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Outcome {
+    Done { output: PathBuf, elapsed: Duration },
+    Failed { error: String },
+}
+
+impl Watcher {
+    pub fn new(dir: PathBuf, out_dir: PathBuf) -> io::Result<Watcher> {
+        for sub in [out_dir.clone(), dir.join("done"), dir.join("failed")] {
+            fs::create_dir_all(sub)?;
+        }
+        Ok(Watcher {
+            dir,
+            out_dir,
+            pending: HashMap::new(),
+            last_left: None,
+        })
+    }
+
+    /// Input files that are complete, in name order.
+    pub(crate) fn scan(&mut self) -> io::Result<Vec<PathBuf>> {
+        let mut ready = Vec::new();
+        let mut seen = HashMap::new();
+        for entry in fs::read_dir(&self.dir)? {
+            // A file moved away or deleted between the listing and a look at
+            // it is simply not there.
+            let (entry, metadata) = match entry.and_then(|entry| Ok((entry.metadata()?, entry))) {
+                Ok((metadata, entry)) => (entry, metadata),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !metadata.is_file() || name.starts_with('.') || name.ends_with(READY_SUFFIX) {
+                continue;
+            }
+
+            let state = (metadata.len(), metadata.modified().ok());
+            if marker(&path).exists() || self.pending.get(&path) == Some(&state) {
+                ready.push(path);
+            } else {
+                seen.insert(path, state);
+            }
+        }
+        self.pending = seen;
+        ready.sort();
+        Ok(ready)
+    }
+
+    /// Multiplies the pair in `path`, writes the product and moves the input
+    /// out of the watched directory. A product that cannot be written fails
+    /// the file like one that cannot be computed; only a failure to move the
+    /// input is an error, since the file would be picked up again.
+    pub(crate) fn process(&mut self, path: &Path, job: &Job) -> io::Result<Outcome> {
+        let name = path.file_name().unwrap_or_default();
+        let start = Instant::now();
+        let output = self.out_dir.join(name);
+        let outcome = match self.multiply_file(path, job) {
+            Ok(product) => {
+                let written = File::create(&output).and_then(|mut file| {
+                    let threads = 2 * rayon::current_num_threads();
+                    product.write_text_par(&mut file, 1024, threads, job.precision).inspect_err(|_| {
+                        // Not left looking like a finished product.
+                        let _ = fs::remove_file(&output);
+                    })
+                });
+                match written {
+                    Ok(()) => Outcome::Done {
+                        output,
+                        elapsed: start.elapsed(),
+                    },
+                    Err(err) => Outcome::Failed {
+                        error: format!("cannot write {}: {}", output.display(), err),
+                    },
+                }
+            }
+            Err(error) => Outcome::Failed { error },
+        };
+
+        let folder = match outcome {
+            Outcome::Done { .. } => "done",
+            Outcome::Failed { .. } => "failed",
+        };
+        fs::rename(path, self.dir.join(folder).join(name))?;
+        let _ = fs::remove_file(marker(path));
+        Ok(outcome)
+    }
+}
+
+fn marker(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(READY_SUFFIX);
+    path.with_file_name(name)
+}
+
+impl Watcher {
+    // A first operand with the same text as the last file's is not parsed or
+    // prepared again.
+    fn multiply_file(&mut self, path: &Path, job: &Job) -> Result<Matrix, String> {
+        let text = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        let parts = text_reader::split_operands(&text);
+        if parts.len() < 2 {
+            return Err("expected 2 matrices separated by X".to_owned());
+        }
+        let parse = |name: &str, part: &str| {
+            Matrix::from_string_with(part, &job.parse)
+                .map(|(matrix, _)| matrix)
+                .map_err(|err| format!("{} matrix: {}", name, err))
+        };
+
+        if self.last_left.as_ref().is_none_or(|(last, _)| last != parts[0]) {
+            self.last_left = None;
+            let left = PreparedMatrix::from_arc(parse("first", parts[0])?.into(), &job.multiply);
+            self.last_left = Some((parts[0].to_owned(), left));
+        }
+        let right = parse("second", parts[1])?;
+        let (_, left) = self.last_left.as_ref().unwrap();
+        left.multiply(&right).map_err(|err| err.to_string())
+    }
+
+    #[cfg(test)]
+    fn reused_left(&self) -> Option<&str> {
+        self.last_left.as_ref().map(|(text, _)| text.as_str())
+    }
+}
+
+pub(crate) fn log_line(path: &Path, outcome: &Outcome) -> String {
+    let file = quote(&path.file_name().unwrap_or_default().to_string_lossy());
+    match outcome {
+        Outcome::Done { output, elapsed } => format!(
+            "{{\"file\":{},\"status\":\"done\",\"output\":{},\"elapsed_ms\":{}}}",
+            file,
+            quote(&output.to_string_lossy()),
+            elapsed.as_secs_f64() * 1000.0
+        ),
+        Outcome::Failed { error } => {
+            format!("{{\"file\":{},\"status\":\"failed\",\"error\":{}}}", file, quote(error))
+        }
+    }
+}
+
+/// Processes files until `stop` is cancelled, checking every `interval`.
+/// A file already being processed is finished first.
+pub async fn watch(mut watcher: Watcher, job: Job, interval: Duration, stop: CancelToken) -> io::Result<()> {
+    while !stop.is_cancelled() {
+        for path in watcher.scan()? {
+            // The multiply is CPU-bound; hand this worker's other tasks, like
+            // the signal handlers, to another thread meanwhile.
+            let outcome = tokio::task::block_in_place(|| watcher.process(&path, &job));
+            match outcome {
+                Ok(outcome) => println!("{}", log_line(&path, &outcome)),
+                Err(err) => eprintln!("Error: cannot process {}: {}", path.display(), err),
+            }
+            if stop.is_cancelled() {
+                break;
+            }
+        }
+        tokio::time::sleep(interval).await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("matrix-mul-watch-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn job() -> Job {
+        Job {
+            parse: ParseOptions::default(),
+            multiply: MultiplyOptions::new(),
+            precision: Precision::Full,
+        }
+    }
+
+    #[test]
+    fn processes_dropped_files() {
+        let dir = temp_dir("drop");
+        let mut watcher = Watcher::new(dir.clone(), dir.join("out")).unwrap();
+        fs::write(dir.join("good.txt"), "1 2\n3 4\nX\n5 6\n7 8").unwrap();
+        fs::write(dir.join("bad.txt"), "1 2\n3 4\nX\n5 6 7").unwrap();
+
+        // Nothing is ready until the sizes have been seen once.
+        assert!(watcher.scan().unwrap().is_empty());
+        let ready = watcher.scan().unwrap();
+        assert_eq!(ready, vec![dir.join("bad.txt"), dir.join("good.txt")]);
+
+        let outcomes: Vec<Outcome> = ready.iter().map(|path| watcher.process(path, &job()).unwrap()).collect();
+        assert!(matches!(&outcomes[0], Outcome::Failed { error } if error.contains("dimension mismatch")));
+        assert!(matches!(&outcomes[1], Outcome::Done { output, .. } if *output == dir.join("out/good.txt")));
+        assert_eq!(fs::read_to_string(dir.join("out/good.txt")).unwrap(), "19 22\n43 50\n");
+
+        assert!(dir.join("done/good.txt").exists());
+        assert!(dir.join("failed/bad.txt").exists());
+        assert!(!dir.join("good.txt").exists() && !dir.join("bad.txt").exists());
+        assert!(!dir.join("out/bad.txt").exists());
+        assert!(watcher.scan().unwrap().is_empty());
+
+        // An output that cannot be written fails the file rather than
+        // leaving it to be retried forever.
+        fs::create_dir(dir.join("out/blocked.txt")).unwrap();
+        fs::write(dir.join("blocked.txt"), "2\nX\n3").unwrap();
+        let outcome = watcher.process(&dir.join("blocked.txt"), &job()).unwrap();
+        assert!(matches!(&outcome, Outcome::Failed { error } if error.starts_with("cannot write")), "{:?}", outcome);
+        assert!(dir.join("failed/blocked.txt").exists());
+
+        let line = log_line(&dir.join("bad.txt"), &outcomes[0]);
+        assert!(line.starts_with("{\"file\":\"bad.txt\",\"status\":\"failed\",\"error\":"), "{}", line);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reuses_repeated_left_operand() {
+        let dir = temp_dir("repeat");
+        let mut watcher = Watcher::new(dir.clone(), dir.join("out")).unwrap();
+        let files = [("a.txt", "1 2\n3 4\nX\n5 6\n7 8"), ("b.txt", "1 2\n3 4\nX\n1 0\n0 1"), ("c.txt", "2\nX\n3")];
+        for (name, text) in files {
+            fs::write(dir.join(name), text).unwrap();
+        }
+
+        let outputs = ["19 22\n43 50\n", "1 2\n3 4\n", "6\n"];
+        for ((name, text), output) in files.iter().zip(outputs) {
+            assert!(matches!(watcher.process(&dir.join(name), &job()).unwrap(), Outcome::Done { .. }));
+            assert_eq!(fs::read_to_string(dir.join("out").join(name)).unwrap(), output);
+            let expected = if *name == "c.txt" { "2" } else { "1 2\n3 4" };
+            assert_eq!(watcher.reused_left(), Some(expected), "{}", text);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn waits_for_complete_files() {
+        let dir = temp_dir("partial");
+        let mut watcher = Watcher::new(dir.clone(), dir.join("out")).unwrap();
+        let path = dir.join("pair.txt");
+
+        fs::write(&path, "1 2\n3 4\nX\n").unwrap();
+        assert!(watcher.scan().unwrap().is_empty());
+        // Still growing at the next scan.
+        fs::write(&path, "1 2\n3 4\nX\n1 0\n0 1").unwrap();
+        assert!(watcher.scan().unwrap().is_empty());
+        assert_eq!(watcher.scan().unwrap(), vec![path.clone()]);
+
+        // A marker makes a file ready at once, and is removed with it.
+        let other = dir.join("other.txt");
+        fs::write(&other, "2\nX\n3").unwrap();
+        fs::write(dir.join("other.txt.ready"), "").unwrap();
+        let ready = watcher.scan().unwrap();
+        assert!(ready.contains(&other));
+        watcher.process(&other, &job()).unwrap();
+        assert!(!dir.join("other.txt.ready").exists());
+        assert_eq!(fs::read_to_string(dir.join("out/other.txt")).unwrap(), "6\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn stops_when_cancelled() {
+        let dir = temp_dir("stop");
+        let watcher = Watcher::new(dir.clone(), dir.join("out")).unwrap();
+        fs::write(dir.join("pair.txt"), "2\nX\n3").unwrap();
+        fs::write(dir.join("pair.txt.ready"), "").unwrap();
+
+        let stop = CancelToken::new();
+        let task = tokio::spawn(watch(watcher, job(), Duration::from_millis(10), stop.clone()));
+        while !dir.join("done/pair.txt").exists() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        stop.cancel();
+        task.await.unwrap().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,283 @@
+//! `matrix-mul wizard`: asks for the inputs of a multiply one question at a
+//! time, then prints the equivalent command line and runs it.
+//!
+//! Every answer is checked as it is given, and a bad one asks the same
+//! question again: input files must exist and hold two matrices that can
+//! be multiplied, and sizes must be positive.
+
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+    path::PathBuf,
+};
+
+use crate::Mode;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Source {
+    File(PathBuf),
+    Random { n: usize, m: usize, k: usize },
+}
+
+/// What the answers amount to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Answers {
+    pub(crate) source: Source,
+    pub(crate) mode: Mode,
+    /// The file to write the result to, or stdout, and whether it may be
+    /// replaced.
+    pub(crate) output: Option<PathBuf>,
+    pub(crate) force: bool,
+    /// Significant digits to write, or all of them.
+    pub(crate) digits: Option<usize>,
+}
+
+impl Answers {
+    /// The command-line arguments, without the program name.
+    pub(crate) fn to_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        match &self.source {
+            Source::File(path) => args.extend(["-f".to_owned(), path.to_string_lossy().into_owned()]),
+            Source::Random { n, m, k } => {
+                args.extend(["--size".to_owned(), format!("{}x{}x{}", n, m, k)]);
+            }
+        }
+        let mode = match self.mode {
+            Mode::Seq => "seq",
+            Mode::Par => "par",
+            Mode::Blocked => "blocked",
+            Mode::Strassen => "strassen",
+            Mode::All => "all",
+        };
+        args.extend(["--mode".to_owned(), mode.to_owned()]);
+        if let Some(path) = &self.output {
+            args.extend(["--output".to_owned(), path.to_string_lossy().into_owned()]);
+        }
+        if self.force {
+            args.push("--force".to_owned());
+        }
+        if let Some(digits) = self.digits {
+            args.extend(["--digits".to_owned(), digits.to_string()]);
+        }
+        args
+    }
+
+    /// `to_args` as one line for a POSIX shell.
+    pub(crate) fn command_line(&self) -> String {
+        let mut line = String::from("matrix-mul");
+        for arg in self.to_args() {
+            line.push(' ');
+            line.push_str(&shell_quote(&arg));
+        }
+        line
+    }
+}
+
+fn shell_quote(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./=:@,+".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        arg.to_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+/// Shapes of the two matrices in an input file, as the parser would read
+/// them.
+fn file_shapes(text: &str) -> Result<[(usize, usize); 2], String> {
+    let parts = matrix_mul::text_reader::split_operands(text);
+    if parts.len() < 2 {
+        return Err("expected 2 matrices separated by X".to_owned());
+    }
+    let shape = |part: &str| {
+        let rows: Vec<&str> = part.lines().filter(|line| !line.trim().is_empty()).collect();
+        (rows.len(), rows.first().map_or(0, |row| row.split_whitespace().count()))
+    };
+    Ok([shape(parts[0]), shape(parts[1])])
+}
+
+/// Asks questions on `output` and reads the answers from `input`.
+pub(crate) struct Wizard<R, W> {
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead, W: Write> Wizard<R, W> {
+    pub(crate) fn new(input: R, output: W) -> Wizard<R, W> {
+        Wizard { input, output }
+    }
+
+    // Asks until `parse` accepts the answer. An empty answer is `default`,
+    // when there is one.
+    fn ask<T>(
+        &mut self,
+        question: &str,
+        default: Option<&str>,
+        mut parse: impl FnMut(&str) -> Result<T, String>,
+    ) -> io::Result<T> {
+        loop {
+            match default {
+                Some(default) => write!(self.output, "{} [{}]: ", question, default)?,
+                None => write!(self.output, "{}: ", question)?,
+            }
+            self.output.flush()?;
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "input ended before the last answer"));
+            }
+            let answer = match line.trim() {
+                "" => default.unwrap_or(""),
+                answer => answer,
+            };
+            match parse(answer) {
+                Ok(value) => return Ok(value),
+                Err(message) => writeln!(self.output, "  {}", message)?,
+            }
+        }
+    }
+
+    fn size(&mut self, question: &str) -> io::Result<usize> {
+        self.ask(question, None, |answer| match answer.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(format!("'{}' is not a positive whole number", answer)),
+        })
+    }
+
+    pub(crate) fn run(&mut self) -> io::Result<Answers> {
+        let from_file = self.ask("Multiply matrices from a file, or random ones? (file/random)", Some("file"), |answer| {
+            match answer.to_ascii_lowercase().as_str() {
+                "file" | "f" => Ok(true),
+                "random" | "r" => Ok(false),
+                _ => Err("answer file or random".to_owned()),
+            }
+        })?;
+
+        let source = if from_file {
+            let path = self.ask("Input file (two matrices separated by a line with X)", None, |answer| {
+                let text = fs::read_to_string(answer).map_err(|err| format!("cannot read {}: {}", answer, err))?;
+                let [a, b] = file_shapes(&text)?;
+                if a.1 != b.0 {
+                    return Err(format!("a {}x{} matrix cannot be multiplied by a {}x{} one", a.0, a.1, b.0, b.1));
+                }
+                Ok(PathBuf::from(answer))
+            })?;
+            Source::File(path)
+        } else {
+            let n = self.size("Rows of the first matrix")?;
+            let m = self.size("Columns of the first matrix, which are the rows of the second")?;
+            let k = self.size("Columns of the second matrix")?;
+            Source::Random { n, m, k }
+        };
+
+        let mode = self.ask("Run sequentially, in parallel, in parallel tiles, by Strassen's method, or all to compare? (seq/par/blocked/strassen/all)", Some("par"), |answer| {
+            match answer.to_ascii_lowercase().as_str() {
+                "seq" => Ok(Mode::Seq),
+                "par" => Ok(Mode::Par),
+                "blocked" => Ok(Mode::Blocked),
+                "strassen" => Ok(Mode::Strassen),
+                "all" | "both" => Ok(Mode::All),
+                _ => Err("answer seq, par, blocked, strassen or all".to_owned()),
+            }
+        })?;
+
+        let (output, force) = loop {
+            let output = self.ask("File to write the result to, or - for the screen", Some("output.txt"), |answer| {
+                Ok(if answer == "-" { None } else { Some(PathBuf::from(answer)) })
+            })?;
+            let Some(path) = output.as_ref().filter(|path| path.exists()) else {
+                break (output, false);
+            };
+            let question = format!("{} exists. Replace it? (yes/no)", path.display());
+            let replace = self.ask(&question, Some("no"), |answer| match answer.to_ascii_lowercase().as_str() {
+                "yes" | "y" => Ok(true),
+                "no" | "n" => Ok(false),
+                _ => Err("answer yes or no".to_owned()),
+            })?;
+            if replace {
+                break (output, true);
+            }
+        };
+
+        let digits = self.ask("Significant digits to write", Some("all"), |answer| match answer {
+            "all" => Ok(None),
+            _ => match answer.parse::<usize>() {
+                Ok(digits) if digits > 0 => Ok(Some(digits)),
+                _ => Err("answer a positive number of digits, or all".to_owned()),
+            },
+        })?;
+
+        Ok(Answers { source, mode, output, force, digits })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(script: &str) -> (io::Result<Answers>, String) {
+        let mut output = Vec::new();
+        let answers = Wizard::new(script.as_bytes(), &mut output).run();
+        (answers, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn random_sizes() {
+        let (answers, transcript) = run("random\n0\nten\n10\n20\n30\n\n-\n\n");
+        let answers = answers.unwrap();
+        assert_eq!(
+            answers,
+            Answers {
+                source: Source::Random { n: 10, m: 20, k: 30 },
+                mode: Mode::Par,
+                output: None,
+                force: false,
+                digits: None,
+            }
+        );
+        assert_eq!(answers.command_line(), "matrix-mul --size 10x20x30 --mode par");
+        assert_eq!(transcript.matches("is not a positive whole number").count(), 2, "{}", transcript);
+    }
+
+    #[test]
+    fn input_file_is_checked() {
+        let dir = std::env::temp_dir().join(format!("matrix-mul-wizard-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let bad = dir.join("bad pair.txt");
+        let good = dir.join("good.txt");
+        fs::write(&bad, "1 2\n3 4\nX\n1 2 3").unwrap();
+        fs::write(&good, "1 2\n3 4\nX\n5 6\n7 8").unwrap();
+
+        let missing = dir.join("missing.txt");
+        // The output is checked too: good.txt exists, so replacing it is
+        // only agreed to the second time.
+        let script = format!(
+            "f\n{}\n{}\n{2}\nboth\n{2}\nmaybe\nn\n{2}\ny\n4\n",
+            missing.display(),
+            bad.display(),
+            good.display()
+        );
+        let (answers, transcript) = run(&script);
+        let answers = answers.unwrap();
+        assert_eq!(answers.source, Source::File(good.clone()));
+        assert_eq!((answers.mode, answers.digits), (Mode::All, Some(4)));
+        assert!(transcript.contains("cannot read"), "{}", transcript);
+        assert!(transcript.contains("a 2x2 matrix cannot be multiplied by a 1x3 one"), "{}", transcript);
+        assert!(transcript.contains("good.txt exists. Replace it?") && transcript.contains("answer yes or no"), "{}", transcript);
+        let good_arg = good.to_string_lossy();
+        assert_eq!(answers.to_args(), ["-f", &good_arg, "--mode", "all", "--output", &good_arg, "--force", "--digits", "4"]);
+
+        let quoted = Answers {
+            source: Source::File(bad),
+            ..answers
+        };
+        assert!(quoted.command_line().starts_with("matrix-mul -f '"), "{}", quoted.command_line());
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn input_ends_early() {
+        let (answers, _) = run("random\n3\n");
+        assert_eq!(answers.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,412 @@
+//! Whole runs of the binary, for what only shows across processes.
+
+use std::{
+    fs,
+    io::Write,
+    path::Path,
+    process::{Command, Output, Stdio},
+};
+
+fn matrix_mul(args: &[&str]) -> Vec<u8> {
+    let output = Command::new(env!("CARGO_BIN_EXE_matrix-mul")).args(args).output().unwrap();
+    assert!(output.status.success(), "{:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+    output.stdout
+}
+
+// Every file `dir` holds, by name.
+fn files(dir: &Path) -> Vec<(String, Vec<u8>)> {
+    let mut files: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            (path.file_name().unwrap().to_string_lossy().into_owned(), fs::read(&path).unwrap())
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+#[test]
+fn deterministic_runs_are_identical() {
+    let mut runs = Vec::new();
+    for threads in ["2", "8", "2"] {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out.txt");
+        let base = ["--deterministic-run", "42", "-n", "70", "-m", "50", "-k", "60", "--threads", threads];
+        let mut product = vec!["--mode", "all", "--spot-check", "8", "--write-all-results", "-o", output.to_str().unwrap()];
+        product.extend_from_slice(&base);
+        matrix_mul(&product);
+        let mut report = vec!["--mode", "par", "--op", "accuracy-report", "-q"];
+        report.extend_from_slice(&base);
+        runs.push((files(dir.path()), matrix_mul(&report)));
+    }
+
+    let (files, report) = &runs[0];
+    let names: Vec<&str> = files.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, ["out.blocked.txt", "out.par.txt", "out.seeds.txt", "out.seq.txt", "out.strassen.txt", "out.txt"]);
+    assert!(String::from_utf8_lossy(&files[2].1).starts_with("deterministic-run 42\nfirst "));
+    assert!(String::from_utf8_lossy(report).contains("max abs error"));
+    for run in &runs[1..] {
+        assert!(run == &runs[0]);
+    }
+
+    // A different seed is a different run; convert prints no timings.
+    let random = |seed| matrix_mul(&["--mode", "seq", "--op", "convert", "--size", "3", "--deterministic-run", seed]);
+    assert_eq!(random("42"), random("42"));
+    assert_ne!(random("42"), random("43"));
+}
+
+#[test]
+fn deterministic_run_refuses_timing() {
+    let output = Command::new(env!("CARGO_BIN_EXE_matrix-mul"))
+        .args(["--mode", "all", "--deterministic-run", "1", "-n", "4", "--write", "fastest"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("--write fastest"), "{}", stderr);
+}
+
+#[test]
+fn shapes_only() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = |name: &str| dir.path().join(name).to_str().unwrap().to_owned();
+    fs::write(path("ab.txt"), "1 2 3\n4 5 6\nX\n1\n2\n3\n").unwrap();
+    fs::write(path("c.csv"), "1,2\n3,4\n5,6\n").unwrap();
+    matrix_mul(&["--mode", "seq", "--op", "convert", "--size", "3", "--seed", "1", "-o", &path("d.bin")]);
+
+    assert_eq!(matrix_mul(&["shape", &path("ab.txt")]), b"2x3\n3x1\n");
+    assert_eq!(matrix_mul(&["shape", &path("d.bin")]), b"3x3\n");
+    let json = String::from_utf8(matrix_mul(&["shape", "--json", &path("c.csv")])).unwrap();
+    assert!(json.ends_with(",\"matrices\":[{\"rows\":3,\"cols\":2}]}\n"), "{}", json);
+    matrix_mul(&["shape", &path("ab.txt"), "--expect-shape", "2x3", "--expect-shape", "3x1"]);
+
+    // On stdin the magic bytes tell binary from text.
+    let stdin = |bytes: Vec<u8>| {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_matrix-mul"))
+            .args(["shape", "-"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(&bytes).unwrap();
+        child.wait_with_output().unwrap().stdout
+    };
+    assert_eq!(stdin(fs::read(path("d.bin")).unwrap()), b"3x3\n");
+    assert_eq!(stdin(fs::read(path("ab.txt")).unwrap()), b"2x3\n3x1\n");
+    assert_eq!(stdin(b"1".to_vec()), b"1x1\n");
+
+    // The wrong shape is its own exit code, checked before any compute.
+    let code = |args: &[&str]| Command::new(env!("CARGO_BIN_EXE_matrix-mul")).args(args).output().unwrap().status.code();
+    assert_eq!(code(&["shape", &path("d.bin"), "--expect-shape", "1x3"]), Some(3));
+    assert_eq!(code(&["--mode", "seq", "-f", &path("ab.txt"), "--expect-shape", "3x3"]), Some(3));
+    assert_eq!(code(&["--mode", "seq", "-f", &path("c.csv"), "-f", &path("c.csv")]), Some(3));
+    let product = matrix_mul(&["--mode", "seq", "-f", &path("ab.txt"), "--expect-shape", "2x1", "--write", "none"]);
+    assert!(String::from_utf8(product).unwrap().starts_with("Done!"));
+}
+
+#[test]
+fn seeded_inputs() {
+    let random = |args: &[&str]| {
+        let mut full = vec!["--mode", "seq", "--op", "convert", "--size", "4"];
+        full.extend_from_slice(args);
+        String::from_utf8(matrix_mul(&full)).unwrap()
+    };
+    assert_eq!(random(&["--seed", "42"]), random(&["--seed", "42"]));
+    assert_ne!(random(&["--seed", "42"]), random(&["--seed", "43"]));
+    assert_ne!(random(&[]), random(&[]));
+
+    let integers = random(&["--seed", "1", "--dist", "integer", "--min", "-3", "--max", "3"]);
+    let values: Vec<f64> = integers.split_whitespace().map(|x| x.parse().unwrap()).collect();
+    assert_eq!(values.len(), 16);
+    assert!(values.iter().all(|x| x.fract() == 0.0 && (-3.0..=3.0).contains(x)), "{}", integers);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_matrix-mul"))
+        .args(["--mode", "seq", "--size", "4", "--dist", "integer", "--min", "0.2", "--max", "0.8"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("needs an integer"));
+
+    let output = Command::new(env!("CARGO_BIN_EXE_matrix-mul"))
+        .args(["--mode", "seq", "--size", "4", "--min", "-1e308", "--max", "1e308"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("wider than an f64 can hold"));
+}
+
+// A run with `input` on stdin.
+fn piped(args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_matrix-mul"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn pipeline() {
+    // Only the product on stdout; the timings go to stderr.
+    for args in [&["--mode", "par"][..], &["--mode", "seq", "--file", "-"]] {
+        let output = piped(args, "1 2\n3 4\nX\n5 6\n7 8\n");
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "19 22\n43 50\n");
+        assert!(String::from_utf8_lossy(&output.stderr).contains("Done!"));
+    }
+
+    let output = piped(&["--mode", "seq"], "1 2\n3 4\n");
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("expected 2 matrices separated by X"), "{}", stderr);
+    assert_eq!(piped(&["--mode", "seq", "-f", "-", "-f", "-"], "1\n").status.code(), Some(1));
+
+    // The seeds of a deterministic run too.
+    let output = piped(&["--mode", "seq", "--deterministic-run", "7"], "1 2\nX\n3\n4\n");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "11\n");
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("deterministic-run 7\n"));
+}
+
+#[test]
+fn chains() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = |name: &str| dir.path().join(name).to_str().unwrap().to_owned();
+    fs::write(path("abc.txt"), "1 2\n3 4\nX\n5 6\n7 8\nX\n1\n1\n").unwrap();
+    fs::write(path("d.csv"), "2,0\n").unwrap();
+
+    let output = piped(&["--mode", "par", "--op", "chain", "-f", &path("abc.txt"), "-f", &path("d.csv"), "--verbose"], "");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "82 0\n186 0\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Chain order: ((M1 (M2 M3)) M4)\nFlops: 24 in that order, 32 left to right"), "{}", stderr);
+
+    let output = piped(&["--mode", "par", "--op", "chain", "-f", &path("abc.txt"), "-f", &path("abc.txt")], "");
+    assert_eq!(output.status.code(), Some(3));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("matrices 3 and 4 of the chain"));
+
+    // The 4x4 in the middle is over the limit, though the first matrix and
+    // the product are not.
+    let square = "1 1 1 1\n".repeat(4);
+    fs::write(path("wide.txt"), format!("1 1 1 1\nX\n{}X\n1\n1\n1\n1\n", square)).unwrap();
+    let output = piped(&["--mode", "par", "--op", "chain", "-f", &path("wide.txt"), "--max-elements", "10"], "");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("needs a matrix of 16 elements, over the limit of 10"), "{}", stderr);
+    let output = piped(&["--mode", "par", "--op", "chain", "-f", &path("wide.txt"), "--max-elements", "16"], "");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "16\n");
+}
+
+#[test]
+fn bad_timeouts_are_usage_errors() {
+    // Rejected while parsing the arguments, before --serve binds.
+    for timeout in ["-1", "nan", "1e300"] {
+        let output = piped(&["--serve", "127.0.0.1:0", "--mode", "par", &format!("--timeout={}", timeout)], "");
+        assert_eq!(output.status.code(), Some(2), "{}", timeout);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("--timeout"), "{}", timeout);
+    }
+}
+
+#[test]
+fn typed_runs_are_checked_like_f64() {
+    let input = "1 2\n3 4\nX\n5\n6\n";
+    for dtype in ["f32", "i64"] {
+        let run = |extra: &[&str]| {
+            let mut args = vec!["--dtype", dtype, "-f", "-"];
+            args.extend_from_slice(extra);
+            piped(&args, input)
+        };
+        let output = run(&["--mode", "seq"]);
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "17\n39\n", "{}", dtype);
+
+        let output = run(&["--mode", "seq", "--max-elements", "3"]);
+        assert_eq!(output.status.code(), Some(1), "{}", dtype);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("over the limit of 3"), "{}", dtype);
+        let output = run(&["--mode", "seq", "--max-memory", "30"]);
+        assert_eq!(output.status.code(), Some(1), "{}", dtype);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("over the limit of 30 B"), "{}", dtype);
+
+        let output = run(&["--mode", "par", "--expect-shape", "9x9"]);
+        assert_eq!(output.status.code(), Some(3), "{}", dtype);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("the result is 2x1, not 9x9"), "{}", dtype);
+
+        for mode in ["blocked", "strassen", "all"] {
+            let output = run(&["--mode", mode]);
+            assert_eq!(output.status.code(), Some(1), "{} {}", dtype, mode);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            assert!(stderr.contains("multiplies with --mode seq or par only"), "{}", stderr);
+        }
+    }
+}
+
+#[test]
+fn integer_operands_one_per_file() {
+    // As exported by a database: one matrix per CSV file, with integers
+    // an f64 would round.
+    let dir = tempfile::tempdir().unwrap();
+    let path = |name: &str| dir.path().join(name).to_str().unwrap().to_owned();
+    fs::write(path("a.csv"), "9007199254740993,1\n2,3\n").unwrap();
+    fs::write(path("b.csv"), "1\n1\n").unwrap();
+
+    let product = matrix_mul(&["--mode", "seq", "--dtype", "i64", "-f", &path("a.csv"), "-f", &path("b.csv")]);
+    assert_eq!(String::from_utf8(product).unwrap(), "9007199254740994\n5\n");
+    let output = piped(&["--mode", "seq", "--dtype", "i64", "-f", &path("a.csv"), "-f", &path("b.csv"), "-f", &path("b.csv")], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("one per --file, but got 3 files"));
+}
+
+#[test]
+fn missing_control_socket() {
+    let dir = tempfile::tempdir().unwrap();
+    let socket = dir.path().join("none.sock");
+    let output = piped(&["--mode", "seq", "--control-socket", socket.to_str().unwrap()], "1\nX\n1\n");
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.starts_with(&format!("Error: cannot connect to control socket {}: ", socket.display())), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+}
+
+#[test]
+fn mixing_time_is_opt_in() {
+    let chain = "0.5 0.5\n0.25 0.75\n";
+    let output = piped(&["--mode", "seq", "--op", "stationary", "-f", "-"], chain);
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("state 1: ") && !stderr.contains("Mixing time"), "{}", stderr);
+
+    let output = piped(&["--mode", "seq", "--op", "stationary", "--mix-time", "-f", "-"], chain);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Mixing time estimate (TV distance 0.01): "));
+    let output = piped(&["--mode", "seq", "--mix-time"], "1\nX\n1\n");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--mix-time only applies to --op stationary"));
+}
+
+#[test]
+fn coo_shapes_are_limited_before_allocating() {
+    let output = piped(&["--mode", "seq", "--input-format", "coo", "--file", "-"], "0 4000000000 1\nX\n0 0 1\n");
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("a 1x4000000001 matrix"), "{}", stderr);
+
+    let args = ["--mode", "seq", "--input-format", "coo", "--file", "-", "--max-elements", "100"];
+    let output = piped(&args, "0 0 1\n99 0 1\nX\n0 0 1\n");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let output = piped(&args, "0 0 1\n100 0 1\nX\n0 0 1\n");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("101x1 matrix has 101 elements, over the limit of 100"));
+}
+
+#[test]
+fn epilogues_on_every_path() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = |name: &str| dir.path().join(name).to_str().unwrap().to_owned();
+    fs::write(path("bias.txt"), "10 20\n30 40\n").unwrap();
+    let bias = path("bias.txt");
+    let tiles = path("tiles");
+    let runs: [(&[&str], &str); 2] = [
+        (&["--mode", "par", "--tile-dir", &tiles], "1 2\n3 4\nX\n5 6\n7 8\n"),
+        (&["--mode", "par", "--input-format", "coo", "--coo-target", "csr"], "0 0 1\n0 1 2\n1 0 3\n1 1 4\nX\n0 0 5\n0 1 6\n1 0 7\n1 1 8\n"),
+    ];
+    for (args, input) in runs {
+        let output = piped(&[args, &["--add-after", &bias, "--spot-check", "4"]].concat(), input);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(output.status.success(), "{:?}: {}", args, stderr);
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "29 42\n73 90\n", "{:?}", args);
+    }
+}
+
+#[test]
+fn padded_binary_files() {
+    const SENTINEL: f64 = -12345.678;
+    let dir = tempfile::tempdir().unwrap();
+    let path = |name: &str| dir.path().join(name).to_str().unwrap().to_owned();
+    // 1..=rows*cols in rows `ld` long, the padding SENTINEL.
+    let padded = |rows: u64, cols: u64, ld: u64| {
+        let mut bytes = b"MMULBIN\0\x01\0\0\0\0\0\0\0".to_vec();
+        bytes.extend_from_slice(&rows.to_le_bytes());
+        bytes.extend_from_slice(&cols.to_le_bytes());
+        for i in 0..rows {
+            for j in 0..ld {
+                let x = if j < cols { (i * cols + j + 1) as f64 } else { SENTINEL };
+                bytes.extend_from_slice(&x.to_le_bytes());
+            }
+        }
+        bytes
+    };
+    fs::write(path("a.bin"), padded(2, 3, 5)).unwrap();
+    fs::write(path("b.bin"), padded(3, 2, 4)).unwrap();
+
+    let (a, b) = (path("a.bin"), path("b.bin"));
+    let run = |extra: &[&str]| {
+        let mut args = vec!["--mode", "seq", "-f", &a, "-f", &b, "--lda", "5"];
+        args.extend_from_slice(extra);
+        Command::new(env!("CARGO_BIN_EXE_matrix-mul")).args(&args).output().unwrap()
+    };
+    let output = run(&["--ldb", "4", "-o", &path("c.txt")]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(fs::read_to_string(path("c.txt")).unwrap(), "22 28\n49 64\n");
+
+    // Written with padding of zeros, and read back through it.
+    let output = run(&["--ldb", "4", "--ldc", "3", "-o", &path("c.bin")]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let written = fs::read(path("c.bin")).unwrap();
+    assert_eq!(written.len(), 32 + 2 * 3 * 8);
+    assert!(written[32 + 16..32 + 24].iter().all(|&b| b == 0));
+
+    // Refused before the multiply, rather than overflowing after it.
+    let output = run(&["--ldb", "4", "--ldc", "18446744073709551615", "-o", &path("huge.bin")]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Error: 2 rows 18446744073709551615 elements apart do not fit in memory"), "{}", stderr);
+    assert!(!stderr.contains("Done!") && !Path::new(&path("huge.bin")).exists(), "{}", stderr);
+    let back = matrix_mul(&["--mode", "seq", "--op", "convert", "-f", &path("c.bin"), "--lda", "3", "-o", &path("d.txt")]);
+    assert!(back.is_empty());
+    assert_eq!(fs::read_to_string(path("d.txt")).unwrap(), "22 28\n49 64\n");
+
+    for (extra, message) in [
+        (&["--ldb", "1"][..], "leading dimension 1 is less than the 2 columns"),
+        (&[], "second matrix"),
+        (&["--ldb", "4", "--ldc", "3", "-o", &path("c.csv")], "--ldc applies only to binary outputs"),
+    ] {
+        let output = run(extra);
+        assert!(!output.status.success(), "{:?}", extra);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains(message), "{:?}: {}", extra, stderr);
+    }
+}
+
+#[test]
+fn binary_outputs_are_identical() {
+    let dir = tempfile::tempdir().unwrap();
+    let write = |name: &str, extra: &[&str]| {
+        let path = dir.path().join(name);
+        let mut args = vec!["--mode", "seq", "--op", "convert", "--size", "6", "--seed", "229", "-o"];
+        args.push(path.to_str().unwrap());
+        args.extend_from_slice(extra);
+        matrix_mul(&args);
+        fs::read(path).unwrap()
+    };
+    let first = write("first.bin", &[]);
+    assert_eq!(first.len(), 32 + 6 * 6 * 8);
+    assert_eq!(first, write("second.bin", &[]));
+    // A leading dimension of the column count is the dense layout.
+    assert_eq!(first, write("dense.bin", &["--ldc", "6"]));
+}
+
+#[test]
+fn previews_only_from_kernels_that_report_rows() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("out.txt");
+    let run = |mode: &str| {
+        let args = ["--mode", mode, "--size", "8", "--seed", "1", "--preview-every", "1s", "-o", output.to_str().unwrap()];
+        Command::new(env!("CARGO_BIN_EXE_matrix-mul")).args(args).output().unwrap()
+    };
+    for mode in ["blocked", "strassen"] {
+        let result = run(mode);
+        assert!(!result.status.success(), "{}", mode);
+        assert!(String::from_utf8_lossy(&result.stderr).contains("--preview-every needs --mode seq, par or all"));
+    }
+    assert!(run("par").status.success());
+    assert!(dir.path().join("out.preview.txt").exists());
+}
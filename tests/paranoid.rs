@@ -0,0 +1,26 @@
+//! The kernels with the invariant checks forced on, for release builds:
+//! `cargo test --release --features paranoid --test paranoid`.
+
+use matrix_mul::{Matrix, MultiplyOptions, PreparedMatrix};
+
+#[test]
+fn kernels_agree_under_the_checks() {
+    for (n, m, k) in [(1, 1, 1), (10, 5, 7), (50, 13, 70), (150, 400, 3), (64, 65, 63)] {
+        let (a, b) = (Matrix::random_seeded(n, m, 1), Matrix::random_seeded(m, k, 2));
+        let product = a.multiply(&b).unwrap();
+        assert_eq!(a.multiply_par(&b).unwrap(), product, "{}x{}x{}", n, m, k);
+        assert_eq!(a.multiply_par_profiled(&b).unwrap().0, product, "{}x{}x{}", n, m, k);
+        let blocked = a.multiply_blocked(&b, 16).unwrap();
+        assert_eq!(a.multiply_blocked_par(&b, 16).unwrap(), blocked, "{}x{}x{}", n, m, k);
+        let prepared = PreparedMatrix::prepare(&a, &MultiplyOptions::new());
+        assert_eq!(prepared.multiply(&b).unwrap(), product, "{}x{}x{}", n, m, k);
+        let strassen = a.multiply_strassen(&b, 4).unwrap();
+        assert!(strassen.approx_eq(&product, 1e-9, 1e-9), "{}x{}x{}", n, m, k);
+    }
+}
+
+#[test]
+#[should_panic(expected = "invariant: 2x2 matrix backed by 3 elements")]
+fn checks_are_on() {
+    Matrix::new_unchecked(2, 2, vec![0.0; 3]);
+}